@@ -0,0 +1,21 @@
+use near_sdk::log;
+
+/// Products above this fraction of `u128::MAX` are reported before the
+/// checked multiplication that would eventually overflow.
+pub const OVERFLOW_AUDIT_THRESHOLD: u128 = u128::MAX / 16;
+
+/// Logs a warning when `a * b` is close to (or past) the `u128` limit.
+/// Compiled in for debug builds and the `overflow-audit` feature; a no-op
+/// otherwise.
+#[cfg(any(debug_assertions, feature = "overflow-audit"))]
+pub(crate) fn audit_mul(label: &str, a: u128, b: u128) {
+    if a.checked_mul(b)
+        .is_none_or(|product| product > OVERFLOW_AUDIT_THRESHOLD)
+    {
+        log!("Overflow audit: {} near u128 limit ({} * {})", label, a, b);
+    }
+}
+
+#[cfg(not(any(debug_assertions, feature = "overflow-audit")))]
+#[inline(always)]
+pub(crate) fn audit_mul(_label: &str, _a: u128, _b: u128) {}
@@ -0,0 +1,67 @@
+use crate::types::EventLevel;
+use crate::Contract;
+use near_sdk::json_types::U128;
+use near_sdk::{near, AccountId};
+
+/// NEP-297 events of the `cdp` standard. Each belongs to an `EventLevel`
+/// and is only logged when `event_level` reaches it.
+#[near(event_json(standard = "cdp"))]
+pub enum CdpEvent {
+    #[event_version("1.0.0")]
+    Borrow {
+        owner_id: AccountId,
+        collateral_id: AccountId,
+        amount: U128,
+        fee: U128,
+    },
+    #[event_version("1.0.0")]
+    Repay {
+        owner_id: AccountId,
+        collateral_id: AccountId,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    Redeem {
+        trove_owner: AccountId,
+        collateral_id: AccountId,
+        debt_redeemed: U128,
+        collateral_out: U128,
+    },
+    #[event_version("1.0.0")]
+    Liquidate {
+        owner_id: AccountId,
+        collateral_id: AccountId,
+        debt_offset: U128,
+        collateral_seized: U128,
+    },
+    #[event_version("1.0.0")]
+    PriceUpdate {
+        collateral_id: AccountId,
+        price: U128,
+        decimals: u8,
+    },
+    /// A borrow left the trove below the recovery ratio.
+    #[event_version("1.0.0")]
+    TroveAtRisk {
+        owner_id: AccountId,
+        collateral_id: AccountId,
+        collateral_ratio_bps: U128,
+    },
+}
+
+impl CdpEvent {
+    pub fn level(&self) -> EventLevel {
+        match self {
+            CdpEvent::PriceUpdate { .. } | CdpEvent::TroveAtRisk { .. } => EventLevel::Verbose,
+            _ => EventLevel::Core,
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn emit_event(&self, event: CdpEvent) {
+        if event.level() <= self.event_level {
+            event.emit();
+        }
+    }
+}
@@ -1,109 +1,251 @@
+use crate::audit::audit_mul;
+use crate::events::CdpEvent;
 use crate::types::{
-    CollateralConfigInternal, CollateralRewardKey, PriceFeedInternal, StabilityDeposit,
-    TransferAction, TroveInternal, TroveKey, BPS_DENOMINATOR, GAS_FOR_FT_TRANSFER, REWARD_SCALE,
+    CollateralConfig, CollateralConfigInternal, CollateralRewardKey, DepositKey, InterestIndex,
+    LiquidatableTrove, LiquidationSortBy, Operation, PoolId, PriceFeedInternal, PythPrice,
+    QueuedWithdrawal, RedemptionIndexKey, RedemptionSelfPolicy, StabilityDeposit, StabilityPool,
+    StabilityPoolMode, StorageKey, SwapOutcome, SwapPurpose, SwapRequest, SwapTransferMsg,
+    TcrSnapshot, TransferAction, TreasuryConversion, TroveInternal, TroveKey, BPS_DENOMINATOR,
+    GAS_FOR_CALLBACK, GAS_FOR_CALLBACK_WITH_RETRY, GAS_FOR_FT_TRANSFER, GAS_FOR_PRICE_PULL,
+    GAS_FOR_SWAP, GAS_FOR_SWAP_TRANSFER_CALL, INTEREST_INDEX_SCALE, MAX_COLLATERALS,
+    MAX_REFERRER_BPS, MAX_SIGNED_PRICE_AGE_MS, MAX_TCR_HISTORY, MS_PER_YEAR, REWARD_SCALE,
+    TCR_HISTORY_MIN_CHANGE_BPS,
 };
-use crate::{ext_ft, Contract};
-use near_contract_standards::fungible_token::events::FtBurn;
+use crate::{ext_ft, ext_intents, ext_pyth, ext_self, Contract};
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
 use near_contract_standards::fungible_token::Balance;
+use near_sdk::collections::{TreeMap, UnorderedSet};
 use near_sdk::json_types::U128;
-use near_sdk::serde_json;
-use near_sdk::{env, require, AccountId, NearToken, Promise};
+use near_sdk::{borsh, serde_json};
+use near_sdk::{env, log, require, AccountId, NearToken, Promise, PromiseResult};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 
 impl Contract {
-    pub(crate) fn settle_stability_rewards(&mut self, account_id: &AccountId) {
-        let mut deposit = self
-            .stability_pool_deposits
-            .get(account_id)
-            .unwrap_or_else(|| StabilityDeposit::new(self.stability_pool_epoch));
-        self.ensure_deposit_epoch(account_id, &mut deposit);
-        if deposit.shares == 0 || self.stability_pool_total_shares == 0 {
-            self.stability_pool_deposits.insert(account_id, &deposit);
-            return;
+    /// Pool that absorbs liquidations of `collateral_id`.
+    pub(crate) fn pool_for_collateral(&self, collateral_id: &AccountId) -> PoolId {
+        let config = self.expect_config(collateral_id);
+        if let Some(insurance_pool_id) = config.insurance_pool_id {
+            return PoolId::Dedicated(insurance_pool_id);
         }
-        let keys = self.reward_per_share_keys();
-        let mut updated = false;
-        for collateral_id in keys {
-            let global = self.reward_per_share.get(&collateral_id).unwrap_or(0);
-            let paid = deposit
-                .reward_debt
-                .get(&collateral_id)
-                .copied()
-                .unwrap_or(0);
-            if global > paid {
-                let delta = global - paid;
-                let pending = deposit
-                    .shares
-                    .checked_mul(delta)
-                    .expect("Reward mul overflow")
-                    / REWARD_SCALE;
-                if pending > 0 {
-                    self.enqueue_collateral_reward(account_id, &collateral_id, pending);
-                }
+        match config.stability_pool_mode {
+            StabilityPoolMode::Shared => PoolId::Shared,
+            StabilityPoolMode::Dedicated => PoolId::Dedicated(collateral_id.clone()),
+        }
+    }
+
+    /// Settles the caller's deposits in every pool that can hold
+    /// `collateral_id` rewards.
+    pub(crate) fn settle_collateral_rewards(
+        &mut self,
+        account_id: &AccountId,
+        collateral_id: &AccountId,
+    ) {
+        let dedicated = PoolId::Dedicated(collateral_id.clone());
+        self.settle_stability_rewards(account_id, &dedicated);
+        if let Some(insurance_pool_id) = self
+            .configs
+            .get(collateral_id)
+            .and_then(|config| config.insurance_pool_id)
+        {
+            let pool_id = PoolId::Dedicated(insurance_pool_id);
+            if pool_id != dedicated {
+                self.settle_stability_rewards(account_id, &pool_id);
             }
-            deposit.reward_debt.insert(collateral_id.clone(), global);
-            updated = true;
         }
-        if updated {
-            self.stability_pool_deposits.insert(account_id, &deposit);
+    }
+
+    /// Maps the optional `collateral_id` argument of the pool methods to a
+    /// pool: `None` selects the shared pool.
+    pub(crate) fn resolve_pool(collateral_id: Option<AccountId>) -> PoolId {
+        collateral_id.map_or(PoolId::Shared, PoolId::Dedicated)
+    }
+
+    pub(crate) fn pool(&self, pool_id: &PoolId) -> StabilityPool {
+        self.stability_pools.get(pool_id).unwrap_or_default()
+    }
+
+    pub(crate) fn settle_stability_rewards(&mut self, account_id: &AccountId, pool_id: &PoolId) {
+        let key = DepositKey::new(account_id, pool_id);
+        if self.stability_pool_deposits.get(&key).is_none() {
+            return;
+        }
+        let pool = self.pool(pool_id);
+        let deposit = self.settled_deposit(account_id, pool_id, &pool);
+        self.stability_pool_deposits.insert(&key, &deposit);
+    }
+
+    /// Loads a deposit with everything it has earned moved into the
+    /// claimable balance and its snapshot synced to the live accumulators.
+    pub(crate) fn settled_deposit(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: &PoolId,
+        pool: &StabilityPool,
+    ) -> StabilityDeposit {
+        let mut deposit = self
+            .stability_pool_deposits
+            .get(&DepositKey::new(account_id, pool_id))
+            .unwrap_or_else(|| StabilityDeposit::new(pool.epoch));
+        self.ensure_deposit_epoch(account_id, pool_id, pool, &mut deposit);
+        for (collateral_id, global) in &pool.reward_per_share {
+            let pending = deposit.pending_reward(collateral_id, *global);
+            self.settle_pool_reward(account_id, collateral_id, pending);
+            deposit.reward_debt.insert(collateral_id.clone(), *global);
         }
+        deposit
     }
 
+    /// Pays out a deposit left over from a drained epoch using the
+    /// accumulator values frozen when that epoch ended, then resets it into
+    /// the current epoch with zero shares.
     pub(crate) fn ensure_deposit_epoch(
         &mut self,
         account_id: &AccountId,
+        pool_id: &PoolId,
+        pool: &StabilityPool,
         deposit: &mut StabilityDeposit,
     ) {
-        if deposit.epoch == self.stability_pool_epoch {
+        if deposit.epoch == pool.epoch {
             return;
         }
         if deposit.shares > 0 {
-            let keys = self.reward_per_share_keys();
-            for collateral_id in keys {
-                let global = self.reward_per_share.get(&collateral_id).unwrap_or(0);
-                let paid = deposit
-                    .reward_debt
-                    .get(&collateral_id)
-                    .copied()
-                    .unwrap_or(0);
-                if global > paid {
-                    let pending = deposit
-                        .shares
-                        .checked_mul(global - paid)
-                        .expect("Epoch reward overflow")
-                        / REWARD_SCALE;
-                    if pending > 0 {
-                        self.enqueue_collateral_reward(account_id, &collateral_id, pending);
-                    }
-                }
+            for (collateral_id, global) in self.epoch_reward_per_share(pool_id, deposit.epoch) {
+                let pending = deposit.pending_reward(&collateral_id, global);
+                self.settle_pool_reward(account_id, &collateral_id, pending);
             }
         }
         deposit.reward_debt.clear();
         deposit.shares = 0;
-        deposit.epoch = self.stability_pool_epoch;
+        deposit.queued_shares = 0;
+        deposit.epoch = pool.epoch;
     }
 
-    pub(crate) fn shares_from_amount(&self, amount: Balance) -> Balance {
-        if self.stability_pool_total_shares == 0 || self.stability_pool_total_nusd == 0 {
-            amount
+    /// Per-collateral reward accumulators of `pool_id` as they stood at the
+    /// end of `epoch`, or their live values for the current epoch.
+    pub(crate) fn epoch_reward_per_share(
+        &self,
+        pool_id: &PoolId,
+        epoch: u64,
+    ) -> BTreeMap<AccountId, u128> {
+        let pool = self.pool(pool_id);
+        if epoch == pool.epoch {
+            pool.reward_per_share
         } else {
-            amount
-                .checked_mul(self.stability_pool_total_shares)
-                .expect("Share calc overflow")
-                / self.stability_pool_total_nusd
+            self.epoch_reward_snapshots
+                .get(&(pool_id.clone(), epoch))
+                .unwrap_or_default()
         }
     }
 
-    pub(crate) fn shares_for_withdraw(&self, amount: Balance) -> Balance {
-        self.shares_from_amount(amount)
+    /// Credits `amount` of nUSD already held by the contract to the
+    /// account's deposit in `pool_id`.
+    pub(crate) fn pool_deposit(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: &PoolId,
+        amount: Balance,
+    ) {
+        let mut pool = self.pool(pool_id);
+        let mut deposit = self.settled_deposit(account_id, pool_id, &pool);
+        self.index_stability_depositor(account_id, pool_id, deposit.shares);
+        let shares = pool.shares_from_amount(amount);
+        require!(shares > 0, "Shares must be > 0");
+        deposit.shares = deposit
+            .shares
+            .checked_add(shares)
+            .expect("Deposit share overflow");
+        deposit.last_deposit_timestamp = Self::now_ms();
+        pool.total_shares = pool
+            .total_shares
+            .checked_add(shares)
+            .expect("Pool share overflow");
+        pool.total_nusd = pool
+            .total_nusd
+            .checked_add(amount)
+            .expect("Pool balance overflow");
+        self.stability_pool_total_nusd = self
+            .stability_pool_total_nusd
+            .checked_add(amount)
+            .expect("Pool balance overflow");
+        self.stability_pools.insert(pool_id, &pool);
+        self.stability_pool_deposits
+            .insert(&DepositKey::new(account_id, pool_id), &deposit);
+    }
+
+    /// Removes `amount` (or the whole deposit) from `pool_id` and returns
+    /// the nUSD released. The nUSD stays on the contract account.
+    pub(crate) fn pool_withdraw(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: &PoolId,
+        amount: Option<Balance>,
+    ) -> Balance {
+        let mut pool = self.pool(pool_id);
+        let mut deposit = self.settled_deposit(account_id, pool_id, &pool);
+        require!(deposit.shares > 0, "Nothing deposited");
+        self.index_stability_depositor(account_id, pool_id, deposit.shares);
+        let available = pool.amount_from_shares(deposit.unlocked_shares());
+        require!(
+            available > 0 || deposit.queued_shares == 0,
+            "Deposit locked by a queued withdrawal"
+        );
+        require!(available > 0, "Pool depleted");
+        let requested = amount.unwrap_or(available);
+        require!(requested > 0, "Amount must be > 0");
+        require!(requested <= available, "Insufficient balance");
+        let shares = pool.shares_from_amount(requested);
+        require!(shares > 0, "Share calculation underflow");
+
+        deposit.shares = deposit
+            .shares
+            .checked_sub(shares)
+            .expect("Withdraw exceeds shares");
+        pool.total_shares = pool
+            .total_shares
+            .checked_sub(shares)
+            .expect("Pool share underflow");
+        pool.total_nusd = pool
+            .total_nusd
+            .checked_sub(requested)
+            .expect("Pool balance underflow");
+        self.stability_pool_total_nusd = self
+            .stability_pool_total_nusd
+            .checked_sub(requested)
+            .expect("Pool balance underflow");
+        self.stability_pools.insert(pool_id, &pool);
+        self.stability_pool_deposits
+            .insert(&DepositKey::new(account_id, pool_id), &deposit);
+        if deposit.shares == 0 {
+            let mut depositors = self.stability_depositor_set(pool_id);
+            depositors.remove(account_id);
+            self.stability_depositors.insert(pool_id, &depositors);
+        }
+        if pool.total_shares == 0 {
+            self.unindexed_stability_shares.remove(pool_id);
+        }
+        requested
     }
 
-    pub(crate) fn reward_per_share_keys(&self) -> Vec<AccountId> {
-        let keys = self.reward_per_share.keys_as_vector();
-        let mut collaterals = Vec::with_capacity(keys.len() as usize);
-        for idx in 0..keys.len() {
-            collaterals.push(keys.get(idx).unwrap());
+    /// Adds `account_id` to the depositor index of `pool_id`. The `shares`
+    /// it already holds stop counting as unindexed if it was missing.
+    pub(crate) fn index_stability_depositor(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: &PoolId,
+        shares: Balance,
+    ) {
+        let mut depositors = self.stability_depositor_set(pool_id);
+        if !depositors.insert(account_id) {
+            return;
+        }
+        self.stability_depositors.insert(pool_id, &depositors);
+        if let Some(unindexed) = self.unindexed_stability_shares.get(pool_id) {
+            match unindexed.saturating_sub(shares) {
+                0 => self.unindexed_stability_shares.remove(pool_id),
+                left => self.unindexed_stability_shares.insert(pool_id, &left),
+            };
         }
-        collaterals
     }
 
     pub(crate) fn enqueue_collateral_reward(
@@ -119,6 +261,30 @@ impl Contract {
         let mut current = self.collateral_rewards.get(&key).unwrap_or(0);
         current = current.checked_add(amount).expect("Reward overflow");
         self.collateral_rewards.insert(&key, &current);
+        let total = self
+            .collateral_reward_totals
+            .get(collateral_id)
+            .unwrap_or(0);
+        self.collateral_reward_totals.insert(
+            collateral_id,
+            &total.checked_add(amount).expect("Reward overflow"),
+        );
+    }
+
+    /// Moves a depositor's accrued pool reward into their claimable balance.
+    pub(crate) fn settle_pool_reward(
+        &mut self,
+        account_id: &AccountId,
+        collateral_id: &AccountId,
+        amount: Balance,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let unsettled = self.unsettled_rewards.get(collateral_id).unwrap_or(0);
+        self.unsettled_rewards
+            .insert(collateral_id, &unsettled.saturating_sub(amount));
+        self.enqueue_collateral_reward(account_id, collateral_id, amount);
     }
 
     pub(crate) fn claim_collateral(
@@ -128,52 +294,143 @@ impl Contract {
         amount: Option<Balance>,
     ) -> Promise {
         let key = CollateralRewardKey::new(account_id, collateral_id);
-        let mut claimable = self.collateral_rewards.get(&key).unwrap_or(0);
+        let claimable = self.collateral_rewards.get(&key).unwrap_or(0);
         require!(claimable > 0, "Nothing to claim");
         let to_claim = amount.unwrap_or(claimable);
-        require!(to_claim > 0, "Amount must be > 0");
-        require!(to_claim <= claimable, "Amount exceeds claimable");
-        claimable -= to_claim;
+        require!(
+            to_claim >= self.min_claimable(collateral_id),
+            "Claim below minimum claimable amount"
+        );
+        self.debit_collateral_reward(account_id, collateral_id, to_claim);
+        if *account_id == self.treasury_id {
+            self.ensure_treasury_reserve(collateral_id);
+        }
+        self.send_collateral(account_id.clone(), collateral_id.clone(), to_claim)
+    }
+
+    /// nUSD value of treasury-held `collateral_id` required by
+    /// `min_treasury_reserve_bps`, and the value actually held. `None`
+    /// without a price.
+    pub(crate) fn treasury_reserve(&self, collateral_id: &AccountId) -> Option<(Balance, Balance)> {
+        let config = self.configs.get(collateral_id)?;
+        let price = self.price_feeds.get(collateral_id)?;
+        let required = Self::mul_div(
+            self.total_debt.get(collateral_id).unwrap_or(0),
+            config.min_treasury_reserve_bps as u128,
+            BPS_DENOMINATOR,
+        );
+        let held = self
+            .collateral_rewards
+            .get(&CollateralRewardKey::new(&self.treasury_id, collateral_id))
+            .unwrap_or(0);
+        Some((required, Self::collateral_value(held, &price)))
+    }
+
+    /// Rejects a treasury withdrawal that left its reserve of
+    /// `collateral_id` below the floor.
+    pub(crate) fn ensure_treasury_reserve(&self, collateral_id: &AccountId) {
+        let floor = self
+            .configs
+            .get(collateral_id)
+            .map_or(0, |config| config.min_treasury_reserve_bps);
+        if floor == 0 {
+            return;
+        }
+        require!(
+            self.treasury_reserve(collateral_id)
+                .is_some_and(|(required, held)| held >= required),
+            "Treasury reserve floor breached"
+        );
+    }
+
+    pub(crate) fn min_claimable(&self, collateral_id: &AccountId) -> Balance {
+        self.configs
+            .get(collateral_id)
+            .map_or(0, |config| config.min_claimable)
+    }
+
+    pub(crate) fn debit_collateral_reward(
+        &mut self,
+        account_id: &AccountId,
+        collateral_id: &AccountId,
+        amount: Balance,
+    ) {
+        require!(amount > 0, "Amount must be > 0");
+        let key = CollateralRewardKey::new(account_id, collateral_id);
+        let mut claimable = self.collateral_rewards.get(&key).unwrap_or(0);
+        require!(amount <= claimable, "Amount exceeds claimable");
+        claimable -= amount;
         if claimable == 0 {
             self.collateral_rewards.remove(&key);
         } else {
             self.collateral_rewards.insert(&key, &claimable);
         }
-        self.send_collateral(account_id.clone(), collateral_id.clone(), to_claim)
+        // Rewards carried over by `migrate` were never added to the total.
+        let total = self
+            .collateral_reward_totals
+            .get(collateral_id)
+            .unwrap_or(0);
+        self.collateral_reward_totals
+            .insert(collateral_id, &total.saturating_sub(amount));
     }
 
     pub(crate) fn accrue_reward_per_share(
         &mut self,
+        pool_id: &PoolId,
         collateral_id: &AccountId,
         reward_amount: Balance,
     ) {
         if reward_amount == 0 {
             return;
         }
-        if self.stability_pool_total_shares == 0 {
-            let owner_id = self.owner_id.clone();
-            self.enqueue_collateral_reward(&owner_id, collateral_id, reward_amount);
+        let mut pool = self.pool(pool_id);
+        if pool.total_shares == 0 {
+            let treasury_id = self.treasury_id.clone();
+            self.enqueue_collateral_reward(&treasury_id, collateral_id, reward_amount);
             return;
         }
-        let mut accrued = self.reward_per_share.get(collateral_id).unwrap_or(0);
-        accrued = accrued
-            .checked_add(
-                reward_amount
-                    .checked_mul(REWARD_SCALE)
-                    .expect("Reward scaling overflow")
-                    / self.stability_pool_total_shares,
-            )
+        audit_mul("reward scaling", reward_amount, REWARD_SCALE);
+        let increment = reward_amount
+            .checked_mul(REWARD_SCALE)
+            .expect("Reward scaling overflow")
+            / pool.total_shares;
+        let accrued = pool
+            .reward_per_share
+            .entry(collateral_id.clone())
+            .or_insert(0);
+        *accrued = accrued
+            .checked_add(increment)
             .expect("Reward per share overflow");
-        self.reward_per_share.insert(collateral_id, &accrued);
+        // Track what the accumulator actually hands out; the rounding
+        // remainder would otherwise sit unclaimable in the contract.
+        audit_mul("reward distribution", increment, pool.total_shares);
+        let distributed = increment
+            .checked_mul(pool.total_shares)
+            .expect("Reward scaling overflow")
+            / REWARD_SCALE;
+        self.stability_pools.insert(pool_id, &pool);
+        let treasury_id = self.treasury_id.clone();
+        self.enqueue_collateral_reward(&treasury_id, collateral_id, reward_amount - distributed);
+        let unsettled = self.unsettled_rewards.get(collateral_id).unwrap_or(0);
+        self.unsettled_rewards.insert(
+            collateral_id,
+            &unsettled.checked_add(distributed).expect("Reward overflow"),
+        );
     }
 
-    pub(crate) fn burn_from_stability_pool(&mut self, amount: Balance) {
+    pub(crate) fn burn_from_stability_pool(&mut self, pool_id: &PoolId, amount: Balance) {
         require!(amount > 0, "Amount must be > 0");
+        self.ensure_self_registered();
+        let mut pool = self.pool(pool_id);
         require!(
-            self.stability_pool_total_nusd >= amount,
+            pool.total_nusd >= amount,
             "Insufficient stability pool balance"
         );
-        self.stability_pool_total_nusd -= amount;
+        pool.total_nusd -= amount;
+        self.stability_pool_total_nusd = self
+            .stability_pool_total_nusd
+            .checked_sub(amount)
+            .expect("Pool balance underflow");
         self.nusd
             .internal_withdraw(&env::current_account_id(), amount);
         FtBurn {
@@ -182,40 +439,272 @@ impl Contract {
             memo: Some("cdp_liquidation"),
         }
         .emit();
-        if self.stability_pool_total_nusd == 0 {
-            self.stability_pool_total_shares = 0;
-            self.stability_pool_epoch = self.stability_pool_epoch.saturating_add(1);
+        if pool.total_nusd == 0 {
+            // Deposits from the drained epoch settle against the frozen
+            // snapshot, so the live accumulators restart from zero.
+            let snapshot = std::mem::take(&mut pool.reward_per_share);
+            self.epoch_reward_snapshots
+                .insert(&(pool_id.clone(), pool.epoch), &snapshot);
+            pool.total_shares = 0;
+            pool.epoch = pool.epoch.saturating_add(1);
+            self.unindexed_stability_shares.remove(pool_id);
+        }
+        self.stability_pools.insert(pool_id, &pool);
+    }
+
+    /// Registers the contract's own account with nUSD if it somehow lost
+    /// its registration; stability pool nUSD is held there.
+    pub(crate) fn ensure_self_registered(&mut self) {
+        let current_id = env::current_account_id();
+        if !self.nusd.accounts.contains_key(&current_id) {
+            self.nusd.internal_register_account(&current_id);
+            log!("Re-registered {} for nUSD", current_id);
+        }
+    }
+
+    /// Adds nUSD already held by the contract to `pool_id` without minting
+    /// shares, raising the value of every existing share. With no depositors
+    /// left the nUSD goes to the treasury instead.
+    pub(crate) fn credit_stability_pool(&mut self, pool_id: &PoolId, amount: Balance) {
+        let mut pool = self.pool(pool_id);
+        if pool.total_shares == 0 {
+            self.nusd.internal_transfer(
+                &env::current_account_id(),
+                &self.treasury_id.clone(),
+                amount,
+                Some("cdp_rebalance".to_string()),
+            );
+            return;
         }
+        pool.total_nusd = pool
+            .total_nusd
+            .checked_add(amount)
+            .expect("Stability pool overflow");
+        self.stability_pool_total_nusd = self
+            .stability_pool_total_nusd
+            .checked_add(amount)
+            .expect("Stability pool overflow");
+        self.stability_pools.insert(pool_id, &pool);
+    }
+
+    pub(crate) fn borrow_fee(config: &CollateralConfigInternal, amount: Balance) -> Balance {
+        Self::mul_div(amount, config.borrow_fee_bps as u128, BPS_DENOMINATOR)
+    }
+
+    /// Part of `collateral_out` a redemption keeps back for the treasury.
+    pub(crate) fn redemption_fee(
+        config: &CollateralConfigInternal,
+        collateral_out: Balance,
+    ) -> Balance {
+        Self::mul_div(
+            collateral_out,
+            config.redemption_fee_bps as u128,
+            BPS_DENOMINATOR,
+        )
     }
 
-    pub(crate) fn sync_reward_debt_snapshot(&self, deposit: &mut StabilityDeposit) {
-        for collateral_id in self.reward_per_share_keys() {
-            let global = self.reward_per_share.get(&collateral_id).unwrap_or(0);
-            deposit.reward_debt.insert(collateral_id, global);
+    /// Mints a borrow fee: `fee_to_stability_pool_bps` of it tops up the
+    /// collateral's stability pool, the rest goes to the treasury.
+    pub(crate) fn distribute_borrow_fee(&mut self, collateral_id: &AccountId, fee: Balance) {
+        if fee == 0 {
+            return;
+        }
+        let to_pool = Self::mul_div(fee, self.fee_to_stability_pool_bps as u128, BPS_DENOMINATOR);
+        if to_pool > 0 {
+            let current_id = env::current_account_id();
+            self.nusd.internal_deposit(&current_id, to_pool);
+            FtMint {
+                owner_id: &current_id,
+                amount: U128(to_pool),
+                memo: Some("cdp_borrow_fee"),
+            }
+            .emit();
+            self.credit_stability_pool(&self.pool_for_collateral(collateral_id), to_pool);
+        }
+        let treasury_share = fee - to_pool;
+        if treasury_share > 0 {
+            let treasury_id = self.treasury_id.clone();
+            self.nusd.internal_deposit(&treasury_id, treasury_share);
+            FtMint {
+                owner_id: &treasury_id,
+                amount: U128(treasury_share),
+                memo: Some("cdp_borrow_fee"),
+            }
+            .emit();
         }
     }
+
+    /// nUSD held on the contract account that is not backing stability pool
+    /// deposits, e.g. swap output delivered by the Intents router.
+    pub(crate) fn unallocated_nusd(&self) -> Balance {
+        self.nusd
+            .internal_unwrap_balance_of(&env::current_account_id())
+            .saturating_sub(self.stability_pool_total_nusd)
+            .saturating_sub(self.insurance_fund_balance)
+    }
+
+    /// Sends `request` to `router_id` and settles it in `on_swap_complete` for
+    /// `caller_id`. With `retry`, a failed swap is re-issued once through the
+    /// fallback router.
+    pub(crate) fn dispatch_swap(
+        &self,
+        router_id: AccountId,
+        request: SwapRequest,
+        caller_id: AccountId,
+        purpose: SwapPurpose,
+        retry: bool,
+    ) -> Promise {
+        let callback_gas = if retry {
+            GAS_FOR_CALLBACK_WITH_RETRY
+        } else {
+            GAS_FOR_CALLBACK
+        };
+        ext_intents::ext(router_id)
+            .with_attached_deposit(NearToken::from_yoctonear(request.deposit.0))
+            .with_static_gas(GAS_FOR_SWAP)
+            .execute_swap(
+                request.caller_id.clone(),
+                request.input_token.clone(),
+                request.output_token.clone(),
+                request.amount_in,
+                request.min_out,
+                request.routing_hint.clone(),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(callback_gas)
+                    .on_swap_complete(
+                        caller_id,
+                        request.input_token.clone(),
+                        request.amount_in,
+                        request.deposit,
+                        purpose,
+                        retry.then_some(request),
+                    ),
+            )
+    }
+
+    /// Hands treasury collateral to `router_id` with `ft_transfer_call`; the
+    /// router pays the nUSD straight to the treasury and returns any input
+    /// it did not swap.
+    pub(crate) fn dispatch_treasury_convert(
+        &self,
+        router_id: AccountId,
+        conversion: TreasuryConversion,
+        retry: bool,
+    ) -> Promise {
+        let callback_gas = if retry {
+            GAS_FOR_CALLBACK_WITH_RETRY
+        } else {
+            GAS_FOR_CALLBACK
+        };
+        let msg = serde_json::to_string(&SwapTransferMsg {
+            output_token: env::current_account_id(),
+            min_out: conversion.min_out,
+            routing_hint: conversion.routing_hint.clone(),
+            receiver_id: conversion.treasury_id.clone(),
+        })
+        .expect("Swap message serialization");
+        ext_ft::ext(conversion.collateral_id.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(GAS_FOR_SWAP_TRANSFER_CALL)
+            .ft_transfer_call(router_id, conversion.amount, None, msg)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(callback_gas)
+                    .on_treasury_convert(conversion, retry),
+            )
+    }
+
+    /// Attached NEAR the router reported as unused, out of `deposit`.
+    pub(crate) fn unused_swap_deposit(result: &PromiseResult, deposit: Balance) -> Balance {
+        match result {
+            PromiseResult::Successful(value) => serde_json::from_slice::<SwapOutcome>(value)
+                .ok()
+                .and_then(|outcome| outcome.deposit_used)
+                .map_or(0, |used| deposit.saturating_sub(used.0)),
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn swap_amount_out(result: PromiseResult) -> Option<Balance> {
+        match result {
+            PromiseResult::Successful(value) => serde_json::from_slice::<SwapOutcome>(&value)
+                .ok()
+                .map(|outcome| outcome.amount_out.0),
+            _ => None,
+        }
+    }
+
     pub(crate) fn internal_deposit_collateral(
         &mut self,
         owner_id: AccountId,
         collateral_id: AccountId,
         amount: Balance,
     ) {
+        self.assert_not_paused();
         require!(amount > 0, "Amount must be > 0");
-        self.expect_config(&collateral_id);
-        let key = Self::trove_key(&owner_id, &collateral_id);
-        let mut trove = self.troves.get(&key).unwrap_or(TroveInternal {
-            owner_id: owner_id.clone(),
-            collateral_id: collateral_id.clone(),
-            collateral_amount: 0,
-            debt_amount: 0,
-            last_update_timestamp: Self::now_ms(),
-        });
+        let config = self.expect_config(&collateral_id);
+        let amount = Self::net_of_transfer_fee(&config, amount);
+        require!(amount > 0, "Amount too small after transfer fee");
+        self.credit_trove_collateral(owner_id, collateral_id, amount);
+    }
+
+    /// Adds collateral the contract already holds to the owner's trove,
+    /// opening one if needed.
+    pub(crate) fn credit_trove_collateral(
+        &mut self,
+        owner_id: AccountId,
+        collateral_id: AccountId,
+        amount: Balance,
+    ) {
+        let config = self.expect_config(&collateral_id);
+        self.assert_not_written_off(&collateral_id);
+        let mut trove = self
+            .troves
+            .get(&Self::trove_key(&owner_id, &collateral_id))
+            .unwrap_or_else(|| {
+                if let Some(max_troves) = config.max_active_troves {
+                    require!(
+                        self.trove_owner_set(&collateral_id).len() < max_troves,
+                        "Collateral trove limit reached"
+                    );
+                }
+                self.add_trove_owner(&owner_id, &collateral_id);
+                TroveInternal {
+                    owner_id: owner_id.clone(),
+                    collateral_id: collateral_id.clone(),
+                    collateral_amount: 0,
+                    debt_amount: 0,
+                    last_update_timestamp: Self::now_ms(),
+                    last_liquidation_timestamp: 0,
+                    opened_timestamp: Self::now_ms(),
+                    interest_index: 0,
+                }
+            });
+        self.accrue_interest(&mut trove);
         trove.collateral_amount = trove
             .collateral_amount
             .checked_add(amount)
             .expect("Collateral overflow");
         trove.last_update_timestamp = Self::now_ms();
-        self.troves.insert(&key, &trove);
+        self.save_trove(&owner_id, &collateral_id, &trove);
+    }
+
+    /// The part of a deposit the contract can count on receiving, rounding
+    /// the fee up.
+    pub(crate) fn net_of_transfer_fee(
+        config: &CollateralConfigInternal,
+        amount: Balance,
+    ) -> Balance {
+        if !config.has_transfer_fee {
+            return amount;
+        }
+        let fee = amount
+            .checked_mul(config.transfer_fee_bps as u128)
+            .expect("Transfer fee overflow")
+            .div_ceil(BPS_DENOMINATOR);
+        amount - fee
     }
 
     pub(crate) fn send_collateral(
@@ -247,6 +736,19 @@ impl Contract {
             .unwrap_or_else(|| env::panic_str("Price not available"))
     }
 
+    /// The stored price, rejected once older than `max_price_age_ms`.
+    pub(crate) fn expect_fresh_price_internal(
+        &self,
+        collateral_id: &AccountId,
+    ) -> PriceFeedInternal {
+        let price = self.expect_price_internal(collateral_id);
+        require!(
+            !self.is_feed_stale(&price, &self.expect_config(collateral_id)),
+            "Price too stale"
+        );
+        price
+    }
+
     pub(crate) fn expect_trove(
         &self,
         owner_id: &AccountId,
@@ -257,17 +759,369 @@ impl Contract {
             .unwrap_or_else(|| env::panic_str("Trove not found"))
     }
 
+    /// Loads a trove with interest accrued up to now, persisting the accrual.
+    pub(crate) fn expect_trove_accrued(
+        &mut self,
+        owner_id: &AccountId,
+        collateral_id: &AccountId,
+    ) -> TroveInternal {
+        let mut trove = self.expect_trove(owner_id, collateral_id);
+        if self.accrue_interest(&mut trove) > 0 {
+            self.save_trove(owner_id, collateral_id, &trove);
+        }
+        trove
+    }
+
+    /// The trove as it would look after accruing interest now.
+    pub(crate) fn projected_trove(
+        &self,
+        owner_id: &AccountId,
+        collateral_id: &AccountId,
+    ) -> Option<TroveInternal> {
+        let mut trove = self.troves.get(&Self::trove_key(owner_id, collateral_id))?;
+        if let Some(config) = self.configs.get(collateral_id) {
+            let index = self.current_interest_index(collateral_id, &config);
+            trove.debt_amount = trove
+                .debt_amount
+                .checked_add(Self::pending_interest(&trove, index.value))
+                .expect("Debt overflow");
+        }
+        Some(trove)
+    }
+
+    /// The collateral's annual rate right now. With `dynamic_interest` it
+    /// stays at `interest_rate_bps` up to `interest_kink_bps` of the debt
+    /// ceiling, then rises linearly to `max_interest_rate_bps` at the
+    /// ceiling. The interest index grows at this rate until utilization or
+    /// the config next changes.
+    pub(crate) fn interest_rate_bps(
+        &self,
+        collateral_id: &AccountId,
+        config: &CollateralConfigInternal,
+    ) -> u16 {
+        if !config.dynamic_interest {
+            return config.interest_rate_bps;
+        }
+        let total_debt = self.total_debt.get(collateral_id).unwrap_or(0);
+        let ceiling = Self::effective_debt_ceiling(config);
+        let utilization = if ceiling == 0 {
+            BPS_DENOMINATOR
+        } else {
+            Self::mul_div(total_debt, BPS_DENOMINATOR, ceiling).min(BPS_DENOMINATOR)
+        };
+        let kink = config.interest_kink_bps as u128;
+        if utilization <= kink {
+            return config.interest_rate_bps;
+        }
+        let spread = (config.max_interest_rate_bps - config.interest_rate_bps) as u128;
+        let increase = Self::mul_div(spread, utilization - kink, BPS_DENOMINATOR - kink);
+        config.interest_rate_bps + increase as u16
+    }
+
+    /// The collateral's interest index grown at the current rate since its
+    /// last update.
+    pub(crate) fn current_interest_index(
+        &self,
+        collateral_id: &AccountId,
+        config: &CollateralConfigInternal,
+    ) -> InterestIndex {
+        let now = Self::now_ms();
+        let Some(mut index) = self.interest_indices.get(collateral_id) else {
+            return InterestIndex {
+                value: INTEREST_INDEX_SCALE,
+                last_update_timestamp: now,
+            };
+        };
+        let rate = self.interest_rate_bps(collateral_id, config);
+        let elapsed = now.saturating_sub(index.last_update_timestamp);
+        if rate > 0 && elapsed > 0 {
+            let growth = Self::mul_div(
+                index.value,
+                rate as u128 * elapsed as u128,
+                BPS_DENOMINATOR * MS_PER_YEAR as u128,
+            );
+            index.value = index
+                .value
+                .checked_add(growth)
+                .expect("Interest index overflow");
+        }
+        index.last_update_timestamp = now;
+        index
+    }
+
+    /// Brings the collateral's interest index up to now. Called before
+    /// anything that changes its rate: total debt or the config.
+    pub(crate) fn update_interest_index(&mut self, collateral_id: &AccountId) -> u128 {
+        let config = self.expect_config(collateral_id);
+        let index = self.current_interest_index(collateral_id, &config);
+        self.interest_indices.insert(collateral_id, &index);
+        index.value
+    }
+
+    /// Interest on the trove's debt since its index snapshot.
+    pub(crate) fn pending_interest(trove: &TroveInternal, interest_index: u128) -> Balance {
+        if trove.debt_amount == 0
+            || trove.interest_index == 0
+            || interest_index <= trove.interest_index
+        {
+            return 0;
+        }
+        Self::mul_div(trove.debt_amount, interest_index, trove.interest_index) - trove.debt_amount
+    }
+
+    /// Adds pending interest to the trove's debt, moves its index snapshot
+    /// to now and mints the interest as nUSD: `interest_to_equity_bps` of
+    /// it to the insurance fund, the rest to the treasury. Returns the
+    /// interest accrued.
+    pub(crate) fn accrue_interest(&mut self, trove: &mut TroveInternal) -> Balance {
+        let index = self.update_interest_index(&trove.collateral_id);
+        let interest = Self::pending_interest(trove, index);
+        trove.interest_index = index;
+        if interest == 0 {
+            return 0;
+        }
+        trove.debt_amount = trove
+            .debt_amount
+            .checked_add(interest)
+            .expect("Debt overflow");
+        trove.last_update_timestamp = Self::now_ms();
+        // Interest may push a collateral past its ceiling; the ceiling only
+        // limits new borrowing.
+        let total = self.total_debt.get(&trove.collateral_id).unwrap_or(0);
+        self.total_debt.insert(
+            &trove.collateral_id,
+            &total.checked_add(interest).expect("Total debt overflow"),
+        );
+
+        let equity = interest
+            .checked_mul(self.interest_to_equity_bps as u128)
+            .expect("Interest split overflow")
+            / BPS_DENOMINATOR;
+        if equity > 0 {
+            let current_id = env::current_account_id();
+            self.nusd.internal_deposit(&current_id, equity);
+            self.insurance_fund_balance = self
+                .insurance_fund_balance
+                .checked_add(equity)
+                .expect("Insurance fund overflow");
+            FtMint {
+                owner_id: &current_id,
+                amount: U128(equity),
+                memo: Some("cdp_insurance_fund"),
+            }
+            .emit();
+        }
+        let treasury_share = interest - equity;
+        if treasury_share > 0 {
+            let treasury_id = self.treasury_id.clone();
+            self.nusd.internal_deposit(&treasury_id, treasury_share);
+            FtMint {
+                owner_id: &treasury_id,
+                amount: U128(treasury_share),
+                memo: Some("cdp_interest"),
+            }
+            .emit();
+        }
+        interest
+    }
+
+    /// Splits a redemption of `amount` nUSD into the collateral paid out, the
+    /// nUSD that exactly pays for it (rounded up) and the unused remainder,
+    /// which stays with the redeemer.
+    pub(crate) fn redeem_split(
+        amount: Balance,
+        price: &PriceFeedInternal,
+    ) -> (Balance, Balance, Balance) {
+        let divisor = Self::decimals_factor(price.decimals);
+        let collateral_out =
+            amount.checked_mul(divisor).expect("Redeem amount overflow") / price.price;
+        let nusd_used = collateral_out
+            .checked_mul(price.price)
+            .expect("Redeem value overflow")
+            .div_ceil(divisor);
+        (collateral_out, nusd_used, amount - nusd_used)
+    }
+
+    /// `a * b / c` rounded down, without overflowing on the full
+    /// intermediate product.
+    pub(crate) fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+        (a / c)
+            .checked_mul(b)
+            .and_then(|whole| whole.checked_add((a % c).checked_mul(b)? / c))
+            .expect("Mul div overflow")
+    }
+
+    /// `a * b / c` rounded up.
+    pub(crate) fn mul_div_ceil(a: u128, b: u128, c: u128) -> u128 {
+        let remainder = (a % c).checked_mul(b).expect("Mul div overflow") % c;
+        Self::mul_div(a, b, c) + u128::from(remainder != 0)
+    }
+
     pub(crate) fn save_trove(
         &mut self,
         owner_id: &AccountId,
         collateral_id: &AccountId,
         trove: &TroveInternal,
     ) {
-        self.troves
+        let previous = self
+            .troves
             .insert(&Self::trove_key(owner_id, collateral_id), trove);
+        self.reindex_for_redemption(collateral_id, previous.as_ref(), Some(trove));
+        let total = self.total_collateral.get(collateral_id).unwrap_or(0);
+        let total = (total + trove.collateral_amount)
+            .checked_sub(previous.map_or(0, |previous| previous.collateral_amount))
+            .expect("Total collateral underflow");
+        self.total_collateral.insert(collateral_id, &total);
+        self.record_tcr(collateral_id);
+    }
+
+    pub(crate) fn remove_trove(&mut self, owner_id: &AccountId, collateral_id: &AccountId) {
+        let key = Self::trove_key(owner_id, collateral_id);
+        self.merge_approvals.remove(&key);
+        self.rebase_divergences.remove(&key);
+        if let Some(trove) = self.troves.remove(&key) {
+            self.reindex_for_redemption(collateral_id, Some(&trove), None);
+            let total = self.total_collateral.get(collateral_id).unwrap_or(0);
+            self.total_collateral.insert(
+                collateral_id,
+                &total
+                    .checked_sub(trove.collateral_amount)
+                    .expect("Total collateral underflow"),
+            );
+        }
+        let mut owners = self.trove_owner_set(collateral_id);
+        owners.remove(owner_id);
+        self.trove_owners.insert(collateral_id, &owners);
+        let mut collaterals = self.owner_collaterals.get(owner_id).unwrap_or_default();
+        collaterals.retain(|id| id != collateral_id);
+        if collaterals.is_empty() {
+            self.owner_collaterals.remove(owner_id);
+        } else {
+            self.owner_collaterals.insert(owner_id, &collaterals);
+        }
+        self.record_tcr(collateral_id);
+    }
+
+    pub(crate) fn redemption_index_of(
+        &self,
+        collateral_id: &AccountId,
+    ) -> TreeMap<RedemptionIndexKey, ()> {
+        self.redemption_index.get(collateral_id).unwrap_or_else(|| {
+            TreeMap::new(StorageKey::RedemptionIndexPerCollateral {
+                collateral_hash: env::sha256(collateral_id.as_bytes()),
+            })
+        })
+    }
+
+    /// The trove's place in the redemption index; debt-free troves have
+    /// none.
+    pub(crate) fn redemption_index_key(trove: &TroveInternal) -> Option<RedemptionIndexKey> {
+        if trove.debt_amount == 0 {
+            return None;
+        }
+        let normalized_debt = if trove.interest_index == 0 {
+            trove.debt_amount
+        } else {
+            Self::mul_div(
+                trove.debt_amount,
+                INTEREST_INDEX_SCALE,
+                trove.interest_index,
+            )
+        };
+        Some(RedemptionIndexKey {
+            collateral_amount: trove.collateral_amount,
+            normalized_debt: normalized_debt.max(1),
+            owner_id: trove.owner_id.clone(),
+        })
+    }
+
+    /// Moves a trove's redemption index entry from its `previous` state to
+    /// its `current` one.
+    pub(crate) fn reindex_for_redemption(
+        &mut self,
+        collateral_id: &AccountId,
+        previous: Option<&TroveInternal>,
+        current: Option<&TroveInternal>,
+    ) {
+        let previous = previous.and_then(Self::redemption_index_key);
+        let current = current.and_then(Self::redemption_index_key);
+        if previous == current {
+            return;
+        }
+        let mut index = self.redemption_index_of(collateral_id);
+        if let Some(key) = previous {
+            index.remove(&key);
+        }
+        if let Some(key) = current {
+            index.insert(&key, &());
+        }
+        self.redemption_index.insert(collateral_id, &index);
+    }
+
+    /// Indexes a new trove both by collateral and by owner.
+    pub(crate) fn add_trove_owner(&mut self, owner_id: &AccountId, collateral_id: &AccountId) {
+        let mut owners = self.trove_owner_set(collateral_id);
+        owners.insert(owner_id);
+        self.trove_owners.insert(collateral_id, &owners);
+        let mut collaterals = self.owner_collaterals.get(owner_id).unwrap_or_default();
+        if !collaterals.contains(collateral_id) {
+            collaterals.push(collateral_id.clone());
+            self.owner_collaterals.insert(owner_id, &collaterals);
+        }
+    }
+
+    /// Appends the collateral's current TCR to its bounded history when it
+    /// moved by `TCR_HISTORY_MIN_CHANGE_BPS`. A snapshot taken earlier in
+    /// the same block is replaced, so intermediate totals within one call
+    /// never show up.
+    pub(crate) fn record_tcr(&mut self, collateral_id: &AccountId) {
+        let Some(tcr_bps) = self.total_collateral_ratio(collateral_id) else {
+            return;
+        };
+        let timestamp_ms = Self::now_ms();
+        let mut history = self.tcr_history.get(collateral_id).unwrap_or_default();
+        let replaced = history
+            .back()
+            .is_some_and(|last| last.timestamp_ms == timestamp_ms);
+        if replaced {
+            history.pop_back();
+        }
+        let moved = history
+            .back()
+            .is_none_or(|last| last.tcr_bps.abs_diff(tcr_bps) >= TCR_HISTORY_MIN_CHANGE_BPS);
+        if !moved && !replaced {
+            return;
+        }
+        if moved {
+            if history.len() == MAX_TCR_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(TcrSnapshot {
+                tcr_bps,
+                timestamp_ms,
+            });
+        }
+        self.tcr_history.insert(collateral_id, &history);
+    }
+
+    pub(crate) fn stability_depositor_set(&self, pool_id: &PoolId) -> UnorderedSet<AccountId> {
+        self.stability_depositors.get(pool_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::StabilityDepositorsPerPool {
+                pool_hash: env::sha256(&borsh::to_vec(pool_id).expect("Pool id serialization")),
+            })
+        })
+    }
+
+    pub(crate) fn trove_owner_set(&self, collateral_id: &AccountId) -> UnorderedSet<AccountId> {
+        self.trove_owners.get(collateral_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::TroveOwnersPerCollateral {
+                collateral_hash: env::sha256(collateral_id.as_bytes()),
+            })
+        })
     }
 
     pub(crate) fn add_total_debt(&mut self, collateral_id: &AccountId, delta: i128) {
+        self.update_interest_index(collateral_id);
         let mut total = self.total_debt.get(collateral_id).unwrap_or(0);
         if delta >= 0 {
             let increased = total
@@ -285,16 +1139,112 @@ impl Contract {
         } else {
             self.total_debt.insert(collateral_id, &total);
         }
+        self.record_tcr(collateral_id);
+    }
+
+    /// Takes `amount` off the collateral's debt total and books it as bad
+    /// debt.
+    pub(crate) fn record_bad_debt(&mut self, collateral_id: &AccountId, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        self.add_total_debt(collateral_id, -(amount as i128));
+        let bad_debt = self.bad_debt.get(collateral_id).unwrap_or(0);
+        self.bad_debt.insert(
+            collateral_id,
+            &bad_debt.checked_add(amount).expect("Bad debt overflow"),
+        );
+    }
+
+    /// Mints `keeper_nusd_reward` for each of `troves` to the keeper. The
+    /// reward is unbacked, so it is booked as the collateral's bad debt for
+    /// the insurance fund to cover.
+    pub(crate) fn pay_keeper_reward(
+        &mut self,
+        keeper: &AccountId,
+        collateral_id: &AccountId,
+        troves: u64,
+    ) {
+        let reward = self
+            .keeper_nusd_reward
+            .checked_mul(troves as u128)
+            .expect("Keeper reward overflow");
+        if reward == 0 {
+            return;
+        }
+        if !self.nusd.accounts.contains_key(keeper) {
+            log!("Keeper {} not registered; reward skipped", keeper);
+            return;
+        }
+        self.nusd.internal_deposit(keeper, reward);
+        FtMint {
+            owner_id: keeper,
+            amount: U128(reward),
+            memo: Some("cdp_keeper_reward"),
+        }
+        .emit();
+        let bad_debt = self.bad_debt.get(collateral_id).unwrap_or(0);
+        self.bad_debt.insert(
+            collateral_id,
+            &bad_debt.checked_add(reward).expect("Bad debt overflow"),
+        );
     }
 
     pub(crate) fn ensure_debt_ceiling(&self, collateral_id: &AccountId, new_total: Balance) {
         let config = self.expect_config(collateral_id);
         require!(
-            new_total <= config.debt_ceiling,
+            new_total <= Self::effective_debt_ceiling(&config),
             "Collateral debt ceiling reached"
         );
     }
 
+    /// `debt_ceiling`, or the point reached so far on the ramp up to it
+    /// from `initial_debt_ceiling`.
+    pub(crate) fn effective_debt_ceiling(config: &CollateralConfigInternal) -> Balance {
+        let Some(initial) = config.initial_debt_ceiling else {
+            return config.debt_ceiling;
+        };
+        let elapsed = Self::now_ms().saturating_sub(config.registered_at_ms);
+        if elapsed >= config.ceiling_ramp_duration_ms {
+            return config.debt_ceiling;
+        }
+        initial
+            + Self::mul_div(
+                config.debt_ceiling - initial,
+                elapsed as u128,
+                config.ceiling_ramp_duration_ms as u128,
+            )
+    }
+
+    /// Stores a validated config, keeping the ramp start of a collateral
+    /// that is already registered.
+    pub(crate) fn insert_config(&mut self, token_id: &AccountId, config: CollateralConfig) {
+        let mut internal: CollateralConfigInternal = config.into();
+        if let Some(insurance_pool_id) = internal
+            .insurance_pool_id
+            .as_ref()
+            .filter(|id| *id != token_id)
+        {
+            require!(
+                self.configs
+                    .get(insurance_pool_id)
+                    .is_some_and(|pool_config| {
+                        pool_config.stability_pool_mode == StabilityPoolMode::Dedicated
+                            && pool_config.insurance_pool_id.is_none()
+                    }),
+                "Insurance pool must be a dedicated collateral"
+            );
+        }
+        internal.registered_at_ms = self
+            .configs
+            .get(token_id)
+            .map_or_else(Self::now_ms, |existing| existing.registered_at_ms);
+        if self.configs.get(token_id).is_some() {
+            self.update_interest_index(token_id);
+        }
+        self.configs.insert(token_id, &internal);
+    }
+
     pub(crate) fn collateral_ratio(
         &self,
         collateral: Balance,
@@ -304,15 +1254,663 @@ impl Contract {
         if debt == 0 {
             return u128::MAX;
         }
-        let price_value = price.price;
-        let divisor = Self::decimals_factor(price.decimals);
-        let value = collateral
-            .checked_mul(price_value)
-            .expect("Collateral value overflow")
-            / divisor;
+        let value = Self::collateral_value(collateral, price);
+        audit_mul("collateral ratio", value, BPS_DENOMINATOR);
         value.checked_mul(BPS_DENOMINATOR).expect("Ratio overflow") / debt
     }
 
+    pub(crate) fn collateral_value(collateral: Balance, price: &PriceFeedInternal) -> Balance {
+        audit_mul("collateral value", collateral, price.price);
+        collateral
+            .checked_mul(price.price)
+            .expect("Collateral value overflow")
+            / Self::decimals_factor(price.decimals)
+    }
+
+    /// Whether a partially liquidated trove is still protected from another
+    /// liquidation.
+    pub(crate) fn in_reliquidation_cooldown(&self, trove: &TroveInternal) -> bool {
+        trove.last_liquidation_timestamp > 0
+            && Self::now_ms()
+                < trove
+                    .last_liquidation_timestamp
+                    .saturating_add(self.reliquidation_cooldown_ms)
+    }
+
+    /// Queues the withdrawal and returns its id when it would take the
+    /// account's withdrawals within the current `withdrawal_queue_delay_ms`
+    /// window past `large_withdrawal_threshold`, locking its shares; `None`
+    /// means it should be paid now and counts towards the window.
+    pub(crate) fn maybe_queue_withdrawal(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: &PoolId,
+        amount: Option<Balance>,
+    ) -> Option<u64> {
+        let threshold = self.large_withdrawal_threshold?;
+        let pool = self.pool(pool_id);
+        let mut deposit = self.settled_deposit(account_id, pool_id, &pool);
+        let key = DepositKey::new(account_id, pool_id);
+        let available = pool.amount_from_shares(deposit.unlocked_shares());
+        let requested = amount.unwrap_or(available);
+        let now = Self::now_ms();
+        if now
+            >= deposit
+                .withdrawal_window_start
+                .saturating_add(self.withdrawal_queue_delay_ms)
+        {
+            deposit.withdrawal_window_start = now;
+            deposit.withdrawn_in_window = 0;
+        }
+        let window_total = deposit
+            .withdrawn_in_window
+            .checked_add(requested)
+            .expect("Withdrawal window overflow");
+        if window_total <= threshold {
+            deposit.withdrawn_in_window = window_total;
+            self.stability_pool_deposits.insert(&key, &deposit);
+            return None;
+        }
+        require!(requested <= available, "Insufficient balance");
+        let shares = pool.shares_from_amount(requested);
+        require!(shares > 0, "Share calculation underflow");
+        deposit.queued_shares += shares;
+        self.stability_pool_deposits.insert(&key, &deposit);
+        let id = self.next_withdrawal_id;
+        self.next_withdrawal_id += 1;
+        let ready_at = now.saturating_add(self.withdrawal_queue_delay_ms);
+        self.queued_withdrawals.insert(
+            &id,
+            &QueuedWithdrawal {
+                account_id: account_id.clone(),
+                pool_id: pool_id.clone(),
+                epoch: pool.epoch,
+                shares,
+                ready_at,
+            },
+        );
+        log!(
+            "Withdrawal {} of {} nUSD by {} queued until {}",
+            id,
+            requested,
+            account_id,
+            ready_at
+        );
+        Some(id)
+    }
+
+    /// Guards the entrypoints that add exposure while the protocol is
+    /// paused; exits such as `repay` and pool withdrawals stay open.
+    pub(crate) fn assert_not_paused(&self) {
+        require!(!self.paused, "Protocol paused");
+    }
+
+    pub(crate) fn assert_operation_live(&self, operation: Operation) {
+        match operation {
+            Operation::Borrow => require!(!self.borrow_paused, "Borrowing paused"),
+            Operation::Redeem => require!(!self.redeem_paused, "Redemptions paused"),
+            Operation::Liquidate => require!(!self.liquidate_paused, "Liquidations paused"),
+            Operation::Stability => require!(!self.stability_paused, "Stability pool paused"),
+        }
+    }
+
+    /// Whether the account deposited into `pool_id` too recently to withdraw.
+    pub(crate) fn in_stability_withdraw_cooldown(
+        &self,
+        account_id: &AccountId,
+        pool_id: &PoolId,
+    ) -> bool {
+        self.stability_pool_deposits
+            .get(&DepositKey::new(account_id, pool_id))
+            .is_some_and(|deposit| {
+                Self::now_ms()
+                    < deposit
+                        .last_deposit_timestamp
+                        .saturating_add(self.stability_withdraw_cooldown_ms)
+            })
+    }
+
+    pub(crate) fn is_feed_stale(
+        &self,
+        price: &PriceFeedInternal,
+        config: &CollateralConfigInternal,
+    ) -> bool {
+        config.max_price_age_ms.is_some_and(|max_age| {
+            Self::now_ms() > price.last_update_timestamp.saturating_add(max_age)
+        })
+    }
+
+    /// Price-independent check that `after` is at least as collateralized
+    /// as `before`.
+    pub(crate) fn ratio_not_decreased(before: &TroveInternal, after: &TroveInternal) -> bool {
+        after
+            .collateral_amount
+            .checked_mul(before.debt_amount)
+            .expect("Ratio overflow")
+            >= before
+                .collateral_amount
+                .checked_mul(after.debt_amount)
+                .expect("Ratio overflow")
+    }
+
+    /// Lowest ratio `borrow` accepts: MCR plus the collateral's safety buffer.
+    pub(crate) fn min_borrow_ratio_bps(config: &CollateralConfigInternal) -> u128 {
+        config.min_collateral_ratio_bps as u128 + config.borrow_safety_buffer_bps as u128
+    }
+
+    /// Liquidation penalty for a trove at `ratio`. With `scaled_penalty` it
+    /// grows linearly from the base penalty at MCR to `max_penalty_bps` at a
+    /// zero ratio.
+    pub(crate) fn effective_penalty_bps(config: &CollateralConfigInternal, ratio: u128) -> u128 {
+        let base = config.liquidation_penalty_bps as u128;
+        if !config.scaled_penalty {
+            return base;
+        }
+        let mcr = config.min_collateral_ratio_bps as u128;
+        let shortfall = mcr.saturating_sub(ratio);
+        base + (config.max_penalty_bps as u128 - base) * shortfall / mcr
+    }
+
+    /// Below MCR, or below the recovery ratio while the collateral is in
+    /// recovery mode at `price`.
+    pub(crate) fn is_liquidatable(
+        &self,
+        trove: &TroveInternal,
+        config: &CollateralConfigInternal,
+        price: &PriceFeedInternal,
+    ) -> bool {
+        let threshold = if self.is_in_recovery_at(&trove.collateral_id, config, price) {
+            config.recovery_collateral_ratio_bps
+        } else {
+            config.min_collateral_ratio_bps
+        };
+        trove.debt_amount > 0
+            && self.collateral_ratio(trove.collateral_amount, trove.debt_amount, price)
+                < threshold as u128
+    }
+
+    /// Liquidatable troves among `owners`, ordered by `sort_by`.
+    pub(crate) fn liquidatable_troves(
+        &self,
+        collateral_id: &AccountId,
+        owners: impl Iterator<Item = AccountId>,
+        sort_by: LiquidationSortBy,
+    ) -> Vec<LiquidatableTrove> {
+        let (config, price) = match (
+            self.configs.get(collateral_id),
+            self.price_feeds.get(collateral_id),
+        ) {
+            (Some(config), Some(price)) => (config, price),
+            _ => return Vec::new(),
+        };
+        let mut candidates: Vec<LiquidatableTrove> = owners
+            .filter_map(|owner_id| self.troves.get(&Self::trove_key(&owner_id, collateral_id)))
+            .filter(|trove| {
+                self.is_liquidatable(trove, &config, &price)
+                    && !self.in_reliquidation_cooldown(trove)
+            })
+            .map(|trove| LiquidatableTrove {
+                collateral_ratio_bps: U128(self.collateral_ratio(
+                    trove.collateral_amount,
+                    trove.debt_amount,
+                    &price,
+                )),
+                owner_id: trove.owner_id,
+                collateral_amount: U128(trove.collateral_amount),
+                debt_amount: U128(trove.debt_amount),
+            })
+            .collect();
+        match sort_by {
+            LiquidationSortBy::Ratio => candidates.sort_by_key(|c| c.collateral_ratio_bps.0),
+            LiquidationSortBy::Debt => candidates.sort_by_key(|c| Reverse(c.debt_amount.0)),
+        }
+        candidates
+    }
+
+    /// Whether the trove is still within the collateral's redemption-free
+    /// window after opening.
+    pub(crate) fn is_too_young_to_redeem(
+        trove: &TroveInternal,
+        config: &CollateralConfigInternal,
+    ) -> bool {
+        Self::now_ms()
+            < trove
+                .opened_timestamp
+                .saturating_add(config.redemption_min_trove_age_ms)
+    }
+
+    /// Up to `max_iterations` troves `redeem_multi` may draw from, lowest
+    /// collateral ratio first, with the redeemer's own trove placed per
+    /// `redemption_self_policy`.
+    pub(crate) fn redemption_candidates(
+        &self,
+        collateral_id: &AccountId,
+        redeemer: &AccountId,
+        max_iterations: u64,
+    ) -> Vec<TroveInternal> {
+        let policy = self.redemption_self_policy;
+        let mut owners: Vec<AccountId> = Vec::new();
+        if policy == RedemptionSelfPolicy::First
+            && self
+                .troves
+                .get(&Self::trove_key(redeemer, collateral_id))
+                .is_some_and(|trove| trove.debt_amount > 0)
+        {
+            owners.push(redeemer.clone());
+        }
+        let limit = (max_iterations as usize).saturating_sub(owners.len());
+        owners.extend(
+            self.redemption_index_of(collateral_id)
+                .iter()
+                .map(|(key, ())| key.owner_id)
+                .filter(|owner_id| policy == RedemptionSelfPolicy::Include || owner_id != redeemer)
+                .take(limit),
+        );
+        owners
+            .iter()
+            .filter_map(|owner_id| self.troves.get(&Self::trove_key(owner_id, collateral_id)))
+            .collect()
+    }
+
+    /// Liquid NEAR minus the cost of the storage the contract currently uses.
+    pub(crate) fn withdrawable_near(&self) -> Balance {
+        let storage_cost = env::storage_byte_cost()
+            .saturating_mul(env::storage_usage() as u128)
+            .as_yoctonear();
+        env::account_balance()
+            .as_yoctonear()
+            .saturating_sub(storage_cost)
+    }
+
+    /// System-wide collateral ratio of a collateral type in bps, or `None`
+    /// without a price.
+    pub(crate) fn total_collateral_ratio(&self, collateral_id: &AccountId) -> Option<u128> {
+        let price = self.price_feeds.get(collateral_id)?;
+        Some(self.collateral_ratio(
+            self.total_collateral.get(collateral_id).unwrap_or(0),
+            self.total_debt.get(collateral_id).unwrap_or(0),
+            &price,
+        ))
+    }
+
+    /// nUSD value of all trove collateral with a price, written-off tokens
+    /// excluded.
+    pub(crate) fn system_collateral_value(&self) -> Balance {
+        self.configs
+            .keys()
+            .filter(|collateral_id| !self.written_off_collaterals.contains(collateral_id))
+            .filter_map(|collateral_id| {
+                let price = self.price_feeds.get(&collateral_id)?;
+                let total = self.total_collateral.get(&collateral_id).unwrap_or(0);
+                Some(Self::collateral_value(total, &price))
+            })
+            .fold(0, |sum: Balance, value| {
+                sum.checked_add(value).expect("Collateral value overflow")
+            })
+    }
+
+    /// Trove debt across every collateral, written-off ones included.
+    pub(crate) fn system_debt(&self) -> Balance {
+        self.configs
+            .keys()
+            .map(|collateral_id| self.total_debt.get(&collateral_id).unwrap_or(0))
+            .fold(0, |sum: Balance, debt| {
+                sum.checked_add(debt).expect("Total debt overflow")
+            })
+    }
+
+    /// nUSD supply as a share of `system_collateral_value` in bps, or `None`
+    /// when there is no priced collateral.
+    pub(crate) fn system_utilization_bps(&self) -> Option<u128> {
+        let value = self.system_collateral_value();
+        (value > 0).then(|| Self::mul_div(self.nusd.total_supply, BPS_DENOMINATOR, value))
+    }
+
+    pub(crate) fn ensure_system_utilization(&self) {
+        if let Some(max_bps) = self.max_system_utilization_bps {
+            let max_supply = Self::mul_div(
+                self.system_collateral_value(),
+                max_bps as u128,
+                BPS_DENOMINATOR,
+            );
+            require!(
+                self.nusd.total_supply <= max_supply,
+                "System utilization cap reached"
+            );
+        }
+    }
+
+    pub(crate) fn is_in_recovery(
+        &self,
+        collateral_id: &AccountId,
+        config: &CollateralConfigInternal,
+    ) -> bool {
+        self.price_feeds
+            .get(collateral_id)
+            .is_some_and(|price| self.is_in_recovery_at(collateral_id, config, &price))
+    }
+
+    /// New debt needs the borrow ratio, the recovery ratio as well while the
+    /// collateral is in recovery mode, and a backing pool of at least
+    /// `min_stability_pool_size`.
+    pub(crate) fn assert_borrow_ratio(
+        &self,
+        collateral_id: &AccountId,
+        config: &CollateralConfigInternal,
+        price: &PriceFeedInternal,
+        ratio: u128,
+    ) {
+        require!(
+            ratio >= Self::min_borrow_ratio_bps(config),
+            "Insufficient collateral"
+        );
+        if self.is_in_recovery_at(collateral_id, config, price) {
+            require!(
+                ratio >= config.recovery_collateral_ratio_bps as u128,
+                "Would violate recovery ratio"
+            );
+        }
+        require!(
+            self.pool(&self.pool_for_collateral(collateral_id))
+                .total_nusd
+                >= config.min_stability_pool_size,
+            "Stability pool below minimum size"
+        );
+    }
+
+    /// Run after a borrow is booked: new debt may not leave the collateral
+    /// in recovery mode.
+    pub(crate) fn assert_out_of_recovery(
+        &self,
+        collateral_id: &AccountId,
+        config: &CollateralConfigInternal,
+        price: &PriceFeedInternal,
+    ) {
+        require!(
+            !self.is_in_recovery_at(collateral_id, config, price),
+            "Borrow would leave system in recovery mode"
+        );
+    }
+
+    /// With `lock_withdrawals_when_liquidatable`, a trove that is already
+    /// liquidatable keeps its collateral until its ratio recovers.
+    pub(crate) fn assert_withdrawal_unlocked(&self, trove: &TroveInternal) {
+        let config = self.expect_config(&trove.collateral_id);
+        if !config.lock_withdrawals_when_liquidatable {
+            return;
+        }
+        if let Some(price) = self.price_feeds.get(&trove.collateral_id) {
+            require!(
+                !self.is_liquidatable(trove, &config, &price),
+                "Withdrawals locked while liquidatable"
+            );
+        }
+    }
+
+    /// Collateral can only leave a trove that stays above MCR, or above the
+    /// recovery ratio while in recovery mode.
+    pub(crate) fn assert_withdrawal_ratio(
+        config: &CollateralConfigInternal,
+        ratio: u128,
+        in_recovery: bool,
+    ) {
+        require!(
+            ratio >= config.min_collateral_ratio_bps as u128,
+            "Would violate MCR"
+        );
+        if in_recovery {
+            require!(
+                ratio >= config.recovery_collateral_ratio_bps as u128,
+                "Would violate recovery ratio"
+            );
+        }
+    }
+
+    /// Whether the system-wide ratio at `price` is below the recovery ratio.
+    pub(crate) fn is_in_recovery_at(
+        &self,
+        collateral_id: &AccountId,
+        config: &CollateralConfigInternal,
+        price: &PriceFeedInternal,
+    ) -> bool {
+        let tcr = self.collateral_ratio(
+            self.total_collateral.get(collateral_id).unwrap_or(0),
+            self.total_debt.get(collateral_id).unwrap_or(0),
+            price,
+        );
+        tcr < config.recovery_collateral_ratio_bps as u128
+    }
+
+    /// Registered collaterals by account id, so aggregate views list them
+    /// in the same order whatever order they were registered in.
+    pub(crate) fn sorted_collateral_ids(&self) -> Vec<AccountId> {
+        let mut collateral_ids = self.configs.keys_as_vector().to_vec();
+        collateral_ids.sort();
+        collateral_ids
+    }
+
+    pub(crate) fn assert_collateral_count(&self) {
+        require!(
+            self.configs.len() <= MAX_COLLATERALS,
+            "Too many collaterals"
+        );
+    }
+
+    pub(crate) fn assert_valid_config(config: &CollateralConfig) {
+        require!(
+            config.min_collateral_ratio_bps >= 1100,
+            "MCR must be >= 110%"
+        );
+        require!(
+            config.recovery_collateral_ratio_bps >= config.min_collateral_ratio_bps,
+            "Recovery ratio must be >= MCR"
+        );
+        // The referrer cut comes out of the same seized collateral, so the
+        // largest penalty plus the largest referrer share must fit in it.
+        require!(
+            (config.liquidation_penalty_bps as u128) < BPS_DENOMINATOR,
+            "Penalty must be < 100%"
+        );
+        require!(
+            (config.liquidation_penalty_bps + MAX_REFERRER_BPS) as u128 <= BPS_DENOMINATOR,
+            "Penalty plus referrer share must be <= 100%"
+        );
+        if config.scaled_penalty {
+            require!(
+                config.max_penalty_bps >= config.liquidation_penalty_bps,
+                "Max penalty must be >= penalty"
+            );
+            require!(
+                (config.max_penalty_bps as u128) < BPS_DENOMINATOR,
+                "Max penalty must be < 100%"
+            );
+            require!(
+                (config.max_penalty_bps + MAX_REFERRER_BPS) as u128 <= BPS_DENOMINATOR,
+                "Max penalty plus referrer share must be <= 100%"
+            );
+        }
+        require!(
+            (config.transfer_fee_bps as u128) < BPS_DENOMINATOR,
+            "Transfer fee must be < 100%"
+        );
+        require!(
+            (config.min_treasury_reserve_bps as u128) <= BPS_DENOMINATOR,
+            "Treasury reserve must be <= 100%"
+        );
+        require!(
+            (config.borrow_fee_bps as u128) < BPS_DENOMINATOR,
+            "Borrow fee must be < 100%"
+        );
+        require!(
+            (config.redemption_fee_bps as u128) < BPS_DENOMINATOR,
+            "Redemption fee must be < 100%"
+        );
+        if let Some(initial) = config.initial_debt_ceiling {
+            require!(
+                initial.0 <= config.debt_ceiling.0,
+                "Initial debt ceiling must be <= debt ceiling"
+            );
+        }
+        if config.dynamic_interest {
+            require!(
+                config.max_interest_rate_bps >= config.interest_rate_bps,
+                "Max interest rate must be >= interest rate"
+            );
+            require!(
+                (config.interest_kink_bps as u128) < BPS_DENOMINATOR,
+                "Interest kink must be < 100%"
+            );
+        }
+    }
+
+    pub(crate) fn assert_price_format(&self, collateral_id: &AccountId, price: u128, decimals: u8) {
+        require!(decimals <= 18, "Decimals must be <= 18");
+        require!(price > 0, "Price must be positive");
+        if let Some(expected) = self
+            .configs
+            .get(collateral_id)
+            .and_then(|config| config.expected_price_decimals)
+        {
+            require!(decimals == expected, "Unexpected price decimals");
+        }
+    }
+
+    /// Validates an oracle price against the collateral's format and floor
+    /// before storing it.
+    pub(crate) fn internal_submit_price(
+        &mut self,
+        collateral_id: &AccountId,
+        price: u128,
+        decimals: u8,
+        timestamp_ms: u64,
+    ) {
+        self.assert_price_format(collateral_id, price, decimals);
+        if let Some(min_price) = self
+            .configs
+            .get(collateral_id)
+            .and_then(|config| config.min_reasonable_price)
+        {
+            require!(
+                Self::normalize_price(price, decimals) >= min_price,
+                "Price below reasonable minimum"
+            );
+        }
+        self.store_price(collateral_id, price, decimals, timestamp_ms);
+    }
+
+    /// Asks the Pyth contract for the collateral's current price; the
+    /// callback stores it with `store_pulled_price`.
+    pub(crate) fn pull_price(&self, collateral_id: &AccountId) -> Promise {
+        let config = self.expect_config(collateral_id);
+        ext_pyth::ext(self.pyth_oracle_id.clone())
+            .with_static_gas(GAS_FOR_PRICE_PULL)
+            .get_price(config.oracle_price_id)
+    }
+
+    /// Stores the price returned by `pull_price`, panicking if the pull
+    /// failed so the dependent operation does not run on a stale price.
+    /// The price keeps Pyth's publish time, so it is held to the same
+    /// ordering and age limits as a signed price.
+    pub(crate) fn store_pulled_price(&mut self, collateral_id: &AccountId) {
+        let pulled = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<Option<PythPrice>>(&value)
+                .ok()
+                .flatten(),
+            _ => None,
+        };
+        let pulled = pulled.unwrap_or_else(|| env::panic_str("Price refresh failed"));
+        require!(pulled.price.0 > 0, "Price must be positive");
+        let (price, decimals) = if pulled.expo <= 0 {
+            let decimals = u8::try_from(-(pulled.expo as i64))
+                .unwrap_or_else(|_| env::panic_str("Decimals must be <= 18"));
+            (pulled.price.0 as u128, decimals)
+        } else {
+            let factor = 10u128
+                .checked_pow(pulled.expo as u32)
+                .expect("Price overflow");
+            let price = (pulled.price.0 as u128)
+                .checked_mul(factor)
+                .expect("Price overflow");
+            (price, 0)
+        };
+        let publish_ms = u64::try_from(pulled.publish_time)
+            .ok()
+            .and_then(|seconds| seconds.checked_mul(1_000))
+            .unwrap_or_else(|| env::panic_str("Invalid publish time"))
+            .min(Self::now_ms());
+        if let Some(feed) = self.price_feeds.get(collateral_id) {
+            require!(
+                publish_ms >= feed.last_update_timestamp,
+                "Pulled price is older than the feed"
+            );
+        }
+        let max_age = self
+            .expect_config(collateral_id)
+            .max_price_age_ms
+            .unwrap_or(MAX_SIGNED_PRICE_AGE_MS);
+        require!(
+            Self::now_ms() - publish_ms <= max_age,
+            "Pulled price too old"
+        );
+        self.internal_submit_price(collateral_id, price, decimals, publish_ms);
+    }
+
+    /// Balance returned by an `ft_balance_of` query.
+    pub(crate) fn queried_balance() -> Balance {
+        match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).ok(),
+            _ => None,
+        }
+        .unwrap_or_else(|| env::panic_str("Balance query failed"))
+        .0
+    }
+
+    pub(crate) fn store_price(
+        &mut self,
+        collateral_id: &AccountId,
+        price: u128,
+        decimals: u8,
+        timestamp_ms: u64,
+    ) {
+        let feed = PriceFeedInternal {
+            price,
+            decimals,
+            last_update_timestamp: timestamp_ms,
+        };
+        self.price_feeds.insert(collateral_id, &feed);
+        self.record_tcr(collateral_id);
+        self.emit_event(CdpEvent::PriceUpdate {
+            collateral_id: collateral_id.clone(),
+            price: U128(price),
+            decimals,
+        });
+    }
+
+    /// Bytes the oracle signs for `submit_signed_price`. Binding the contract
+    /// account keeps a signature from being replayed on another deployment.
+    pub(crate) fn signed_price_message(
+        collateral_id: &AccountId,
+        price: u128,
+        decimals: u8,
+        publish_time: u64,
+    ) -> Vec<u8> {
+        borsh::to_vec(&(
+            env::current_account_id(),
+            collateral_id,
+            price,
+            decimals,
+            publish_time,
+        ))
+        .expect("Price message serialization")
+    }
+
+    /// Scales a price to 18 decimals so it can be compared across feeds.
+    pub(crate) fn normalize_price(price: u128, decimals: u8) -> u128 {
+        price
+            .checked_mul(Self::decimals_factor(18 - decimals))
+            .expect("Price overflow")
+    }
+
     pub(crate) fn decimals_factor(decimals: u8) -> u128 {
         10u128.pow(decimals as u32)
     }
@@ -338,6 +1936,13 @@ impl Contract {
         env::block_timestamp() / 1_000_000
     }
 
+    pub(crate) fn assert_not_written_off(&self, collateral_id: &AccountId) {
+        require!(
+            !self.written_off_collaterals.contains(collateral_id),
+            "Collateral written off"
+        );
+    }
+
     pub(crate) fn assert_owner(&self) {
         require!(env::predecessor_account_id() == self.owner_id, "Owner only");
     }
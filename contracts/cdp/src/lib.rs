@@ -1,13 +1,17 @@
+mod audit;
+mod events;
 mod types;
+use crate::events::CdpEvent;
 use crate::types::{
-    CollateralConfig, CollateralConfigInternal, PriceFeedInternal, StorageKey, TokenId,
-    TransferAction, TroveInternal, TroveKey, GAS_FOR_CALLBACK, GAS_FOR_SWAP,
+    CollateralConfig, CollateralConfigInternal, PriceFeedInternal, RedeemConversion, StorageKey,
+    SwapPurpose, SwapRequest, TokenId, TransferAction, TreasuryConversion, TroveInternal, TroveKey,
+    GAS_FOR_BALANCE_QUERY, GAS_FOR_CALLBACK, GAS_FOR_REDEEM_CONVERSION,
 };
 
 use near_contract_standards::fungible_token::core::FungibleTokenCore;
 use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
 use near_contract_standards::fungible_token::metadata::{
-    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+    FungibleTokenMetadata, FungibleTokenMetadataProvider,
 };
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
@@ -15,15 +19,19 @@ use near_contract_standards::fungible_token::{Balance, FungibleToken};
 use near_contract_standards::storage_management::{
     StorageBalance, StorageBalanceBounds, StorageManagement,
 };
-use near_sdk::collections::{LookupMap, UnorderedMap};
-use near_sdk::json_types::{U128, U64};
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{Base64VecU8, I128, U128, U64};
 use near_sdk::store::LazyOption;
 use near_sdk::{
-    assert_one_yocto, env, ext_contract, log, near, near_bindgen, require, AccountId, NearToken,
-    PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
+    assert_one_yocto, env, ext_contract, log, near, near_bindgen, require, AccountId, CurveType,
+    NearToken, PanicOnDefault, Promise, PromiseOrValue, PromiseResult, PublicKey,
 };
+use std::collections::{BTreeMap, VecDeque};
 
 mod internal;
+mod migration;
+#[cfg(feature = "test-helpers")]
+mod test_helpers;
 mod views;
 
 #[ext_contract(ext_intents)]
@@ -39,9 +47,22 @@ pub trait NearIntentsDex {
     );
 }
 
+#[ext_contract(ext_pyth)]
+pub trait PythOracle {
+    fn get_price(&self, price_identifier: String) -> Option<types::PythPrice>;
+}
+
 #[ext_contract(ext_ft)]
 pub trait ExternalFungibleToken {
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> U128;
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
 }
 
 #[allow(dead_code)]
@@ -52,25 +73,109 @@ trait ContractCallbacks {
         caller_id: AccountId,
         input_token: AccountId,
         amount_in: U128,
-    ) -> bool;
+        deposit: U128,
+        purpose: SwapPurpose,
+        retry: Option<SwapRequest>,
+    ) -> PromiseOrValue<bool>;
+    fn on_treasury_convert(
+        &mut self,
+        conversion: TreasuryConversion,
+        retry: bool,
+    ) -> PromiseOrValue<bool>;
+    fn on_price_refreshed_liquidate(
+        &mut self,
+        keeper: AccountId,
+        collateral_id: AccountId,
+        owners: Vec<AccountId>,
+        min_total_collateral_seized: Option<U128>,
+        referrer: Option<AccountId>,
+    ) -> U64;
+    fn on_price_refreshed_redeem(
+        &mut self,
+        redeemer: AccountId,
+        collateral_id: AccountId,
+        trove_owner: AccountId,
+        amount: U128,
+        convert: Option<RedeemConversion>,
+        deposit: U128,
+    ) -> Promise;
+    fn on_rebase_balance(&mut self, collateral_id: AccountId, account_id: AccountId) -> bool;
 }
 
 #[near(contract_state)]
 #[derive(PanicOnDefault)]
 pub struct Contract {
     owner_id: AccountId,
+    /// Proposed by the owner; becomes `owner_id` once it accepts.
+    pending_owner: Option<AccountId>,
+    treasury_id: AccountId,
     intent_router_id: AccountId,
+    /// Router a failed swap is retried through when `retry_swaps_via_fallback`.
+    fallback_router_id: Option<AccountId>,
+    retry_swaps_via_fallback: bool,
     pyth_oracle_id: AccountId,
     configs: UnorderedMap<TokenId, CollateralConfigInternal>,
     troves: LookupMap<TroveKey, TroveInternal>,
+    trove_owners: LookupMap<TokenId, UnorderedSet<AccountId>>,
+    /// Collaterals each account has a trove against; bounded by
+    /// `MAX_COLLATERALS`.
+    owner_collaterals: LookupMap<AccountId, Vec<TokenId>>,
+    /// Each collateral's indebted troves in ascending collateral ratio.
+    redemption_index: LookupMap<TokenId, TreeMap<types::RedemptionIndexKey, ()>>,
     total_debt: LookupMap<TokenId, Balance>,
+    interest_indices: LookupMap<TokenId, types::InterestIndex>,
     price_feeds: LookupMap<TokenId, PriceFeedInternal>,
-    stability_pool_deposits: LookupMap<AccountId, types::StabilityDeposit>,
+    stability_pool_deposits: LookupMap<types::DepositKey, types::StabilityDeposit>,
     collateral_rewards: LookupMap<types::CollateralRewardKey, Balance>,
-    reward_per_share: UnorderedMap<TokenId, u128>,
-    stability_pool_total_shares: Balance,
+    /// Sum of `collateral_rewards` per collateral.
+    collateral_reward_totals: LookupMap<TokenId, Balance>,
+    stability_pools: LookupMap<types::PoolId, types::StabilityPool>,
+    epoch_reward_snapshots: LookupMap<(types::PoolId, u64), BTreeMap<TokenId, u128>>,
+    written_off_collaterals: UnorderedSet<TokenId>,
+    bad_debt: LookupMap<TokenId, Balance>,
+    unsettled_rewards: LookupMap<TokenId, Balance>,
+    rebalance_fraction_bps: u16,
+    referrer_bps: u16,
+    total_collateral: LookupMap<TokenId, Balance>,
+    reliquidation_cooldown_ms: u64,
+    /// Minimum time between a stability deposit and its withdrawal.
+    stability_withdraw_cooldown_ms: u64,
+    paused: bool,
+    /// Lets depositors skip the withdraw cooldown while `paused`.
+    allow_withdrawals_when_paused: bool,
+    borrow_paused: bool,
+    redeem_paused: bool,
+    liquidate_paused: bool,
+    stability_paused: bool,
+    redemption_self_policy: types::RedemptionSelfPolicy,
+    interest_to_equity_bps: u16,
+    /// Share of each borrow fee that tops up the stability pool.
+    fee_to_stability_pool_bps: u16,
+    /// Interest-funded nUSD held on the contract account to cover bad debt.
+    insurance_fund_balance: Balance,
+    max_borrow_per_tx: Option<Balance>,
+    /// Cap on nUSD supply as a share of all priced collateral value.
+    max_system_utilization_bps: Option<u16>,
+    stability_depositors: LookupMap<types::PoolId, UnorderedSet<AccountId>>,
+    /// Shares held by deposits missing from `stability_depositors`; a pool
+    /// without an entry has a complete index.
+    unindexed_stability_shares: LookupMap<types::PoolId, Balance>,
+    epoch_resyncs: LookupMap<types::PoolId, types::EpochResync>,
+    oracle_signers: LookupMap<TokenId, types::OracleSigner>,
+    /// Stability withdrawals above this wait `withdrawal_queue_delay_ms`;
+    /// `None` keeps every withdrawal instant.
+    large_withdrawal_threshold: Option<Balance>,
+    withdrawal_queue_delay_ms: u64,
+    queued_withdrawals: LookupMap<u64, types::QueuedWithdrawal>,
+    next_withdrawal_id: u64,
+    /// Account each trove's owner allowed to merge it into their own.
+    merge_approvals: LookupMap<TroveKey, AccountId>,
+    rebase_divergences: LookupMap<TroveKey, types::RebaseDivergence>,
+    keeper_nusd_reward: Balance,
+    event_level: types::EventLevel,
+    tcr_history: LookupMap<TokenId, VecDeque<types::TcrSnapshot>>,
+    /// nUSD across all stability pools.
     stability_pool_total_nusd: Balance,
-    stability_pool_epoch: u64,
     nusd: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
 }
@@ -85,6 +190,7 @@ impl Contract {
         metadata: FungibleTokenMetadata,
     ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
+        metadata.assert_valid();
 
         let mut nusd = FungibleToken::new(StorageKey::FungibleToken);
         let current_id = env::current_account_id();
@@ -93,22 +199,81 @@ impl Contract {
             nusd.internal_register_account(&owner_id);
         }
 
+        Self::initial_state(
+            owner_id,
+            intent_router_id,
+            pyth_oracle_id,
+            nusd,
+            LazyOption::new(StorageKey::TokenMetadata, Some(metadata)),
+        )
+    }
+
+    /// Fresh state around an existing nUSD token, shared by `new` and the
+    /// legacy migration.
+    fn initial_state(
+        owner_id: AccountId,
+        intent_router_id: AccountId,
+        pyth_oracle_id: AccountId,
+        nusd: FungibleToken,
+        metadata: LazyOption<FungibleTokenMetadata>,
+    ) -> Self {
         Self {
+            treasury_id: owner_id.clone(),
             owner_id,
+            pending_owner: None,
             intent_router_id,
+            fallback_router_id: None,
+            retry_swaps_via_fallback: false,
             pyth_oracle_id,
             configs: UnorderedMap::new(StorageKey::CollateralConfigs),
             troves: LookupMap::new(StorageKey::Troves),
+            trove_owners: LookupMap::new(StorageKey::TroveOwners),
+            owner_collaterals: LookupMap::new(StorageKey::OwnerCollaterals),
+            redemption_index: LookupMap::new(StorageKey::RedemptionIndex),
             total_debt: LookupMap::new(StorageKey::TotalDebt),
+            interest_indices: LookupMap::new(StorageKey::InterestIndices),
             price_feeds: LookupMap::new(StorageKey::PriceFeeds),
             stability_pool_deposits: LookupMap::new(StorageKey::StabilityPoolDeposits),
             collateral_rewards: LookupMap::new(StorageKey::CollateralRewards),
-            reward_per_share: UnorderedMap::new(StorageKey::RewardPerShare),
-            stability_pool_total_shares: 0,
+            collateral_reward_totals: LookupMap::new(StorageKey::CollateralRewardTotals),
+            stability_pools: LookupMap::new(StorageKey::StabilityPools),
+            epoch_reward_snapshots: LookupMap::new(StorageKey::EpochRewardSnapshots),
+            written_off_collaterals: UnorderedSet::new(StorageKey::WrittenOffCollaterals),
+            bad_debt: LookupMap::new(StorageKey::BadDebt),
+            unsettled_rewards: LookupMap::new(StorageKey::UnsettledRewards),
+            rebalance_fraction_bps: 0,
+            referrer_bps: 0,
+            total_collateral: LookupMap::new(StorageKey::TotalCollateral),
+            reliquidation_cooldown_ms: 0,
+            stability_withdraw_cooldown_ms: 0,
+            paused: false,
+            allow_withdrawals_when_paused: true,
+            borrow_paused: false,
+            redeem_paused: false,
+            liquidate_paused: false,
+            stability_paused: false,
+            redemption_self_policy: types::RedemptionSelfPolicy::Include,
+            interest_to_equity_bps: 0,
+            fee_to_stability_pool_bps: 0,
+            insurance_fund_balance: 0,
+            max_borrow_per_tx: None,
+            max_system_utilization_bps: None,
+            stability_depositors: LookupMap::new(StorageKey::StabilityDepositors),
+            unindexed_stability_shares: LookupMap::new(StorageKey::UnindexedStabilityShares),
+            epoch_resyncs: LookupMap::new(StorageKey::EpochResyncs),
+            oracle_signers: LookupMap::new(StorageKey::OracleSigners),
+            large_withdrawal_threshold: None,
+            withdrawal_queue_delay_ms: 0,
+            queued_withdrawals: LookupMap::new(StorageKey::QueuedWithdrawals),
+            next_withdrawal_id: 0,
+            merge_approvals: LookupMap::new(StorageKey::MergeApprovals),
+            rebase_divergences: LookupMap::new(StorageKey::RebaseDivergences),
+            keeper_nusd_reward: 0,
+            event_level: types::EventLevel::Core,
+            tcr_history: LookupMap::new(StorageKey::TcrHistory),
             stability_pool_total_nusd: 0,
-            stability_pool_epoch: 0,
             nusd,
-            metadata: LazyOption::new(StorageKey::TokenMetadata, Some(metadata)),
+            metadata,
         }
     }
 
@@ -116,16 +281,187 @@ impl Contract {
     pub fn register_collateral(&mut self, token_id: AccountId, config: CollateralConfig) {
         assert_one_yocto();
         self.assert_owner();
+        Self::assert_valid_config(&config);
+        self.insert_config(&token_id, config);
+        self.assert_collateral_count();
+    }
+
+    /// Registers several collaterals atomically; one invalid entry rejects
+    /// the whole batch.
+    #[payable]
+    pub fn register_collaterals(&mut self, entries: Vec<(AccountId, CollateralConfig)>) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(!entries.is_empty(), "Entries required");
+        require!(
+            entries.len() <= crate::types::MAX_COLLATERALS_PER_BATCH,
+            "Too many entries"
+        );
+        for (_, config) in &entries {
+            Self::assert_valid_config(config);
+        }
+        for (token_id, config) in entries {
+            self.insert_config(&token_id, config);
+        }
+        self.assert_collateral_count();
+    }
+
+    /// Replaces the token metadata returned by `ft_metadata`. Decimals are
+    /// fixed at deployment since balances are stored in base units.
+    #[payable]
+    pub fn set_metadata(&mut self, metadata: FungibleTokenMetadata) {
+        assert_one_yocto();
+        self.assert_owner();
+        metadata.assert_valid();
+        require!(
+            metadata.decimals == self.ft_metadata().decimals,
+            "Decimals cannot change"
+        );
+        self.metadata.set(Some(metadata));
+    }
+
+    #[payable]
+    pub fn set_treasury_id(&mut self, treasury_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            self.nusd.accounts.contains_key(&treasury_id),
+            "Treasury must be registered"
+        );
+        self.treasury_id = treasury_id;
+    }
+
+    /// First step of an ownership transfer; nothing changes until
+    /// `new_owner` calls `accept_ownership`. A new proposal replaces the
+    /// pending one.
+    #[payable]
+    pub fn propose_new_owner(&mut self, new_owner: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(new_owner != self.owner_id, "Already the owner");
+        self.pending_owner = Some(new_owner);
+    }
+
+    #[payable]
+    pub fn accept_ownership(&mut self) {
+        assert_one_yocto();
+        let caller = env::predecessor_account_id();
+        require!(
+            self.pending_owner.as_ref() == Some(&caller),
+            "Only the pending owner can accept"
+        );
+        self.pending_owner = None;
+        log!("Ownership transferred from {} to {}", self.owner_id, caller);
+        self.owner_id = caller;
+    }
+
+    #[payable]
+    pub fn cancel_ownership_transfer(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(self.pending_owner.is_some(), "No pending owner");
+        self.pending_owner = None;
+    }
+
+    /// Sends out NEAR held above what the contract's storage requires, e.g.
+    /// accumulated one-yocto deposits and unspent swap deposits.
+    #[payable]
+    pub fn withdraw_near(&mut self, amount: U128, to: AccountId) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(amount.0 > 0, "Amount must be > 0");
+        require!(
+            amount.0 <= self.withdrawable_near(),
+            "Amount exceeds NEAR above storage requirement"
+        );
+        Promise::new(to).transfer(NearToken::from_yoctonear(amount.0))
+    }
+
+    /// Incident response for a collateral token that has lost its value.
+    /// Irreversible: new deposits, borrows, redemptions and liquidations are
+    /// blocked and borrowers may exit through `abandon_trove`.
+    #[payable]
+    pub fn write_off_collateral(&mut self, collateral_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.expect_config(&collateral_id);
+        require!(
+            self.written_off_collaterals.insert(&collateral_id),
+            "Collateral already written off"
+        );
+        log!("Collateral {} written off", collateral_id);
+    }
+
+    /// Surrenders the caller's written-off collateral to the treasury and
+    /// moves the trove debt to the collateral's bad-debt balance. The caller
+    /// keeps any nUSD they minted.
+    #[payable]
+    pub fn abandon_trove(&mut self, collateral_id: AccountId) -> U128 {
+        assert_one_yocto();
         require!(
-            config.min_collateral_ratio_bps >= 1100,
-            "MCR must be >= 110%"
+            self.written_off_collaterals.contains(&collateral_id),
+            "Collateral not written off"
+        );
+        let caller = env::predecessor_account_id();
+        let trove = self.expect_trove(&caller, &collateral_id);
+        self.remove_trove(&caller, &collateral_id);
+        self.record_bad_debt(&collateral_id, trove.debt_amount);
+        if trove.collateral_amount > 0 {
+            let treasury_id = self.treasury_id.clone();
+            self.enqueue_collateral_reward(&treasury_id, &collateral_id, trove.collateral_amount);
+        }
+        log!(
+            "Trove {} abandoned {} debt on written-off {}",
+            caller,
+            trove.debt_amount,
+            collateral_id
         );
+        U128(trove.debt_amount)
+    }
+
+    /// Accrues the trove's pending interest so `total_debt` stays current
+    /// between owner interactions. Callable by anyone; returns the interest
+    /// added.
+    #[payable]
+    pub fn accrue_trove_interest(&mut self, owner_id: AccountId, collateral_id: AccountId) -> U128 {
+        assert_one_yocto();
+        let mut trove = self.expect_trove(&owner_id, &collateral_id);
+        let interest = self.accrue_interest(&mut trove);
+        if interest > 0 {
+            self.save_trove(&owner_id, &collateral_id, &trove);
+        }
+        U128(interest)
+    }
+
+    /// Sweeps a trove whose collateral is worth less than both the
+    /// collateral's `dust_trove_threshold` and its debt. The whole debt is
+    /// written off as bad debt and the dust collateral is returned to the
+    /// owner as a claimable reward. Callable by anyone; returns the bad
+    /// debt booked.
+    #[payable]
+    pub fn cleanup_trove(&mut self, collateral_id: AccountId, owner_id: AccountId) -> U128 {
+        assert_one_yocto();
+        self.assert_not_written_off(&collateral_id);
+        let config = self.expect_config(&collateral_id);
+        require!(config.dust_trove_threshold > 0, "Dust cleanup disabled");
+        let price = self.expect_price_internal(&collateral_id);
+        require!(!self.is_feed_stale(&price, &config), "Price is stale");
+        let trove = self.expect_trove_accrued(&owner_id, &collateral_id);
+        let value = Self::collateral_value(trove.collateral_amount, &price);
+        require!(value < config.dust_trove_threshold, "Trove is not dust");
         require!(
-            config.recovery_collateral_ratio_bps >= config.min_collateral_ratio_bps,
-            "Recovery ratio must be >= MCR"
+            value < trove.debt_amount,
+            "Trove collateral covers its debt"
+        );
+        self.remove_trove(&owner_id, &collateral_id);
+        self.record_bad_debt(&collateral_id, trove.debt_amount);
+        self.enqueue_collateral_reward(&owner_id, &collateral_id, trove.collateral_amount);
+        log!(
+            "Dust trove {} cleaned up, {} debt written off",
+            owner_id,
+            trove.debt_amount
         );
-        let internal: CollateralConfigInternal = config.into();
-        self.configs.insert(&token_id, &internal);
+        U128(trove.debt_amount)
     }
 
     pub fn submit_price(&mut self, collateral_id: AccountId, price: U128, decimals: u8) {
@@ -133,48 +469,189 @@ impl Contract {
             env::predecessor_account_id() == self.pyth_oracle_id,
             "Only oracle contract can submit prices"
         );
-        require!(decimals <= 18, "Decimals must be <= 18");
-        require!(price.0 > 0, "Price must be positive");
-        let feed = PriceFeedInternal {
-            price: price.0,
-            decimals,
-            last_update_timestamp: Self::now_ms(),
-        };
-        self.price_feeds.insert(&collateral_id, &feed);
+        self.internal_submit_price(&collateral_id, price.0, decimals, Self::now_ms());
+    }
+
+    /// Accepts a price signed off-chain by the collateral's oracle key from
+    /// any relayer. `publish_time` (ms) must be newer than the feed's last
+    /// update, within the collateral's `max_price_age_ms` of now, and
+    /// becomes the feed's update time.
+    pub fn submit_signed_price(
+        &mut self,
+        collateral_id: AccountId,
+        price: U128,
+        decimals: u8,
+        publish_time: U64,
+        signature: Base64VecU8,
+    ) {
+        let mut signer = self
+            .oracle_signers
+            .get(&collateral_id)
+            .unwrap_or_else(|| env::panic_str("No oracle key for collateral"));
+        require!(
+            publish_time.0 > signer.last_publish_time,
+            "Signed price is not newer"
+        );
+        require!(
+            publish_time.0 <= Self::now_ms(),
+            "Publish time is in the future"
+        );
+        if let Some(feed) = self.price_feeds.get(&collateral_id) {
+            require!(
+                publish_time.0 > feed.last_update_timestamp,
+                "Signed price is older than the feed"
+            );
+        }
+        let max_age = self
+            .expect_config(&collateral_id)
+            .max_price_age_ms
+            .unwrap_or(types::MAX_SIGNED_PRICE_AGE_MS);
+        require!(
+            Self::now_ms() - publish_time.0 <= max_age,
+            "Signed price too old"
+        );
+        let signature: [u8; 64] = signature
+            .0
+            .as_slice()
+            .try_into()
+            .unwrap_or_else(|_| env::panic_str("Invalid signature length"));
+        let public_key: [u8; 32] = signer.public_key.as_bytes()[1..]
+            .try_into()
+            .unwrap_or_else(|_| env::panic_str("Invalid oracle key"));
+        let message = Self::signed_price_message(&collateral_id, price.0, decimals, publish_time.0);
+        require!(
+            env::ed25519_verify(&signature, &message, &public_key),
+            "Invalid price signature"
+        );
+        self.internal_submit_price(&collateral_id, price.0, decimals, publish_time.0);
+        signer.last_publish_time = publish_time.0;
+        self.oracle_signers.insert(&collateral_id, &signer);
+    }
+
+    /// Sets the ed25519 key whose signed prices `submit_signed_price`
+    /// accepts for a collateral; `None` disables signed prices.
+    #[payable]
+    pub fn set_oracle_public_key(
+        &mut self,
+        collateral_id: AccountId,
+        public_key: Option<PublicKey>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.expect_config(&collateral_id);
+        match public_key {
+            Some(public_key) => {
+                require!(
+                    public_key.curve_type() == CurveType::ED25519,
+                    "Oracle key must be ed25519"
+                );
+                let last_publish_time = self
+                    .oracle_signers
+                    .get(&collateral_id)
+                    .map_or(0, |signer| signer.last_publish_time);
+                self.oracle_signers.insert(
+                    &collateral_id,
+                    &types::OracleSigner {
+                        public_key,
+                        last_publish_time,
+                    },
+                );
+            }
+            None => {
+                self.oracle_signers.remove(&collateral_id);
+            }
+        }
+    }
+
+    /// Governance override for a price the oracle path rejected as
+    /// implausible. Skips the `min_reasonable_price` floor only.
+    #[payable]
+    pub fn override_price(&mut self, collateral_id: AccountId, price: U128, decimals: u8) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.assert_price_format(&collateral_id, price.0, decimals);
+        self.store_price(&collateral_id, price.0, decimals, Self::now_ms());
+        log!(
+            "Price for {} overridden to {} with {} decimals",
+            collateral_id,
+            price.0,
+            decimals
+        );
     }
 
+    /// Mints `amount` to the receiver; the caller's debt grows by `amount`
+    /// plus the `borrow_fee_bps` fee, which is minted to the treasury and
+    /// the stability pool.
     #[payable]
-    pub fn borrow(&mut self, collateral_id: AccountId, amount: U128) {
+    pub fn borrow(
+        &mut self,
+        collateral_id: AccountId,
+        amount: U128,
+        receiver: Option<AccountId>,
+    ) -> types::BorrowResult {
         assert_one_yocto();
+        self.assert_not_paused();
+        self.assert_operation_live(types::Operation::Borrow);
         require!(amount.0 > 0, "Amount must be > 0");
+        if let Some(max_borrow) = self.max_borrow_per_tx {
+            require!(
+                amount.0 <= max_borrow,
+                "Borrow exceeds per-transaction limit"
+            );
+        }
         let caller = env::predecessor_account_id();
-        let mut trove = self.expect_trove(&caller, &collateral_id);
+        let receiver_id = receiver.unwrap_or_else(|| caller.clone());
+        require!(
+            self.nusd.accounts.contains_key(&receiver_id),
+            "Receiver not registered"
+        );
+        self.assert_not_written_off(&collateral_id);
+        let mut trove = self.expect_trove_accrued(&caller, &collateral_id);
         let config = self.expect_config(&collateral_id);
-        let price = self.expect_price_internal(&collateral_id);
+        let price = self.expect_fresh_price_internal(&collateral_id);
 
+        let fee = Self::borrow_fee(&config, amount.0);
         let new_debt = trove
             .debt_amount
             .checked_add(amount.0)
+            .and_then(|debt| debt.checked_add(fee))
             .expect("Debt overflow");
         self.ensure_debt_ceiling(&collateral_id, new_debt);
         let ratio = self.collateral_ratio(trove.collateral_amount, new_debt, &price);
-        require!(
-            ratio >= config.min_collateral_ratio_bps as u128,
-            "Insufficient collateral"
-        );
+        self.assert_borrow_ratio(&collateral_id, &config, &price, ratio);
+        if ratio < config.recovery_collateral_ratio_bps as u128 {
+            self.emit_event(CdpEvent::TroveAtRisk {
+                owner_id: caller.clone(),
+                collateral_id: collateral_id.clone(),
+                collateral_ratio_bps: U128(ratio),
+            });
+        }
 
         trove.debt_amount = new_debt;
         trove.last_update_timestamp = Self::now_ms();
         self.save_trove(&caller, &collateral_id, &trove);
-        self.add_total_debt(&collateral_id, amount.0 as i128);
+        self.add_total_debt(&collateral_id, (amount.0 + fee) as i128);
+        self.assert_out_of_recovery(&collateral_id, &config, &price);
 
-        self.nusd.internal_deposit(&caller, amount.0);
+        self.nusd.internal_deposit(&receiver_id, amount.0);
+        self.distribute_borrow_fee(&collateral_id, fee);
+        self.ensure_system_utilization();
         FtMint {
-            owner_id: &caller,
+            owner_id: &receiver_id,
             amount,
             memo: Some("cdp_borrow"),
         }
         .emit();
+        self.emit_event(CdpEvent::Borrow {
+            owner_id: caller,
+            collateral_id,
+            amount,
+            fee: U128(fee),
+        });
+        types::BorrowResult {
+            minted: amount,
+            fee: U128(fee),
+        }
     }
 
     #[payable]
@@ -201,593 +678,6131 @@ impl Contract {
     ) -> Promise {
         assert_one_yocto();
         let caller = env::predecessor_account_id();
-        let mut trove = self.expect_trove(&caller, &collateral_id);
-        require!(trove.collateral_amount >= amount.0, "Not enough collateral");
-        trove.collateral_amount -= amount.0;
-        if trove.debt_amount > 0 {
-            let price = self.expect_price_internal(&collateral_id);
-            let config = self.expect_config(&collateral_id);
-            let ratio = self.collateral_ratio(trove.collateral_amount, trove.debt_amount, &price);
-            require!(
-                ratio >= config.min_collateral_ratio_bps as u128,
-                "Would violate MCR"
-            );
-        }
-        trove.last_update_timestamp = Self::now_ms();
-        self.save_trove(&caller, &collateral_id, &trove);
+        self.internal_withdraw_collateral(&caller, &collateral_id, amount.0, None);
         let receiver_id = receiver.unwrap_or(caller.clone());
         self.send_collateral(receiver_id, collateral_id, amount.0)
     }
 
+    /// Burns `repay_amount` nUSD against the caller's debt and withdraws
+    /// `withdraw_amount` collateral, checking MCR once on the final position.
     #[payable]
-    pub fn close_trove(&mut self, collateral_id: AccountId) -> Promise {
+    pub fn repay_and_withdraw(
+        &mut self,
+        collateral_id: AccountId,
+        repay_amount: U128,
+        withdraw_amount: U128,
+        receiver: Option<AccountId>,
+    ) -> Promise {
         assert_one_yocto();
+        require!(repay_amount.0 > 0, "Amount must be > 0");
+        require!(withdraw_amount.0 > 0, "Amount must be > 0");
         let caller = env::predecessor_account_id();
-        let key = Self::trove_key(&caller, &collateral_id);
-        let trove = self
-            .troves
-            .get(&key)
-            .unwrap_or_else(|| env::panic_str("Trove not found"));
-        require!(trove.debt_amount == 0, "Outstanding debt");
-        self.troves.remove(&key);
-        if trove.collateral_amount == 0 {
-            env::panic_str("No collateral to withdraw");
+        self.nusd.internal_withdraw(&caller, repay_amount.0);
+        FtBurn {
+            owner_id: &caller,
+            amount: repay_amount,
+            memo: Some("cdp_repay"),
         }
-        self.send_collateral(caller, collateral_id, trove.collateral_amount)
+        .emit();
+        let before = self.expect_trove_accrued(&caller, &collateral_id);
+        self.internal_repay(&caller, &collateral_id, repay_amount.0);
+        self.internal_withdraw_collateral(&caller, &collateral_id, withdraw_amount.0, Some(before));
+        let receiver_id = receiver.unwrap_or(caller.clone());
+        self.send_collateral(receiver_id, collateral_id, withdraw_amount.0)
     }
 
+    /// Withdraws collateral (`collateral_delta` <= 0) and borrows or repays
+    /// (`debt_delta` > 0 or < 0) in one step, checking the ratio once on the
+    /// result. Collateral is added with `ft_transfer_call` and an
+    /// `adjust_trove` message instead.
     #[payable]
-    pub fn deposit_to_stability_pool(&mut self, amount: U128) {
+    pub fn adjust_trove(
+        &mut self,
+        collateral_id: AccountId,
+        collateral_delta: I128,
+        debt_delta: I128,
+        receiver: Option<AccountId>,
+    ) -> PromiseOrValue<()> {
         assert_one_yocto();
-        require!(amount.0 > 0, "Amount must be > 0");
+        require!(
+            collateral_delta.0 <= 0,
+            "Add collateral with ft_transfer_call"
+        );
         let caller = env::predecessor_account_id();
-        self.settle_stability_rewards(&caller);
-        let mut deposit = self
-            .stability_pool_deposits
-            .get(&caller)
-            .unwrap_or_else(|| types::StabilityDeposit::new(self.stability_pool_epoch));
-        self.ensure_deposit_epoch(&caller, &mut deposit);
-        let shares = self.shares_from_amount(amount.0);
-        require!(shares > 0, "Shares must be > 0");
-        deposit.shares = deposit
-            .shares
-            .checked_add(shares)
-            .expect("Deposit share overflow");
-        self.stability_pool_total_shares = self
-            .stability_pool_total_shares
-            .checked_add(shares)
-            .expect("Pool share overflow");
-        self.stability_pool_total_nusd = self
-            .stability_pool_total_nusd
-            .checked_add(amount.0)
-            .expect("Pool balance overflow");
-        self.sync_reward_debt_snapshot(&mut deposit);
-        self.stability_pool_deposits.insert(&caller, &deposit);
-
-        self.nusd.internal_withdraw(&caller, amount.0);
-        self.nusd
-            .internal_deposit(&env::current_account_id(), amount.0);
+        let receiver_id = receiver.unwrap_or_else(|| caller.clone());
+        self.internal_adjust_trove(
+            &caller,
+            &collateral_id,
+            collateral_delta.0,
+            debt_delta.0,
+            &receiver_id,
+        );
+        if collateral_delta.0 < 0 {
+            PromiseOrValue::Promise(self.send_collateral(
+                receiver_id,
+                collateral_id,
+                collateral_delta.0.unsigned_abs(),
+            ))
+        } else {
+            PromiseOrValue::Value(())
+        }
     }
 
+    /// Repays `percent_bps` of the trove's debt from the caller's nUSD and
+    /// withdraws the same share of its collateral to `receiver`. The repay
+    /// rounds up and the withdrawal down, so the ratio never falls. At
+    /// 10000 bps the emptied trove is removed.
     #[payable]
-    pub fn withdraw_from_stability_pool(&mut self, amount: Option<U128>) {
-        assert_one_yocto();
+    pub fn reduce_position(
+        &mut self,
+        collateral_id: AccountId,
+        percent_bps: u16,
+        receiver: Option<AccountId>,
+    ) -> PromiseOrValue<()> {
+        require!(
+            percent_bps > 0 && percent_bps as u128 <= crate::types::BPS_DENOMINATOR,
+            "Percent must be in (0, 10000] bps"
+        );
         let caller = env::predecessor_account_id();
-        self.settle_stability_rewards(&caller);
-        let mut deposit = self
-            .stability_pool_deposits
-            .get(&caller)
-            .unwrap_or_else(|| types::StabilityDeposit::new(self.stability_pool_epoch));
-        self.ensure_deposit_epoch(&caller, &mut deposit);
-        require!(deposit.shares > 0, "Nothing deposited");
-        let available = deposit.amount(
-            self.stability_pool_total_nusd,
-            self.stability_pool_total_shares,
-        );
-        require!(available > 0, "Pool depleted");
-        let requested = amount.map(|v| v.0).unwrap_or(available);
-        require!(requested > 0, "Amount must be > 0");
-        require!(requested <= available, "Insufficient balance");
-        let shares = self.shares_for_withdraw(requested);
-        require!(shares > 0, "Share calculation underflow");
-
-        deposit.shares = deposit
-            .shares
-            .checked_sub(shares)
-            .expect("Withdraw exceeds shares");
-        self.stability_pool_total_shares = self
-            .stability_pool_total_shares
-            .checked_sub(shares)
-            .expect("Pool share underflow");
-        self.stability_pool_total_nusd = self
-            .stability_pool_total_nusd
-            .checked_sub(requested)
-            .expect("Pool balance underflow");
-        self.stability_pool_deposits.insert(&caller, &deposit);
-
-        self.nusd
-            .internal_withdraw(&env::current_account_id(), requested);
-        self.nusd.internal_deposit(&caller, requested);
+        let trove = self.expect_trove_accrued(&caller, &collateral_id);
+        let repay = trove
+            .debt_amount
+            .checked_mul(percent_bps as u128)
+            .expect("Repay overflow")
+            .div_ceil(crate::types::BPS_DENOMINATOR);
+        let withdraw = Self::mul_div(
+            trove.collateral_amount,
+            percent_bps as u128,
+            crate::types::BPS_DENOMINATOR,
+        );
+        self.adjust_trove(
+            collateral_id,
+            I128(-(withdraw as i128)),
+            I128(-(repay as i128)),
+            receiver,
+        )
     }
 
     #[payable]
-    pub fn claim_collateral_reward(
-        &mut self,
-        collateral_id: AccountId,
-        amount: Option<U128>,
-    ) -> Promise {
+    pub fn close_trove(&mut self, collateral_id: AccountId) -> Promise {
         assert_one_yocto();
         let caller = env::predecessor_account_id();
-        self.settle_stability_rewards(&caller);
-        self.claim_collateral(&caller, &collateral_id, amount.map(|v| v.0))
+        let trove = self.expect_trove_accrued(&caller, &collateral_id);
+        require!(trove.debt_amount == 0, "Outstanding debt");
+        self.remove_trove(&caller, &collateral_id);
+        if trove.collateral_amount == 0 {
+            env::panic_str("No collateral to withdraw");
+        }
+        self.send_collateral(caller, collateral_id, trove.collateral_amount)
     }
 
+    /// Hands the caller's trove, collateral and debt alike, to `new_owner`.
+    /// The new owner must not already have a trove for this collateral; use
+    /// `merge_troves` to combine two.
     #[payable]
-    pub fn redeem(
-        &mut self,
-        collateral_id: AccountId,
-        trove_owner: AccountId,
-        amount: U128,
-    ) -> Promise {
+    pub fn transfer_trove(&mut self, collateral_id: AccountId, new_owner: AccountId) {
         assert_one_yocto();
-        require!(amount.0 > 0, "Amount must be > 0");
-        let redeemer = env::predecessor_account_id();
-        let mut trove = self.expect_trove(&trove_owner, &collateral_id);
-        require!(trove.debt_amount >= amount.0, "Redeem exceeds trove debt");
-
-        let price = self.expect_price_internal(&collateral_id);
-        let divisor = Self::decimals_factor(price.decimals);
-        let collateral_out = amount
-            .0
-            .checked_mul(divisor)
-            .expect("Redeem amount overflow")
-            / price.price;
-        require!(collateral_out > 0, "Redeem amount too small");
+        let caller = env::predecessor_account_id();
+        require!(new_owner != caller, "Cannot transfer trove to self");
         require!(
-            trove.collateral_amount >= collateral_out,
-            "Redeem exceeds collateral"
+            self.nusd.accounts.contains_key(&new_owner),
+            "New owner not registered"
         );
-
-        trove.debt_amount -= amount.0;
-        trove.collateral_amount -= collateral_out;
-        trove.last_update_timestamp = Self::now_ms();
-        if trove.debt_amount == 0 && trove.collateral_amount == 0 {
+        require!(
             self.troves
-                .remove(&Self::trove_key(&trove_owner, &collateral_id));
-        } else {
-            self.save_trove(&trove_owner, &collateral_id, &trove);
-        }
-        self.add_total_debt(&collateral_id, -(amount.0 as i128));
+                .get(&Self::trove_key(&new_owner, &collateral_id))
+                .is_none(),
+            "New owner already has a trove"
+        );
+        let mut trove = self.expect_trove_accrued(&caller, &collateral_id);
+        self.remove_trove(&caller, &collateral_id);
+        trove.owner_id = new_owner.clone();
+        self.save_trove(&new_owner, &collateral_id, &trove);
+        self.add_trove_owner(&new_owner, &collateral_id);
+        log!(
+            "Trove for {} transferred from {} to {}",
+            collateral_id,
+            caller,
+            new_owner
+        );
+    }
 
-        self.nusd.internal_withdraw(&redeemer, amount.0);
-        FtBurn {
-            owner_id: &redeemer,
-            amount,
-            memo: Some("cdp_redeem"),
+    /// Lets `target` merge the caller's trove into their own with
+    /// `merge_troves`; `None` withdraws the approval.
+    #[payable]
+    pub fn approve_trove_merge(&mut self, collateral_id: AccountId, target: Option<AccountId>) {
+        assert_one_yocto();
+        let caller = env::predecessor_account_id();
+        self.expect_trove(&caller, &collateral_id);
+        let key = Self::trove_key(&caller, &collateral_id);
+        match target {
+            Some(target) => {
+                require!(target != caller, "Cannot merge trove into itself");
+                self.merge_approvals.insert(&key, &target);
+            }
+            None => {
+                self.merge_approvals.remove(&key);
+            }
         }
-        .emit();
-
-        self.enqueue_collateral_reward(&redeemer, &collateral_id, collateral_out);
-        Promise::new(env::current_account_id())
     }
 
+    /// Folds `source_owner`'s trove into the caller's. The source owner must
+    /// have approved the caller, and the merged trove must meet MCR.
     #[payable]
-    pub fn liquidate(&mut self, collateral_id: AccountId, owners: Vec<AccountId>) -> U64 {
+    pub fn merge_troves(&mut self, collateral_id: AccountId, source_owner: AccountId) {
         assert_one_yocto();
-        require!(!owners.is_empty(), "Owners required");
-        let price = self.expect_price_internal(&collateral_id);
-        let config = self.expect_config(&collateral_id);
-        let mut processed = 0u64;
-        for owner in owners {
-            let key = Self::trove_key(&owner, &collateral_id);
-            let trove = match self.troves.get(&key) {
-                Some(trove) => trove,
-                None => continue,
-            };
-            if trove.debt_amount == 0 {
-                continue;
-            }
+        let caller = env::predecessor_account_id();
+        require!(source_owner != caller, "Cannot merge trove into itself");
+        require!(
+            self.merge_approvals
+                .get(&Self::trove_key(&source_owner, &collateral_id))
+                .is_some_and(|approved| approved == caller),
+            "Merge not approved by source owner"
+        );
+        self.assert_not_written_off(&collateral_id);
+        let source = self.expect_trove_accrued(&source_owner, &collateral_id);
+        let mut trove = self.expect_trove_accrued(&caller, &collateral_id);
+        trove.collateral_amount = trove
+            .collateral_amount
+            .checked_add(source.collateral_amount)
+            .expect("Collateral overflow");
+        trove.debt_amount = trove
+            .debt_amount
+            .checked_add(source.debt_amount)
+            .expect("Debt overflow");
+        trove.last_liquidation_timestamp = trove
+            .last_liquidation_timestamp
+            .max(source.last_liquidation_timestamp);
+        if trove.debt_amount > 0 {
+            let price = self.expect_price_internal(&collateral_id);
+            let config = self.expect_config(&collateral_id);
             let ratio = self.collateral_ratio(trove.collateral_amount, trove.debt_amount, &price);
-            if ratio >= config.min_collateral_ratio_bps as u128 {
-                continue;
-            }
-            require!(
-                self.stability_pool_total_nusd >= trove.debt_amount,
-                "Insufficient stability pool funds"
-            );
-            let penalty = trove
-                .collateral_amount
-                .checked_mul(config.liquidation_penalty_bps as u128)
-                .expect("Penalty overflow")
-                / crate::types::BPS_DENOMINATOR;
-            let distributable = trove
-                .collateral_amount
-                .checked_sub(penalty)
-                .expect("Distributable underflow");
-            self.accrue_reward_per_share(&collateral_id, distributable);
-            let owner_id = self.owner_id.clone();
-            self.enqueue_collateral_reward(&owner_id, &collateral_id, penalty);
-            self.burn_from_stability_pool(trove.debt_amount);
-            self.add_total_debt(&collateral_id, -(trove.debt_amount as i128));
-            self.troves.remove(&key);
-            processed += 1;
+            let in_recovery = self.is_in_recovery_at(&collateral_id, &config, &price);
+            Self::assert_withdrawal_ratio(&config, ratio, in_recovery);
         }
-        U64(processed)
+        self.remove_trove(&source_owner, &collateral_id);
+        self.save_trove(&caller, &collateral_id, &trove);
+        log!(
+            "Trove for {} of {} merged into {}",
+            collateral_id,
+            source_owner,
+            caller
+        );
     }
 
-    #[payable]
-    pub fn trigger_swap_via_intents(
-        &mut self,
-        input_token: AccountId,
-        output_token: AccountId,
-        amount_in: U128,
-        min_out: U128,
-        routing_hint: Option<String>,
-    ) -> Promise {
-        self.assert_owner();
-        let attached = env::attached_deposit();
+    /// Checks `account_id`'s trove of a rebasing collateral against its
+    /// pro-rata share of the contract's token balance, less collateral owed
+    /// as rewards, flagging a mismatch in `get_rebase_divergence`. Stored
+    /// amounts are left untouched.
+    pub fn sync_rebase(&mut self, collateral_id: AccountId, account_id: AccountId) -> Promise {
         require!(
-            attached > NearToken::from_yoctonear(0),
-            "Attach deposit for Intents execution"
+            self.expect_config(&collateral_id).is_rebasing,
+            "Collateral is not rebasing"
         );
-        require!(amount_in.0 > 0, "Amount must be > 0");
-        let caller = env::predecessor_account_id();
-        ext_intents::ext(self.intent_router_id.clone())
-            .with_attached_deposit(attached)
-            .with_static_gas(GAS_FOR_SWAP)
-            .execute_swap(
-                caller.clone(),
-                input_token.clone(),
-                output_token,
-                amount_in,
-                min_out,
-                routing_hint,
-            )
+        self.expect_trove(&account_id, &collateral_id);
+        ext_ft::ext(collateral_id.clone())
+            .with_static_gas(GAS_FOR_BALANCE_QUERY)
+            .ft_balance_of(env::current_account_id())
             .then(
                 ext_self::ext(env::current_account_id())
                     .with_static_gas(GAS_FOR_CALLBACK)
-                    .on_swap_complete(caller, input_token, amount_in),
+                    .on_rebase_balance(collateral_id, account_id),
             )
     }
 
+    /// Returns whether the trove diverged from the queried balance.
     #[private]
-    pub fn on_swap_complete(
+    pub fn on_rebase_balance(&mut self, collateral_id: AccountId, account_id: AccountId) -> bool {
+        let balance = Self::queried_balance();
+        let key = Self::trove_key(&account_id, &collateral_id);
+        let Some(trove) = self.troves.get(&key) else {
+            return false;
+        };
+        let total = self.total_collateral.get(&collateral_id).unwrap_or(0);
+        if total == 0 {
+            return false;
+        }
+        // Queued and unsettled rewards sit in the same balance but belong
+        // to no trove.
+        let rewards = self
+            .collateral_reward_totals
+            .get(&collateral_id)
+            .unwrap_or(0)
+            .saturating_add(self.unsettled_rewards.get(&collateral_id).unwrap_or(0));
+        let held = balance.saturating_sub(rewards);
+        let actual = Self::mul_div(trove.collateral_amount, held, total);
+        if actual == trove.collateral_amount {
+            self.rebase_divergences.remove(&key);
+            return false;
+        }
+        self.rebase_divergences.insert(
+            &key,
+            &types::RebaseDivergence {
+                recorded: trove.collateral_amount,
+                actual,
+                synced_at: Self::now_ms(),
+            },
+        );
+        log!(
+            "Rebase divergence on {} trove of {}: recorded {}, actual {}",
+            collateral_id,
+            account_id,
+            trove.collateral_amount,
+            actual
+        );
+        true
+    }
+
+    /// Deposits into the pool dedicated to `collateral_id`, or into the
+    /// shared pool when it is omitted.
+    #[payable]
+    pub fn deposit_to_stability_pool(&mut self, amount: U128, collateral_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_not_paused();
+        self.assert_operation_live(types::Operation::Stability);
+        require!(amount.0 > 0, "Amount must be > 0");
+        if let Some(collateral_id) = &collateral_id {
+            require!(
+                self.pool_for_collateral(collateral_id) != types::PoolId::Shared,
+                "Collateral uses the shared pool"
+            );
+        }
+        let caller = env::predecessor_account_id();
+        let pool_id = Self::resolve_pool(collateral_id);
+        self.ensure_self_registered();
+        self.pool_deposit(&caller, &pool_id, amount.0);
+
+        self.nusd.internal_withdraw(&caller, amount.0);
+        self.nusd
+            .internal_deposit(&env::current_account_id(), amount.0);
+    }
+
+    /// Withdrawals above `large_withdrawal_threshold` are queued rather than
+    /// paid; the returned id is passed to `finalize_withdrawal` once the
+    /// delay has passed.
+    #[payable]
+    pub fn withdraw_from_stability_pool(
         &mut self,
-        caller_id: AccountId,
-        input_token: AccountId,
-        amount_in: U128,
-    ) -> bool {
-        match env::promise_result(0) {
-            PromiseResult::Successful(_) => {
-                log!(
-                    "NEAR Intents swap succeeded: caller={}, token={}, amount={}",
-                    caller_id,
-                    input_token,
-                    amount_in.0
-                );
-                true
-            }
-            _ => {
-                log!(
-                    "NEAR Intents swap failed: caller={}, token={}, amount={}",
-                    caller_id,
-                    input_token,
-                    amount_in.0
-                );
-                false
+        amount: Option<U128>,
+        collateral_id: Option<AccountId>,
+    ) -> Option<U64> {
+        assert_one_yocto();
+        self.assert_operation_live(types::Operation::Stability);
+        let caller = env::predecessor_account_id();
+        let pool_id = Self::resolve_pool(collateral_id);
+        if !(self.paused && self.allow_withdrawals_when_paused) {
+            require!(
+                !self.in_stability_withdraw_cooldown(&caller, &pool_id),
+                "Stability deposit in withdraw cooldown"
+            );
+        }
+        if let Some(id) = self.maybe_queue_withdrawal(&caller, &pool_id, amount.map(|v| v.0)) {
+            return Some(U64(id));
+        }
+        let withdrawn = self.pool_withdraw(&caller, &pool_id, amount.map(|v| v.0));
+
+        self.nusd
+            .internal_withdraw(&env::current_account_id(), withdrawn);
+        self.nusd.internal_deposit(&caller, withdrawn);
+        None
+    }
+
+    /// Pays out a queued withdrawal whose delay has passed. Liquidations
+    /// absorbed while it waited reduce the payout; a drained pool voids it.
+    #[payable]
+    pub fn finalize_withdrawal(&mut self, id: U64) -> U128 {
+        assert_one_yocto();
+        self.assert_operation_live(types::Operation::Stability);
+        let queued = self
+            .queued_withdrawals
+            .get(&id.0)
+            .unwrap_or_else(|| env::panic_str("Queued withdrawal not found"));
+        require!(
+            queued.account_id == env::predecessor_account_id(),
+            "Not the withdrawal owner"
+        );
+        require!(
+            Self::now_ms() >= queued.ready_at,
+            "Withdrawal delay has not passed"
+        );
+        self.queued_withdrawals.remove(&id.0);
+
+        let pool = self.pool(&queued.pool_id);
+        let mut deposit = self.settled_deposit(&queued.account_id, &queued.pool_id, &pool);
+        deposit.queued_shares = deposit.queued_shares.saturating_sub(queued.shares);
+        let shares = if queued.epoch == pool.epoch {
+            queued.shares.min(deposit.shares)
+        } else {
+            0
+        };
+        self.stability_pool_deposits.insert(
+            &types::DepositKey::new(&queued.account_id, &queued.pool_id),
+            &deposit,
+        );
+        let amount = pool.amount_from_shares(shares);
+        if amount == 0 {
+            log!(
+                "Queued withdrawal {} voided: nothing left to withdraw",
+                id.0
+            );
+            return U128(0);
+        }
+        let full_exit = shares == deposit.shares;
+        let withdrawn = self.pool_withdraw(
+            &queued.account_id,
+            &queued.pool_id,
+            (!full_exit).then_some(amount),
+        );
+
+        self.nusd
+            .internal_withdraw(&env::current_account_id(), withdrawn);
+        self.nusd.internal_deposit(&queued.account_id, withdrawn);
+        U128(withdrawn)
+    }
+
+    /// Moves `amount` (or the whole deposit) between the shared pool
+    /// (`None`) and a dedicated pool, settling rewards in both first.
+    #[payable]
+    pub fn migrate_stability_deposit(
+        &mut self,
+        from: Option<AccountId>,
+        to: Option<AccountId>,
+        amount: Option<U128>,
+    ) -> U128 {
+        assert_one_yocto();
+        self.assert_operation_live(types::Operation::Stability);
+        require!(from != to, "Source and destination pools are the same");
+        if let Some(collateral_id) = &to {
+            require!(
+                self.pool_for_collateral(collateral_id) != types::PoolId::Shared,
+                "Collateral uses the shared pool"
+            );
+        }
+        let caller = env::predecessor_account_id();
+        let withdrawn = self.pool_withdraw(&caller, &Self::resolve_pool(from), amount.map(|v| v.0));
+        self.pool_deposit(&caller, &Self::resolve_pool(to), withdrawn);
+        U128(withdrawn)
+    }
+
+    /// Backfills the depositor index of the pool for `collateral_id` (the
+    /// shared pool when omitted) with accounts whose deposits predate it.
+    /// Accounts already listed or without current shares are skipped.
+    /// Returns the shares still held outside the index.
+    pub fn index_stability_depositors(
+        &mut self,
+        collateral_id: Option<AccountId>,
+        account_ids: Vec<AccountId>,
+    ) -> U128 {
+        require!(
+            account_ids.len() <= types::MAX_DEPOSITORS_PER_BATCH,
+            "Too many depositors"
+        );
+        let pool_id = Self::resolve_pool(collateral_id);
+        let pool = self.pool(&pool_id);
+        for account_id in &account_ids {
+            let shares = self
+                .stability_pool_deposits
+                .get(&types::DepositKey::new(account_id, &pool_id))
+                .filter(|deposit| deposit.epoch == pool.epoch)
+                .map_or(0, |deposit| deposit.shares);
+            if shares > 0 {
+                self.index_stability_depositor(account_id, &pool_id, shares);
             }
         }
+        U128(self.unindexed_stability_shares.get(&pool_id).unwrap_or(0))
     }
 
-    fn internal_repay(&mut self, owner_id: &AccountId, collateral_id: &AccountId, amount: Balance) {
-        let mut trove = self.expect_trove(owner_id, collateral_id);
-        require!(amount <= trove.debt_amount, "Repay exceeds debt");
-        trove.debt_amount -= amount;
-        trove.last_update_timestamp = Self::now_ms();
-        self.save_trove(owner_id, collateral_id, &trove);
-        self.add_total_debt(collateral_id, -(amount as i128));
+    /// Break-glass repair for a pool whose share total no longer matches its
+    /// deposits. Each call settles up to `MAX_DEPOSITORS_PER_BATCH` indexed
+    /// depositors and returns whether the pass is done; the last call
+    /// compares their current-epoch shares with the recorded total. The
+    /// stability pool must be paused and the depositor index complete. The
+    /// total is only ever lowered and no nUSD leaves the pool, so no
+    /// deposit loses value. Safe to repeat: a consistent pool is left
+    /// untouched.
+    #[payable]
+    pub fn force_epoch_resync(&mut self, collateral_id: Option<AccountId>) -> bool {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            self.stability_paused,
+            "Pause the stability pool before a resync"
+        );
+        let pool_id = Self::resolve_pool(collateral_id);
+        require!(
+            self.unindexed_stability_shares.get(&pool_id).is_none(),
+            "Depositor index incomplete"
+        );
+        let mut pool = self.pool(&pool_id);
+        let mut progress = self
+            .epoch_resyncs
+            .get(&pool_id)
+            .filter(|progress| progress.epoch == pool.epoch)
+            .unwrap_or(types::EpochResync {
+                epoch: pool.epoch,
+                next_index: 0,
+                shares: 0,
+            });
+        let depositors = self.stability_depositor_set(&pool_id);
+        let batch: Vec<AccountId> = depositors
+            .iter()
+            .skip(progress.next_index as usize)
+            .take(types::MAX_DEPOSITORS_PER_BATCH)
+            .collect();
+        for account_id in &batch {
+            let deposit = self.settled_deposit(account_id, &pool_id, &pool);
+            progress.shares = progress
+                .shares
+                .checked_add(deposit.shares)
+                .expect("Pool share overflow");
+            self.stability_pool_deposits
+                .insert(&types::DepositKey::new(account_id, &pool_id), &deposit);
+        }
+        progress.next_index += batch.len() as u64;
+        if progress.next_index < depositors.len() {
+            log!(
+                "Resync: epoch {} settled {} of {} depositors",
+                pool.epoch,
+                progress.next_index,
+                depositors.len()
+            );
+            self.epoch_resyncs.insert(&pool_id, &progress);
+            return false;
+        }
+        self.epoch_resyncs.remove(&pool_id);
+
+        let total_shares = progress.shares;
+        if total_shares == pool.total_shares {
+            log!(
+                "Resync: epoch {} consistent at {} shares",
+                pool.epoch,
+                total_shares
+            );
+            return true;
+        }
+        log!(
+            "Resync: epoch {} records {} shares and {} nUSD, deposits hold {} shares",
+            pool.epoch,
+            pool.total_shares,
+            pool.total_nusd,
+            total_shares
+        );
+        if pool.total_nusd == 0 {
+            // Shares with nothing behind them: close the epoch so the next
+            // deposit starts clean. Rewards were settled above.
+            let snapshot = std::mem::take(&mut pool.reward_per_share);
+            self.epoch_reward_snapshots
+                .insert(&(pool_id.clone(), pool.epoch), &snapshot);
+            pool.total_shares = 0;
+            pool.epoch = pool.epoch.saturating_add(1);
+            log!("Resync: advanced to epoch {}", pool.epoch);
+        } else if total_shares > pool.total_shares {
+            // Raising the total would dilute every deposit.
+            log!("Resync: deposits exceed the recorded total, left unchanged");
+            return true;
+        } else if total_shares == 0 {
+            log!(
+                "Resync: no deposit holds shares, {} nUSD left in place",
+                pool.total_nusd
+            );
+            return true;
+        } else {
+            pool.total_shares = total_shares;
+            log!("Resync: share total set to {}", total_shares);
+        }
+        self.stability_pools.insert(&pool_id, &pool);
+        true
     }
-}
 
-#[near_bindgen]
-impl FungibleTokenCore for Contract {
     #[payable]
-    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
-        self.nusd.ft_transfer(receiver_id, amount, memo)
+    pub fn claim_collateral_reward(
+        &mut self,
+        collateral_id: AccountId,
+        amount: Option<U128>,
+    ) -> Promise {
+        assert_one_yocto();
+        let caller = env::predecessor_account_id();
+        self.settle_stability_rewards(&caller, &types::PoolId::Shared);
+        self.settle_collateral_rewards(&caller, &collateral_id);
+        self.claim_collateral(&caller, &collateral_id, amount.map(|v| v.0))
     }
 
+    /// Claims the whole `collateral_id` reward. When `target_collateral` is
+    /// the same token it is added to the caller's trove rather than sent
+    /// out, and the amount added is returned.
     #[payable]
-    fn ft_transfer_call(
+    pub fn claim_and_deposit(
         &mut self,
-        receiver_id: AccountId,
-        amount: U128,
-        memo: Option<String>,
-        msg: String,
+        collateral_id: AccountId,
+        target_collateral: AccountId,
     ) -> PromiseOrValue<U128> {
-        self.nusd.ft_transfer_call(receiver_id, amount, memo, msg)
+        assert_one_yocto();
+        let caller = env::predecessor_account_id();
+        self.settle_stability_rewards(&caller, &types::PoolId::Shared);
+        self.settle_collateral_rewards(&caller, &collateral_id);
+        if target_collateral != collateral_id {
+            return PromiseOrValue::Promise(self.claim_collateral(&caller, &collateral_id, None));
+        }
+        let claimable = self
+            .collateral_rewards
+            .get(&types::CollateralRewardKey::new(&caller, &collateral_id))
+            .unwrap_or(0);
+        require!(claimable > 0, "Nothing to claim");
+        require!(
+            claimable >= self.min_claimable(&collateral_id),
+            "Claim below minimum claimable amount"
+        );
+        self.debit_collateral_reward(&caller, &collateral_id, claimable);
+        if caller == self.treasury_id {
+            self.ensure_treasury_reserve(&collateral_id);
+        }
+        self.credit_trove_collateral(caller, collateral_id, claimable);
+        PromiseOrValue::Value(U128(claimable))
     }
 
-    fn ft_total_supply(&self) -> U128 {
-        self.nusd.ft_total_supply()
+    /// Claims every collateral reward at or above its `min_claimable`, at
+    /// most `MAX_COLLATERALS_PER_BATCH` collaterals per call. Returns what
+    /// was sent.
+    #[payable]
+    pub fn claim_all_rewards(&mut self) -> Vec<(AccountId, U128)> {
+        assert_one_yocto();
+        let caller = env::predecessor_account_id();
+        self.settle_stability_rewards(&caller, &types::PoolId::Shared);
+        let mut claimed = Vec::new();
+        for collateral_id in self.configs.keys_as_vector().to_vec() {
+            self.settle_collateral_rewards(&caller, &collateral_id);
+            if claimed.len() == crate::types::MAX_COLLATERALS_PER_BATCH {
+                continue;
+            }
+            let claimable = self
+                .collateral_rewards
+                .get(&types::CollateralRewardKey::new(&caller, &collateral_id))
+                .unwrap_or(0);
+            if claimable == 0 || claimable < self.min_claimable(&collateral_id) {
+                continue;
+            }
+            // The treasury claims reserve-bound collateral one at a time with
+            // an amount that respects the floor.
+            if caller == self.treasury_id
+                && self.expect_config(&collateral_id).min_treasury_reserve_bps > 0
+            {
+                log!("Skipping {}: treasury reserve floor", collateral_id);
+                continue;
+            }
+            self.claim_collateral(&caller, &collateral_id, None);
+            claimed.push((collateral_id, U128(claimable)));
+        }
+        claimed
     }
 
-    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
-        self.nusd.ft_balance_of(account_id)
+    /// Claims only the listed collaterals, in order, skipping those with
+    /// nothing claimable or below `min_claimable`. Returns what was sent.
+    #[payable]
+    pub fn claim_rewards(&mut self, collateral_ids: Vec<AccountId>) -> Vec<(AccountId, U128)> {
+        assert_one_yocto();
+        require!(
+            collateral_ids.len() <= crate::types::MAX_COLLATERALS_PER_BATCH,
+            "Too many collaterals"
+        );
+        let caller = env::predecessor_account_id();
+        self.settle_stability_rewards(&caller, &types::PoolId::Shared);
+        let mut claimed = Vec::new();
+        for collateral_id in collateral_ids {
+            if claimed
+                .iter()
+                .any(|(claimed_id, _)| claimed_id == &collateral_id)
+            {
+                continue;
+            }
+            self.expect_config(&collateral_id);
+            self.settle_collateral_rewards(&caller, &collateral_id);
+            let claimable = self
+                .collateral_rewards
+                .get(&types::CollateralRewardKey::new(&caller, &collateral_id))
+                .unwrap_or(0);
+            if claimable == 0 || claimable < self.min_claimable(&collateral_id) {
+                continue;
+            }
+            self.claim_collateral(&caller, &collateral_id, None);
+            claimed.push((collateral_id, U128(claimable)));
+        }
+        claimed
+    }
+
+    /// With `refresh`, pulls a fresh price from the Pyth contract first and
+    /// redeems in the callback. With `convert`, the collateral out is swapped
+    /// through the intents router and the output sent to the redeemer; the
+    /// attached deposit pays for the swap. A failed swap queues the raw
+    /// collateral as a claimable reward instead.
+    #[payable]
+    pub fn redeem(
+        &mut self,
+        collateral_id: AccountId,
+        trove_owner: AccountId,
+        amount: U128,
+        refresh: Option<bool>,
+        convert: Option<RedeemConversion>,
+    ) -> Promise {
+        let attached = env::attached_deposit();
+        if convert.is_some() {
+            require!(
+                attached > NearToken::from_yoctonear(0),
+                "Attach deposit for Intents execution"
+            );
+        } else {
+            assert_one_yocto();
+        }
+        require!(amount.0 > 0, "Amount must be > 0");
+        self.assert_not_written_off(&collateral_id);
+        let redeemer = env::predecessor_account_id();
+        let deposit = U128(attached.as_yoctonear());
+        if refresh.unwrap_or(false) {
+            let gas = if convert.is_some() {
+                GAS_FOR_REDEEM_CONVERSION
+            } else {
+                GAS_FOR_CALLBACK
+            };
+            return self.pull_price(&collateral_id).then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(gas)
+                    .on_price_refreshed_redeem(
+                        redeemer,
+                        collateral_id,
+                        trove_owner,
+                        amount,
+                        convert,
+                        deposit,
+                    ),
+            );
+        }
+        self.execute_redeem(
+            &redeemer,
+            &collateral_id,
+            &trove_owner,
+            amount.0,
+            convert,
+            deposit,
+        )
     }
-}
 
-#[near_bindgen]
-impl FungibleTokenResolver for Contract {
     #[private]
-    fn ft_resolve_transfer(
+    pub fn on_price_refreshed_redeem(
         &mut self,
-        sender_id: AccountId,
-        receiver_id: AccountId,
+        redeemer: AccountId,
+        collateral_id: AccountId,
+        trove_owner: AccountId,
         amount: U128,
-    ) -> U128 {
-        let (used_amount, _) =
-            self.nusd
-                .internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
-        used_amount.into()
+        convert: Option<RedeemConversion>,
+        deposit: U128,
+    ) -> Promise {
+        self.store_pulled_price(&collateral_id);
+        self.execute_redeem(
+            &redeemer,
+            &collateral_id,
+            &trove_owner,
+            amount.0,
+            convert,
+            deposit,
+        )
     }
-}
 
-#[near_bindgen]
-impl FungibleTokenReceiver for Contract {
-    fn ft_on_transfer(
+    /// Redeems up to `amount` nUSD against troves in ascending collateral
+    /// ratio order, visiting at most `max_iterations` troves (capped at
+    /// `MAX_REDEMPTION_ITERATIONS`). Troves below MCR are left to
+    /// liquidation. Returns the nUSD actually redeemed and the troves
+    /// skipped, with the reason.
+    #[payable]
+    pub fn redeem_multi(
         &mut self,
-        sender_id: AccountId,
+        collateral_id: AccountId,
         amount: U128,
-        msg: String,
-    ) -> PromiseOrValue<U128> {
-        let token_id = env::predecessor_account_id();
-        let action = Self::parse_transfer_action(&msg);
+        max_iterations: Option<u64>,
+    ) -> types::RedemptionReport {
+        assert_one_yocto();
+        require!(amount.0 > 0, "Amount must be > 0");
+        self.assert_not_written_off(&collateral_id);
+        let redeemer = env::predecessor_account_id();
+        let price = self.expect_price_internal(&collateral_id);
+        let config = self.expect_config(&collateral_id);
+        let max_iterations = max_iterations
+            .unwrap_or(types::MAX_REDEMPTION_ITERATIONS)
+            .min(types::MAX_REDEMPTION_ITERATIONS);
+        let candidates = self.redemption_candidates(&collateral_id, &redeemer, max_iterations);
+        let mut remaining = amount.0;
+        let mut collateral_out: Balance = 0;
+        let mut skipped = Vec::new();
+        for trove in candidates {
+            if remaining == 0 {
+                break;
+            }
+            let trove = self
+                .projected_trove(&trove.owner_id, &collateral_id)
+                .unwrap_or(trove);
+            let reason = if self.is_liquidatable(&trove, &config, &price) {
+                Some(types::RedemptionSkipReason::Liquidatable)
+            } else if Self::is_too_young_to_redeem(&trove, &config) {
+                Some(types::RedemptionSkipReason::TooYoung)
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                skipped.push(types::SkippedTrove {
+                    owner_id: trove.owner_id,
+                    reason,
+                });
+                continue;
+            }
+            let take = remaining.min(trove.debt_amount);
+            let gross = Self::redeem_split(take, &price).0;
+            if gross - Self::redemption_fee(&config, gross) == 0 {
+                break;
+            }
+            let (out, used) = self.internal_redeem(&trove.owner_id, &collateral_id, take, &price);
+            collateral_out = collateral_out
+                .checked_add(out)
+                .expect("Redeem collateral overflow");
+            remaining -= used;
+        }
+        let redeemed = amount.0 - remaining;
+        require!(redeemed > 0, "Nothing to redeem");
 
-        if token_id == env::current_account_id() {
-            match action {
-                TransferAction::RepayDebt { collateral_id } => {
-                    self.nusd
-                        .internal_withdraw(&env::current_account_id(), amount.0);
-                    FtBurn {
-                        owner_id: &sender_id,
-                        amount,
-                        memo: Some("cdp_repay_via_ft"),
-                    }
-                    .emit();
-                    self.internal_repay(&sender_id, &collateral_id, amount.0);
-                }
-                _ => env::panic_str("Unsupported action for nUSD"),
+        self.nusd.internal_withdraw(&redeemer, redeemed);
+        FtBurn {
+            owner_id: &redeemer,
+            amount: U128(redeemed),
+            memo: Some("cdp_redeem"),
+        }
+        .emit();
+
+        self.enqueue_collateral_reward(&redeemer, &collateral_id, collateral_out);
+        types::RedemptionReport {
+            redeemed: U128(redeemed),
+            skipped,
+        }
+    }
+
+    #[payable]
+    pub fn liquidate(
+        &mut self,
+        collateral_id: AccountId,
+        owners: Vec<AccountId>,
+        min_total_collateral_seized: Option<U128>,
+        referrer: Option<AccountId>,
+        refresh: Option<bool>,
+    ) -> PromiseOrValue<U64> {
+        assert_one_yocto();
+        if refresh.unwrap_or(false) {
+            return PromiseOrValue::Promise(
+                self.pull_price(&collateral_id).then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_CALLBACK)
+                        .on_price_refreshed_liquidate(
+                            env::predecessor_account_id(),
+                            collateral_id,
+                            owners,
+                            min_total_collateral_seized,
+                            referrer,
+                        ),
+                ),
+            );
+        }
+        PromiseOrValue::Value(self.execute_liquidate(
+            &env::predecessor_account_id(),
+            &collateral_id,
+            owners,
+            min_total_collateral_seized,
+            referrer.as_ref(),
+        ))
+    }
+
+    #[private]
+    pub fn on_price_refreshed_liquidate(
+        &mut self,
+        keeper: AccountId,
+        collateral_id: AccountId,
+        owners: Vec<AccountId>,
+        min_total_collateral_seized: Option<U128>,
+        referrer: Option<AccountId>,
+    ) -> U64 {
+        self.store_pulled_price(&collateral_id);
+        self.execute_liquidate(
+            &keeper,
+            &collateral_id,
+            owners,
+            min_total_collateral_seized,
+            referrer.as_ref(),
+        )
+    }
+
+    /// Liquidates like `liquidate`, but withholds `rebalance_fraction_bps` of
+    /// the depositors' collateral and swaps it to nUSD that is credited back
+    /// to the stability pool. A failed swap leaves the withheld collateral as
+    /// depositor rewards.
+    #[payable]
+    pub fn liquidate_and_rebalance(
+        &mut self,
+        collateral_id: AccountId,
+        owners: Vec<AccountId>,
+        min_out: U128,
+        routing_hint: Option<String>,
+    ) -> Promise {
+        let attached = env::attached_deposit();
+        require!(
+            attached > NearToken::from_yoctonear(0),
+            "Attach deposit for Intents execution"
+        );
+        require!(self.rebalance_fraction_bps > 0, "Rebalancing disabled");
+        let (processed, _, withheld) =
+            self.internal_liquidate(&collateral_id, owners, self.rebalance_fraction_bps, None);
+        require!(withheld > 0, "Nothing to rebalance");
+        self.pay_keeper_reward(&env::predecessor_account_id(), &collateral_id, processed);
+        let current_id = env::current_account_id();
+        self.dispatch_swap(
+            self.intent_router_id.clone(),
+            SwapRequest {
+                caller_id: current_id.clone(),
+                input_token: collateral_id,
+                output_token: current_id.clone(),
+                amount_in: U128(withheld),
+                min_out,
+                routing_hint,
+                deposit: U128(attached.as_yoctonear()),
+            },
+            current_id,
+            SwapPurpose::Rebalance { min_out },
+            self.retry_swaps_via_fallback,
+        )
+    }
+
+    /// Sets the router failed swaps are retried through. Retries only happen
+    /// with `retry_on_failure` and a fallback set.
+    #[payable]
+    pub fn set_fallback_router(
+        &mut self,
+        fallback_router_id: Option<AccountId>,
+        retry_on_failure: bool,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            fallback_router_id.is_some() || !retry_on_failure,
+            "Retry requires a fallback router"
+        );
+        require!(
+            fallback_router_id.as_ref() != Some(&self.intent_router_id),
+            "Fallback must differ from the primary router"
+        );
+        self.fallback_router_id = fallback_router_id;
+        self.retry_swaps_via_fallback = retry_on_failure;
+    }
+
+    #[payable]
+    pub fn set_reliquidation_cooldown_ms(&mut self, reliquidation_cooldown_ms: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.reliquidation_cooldown_ms = reliquidation_cooldown_ms;
+    }
+
+    #[payable]
+    pub fn set_stability_withdraw_cooldown_ms(&mut self, stability_withdraw_cooldown_ms: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.stability_withdraw_cooldown_ms = stability_withdraw_cooldown_ms;
+    }
+
+    /// `None` disables the queue; withdrawals already queued still finalize
+    /// at the `ready_at` they were given.
+    #[payable]
+    pub fn set_withdrawal_queue(
+        &mut self,
+        large_withdrawal_threshold: Option<U128>,
+        withdrawal_queue_delay_ms: u64,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.large_withdrawal_threshold = large_withdrawal_threshold.map(|v| v.0);
+        self.withdrawal_queue_delay_ms = withdrawal_queue_delay_ms;
+    }
+
+    /// Halts borrowing, collateral and pool deposits and manual swaps;
+    /// repaying, withdrawing and claiming stay open.
+    #[payable]
+    pub fn set_paused(&mut self, paused: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.paused = paused;
+    }
+
+    /// Pauses or resumes one operation class protocol-wide, independently
+    /// of `set_paused`.
+    #[payable]
+    pub fn set_operation_paused(&mut self, operation: types::Operation, paused: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        match operation {
+            types::Operation::Borrow => self.borrow_paused = paused,
+            types::Operation::Redeem => self.redeem_paused = paused,
+            types::Operation::Liquidate => self.liquidate_paused = paused,
+            types::Operation::Stability => self.stability_paused = paused,
+        }
+    }
+
+    /// Whether a pause lifts the stability withdraw cooldown.
+    #[payable]
+    pub fn set_allow_withdrawals_when_paused(&mut self, allow: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.allow_withdrawals_when_paused = allow;
+    }
+
+    /// Burns insurance fund nUSD against a collateral's bad debt, up to
+    /// `amount` or the whole balance. Returns the debt covered.
+    #[payable]
+    pub fn cover_bad_debt(&mut self, collateral_id: AccountId, amount: Option<U128>) -> U128 {
+        assert_one_yocto();
+        self.assert_owner();
+        let bad_debt = self.bad_debt.get(&collateral_id).unwrap_or(0);
+        require!(bad_debt > 0, "No bad debt");
+        let covered = amount.map_or(bad_debt, |amount| amount.0);
+        require!(covered > 0, "Amount must be > 0");
+        require!(covered <= bad_debt, "Amount exceeds bad debt");
+        require!(
+            covered <= self.insurance_fund_balance,
+            "Insufficient insurance fund"
+        );
+        self.insurance_fund_balance -= covered;
+        self.bad_debt.insert(&collateral_id, &(bad_debt - covered));
+        self.nusd
+            .internal_withdraw(&env::current_account_id(), covered);
+        FtBurn {
+            owner_id: &env::current_account_id(),
+            amount: U128(covered),
+            memo: Some("cdp_cover_bad_debt"),
+        }
+        .emit();
+        U128(covered)
+    }
+
+    /// Caps the nUSD a single borrow may mint; `None` removes the cap.
+    #[payable]
+    pub fn set_max_borrow_per_tx(&mut self, max_borrow_per_tx: Option<U128>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.max_borrow_per_tx = max_borrow_per_tx.map(|amount| amount.0);
+    }
+
+    /// Caps total nUSD supply at a share of all priced collateral value,
+    /// checked on every borrow; `None` removes the cap.
+    #[payable]
+    pub fn set_max_system_utilization_bps(&mut self, max_system_utilization_bps: Option<u16>) {
+        assert_one_yocto();
+        self.assert_owner();
+        if let Some(max_bps) = max_system_utilization_bps {
+            require!(
+                max_bps > 0 && max_bps as u128 <= crate::types::BPS_DENOMINATOR,
+                "Utilization cap must be in (0, 10000] bps"
+            );
+        }
+        self.max_system_utilization_bps = max_system_utilization_bps;
+    }
+
+    /// Share of every borrow fee minted into the borrowed collateral's
+    /// stability pool instead of the treasury.
+    #[payable]
+    pub fn set_fee_to_stability_pool_bps(&mut self, fee_to_stability_pool_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            fee_to_stability_pool_bps as u128 <= crate::types::BPS_DENOMINATOR,
+            "Split must be <= 10000 bps"
+        );
+        self.fee_to_stability_pool_bps = fee_to_stability_pool_bps;
+    }
+
+    #[payable]
+    pub fn set_interest_to_equity_bps(&mut self, interest_to_equity_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            interest_to_equity_bps as u128 <= crate::types::BPS_DENOMINATOR,
+            "Split must be <= 10000 bps"
+        );
+        self.interest_to_equity_bps = interest_to_equity_bps;
+    }
+
+    #[payable]
+    pub fn set_redemption_self_policy(&mut self, policy: types::RedemptionSelfPolicy) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.redemption_self_policy = policy;
+    }
+
+    /// Flat nUSD minted to the keeper per liquidated trove and booked as
+    /// bad debt of that collateral; zero disables it.
+    #[payable]
+    pub fn set_keeper_nusd_reward(&mut self, keeper_nusd_reward: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.keeper_nusd_reward = keeper_nusd_reward.0;
+    }
+
+    #[payable]
+    pub fn set_event_level(&mut self, event_level: types::EventLevel) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.event_level = event_level;
+    }
+
+    #[payable]
+    pub fn set_referrer_bps(&mut self, referrer_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            referrer_bps <= crate::types::MAX_REFERRER_BPS,
+            "Referrer rate too high"
+        );
+        self.referrer_bps = referrer_bps;
+    }
+
+    #[payable]
+    pub fn set_rebalance_fraction_bps(&mut self, rebalance_fraction_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            rebalance_fraction_bps as u128 <= crate::types::BPS_DENOMINATOR,
+            "Fraction must be <= 10000 bps"
+        );
+        self.rebalance_fraction_bps = rebalance_fraction_bps;
+    }
+
+    #[payable]
+    pub fn trigger_swap_via_intents(
+        &mut self,
+        input_token: AccountId,
+        output_token: AccountId,
+        amount_in: U128,
+        min_out: U128,
+        routing_hint: Option<String>,
+    ) -> Promise {
+        self.assert_owner();
+        self.assert_not_paused();
+        let attached = env::attached_deposit();
+        require!(
+            attached > NearToken::from_yoctonear(0),
+            "Attach deposit for Intents execution"
+        );
+        require!(amount_in.0 > 0, "Amount must be > 0");
+        let caller = env::predecessor_account_id();
+        self.dispatch_swap(
+            self.intent_router_id.clone(),
+            SwapRequest {
+                caller_id: caller.clone(),
+                input_token,
+                output_token,
+                amount_in,
+                min_out,
+                routing_hint,
+                deposit: U128(attached.as_yoctonear()),
+            },
+            caller,
+            SwapPurpose::Manual,
+            self.retry_swaps_via_fallback,
+        )
+    }
+
+    #[payable]
+    pub fn treasury_convert(
+        &mut self,
+        collateral_id: AccountId,
+        amount: U128,
+        min_out: U128,
+        routing_hint: Option<String>,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(amount.0 > 0, "Amount must be > 0");
+        self.expect_config(&collateral_id);
+        let treasury_id = self.treasury_id.clone();
+        self.debit_collateral_reward(&treasury_id, &collateral_id, amount.0);
+        self.ensure_treasury_reserve(&collateral_id);
+        self.dispatch_treasury_convert(
+            self.intent_router_id.clone(),
+            TreasuryConversion {
+                collateral_id,
+                amount,
+                min_out,
+                routing_hint,
+                treasury_id,
+            },
+            self.retry_swaps_via_fallback,
+        )
+    }
+
+    /// Settles a treasury conversion. The token reports how much collateral
+    /// the router kept; the rest came back and is restored to the treasury.
+    #[private]
+    pub fn on_treasury_convert(
+        &mut self,
+        conversion: TreasuryConversion,
+        retry: bool,
+    ) -> PromiseOrValue<bool> {
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map_or(0, |used| used.0.min(conversion.amount.0)),
+            _ => 0,
+        };
+        if used == 0 && retry {
+            if let Some(fallback_id) = self.fallback_router_id.clone() {
+                log!("Treasury conversion failed; retrying via {}", fallback_id);
+                return PromiseOrValue::Promise(self.dispatch_treasury_convert(
+                    fallback_id,
+                    conversion,
+                    false,
+                ));
             }
-        } else {
-            match action {
-                TransferAction::DepositCollateral { target_account } => {
-                    let owner = target_account.unwrap_or_else(|| sender_id.clone());
-                    self.internal_deposit_collateral(owner, token_id, amount.0);
+        }
+        let unused = conversion.amount.0 - used;
+        if unused > 0 {
+            self.enqueue_collateral_reward(
+                &conversion.treasury_id,
+                &conversion.collateral_id,
+                unused,
+            );
+        }
+        log!(
+            "Treasury conversion of {} {}: {} swapped, {} restored",
+            conversion.amount.0,
+            conversion.collateral_id,
+            used,
+            unused
+        );
+        PromiseOrValue::Value(used > 0)
+    }
+
+    #[private]
+    pub fn on_swap_complete(
+        &mut self,
+        caller_id: AccountId,
+        input_token: AccountId,
+        amount_in: U128,
+        deposit: U128,
+        purpose: SwapPurpose,
+        retry: Option<SwapRequest>,
+    ) -> PromiseOrValue<bool> {
+        let result = env::promise_result(0);
+        // A manual or redemption swap's caller paid the deposit; hand back
+        // what the router returned unused.
+        if matches!(purpose, SwapPurpose::Manual | SwapPurpose::Redeem { .. }) {
+            let refund = Self::unused_swap_deposit(&result, deposit.0);
+            if refund > 0 {
+                log!("Refunding {} yoctoNEAR of unused swap deposit", refund);
+                Promise::new(caller_id.clone()).transfer(NearToken::from_yoctonear(refund));
+            }
+        }
+        if !matches!(result, PromiseResult::Successful(_)) && self.retry_swaps_via_fallback {
+            if let (Some(request), Some(fallback_id)) = (retry, self.fallback_router_id.clone()) {
+                log!("NEAR Intents swap failed; retrying via {}", fallback_id);
+                return PromiseOrValue::Promise(self.dispatch_swap(
+                    fallback_id,
+                    request,
+                    caller_id,
+                    purpose,
+                    false,
+                ));
+            }
+        }
+        let succeeded = match purpose {
+            SwapPurpose::Manual => matches!(result, PromiseResult::Successful(_)),
+            SwapPurpose::Rebalance { min_out } => match Self::swap_amount_out(result) {
+                Some(amount_out)
+                    if amount_out >= min_out.0 && amount_out <= self.unallocated_nusd() =>
+                {
+                    let pool_id = self.pool_for_collateral(&input_token);
+                    self.credit_stability_pool(&pool_id, amount_out);
+                    true
                 }
-                TransferAction::RepayDebt { .. } => {
-                    env::panic_str("Repay action invalid for external tokens")
+                _ => {
+                    let pool_id = self.pool_for_collateral(&input_token);
+                    self.accrue_reward_per_share(&pool_id, &input_token, amount_in.0);
+                    false
+                }
+            },
+            SwapPurpose::Redeem { min_out } => match Self::swap_amount_out(result) {
+                Some(amount_out) if amount_out >= min_out.0 => true,
+                _ => {
+                    self.enqueue_collateral_reward(&caller_id, &input_token, amount_in.0);
+                    false
                 }
+            },
+        };
+        if succeeded {
+            log!(
+                "NEAR Intents swap succeeded: caller={}, token={}, amount={}",
+                caller_id,
+                input_token,
+                amount_in.0
+            );
+        } else {
+            log!(
+                "NEAR Intents swap failed: caller={}, token={}, amount={}",
+                caller_id,
+                input_token,
+                amount_in.0
+            );
+        }
+        PromiseOrValue::Value(succeeded)
+    }
+
+    fn execute_redeem(
+        &mut self,
+        redeemer: &AccountId,
+        collateral_id: &AccountId,
+        trove_owner: &AccountId,
+        amount: Balance,
+        convert: Option<RedeemConversion>,
+        deposit: U128,
+    ) -> Promise {
+        let price = self.expect_price_internal(collateral_id);
+        let (collateral_out, nusd_used) =
+            self.internal_redeem(trove_owner, collateral_id, amount, &price);
+
+        self.nusd.internal_withdraw(redeemer, nusd_used);
+        FtBurn {
+            owner_id: redeemer,
+            amount: U128(nusd_used),
+            memo: Some("cdp_redeem"),
+        }
+        .emit();
+
+        match convert {
+            Some(convert) => self.dispatch_swap(
+                self.intent_router_id.clone(),
+                SwapRequest {
+                    caller_id: redeemer.clone(),
+                    input_token: collateral_id.clone(),
+                    output_token: convert.output_token,
+                    amount_in: U128(collateral_out),
+                    min_out: convert.min_out,
+                    routing_hint: convert.routing_hint,
+                    deposit,
+                },
+                redeemer.clone(),
+                SwapPurpose::Redeem {
+                    min_out: convert.min_out,
+                },
+                self.retry_swaps_via_fallback,
+            ),
+            None => {
+                self.enqueue_collateral_reward(redeemer, collateral_id, collateral_out);
+                Promise::new(env::current_account_id())
             }
         }
-        PromiseOrValue::Value(U128(0))
     }
-}
 
-#[near_bindgen]
-impl StorageManagement for Contract {
-    #[payable]
-    fn storage_deposit(
-        &mut self,
-        account_id: Option<AccountId>,
-        registration_only: Option<bool>,
-    ) -> StorageBalance {
-        self.nusd.storage_deposit(account_id, registration_only)
+    fn execute_liquidate(
+        &mut self,
+        keeper: &AccountId,
+        collateral_id: &AccountId,
+        owners: Vec<AccountId>,
+        min_total_collateral_seized: Option<U128>,
+        referrer: Option<&AccountId>,
+    ) -> U64 {
+        require!(
+            referrer != Some(keeper),
+            "Liquidator cannot be its own referrer"
+        );
+        let (processed, total_seized, _) =
+            self.internal_liquidate(collateral_id, owners, 0, referrer);
+        if let Some(min_seized) = min_total_collateral_seized {
+            require!(
+                total_seized >= min_seized.0,
+                "Collateral seized below minimum"
+            );
+        }
+        self.pay_keeper_reward(keeper, collateral_id, processed);
+        U64(processed)
+    }
+
+    /// Cancels `amount` of the trove's debt for collateral at the oracle
+    /// price and returns the collateral released to the redeemer, net of the
+    /// redemption fee queued for the treasury.
+    fn internal_redeem(
+        &mut self,
+        trove_owner: &AccountId,
+        collateral_id: &AccountId,
+        amount: Balance,
+        price: &PriceFeedInternal,
+    ) -> (Balance, Balance) {
+        self.assert_operation_live(types::Operation::Redeem);
+        let mut trove = self.expect_trove_accrued(trove_owner, collateral_id);
+        let config = self.expect_config(collateral_id);
+        require!(
+            !Self::is_too_young_to_redeem(&trove, &config),
+            "Trove too young to redeem"
+        );
+        require!(trove.debt_amount >= amount, "Redeem exceeds trove debt");
+        let (collateral_out, nusd_used, _) = Self::redeem_split(amount, price);
+        let fee = Self::redemption_fee(&config, collateral_out);
+        require!(collateral_out - fee > 0, "Redeem amount too small");
+        require!(
+            trove.collateral_amount >= collateral_out,
+            "Redeem exceeds collateral"
+        );
+
+        trove.debt_amount -= nusd_used;
+        trove.collateral_amount -= collateral_out;
+        trove.last_update_timestamp = Self::now_ms();
+        if trove.debt_amount == 0 && trove.collateral_amount == 0 {
+            self.remove_trove(trove_owner, collateral_id);
+        } else {
+            self.save_trove(trove_owner, collateral_id, &trove);
+        }
+        self.add_total_debt(collateral_id, -(nusd_used as i128));
+        if fee > 0 {
+            let treasury_id = self.treasury_id.clone();
+            self.enqueue_collateral_reward(&treasury_id, collateral_id, fee);
+        }
+        self.emit_event(CdpEvent::Redeem {
+            trove_owner: trove_owner.clone(),
+            collateral_id: collateral_id.clone(),
+            debt_redeemed: U128(nusd_used),
+            collateral_out: U128(collateral_out),
+        });
+        (collateral_out - fee, nusd_used)
+    }
+
+    fn internal_repay(&mut self, owner_id: &AccountId, collateral_id: &AccountId, amount: Balance) {
+        let mut trove = self.expect_trove_accrued(owner_id, collateral_id);
+        require!(amount <= trove.debt_amount, "Repay exceeds debt");
+        trove.debt_amount -= amount;
+        trove.last_update_timestamp = Self::now_ms();
+        self.save_trove(owner_id, collateral_id, &trove);
+        self.add_total_debt(collateral_id, -(amount as i128));
+        self.emit_event(CdpEvent::Repay {
+            owner_id: owner_id.clone(),
+            collateral_id: collateral_id.clone(),
+            amount: U128(amount),
+        });
+    }
+
+    /// Returns the number of troves processed, the collateral seized and the
+    /// part of the depositors' share withheld at `withhold_bps`. Seized
+    /// collateral splits into the treasury penalty, the referrer cut and the
+    /// depositors' share.
+    fn internal_liquidate(
+        &mut self,
+        collateral_id: &AccountId,
+        owners: Vec<AccountId>,
+        withhold_bps: u16,
+        referrer: Option<&AccountId>,
+    ) -> (u64, Balance, Balance) {
+        require!(!owners.is_empty(), "Owners required");
+        self.assert_operation_live(types::Operation::Liquidate);
+        self.assert_not_written_off(collateral_id);
+        let config = self.expect_config(collateral_id);
+        let price = if config.liquidate_on_stale_price {
+            self.expect_price_internal(collateral_id)
+        } else {
+            self.expect_fresh_price_internal(collateral_id)
+        };
+        let pool_id = self.pool_for_collateral(collateral_id);
+        let mut processed = 0u64;
+        let mut total_seized: Balance = 0;
+        let mut total_withheld: Balance = 0;
+        for owner in owners {
+            let key = Self::trove_key(&owner, collateral_id);
+            if self.troves.get(&key).is_none() {
+                continue;
+            }
+            let trove = self.expect_trove_accrued(&owner, collateral_id);
+            if !self.is_liquidatable(&trove, &config, &price) {
+                continue;
+            }
+            if self.in_reliquidation_cooldown(&trove) {
+                log!("Skipping {}: liquidation cooldown active", owner);
+                continue;
+            }
+            let available = self.pool(&pool_id).total_nusd;
+            if available == 0 && processed > 0 {
+                log!("Stability pool exhausted; stopping before {}", owner);
+                break;
+            }
+            require!(available > 0, "Insufficient stability pool funds");
+            let ratio = self.collateral_ratio(trove.collateral_amount, trove.debt_amount, &price);
+            // An underfunded pool offsets what it can and seizes the same
+            // share of the collateral, rounded up so the depositors paying
+            // for it never lose to the rounding; the rest stays in the trove.
+            let (debt_offset, seized) = if available >= trove.debt_amount {
+                (trove.debt_amount, trove.collateral_amount)
+            } else {
+                (
+                    available,
+                    Self::mul_div_ceil(trove.collateral_amount, available, trove.debt_amount),
+                )
+            };
+            let penalty = seized
+                .checked_mul(Self::effective_penalty_bps(&config, ratio))
+                .expect("Penalty overflow")
+                / crate::types::BPS_DENOMINATOR;
+            let referral = match referrer {
+                Some(referrer_id) => {
+                    let referral = seized
+                        .checked_mul(self.referrer_bps as u128)
+                        .expect("Referral overflow")
+                        / crate::types::BPS_DENOMINATOR;
+                    self.enqueue_collateral_reward(referrer_id, collateral_id, referral);
+                    referral
+                }
+                None => 0,
+            };
+            let distributable = seized
+                .checked_sub(penalty)
+                .and_then(|rest| rest.checked_sub(referral))
+                .expect("Distributable underflow");
+            let withheld = distributable
+                .checked_mul(withhold_bps as u128)
+                .expect("Withheld overflow")
+                / crate::types::BPS_DENOMINATOR;
+            self.accrue_reward_per_share(&pool_id, collateral_id, distributable - withheld);
+            total_withheld += withheld;
+            let treasury_id = self.treasury_id.clone();
+            self.enqueue_collateral_reward(&treasury_id, collateral_id, penalty);
+            self.burn_from_stability_pool(&pool_id, debt_offset);
+            self.add_total_debt(collateral_id, -(debt_offset as i128));
+            if debt_offset == trove.debt_amount {
+                self.remove_trove(&owner, collateral_id);
+            } else {
+                let mut trove = trove;
+                trove.debt_amount -= debt_offset;
+                trove.collateral_amount -= seized;
+                trove.last_update_timestamp = Self::now_ms();
+                trove.last_liquidation_timestamp = Self::now_ms();
+                self.save_trove(&owner, collateral_id, &trove);
+                log!(
+                    "Partially liquidated {}: {} debt left",
+                    owner,
+                    trove.debt_amount
+                );
+            }
+            self.emit_event(CdpEvent::Liquidate {
+                owner_id: owner.clone(),
+                collateral_id: collateral_id.clone(),
+                debt_offset: U128(debt_offset),
+                collateral_seized: U128(seized),
+            });
+            total_seized = total_seized
+                .checked_add(seized)
+                .expect("Seized collateral overflow");
+            processed += 1;
+        }
+        (processed, total_seized, total_withheld)
+    }
+
+    /// Applies collateral and debt changes to the owner's trove with a single
+    /// ratio check on the result. Added collateral must already be received;
+    /// minted nUSD goes to `receiver_id`, repaid nUSD is burned from the owner.
+    fn internal_adjust_trove(
+        &mut self,
+        owner_id: &AccountId,
+        collateral_id: &AccountId,
+        collateral_delta: i128,
+        debt_delta: i128,
+        receiver_id: &AccountId,
+    ) {
+        require!(
+            collateral_delta != 0 || debt_delta != 0,
+            "Nothing to adjust"
+        );
+        if debt_delta > 0 {
+            self.assert_not_paused();
+            self.assert_operation_live(types::Operation::Borrow);
+        }
+        self.assert_not_written_off(collateral_id);
+        if collateral_delta > 0 {
+            self.internal_deposit_collateral(
+                owner_id.clone(),
+                collateral_id.clone(),
+                collateral_delta as u128,
+            );
+        }
+        let before = self.expect_trove_accrued(owner_id, collateral_id);
+        let mut trove = before.clone();
+        let mut fee = 0;
+        if collateral_delta < 0 {
+            self.assert_withdrawal_unlocked(&before);
+            let amount = collateral_delta.unsigned_abs();
+            require!(trove.collateral_amount >= amount, "Not enough collateral");
+            trove.collateral_amount -= amount;
+        }
+        if debt_delta < 0 {
+            let amount = debt_delta.unsigned_abs();
+            require!(amount <= trove.debt_amount, "Repay exceeds debt");
+            trove.debt_amount -= amount;
+            self.nusd.internal_withdraw(owner_id, amount);
+            FtBurn {
+                owner_id,
+                amount: U128(amount),
+                memo: Some("cdp_repay"),
+            }
+            .emit();
+        } else if debt_delta > 0 {
+            let amount = debt_delta as u128;
+            if let Some(max_borrow) = self.max_borrow_per_tx {
+                require!(amount <= max_borrow, "Borrow exceeds per-transaction limit");
+            }
+            require!(
+                self.nusd.accounts.contains_key(receiver_id),
+                "Receiver not registered"
+            );
+            fee = Self::borrow_fee(&self.expect_config(collateral_id), amount);
+            trove.debt_amount = trove
+                .debt_amount
+                .checked_add(amount)
+                .and_then(|debt| debt.checked_add(fee))
+                .expect("Debt overflow");
+            self.nusd.internal_deposit(receiver_id, amount);
+            FtMint {
+                owner_id: receiver_id,
+                amount: U128(amount),
+                memo: Some("cdp_borrow"),
+            }
+            .emit();
+            self.distribute_borrow_fee(collateral_id, fee);
+        }
+
+        let mut borrow_checked = None;
+        if trove.debt_amount > 0 && (debt_delta > 0 || collateral_delta < 0) {
+            let price = if debt_delta > 0 {
+                self.expect_fresh_price_internal(collateral_id)
+            } else {
+                self.expect_price_internal(collateral_id)
+            };
+            let config = self.expect_config(collateral_id);
+            let ratio = self.collateral_ratio(trove.collateral_amount, trove.debt_amount, &price);
+            if debt_delta > 0 {
+                self.assert_borrow_ratio(collateral_id, &config, &price, ratio);
+                borrow_checked = Some((config, price));
+            } else if self.is_feed_stale(&price, &config) {
+                require!(Self::ratio_not_decreased(&before, &trove), "Price is stale");
+            } else {
+                let in_recovery = self.is_in_recovery_at(collateral_id, &config, &price);
+                Self::assert_withdrawal_ratio(&config, ratio, in_recovery);
+            }
+        }
+        trove.last_update_timestamp = Self::now_ms();
+        if trove.collateral_amount == 0 && trove.debt_amount == 0 {
+            // Nothing is left for `close_trove` to send; drop the empty trove
+            // so it stops counting toward `max_active_troves`.
+            self.remove_trove(owner_id, collateral_id);
+        } else {
+            self.save_trove(owner_id, collateral_id, &trove);
+        }
+        if debt_delta != 0 {
+            self.add_total_debt(collateral_id, debt_delta + fee as i128);
+        }
+        if debt_delta > 0 {
+            self.ensure_system_utilization();
+        }
+        if let Some((config, price)) = borrow_checked {
+            self.assert_out_of_recovery(collateral_id, &config, &price);
+        }
+    }
+
+    fn internal_withdraw_collateral(
+        &mut self,
+        owner_id: &AccountId,
+        collateral_id: &AccountId,
+        amount: Balance,
+        before: Option<TroveInternal>,
+    ) {
+        let mut trove = self.expect_trove_accrued(owner_id, collateral_id);
+        let before = before.unwrap_or_else(|| trove.clone());
+        self.assert_withdrawal_unlocked(&before);
+        require!(trove.collateral_amount >= amount, "Not enough collateral");
+        trove.collateral_amount -= amount;
+        if trove.debt_amount > 0 {
+            let price = self.expect_price_internal(collateral_id);
+            let config = self.expect_config(collateral_id);
+            if self.is_feed_stale(&price, &config) {
+                // Without a usable price only changes that do not lower the
+                // trove's ratio are safe.
+                require!(Self::ratio_not_decreased(&before, &trove), "Price is stale");
+            } else {
+                let ratio =
+                    self.collateral_ratio(trove.collateral_amount, trove.debt_amount, &price);
+                let in_recovery = self.is_in_recovery_at(collateral_id, &config, &price);
+                Self::assert_withdrawal_ratio(&config, ratio, in_recovery);
+            }
+        }
+        trove.last_update_timestamp = Self::now_ms();
+        self.save_trove(owner_id, collateral_id, &trove);
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.nusd.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.nusd.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.nusd.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.nusd.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let (used_amount, _) =
+            self.nusd
+                .internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        used_amount.into()
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        // Some tokens ping receivers with empty transfers; nothing to do.
+        if amount.0 == 0 {
+            return PromiseOrValue::Value(U128(0));
+        }
+        let token_id = env::predecessor_account_id();
+        let action = Self::parse_transfer_action(&msg);
+
+        if token_id == env::current_account_id() {
+            match action {
+                TransferAction::RepayDebt { collateral_id } => {
+                    self.nusd
+                        .internal_withdraw(&env::current_account_id(), amount.0);
+                    FtBurn {
+                        owner_id: &sender_id,
+                        amount,
+                        memo: Some("cdp_repay_via_ft"),
+                    }
+                    .emit();
+                    self.internal_repay(&sender_id, &collateral_id, amount.0);
+                }
+                _ => env::panic_str("Unsupported action for nUSD"),
+            }
+        } else {
+            match action {
+                TransferAction::DepositCollateral { target_account } => {
+                    let owner = target_account.unwrap_or_else(|| sender_id.clone());
+                    self.internal_deposit_collateral(owner, token_id, amount.0);
+                }
+                TransferAction::AdjustTrove {
+                    debt_delta,
+                    receiver,
+                } => {
+                    let receiver_id = receiver.unwrap_or_else(|| sender_id.clone());
+                    self.internal_adjust_trove(
+                        &sender_id,
+                        &token_id,
+                        i128::try_from(amount.0).expect("Amount overflow"),
+                        debt_delta.0,
+                        &receiver_id,
+                    );
+                }
+                TransferAction::RepayDebt { .. } => {
+                    env::panic_str("Repay action invalid for external tokens")
+                }
+            }
+        }
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        self.nusd.storage_deposit(account_id, registration_only)
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        self.nusd.storage_withdraw(amount)
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        self.nusd.storage_unregister(force)
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.nusd.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.nusd.storage_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        self.metadata
+            .get()
+            .clone()
+            .unwrap_or_else(|| env::panic_str("Token metadata not set"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{LiquidatableTrove, LiquidationSortBy, StabilityPoolMode};
+    use near_contract_standards::fungible_token::metadata::FT_METADATA_SPEC;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, NearToken};
+
+    fn metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "nUSD".to_string(),
+            symbol: "nUSD".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        }
+    }
+
+    fn alice() -> AccountId {
+        "alice.testnet".parse().unwrap()
+    }
+
+    fn owner() -> AccountId {
+        "owner.testnet".parse().unwrap()
+    }
+
+    fn intents() -> AccountId {
+        "intents.near".parse().unwrap()
+    }
+
+    fn oracle() -> AccountId {
+        "pyth.near".parse().unwrap()
+    }
+
+    fn collateral_token() -> AccountId {
+        "usdc.fakes".parse().unwrap()
+    }
+
+    fn collateral_config() -> CollateralConfig {
+        CollateralConfig {
+            oracle_price_id: "usdc".to_string(),
+            min_collateral_ratio_bps: 1300,
+            recovery_collateral_ratio_bps: 1500,
+            debt_ceiling: U128(1_000_000_000_000),
+            liquidation_penalty_bps: 50,
+            stability_pool_mode: StabilityPoolMode::Dedicated,
+            max_active_troves: None,
+            expected_price_decimals: None,
+            min_reasonable_price: None,
+            max_price_age_ms: None,
+            interest_rate_bps: 0,
+            has_transfer_fee: false,
+            transfer_fee_bps: 0,
+            scaled_penalty: false,
+            max_penalty_bps: 0,
+            borrow_safety_buffer_bps: 0,
+            redemption_min_trove_age_ms: 0,
+            min_claimable: U128(0),
+            dust_trove_threshold: U128(0),
+            min_treasury_reserve_bps: 0,
+            borrow_fee_bps: 0,
+            dynamic_interest: false,
+            max_interest_rate_bps: 0,
+            interest_kink_bps: 0,
+            initial_debt_ceiling: None,
+            ceiling_ramp_duration_ms: 0,
+            is_rebasing: false,
+            liquidate_on_stale_price: false,
+            lock_withdrawals_when_liquidatable: false,
+            redemption_fee_bps: 0,
+            min_stability_pool_size: U128(0),
+            insurance_pool_id: None,
+        }
+    }
+
+    fn update_config(contract: &mut Contract, config: CollateralConfig) {
+        set_caller(owner(), 1);
+        contract.register_collateral(collateral_token(), config);
+    }
+
+    fn setup_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("cdp.testnet".parse().unwrap())
+            .signer_account_id(owner())
+            .predecessor_account_id(owner());
+        testing_env!(context.clone().build());
+        let mut contract = Contract::new(owner(), intents(), oracle(), metadata());
+
+        testing_env!(context
+            .predecessor_account_id(owner())
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .build());
+        contract.register_collateral(collateral_token(), collateral_config());
+
+        testing_env!(context
+            .predecessor_account_id(oracle())
+            .attached_deposit(NearToken::from_yoctonear(0))
+            .build());
+        contract.submit_price(collateral_token(), U128(20000), 2);
+
+        contract
+    }
+
+    fn bob() -> AccountId {
+        "bob.testnet".parse().unwrap()
+    }
+
+    fn carol() -> AccountId {
+        "carol.testnet".parse().unwrap()
+    }
+
+    fn dave() -> AccountId {
+        "dave.testnet".parse().unwrap()
+    }
+
+    fn set_caller(caller: AccountId, attached_yocto: u128) {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("cdp.testnet".parse().unwrap())
+            .signer_account_id(caller.clone())
+            .predecessor_account_id(caller)
+            .attached_deposit(NearToken::from_yoctonear(attached_yocto));
+        testing_env!(context.build());
+    }
+
+    fn set_price(contract: &mut Contract, price: u128, decimals: u8) {
+        set_caller(oracle(), 0);
+        contract.submit_price(collateral_token(), U128(price), decimals);
+    }
+
+    fn open_trove(contract: &mut Contract, owner: AccountId, collateral: u128, debt: u128) {
+        if contract.storage_balance_of(owner.clone()).is_none() {
+            contract.nusd.internal_register_account(&owner);
+        }
+        set_caller(collateral_token(), 0);
+        contract.ft_on_transfer(
+            owner.clone(),
+            U128(collateral),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        if debt > 0 {
+            set_caller(owner, 1);
+            contract.borrow(collateral_token(), U128(debt), None);
+        }
+    }
+
+    fn deposit_to_pool(contract: &mut Contract, depositor: AccountId, amount: u128) {
+        set_caller(depositor, 1);
+        contract.deposit_to_stability_pool(U128(amount), Some(collateral_token()));
+    }
+
+    #[test]
+    fn borrow_and_repay_flow() {
+        let mut contract = setup_contract();
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("cdp.testnet".parse().unwrap())
+            .signer_account_id(alice())
+            .predecessor_account_id(alice());
+        let storage_deposit = contract.storage_balance_bounds().min;
+        testing_env!(context.clone().attached_deposit(storage_deposit).build());
+        contract.storage_deposit(Some(alice()), None);
+
+        testing_env!(context
+            .predecessor_account_id(collateral_token())
+            .signer_account_id(collateral_token())
+            .attached_deposit(NearToken::from_yoctonear(0))
+            .build());
+        contract.ft_on_transfer(
+            alice(),
+            U128(10_000),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+
+        testing_env!(context
+            .predecessor_account_id(alice())
+            .signer_account_id(alice())
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .build());
+        contract.borrow(collateral_token(), U128(4_000), None);
+        assert_eq!(contract.ft_balance_of(alice()).0, 4_000);
+
+        testing_env!(context
+            .predecessor_account_id(alice())
+            .signer_account_id(alice())
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .build());
+        contract.repay(collateral_token(), U128(1_000));
+        assert_eq!(contract.ft_balance_of(alice()).0, 3_000);
+        let trove = contract
+            .get_trove(alice(), collateral_token())
+            .expect("trove missing");
+        assert_eq!(trove.debt_amount.0, 3_000);
+
+        testing_env!(context
+            .predecessor_account_id(alice())
+            .signer_account_id(alice())
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .build());
+        let _ = contract.withdraw_collateral(collateral_token(), U128(1_000), None);
+    }
+
+    #[test]
+    fn new_deposit_snapshot_prevents_reward_sniping() {
+        let mut contract = setup_contract();
+        let collateral = collateral_token();
+        let alice = alice();
+
+        let pool_id = types::PoolId::Dedicated(collateral.clone());
+        let mut pool = types::StabilityPool {
+            total_shares: 1_000,
+            total_nusd: 1_000,
+            ..Default::default()
+        };
+        pool.reward_per_share
+            .insert(collateral.clone(), types::REWARD_SCALE);
+        contract.stability_pools.insert(&pool_id, &pool);
+        contract.stability_pool_total_nusd = 1_000;
+
+        contract.pool_deposit(&alice, &pool_id, 1_000);
+        contract.settle_stability_rewards(&alice, &pool_id);
+
+        let reward_after = contract
+            .collateral_rewards
+            .get(&types::CollateralRewardKey::new(&alice, &collateral))
+            .unwrap_or(0);
+        assert_eq!(
+            reward_after, 0,
+            "new deposit should not inherit historical rewards"
+        );
+    }
+
+    #[test]
+    fn accrue_without_deposit_rewards_owner() {
+        let mut contract = setup_contract();
+        let collateral = collateral_token();
+
+        contract.accrue_reward_per_share(&types::PoolId::Shared, &collateral, 500);
+
+        let owner_reward = contract
+            .collateral_rewards
+            .get(&types::CollateralRewardKey::new(
+                &contract.owner_id,
+                &collateral,
+            ))
+            .unwrap_or(0);
+        assert_eq!(owner_reward, 500, "owner should receive direct reward");
+    }
+
+    #[test]
+    fn liquidate_respects_min_collateral_seized() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 20_000, 4_000);
+        open_trove(&mut contract, carol(), 100_000, 8_000);
+        deposit_to_pool(&mut contract, carol(), 8_000);
+
+        // At 0.02 both alice and bob sit below the configured MCR.
+        set_price(&mut contract, 2, 2);
+        set_caller(carol(), 1);
+        contract.liquidate(
+            collateral_token(),
+            vec![alice(), bob()],
+            Some(U128(30_000)),
+            None,
+            None,
+        );
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+        assert!(contract.get_trove(bob(), collateral_token()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Collateral seized below minimum")]
+    fn liquidate_reverts_when_price_moves_against_keeper() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 20_000, 4_000);
+        open_trove(&mut contract, carol(), 100_000, 8_000);
+        deposit_to_pool(&mut contract, carol(), 8_000);
+
+        // The keeper quoted both troves at 0.02, but the price ticks up to 0.04
+        // before execution so only alice remains liquidatable.
+        set_price(&mut contract, 4, 2);
+        set_caller(carol(), 1);
+        contract.liquidate(
+            collateral_token(),
+            vec![alice(), bob()],
+            Some(U128(30_000)),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn trove_cap_allows_top_ups_of_existing_troves() {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                max_active_troves: Some(2),
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 10_000, 0);
+        open_trove(&mut contract, bob(), 10_000, 0);
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 2);
+        assert_eq!(contract.get_max_active_troves(collateral_token()), Some(2));
+
+        open_trove(&mut contract, alice(), 5_000, 0);
+        let trove = contract.get_trove(alice(), collateral_token()).unwrap();
+        assert_eq!(trove.collateral_amount.0, 15_000);
+
+        set_caller(bob(), 1);
+        let _ = contract.close_trove(collateral_token());
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 1);
+        open_trove(&mut contract, carol(), 10_000, 0);
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Collateral trove limit reached")]
+    fn trove_cap_rejects_new_troves() {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                max_active_troves: Some(2),
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 10_000, 0);
+        open_trove(&mut contract, bob(), 10_000, 0);
+        open_trove(&mut contract, carol(), 10_000, 0);
+    }
+
+    #[test]
+    fn rewards_from_drained_epoch_survive_later_liquidations() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 100_000, 4_000);
+        open_trove(&mut contract, bob(), 10_000, 4_000);
+        open_trove(&mut contract, carol(), 100_000, 2_000);
+        open_trove(&mut contract, dave(), 5_000, 1_000);
+        deposit_to_pool(&mut contract, alice(), 4_000);
+
+        set_price(&mut contract, 2, 2);
+        set_caller(alice(), 1);
+        contract.liquidate(collateral_token(), vec![bob()], None, None, None);
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            0
+        );
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            9_950
+        );
+
+        // A fresh depositor absorbs the next liquidation in the new epoch; the
+        // stale depositor must not be paid from it.
+        deposit_to_pool(&mut contract, carol(), 2_000);
+        set_caller(carol(), 1);
+        contract.liquidate(collateral_token(), vec![dave()], None, None, None);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            9_950
+        );
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(carol(), collateral_token())
+                .0,
+            4_975
+        );
+
+        set_caller(alice(), 1);
+        let _ = contract.claim_collateral_reward(collateral_token(), None);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            0
+        );
+        assert_eq!(
+            contract
+                .get_stability_pool_deposit(alice(), Some(collateral_token()))
+                .0,
+            0
+        );
+    }
+
+    #[test]
+    fn granular_config_getters() {
+        let contract = setup_contract();
+        assert_eq!(contract.get_mcr(collateral_token()), Some(1300));
+        assert_eq!(
+            contract.get_debt_ceiling(collateral_token()),
+            Some(U128(1_000_000_000_000))
+        );
+        assert_eq!(
+            contract.get_liquidation_penalty(collateral_token()),
+            Some(50)
+        );
+        assert_eq!(contract.get_mcr(alice()), None);
+        assert_eq!(contract.get_debt_ceiling(alice()), None);
+
+        let configs = contract.list_collateral_configs(None, None);
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].0, collateral_token());
+        assert_eq!(configs[0].1.min_collateral_ratio_bps, 1300);
+        assert!(contract.list_collateral_configs(Some(1), None).is_empty());
+    }
+
+    #[test]
+    fn borrow_mints_to_receiver_while_debt_stays_with_caller() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 0);
+        contract.nusd.internal_register_account(&bob());
+
+        set_caller(alice(), 1);
+        contract.borrow(collateral_token(), U128(1_500), Some(bob()));
+
+        assert_eq!(contract.ft_balance_of(alice()).0, 0);
+        assert_eq!(contract.ft_balance_of(bob()).0, 1_500);
+        let trove = contract.get_trove(alice(), collateral_token()).unwrap();
+        assert_eq!(trove.debt_amount.0, 1_500);
+        assert!(contract.get_trove(bob(), collateral_token()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Receiver not registered")]
+    fn borrow_rejects_unregistered_receiver() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 0);
+        set_caller(alice(), 1);
+        contract.borrow(collateral_token(), U128(1_500), Some(carol()));
+    }
+
+    #[test]
+    fn liquidation_candidates_sort_by_ratio_or_debt() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 30_000, 6_000);
+        open_trove(&mut contract, carol(), 8_000, 3_000);
+        open_trove(&mut contract, dave(), 1_000_000, 10_000);
+        deposit_to_pool(&mut contract, dave(), 10_000);
+        set_price(&mut contract, 2, 2);
+
+        let owners = |troves: Vec<LiquidatableTrove>| -> Vec<AccountId> {
+            troves.into_iter().map(|trove| trove.owner_id).collect()
+        };
+        let by_ratio = contract.list_liquidatable(collateral_token(), None, None, None);
+        assert_eq!(owners(by_ratio), vec![alice(), carol(), bob()]);
+        let by_debt = contract.list_liquidatable(
+            collateral_token(),
+            None,
+            None,
+            Some(LiquidationSortBy::Debt),
+        );
+        assert_eq!(owners(by_debt), vec![bob(), alice(), carol()]);
+
+        let ratio_batch = contract.estimate_liquidation_batch(collateral_token(), 3, None);
+        assert_eq!(ratio_batch.owners, vec![alice(), carol()]);
+        assert_eq!(ratio_batch.total_debt.0, 7_000);
+        let debt_batch = contract.estimate_liquidation_batch(
+            collateral_token(),
+            3,
+            Some(LiquidationSortBy::Debt),
+        );
+        assert_eq!(debt_batch.owners, vec![bob(), alice()]);
+        assert_eq!(debt_batch.total_debt.0, 10_000);
+        assert_eq!(debt_batch.total_collateral.0, 40_000);
+    }
+
+    #[test]
+    fn underfunded_pool_partially_liquidates() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 2_000);
+        deposit_to_pool(&mut contract, bob(), 2_000);
+        set_price(&mut contract, 2, 2);
+        set_caller_at(dave(), 5_000);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+
+        // Half the debt is covered, so half the collateral is seized.
+        let trove = contract.get_trove(alice(), collateral_token()).unwrap();
+        assert_eq!(trove.collateral_amount.0, 5_000);
+        assert_eq!(trove.debt_amount.0, 2_000);
+        assert_eq!(trove.last_update_timestamp.0, 5_000);
+        assert_eq!(trove.last_liquidation_timestamp.0, 5_000);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 4_000);
+        assert_eq!(
+            contract.get_total_collateral(collateral_token()).0,
+            1_005_000
+        );
+        assert_eq!(contract.get_total_stability_pool_balance().0, 0);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(bob(), collateral_token())
+                .0,
+            4_975
+        );
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(owner(), collateral_token())
+                .0,
+            25
+        );
+    }
+
+    #[test]
+    fn partial_liquidation_reconciles_with_an_uneven_pool() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 3_000);
+        open_trove(&mut contract, bob(), 1_000_000, 1_001);
+        deposit_to_pool(&mut contract, bob(), 1_001);
+        set_price(&mut contract, 2, 2);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+
+        // 1_001 / 3_000 of 10_000 is 3_336.67 collateral, rounded up.
+        assert_eq!(trove_amounts(&contract, alice()), (6_663, 1_999));
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 1_999 + 1_001);
+        assert_eq!(
+            contract.get_total_collateral(collateral_token()).0,
+            6_663 + 1_000_000
+        );
+        // The drained pool starts a new epoch with no shares left over.
+        let pool = contract.pool(&types::PoolId::Dedicated(collateral_token()));
+        assert_eq!(pool.total_nusd, 0);
+        assert_eq!(pool.total_shares, 0);
+        assert_eq!(pool.epoch, 1);
+        assert_eq!(contract.get_total_stability_pool_balance().0, 0);
+        assert_eq!(
+            contract
+                .nusd
+                .ft_balance_of("cdp.testnet".parse().unwrap())
+                .0,
+            0
+        );
+        let depositors = contract
+            .get_claimable_collateral_reward(bob(), collateral_token())
+            .0;
+        let penalty = contract
+            .get_claimable_collateral_reward(owner(), collateral_token())
+            .0;
+        assert_eq!(depositors + penalty, 3_337);
+    }
+
+    #[test]
+    fn reward_accounting_separates_settled_from_pending() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 4_000);
+        set_price(&mut contract, 2, 2);
+        set_caller(carol(), 1);
+        // The liquidation drains the pool, moving it to a new epoch.
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+
+        let accounting = contract.get_reward_accounting(bob());
+        assert_eq!(accounting.deposits.len(), 1);
+        let deposit = &accounting.deposits[0];
+        assert_eq!(deposit.collateral_id, Some(collateral_token()));
+        assert_eq!((deposit.deposit_epoch, deposit.current_epoch), (0, 1));
+        assert!(deposit.epoch_reset_pending);
+        assert_eq!(accounting.collaterals.len(), 1);
+        let rewards = &accounting.collaterals[0];
+        assert_eq!(rewards.claimable.0, 0);
+        assert_eq!(rewards.pending.0, 9_950);
+
+        let pool_id = types::PoolId::Dedicated(collateral_token());
+        contract.settle_stability_rewards(&bob(), &pool_id);
+        let accounting = contract.get_reward_accounting(bob());
+        let deposit = &accounting.deposits[0];
+        assert!(!deposit.epoch_reset_pending);
+        assert_eq!(deposit.shares.0, 0);
+        let rewards = &accounting.collaterals[0];
+        assert_eq!((rewards.claimable.0, rewards.pending.0), (9_950, 0));
+    }
+
+    #[test]
+    fn borrow_fee_tops_up_stability_pool() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                borrow_fee_bps: 100,
+                ..collateral_config()
+            },
+        );
+        set_caller(owner(), 1);
+        contract.set_fee_to_stability_pool_bps(5_000);
+        assert_eq!(contract.get_config().fee_to_stability_pool_bps, 5_000);
+
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        let (_, debt) = trove_amounts(&contract, alice());
+        assert_eq!(debt, 10_100);
+        assert_eq!(contract.nusd.ft_balance_of(alice()).0, 10_000);
+        assert_eq!(contract.nusd.ft_balance_of(owner()).0, 50);
+        // The virtual shares keep their sliver of the fee.
+        assert_eq!(
+            contract
+                .get_stability_pool_deposit(bob(), Some(collateral_token()))
+                .0,
+            5_049
+        );
+        assert_eq!(contract.get_total_stability_pool_balance().0, 5_050);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 15_100);
+    }
+
+    #[test]
+    fn borrow_reports_minted_amount_and_fee() {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                borrow_fee_bps: 50,
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 1_000, 0);
+        set_caller(alice(), 1);
+        let result = contract.borrow(collateral_token(), U128(20_000), None);
+        assert_eq!(result.minted.0, 20_000);
+        assert_eq!(result.fee.0, 100);
+        assert_eq!(contract.ft_balance_of(alice()).0, 20_000);
+        assert_eq!(contract.ft_balance_of(owner()).0, 100);
+        assert_eq!(trove_amounts(&contract, alice()).1, 20_100);
+    }
+
+    #[test]
+    fn stability_pool_reregisters_contract_account() {
+        let mut contract = setup_contract();
+        let current_id: AccountId = "cdp.testnet".parse().unwrap();
+        contract.nusd.accounts.remove(&current_id);
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        assert_eq!(contract.nusd.ft_balance_of(current_id.clone()).0, 5_000);
+
+        set_price(&mut contract, 2, 2);
+        set_caller(carol(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        assert_eq!(contract.nusd.ft_balance_of(current_id).0, 1_000);
+    }
+
+    #[test]
+    fn trove_risk_bundle_matches_liquidate() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        assert!(contract
+            .get_trove_risk_bundle(carol(), collateral_token())
+            .is_none());
+
+        let healthy = contract
+            .get_trove_risk_bundle(alice(), collateral_token())
+            .unwrap();
+        assert!(!healthy.is_liquidatable);
+        assert_eq!(healthy.min_collateral_ratio_bps, 1_300);
+
+        set_price(&mut contract, 2, 2);
+        let bundle = contract
+            .get_trove_risk_bundle(alice(), collateral_token())
+            .unwrap();
+        assert!(bundle.is_liquidatable);
+        assert_eq!(bundle.collateral_ratio_bps, Some(U128(500)));
+        assert_eq!(bundle.price.unwrap().price.0, 2);
+        assert!(
+            !contract
+                .get_trove_risk_bundle(bob(), collateral_token())
+                .unwrap()
+                .is_liquidatable
+        );
+
+        set_caller(carol(), 1);
+        let processed =
+            contract.liquidate(collateral_token(), vec![alice(), bob()], None, None, None);
+        assert!(matches!(processed, PromiseOrValue::Value(U64(1))));
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+    }
+
+    #[test]
+    fn collateral_to_nusd_value_uses_price_decimals() {
+        let mut contract = setup_contract();
+        assert_eq!(
+            contract.collateral_to_nusd_value(alice(), U128(1_000)),
+            None
+        );
+        assert_eq!(
+            contract
+                .collateral_to_nusd_value(collateral_token(), U128(1_000))
+                .unwrap()
+                .0,
+            200_000
+        );
+        set_price(&mut contract, 1_250_000, 6);
+        assert_eq!(
+            contract
+                .collateral_to_nusd_value(collateral_token(), U128(1_000))
+                .unwrap()
+                .0,
+            1_250
+        );
+    }
+
+    #[test]
+    fn repay_and_deposit_work_without_a_price() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        contract.price_feeds.remove(&collateral_token());
+
+        set_caller(alice(), 1);
+        contract.repay(collateral_token(), U128(1_000));
+        set_caller(collateral_token(), 0);
+        contract.ft_on_transfer(
+            alice(),
+            U128(500),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        set_caller(alice(), 1);
+        contract.adjust_trove(collateral_token(), I128(0), I128(-1_000), None);
+        assert_eq!(trove_amounts(&contract, alice()), (10_500, 2_000));
+
+        // Once the debt is gone collateral can leave without a price too.
+        contract.repay_and_withdraw(collateral_token(), U128(2_000), U128(500), None);
+        contract.close_trove(collateral_token());
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Price not available")]
+    fn withdrawal_with_debt_needs_a_price() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        contract.price_feeds.remove(&collateral_token());
+
+        set_caller(alice(), 1);
+        contract.withdraw_collateral(collateral_token(), U128(100), None);
+    }
+
+    #[test]
+    fn repay_and_withdraw_checks_final_ratio() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        set_price(&mut contract, 10, 2);
+
+        set_caller(alice(), 1);
+        contract.repay_and_withdraw(collateral_token(), U128(2_000), U128(6_000), None);
+        let trove = contract.get_trove(alice(), collateral_token()).unwrap();
+        assert_eq!(trove.debt_amount.0, 2_000);
+        assert_eq!(trove.collateral_amount.0, 4_000);
+        assert_eq!(contract.ft_balance_of(alice()).0, 2_000);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 2_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Would violate MCR")]
+    fn repay_and_withdraw_reverts_below_mcr() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        set_price(&mut contract, 10, 2);
+
+        set_caller(alice(), 1);
+        contract.repay_and_withdraw(collateral_token(), U128(500), U128(6_000), None);
+    }
+
+    #[test]
+    fn abandon_trove_moves_debt_to_bad_debt() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 5_000, 1_000);
+
+        set_caller(owner(), 1);
+        contract.write_off_collateral(collateral_token());
+        assert!(contract.is_collateral_written_off(collateral_token()));
+
+        set_caller(alice(), 1);
+        assert_eq!(contract.abandon_trove(collateral_token()).0, 4_000);
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+        assert_eq!(contract.ft_balance_of(alice()).0, 4_000);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 1_000);
+        assert_eq!(contract.get_bad_debt(collateral_token()).0, 4_000);
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 1);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(owner(), collateral_token())
+                .0,
+            10_000
+        );
+    }
+
+    #[test]
+    fn claim_and_deposit_credits_reward_to_trove() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        set_price(&mut contract, 2, 2);
+        set_caller(carol(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        let reward = contract
+            .get_claimable_collateral_reward(bob(), collateral_token())
+            .0;
+        assert!(reward > 0);
+        let total_collateral = contract.get_total_collateral(collateral_token()).0;
+
+        set_caller(bob(), 1);
+        let deposited = match contract.claim_and_deposit(collateral_token(), collateral_token()) {
+            PromiseOrValue::Value(amount) => amount.0,
+            PromiseOrValue::Promise(_) => panic!("expected an in-protocol deposit"),
+        };
+        assert_eq!(deposited, reward);
+        let (collateral, _) = trove_amounts(&contract, bob());
+        assert_eq!(collateral, 1_000_000 + reward);
+        assert_eq!(
+            contract.get_total_collateral(collateral_token()).0,
+            total_collateral + reward
+        );
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(bob(), collateral_token())
+                .0,
+            0
+        );
+    }
+
+    #[test]
+    fn collateral_lists_sorted_by_account_id() {
+        let mut contract = setup_contract();
+        let wnear: AccountId = "wnear.fakes".parse().unwrap();
+        let aurora: AccountId = "aurora.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(wnear.clone(), collateral_config());
+        contract.register_collateral(aurora.clone(), collateral_config());
+
+        let expected = vec![aurora.clone(), collateral_token(), wnear.clone()];
+        assert_eq!(contract.list_collateral_tokens(), expected);
+        let configs: Vec<AccountId> = contract
+            .list_collateral_configs(None, None)
+            .into_iter()
+            .map(|(token_id, _)| token_id)
+            .collect();
+        assert_eq!(configs, expected);
+        let page = contract.list_collateral_configs(Some(1), Some(1));
+        assert_eq!(page[0].0, collateral_token());
+        // Only the usdc feed is set.
+        assert_eq!(contract.list_unhealthy_oracles(), vec![aurora, wnear]);
+    }
+
+    #[test]
+    fn list_troves_pages_through_every_owner() {
+        let mut contract = setup_contract();
+        for owner in [alice(), bob(), carol()] {
+            open_trove(&mut contract, owner, 10_000, 1_000);
+        }
+        assert_eq!(contract.get_trove_count(collateral_token()), 3);
+
+        let owners: Vec<AccountId> = [(0, 2), (2, 2), (4, 2)]
+            .into_iter()
+            .flat_map(|(from, limit)| {
+                contract.list_troves(collateral_token(), Some(from), Some(limit))
+            })
+            .map(|trove| trove.owner_id)
+            .collect();
+        assert_eq!(owners, vec![alice(), bob(), carol()]);
+
+        set_caller(bob(), 1);
+        contract.repay(collateral_token(), U128(1_000));
+        contract.close_trove(collateral_token());
+        assert_eq!(contract.get_trove_count(collateral_token()), 2);
+        assert_eq!(
+            contract.list_troves(collateral_token(), None, None).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn troves_by_owner_drop_closed_and_liquidated_troves() {
+        let mut contract = setup_contract();
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(wbtc.clone(), collateral_config());
+        set_caller(oracle(), 0);
+        contract.submit_price(wbtc.clone(), U128(500), 0);
+
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 6_000);
+        set_caller(wbtc.clone(), 0);
+        contract.ft_on_transfer(
+            alice(),
+            U128(100),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        set_caller(alice(), 1);
+        contract.borrow(wbtc.clone(), U128(1_000), None);
+        let collaterals = |contract: &Contract| -> Vec<AccountId> {
+            contract
+                .get_troves_by_owner(alice())
+                .into_iter()
+                .map(|trove| trove.collateral_id)
+                .collect()
+        };
+        assert_eq!(
+            collaterals(&contract),
+            vec![collateral_token(), wbtc.clone()]
+        );
+
+        contract.repay(wbtc.clone(), U128(1_000));
+        contract.close_trove(wbtc);
+        assert_eq!(collaterals(&contract), vec![collateral_token()]);
+
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        set_price(&mut contract, 2, 2);
+        set_caller(bob(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        assert!(contract.get_troves_by_owner(alice()).is_empty());
+        assert_eq!(contract.get_troves_by_owner(bob()).len(), 1);
+    }
+
+    #[test]
+    fn collateral_pages_slice_the_sorted_list() {
+        let mut contract = setup_contract();
+        let wnear: AccountId = "wnear.fakes".parse().unwrap();
+        let aurora: AccountId = "aurora.fakes".parse().unwrap();
+        let zcash: AccountId = "zcash.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        for token_id in [&wnear, &aurora, &zcash] {
+            contract.register_collateral(token_id.clone(), collateral_config());
+        }
+
+        assert_eq!(
+            contract.list_collateral_tokens_paged(0, 2),
+            vec![aurora, collateral_token()]
+        );
+        assert_eq!(
+            contract.list_collateral_tokens_paged(2, 5),
+            vec![wnear.clone(), zcash.clone()]
+        );
+        let configs: Vec<AccountId> = contract
+            .get_collateral_configs(2, 1)
+            .into_iter()
+            .map(|(token_id, _)| token_id)
+            .collect();
+        assert_eq!(configs, vec![wnear]);
+        assert!(contract.list_collateral_tokens_paged(4, 1).is_empty());
+        assert!(contract.get_collateral_configs(10, 10).is_empty());
+    }
+
+    #[test]
+    fn price_impact_report_at_stress_price() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        open_trove(&mut contract, dave(), 100, 1_000);
+        deposit_to_pool(&mut contract, bob(), 2_000);
+        // At 0.5 only dave's trove (50 against 1_000) is under the MCR.
+        set_price(&mut contract, 50, 2);
+
+        let report = contract.simulate_price_impact(collateral_token(), U128(2), 2);
+        // At 0.02 alice is worth 200 against 4_000; bob stays at 20_000.
+        assert_eq!(report.liquidatable_troves, 2);
+        assert_eq!(report.newly_liquidatable_troves, 1);
+        assert_eq!(report.debt_at_risk.0, 5_000);
+        assert_eq!(report.stability_pool_nusd.0, 2_000);
+        assert_eq!(report.projected_bad_debt.0, 3_000);
+
+        let calm = contract.simulate_price_impact(collateral_token(), U128(20_000), 2);
+        assert_eq!(calm.liquidatable_troves, 0);
+        assert_eq!(calm.projected_bad_debt.0, 0);
+    }
+
+    #[test]
+    fn bad_debt_listed_per_collateral_and_totalled() {
+        let mut contract = setup_contract();
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(wbtc.clone(), collateral_config());
+        set_caller(oracle(), 0);
+        contract.submit_price(wbtc.clone(), U128(500), 0);
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        contract.nusd.internal_register_account(&bob());
+        set_caller(wbtc.clone(), 0);
+        contract.ft_on_transfer(
+            bob(),
+            U128(600),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        set_caller(bob(), 1);
+        contract.borrow(wbtc.clone(), U128(50_000), None);
+        assert!(contract.list_bad_debt().is_empty());
+
+        set_caller(owner(), 1);
+        contract.write_off_collateral(collateral_token());
+        contract.write_off_collateral(wbtc.clone());
+        set_caller(alice(), 1);
+        contract.abandon_trove(collateral_token());
+        set_caller(bob(), 1);
+        contract.abandon_trove(wbtc.clone());
+
+        assert_eq!(
+            contract.list_bad_debt(),
+            vec![(collateral_token(), U128(4_000)), (wbtc, U128(50_000))]
+        );
+        assert_eq!(contract.get_total_bad_debt().0, 54_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Collateral not written off")]
+    fn abandon_trove_requires_write_off() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        set_caller(alice(), 1);
+        contract.abandon_trove(collateral_token());
+    }
+
+    #[test]
+    #[should_panic(expected = "Owner only")]
+    fn write_off_collateral_is_owner_only() {
+        let mut contract = setup_contract();
+        set_caller(alice(), 1);
+        contract.write_off_collateral(collateral_token());
+    }
+
+    #[test]
+    #[should_panic(expected = "Collateral written off")]
+    fn written_off_collateral_rejects_deposits() {
+        let mut contract = setup_contract();
+        set_caller(owner(), 1);
+        contract.write_off_collateral(collateral_token());
+        open_trove(&mut contract, alice(), 10_000, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected price decimals")]
+    fn submit_price_rejects_unexpected_decimals() {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                expected_price_decimals: Some(2),
+                ..collateral_config()
+            },
+        );
+        set_price(&mut contract, 30_000, 2);
+        assert_eq!(
+            contract.get_price(collateral_token()).unwrap().price.0,
+            30_000
+        );
+        set_price(&mut contract, 300_000_000, 8);
+    }
+
+    #[test]
+    fn total_pending_rewards_match_depositor_claims() {
+        let mut contract = setup_contract();
+        assert_eq!(contract.get_total_pending_rewards(collateral_token()).0, 0);
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 6_000);
+        open_trove(&mut contract, carol(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 6_000);
+        deposit_to_pool(&mut contract, carol(), 4_000);
+        set_price(&mut contract, 2, 2);
+
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+
+        let claimable = |contract: &Contract, account: AccountId| {
+            contract
+                .get_claimable_collateral_reward(account, collateral_token())
+                .0
+        };
+        let total = contract.get_total_pending_rewards(collateral_token()).0;
+        assert_eq!(total, 9_950);
+        assert_eq!(
+            total,
+            claimable(&contract, bob()) + claimable(&contract, carol())
+        );
+
+        // Settling moves bob's share out of the pending total.
+        set_caller(bob(), 1);
+        contract.borrow(collateral_token(), U128(1_000), None);
+        deposit_to_pool(&mut contract, bob(), 1_000);
+        assert_eq!(
+            contract.get_total_pending_rewards(collateral_token()).0,
+            claimable(&contract, carol())
+        );
+    }
+
+    fn set_owner_with_balance(balance: NearToken, storage_usage: u64) {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("cdp.testnet".parse().unwrap())
+            .signer_account_id(owner())
+            .predecessor_account_id(owner())
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .account_balance(balance)
+            .storage_usage(storage_usage);
+        testing_env!(context.build());
+    }
+
+    #[test]
+    fn withdraw_near_keeps_storage_reserve() {
+        let mut contract = setup_contract();
+        // 100 kB of storage costs 1 NEAR at 10^19 yocto per byte; the
+        // attached yocto is credited to the balance as well.
+        set_owner_with_balance(NearToken::from_near(10), 100_000);
+        let withdrawable = contract.get_withdrawable_near();
+        assert_eq!(withdrawable.0, NearToken::from_near(9).as_yoctonear() + 1);
+        let _ = contract.withdraw_near(withdrawable, bob());
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds NEAR above storage requirement")]
+    fn withdraw_near_rejects_dipping_into_storage_reserve() {
+        let mut contract = setup_contract();
+        set_owner_with_balance(NearToken::from_near(10), 100_000);
+        let withdrawable = contract.get_withdrawable_near();
+        let _ = contract.withdraw_near(U128(withdrawable.0 + 1), bob());
+    }
+
+    #[test]
+    fn draining_the_pool_keeps_accrued_rewards_claimable() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, dave(), 10_000, 6_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        open_trove(&mut contract, carol(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        deposit_to_pool(&mut contract, carol(), 5_000);
+        set_price(&mut contract, 2, 2);
+
+        set_caller(owner(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        contract.liquidate(collateral_token(), vec![dave()], None, None, None);
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            0
+        );
+
+        let claimable = |contract: &Contract, account: AccountId| {
+            contract
+                .get_claimable_collateral_reward(account, collateral_token())
+                .0
+        };
+        assert_eq!(claimable(&contract, bob()), 9_950);
+        assert_eq!(claimable(&contract, carol()), 9_950);
+        assert_eq!(
+            claimable(&contract, bob())
+                + claimable(&contract, carol())
+                + claimable(&contract, owner()),
+            20_000
+        );
+
+        // A fresh deposit in the next epoch starts from a reset accumulator.
+        deposit_to_pool(&mut contract, alice(), 1_000);
+        assert_eq!(claimable(&contract, alice()), 0);
+        set_caller(bob(), 1);
+        contract.claim_collateral_reward(collateral_token(), None);
+        assert_eq!(claimable(&contract, bob()), 0);
+    }
+
+    #[test]
+    fn simulate_stability_pool_rate_before_and_after_liquidation() {
+        let mut contract = setup_contract();
+        assert_eq!(
+            contract
+                .simulate_stability_deposit(U128(500), Some(collateral_token()))
+                .0,
+            500_000
+        );
+        assert_eq!(
+            contract
+                .simulate_stability_withdraw(U128(500), Some(collateral_token()))
+                .0,
+            0
+        );
+
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        open_trove(&mut contract, carol(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        deposit_to_pool(&mut contract, carol(), 5_000);
+        assert_eq!(
+            contract
+                .simulate_stability_deposit(U128(600), Some(collateral_token()))
+                .0,
+            600_000
+        );
+        assert_eq!(
+            contract
+                .simulate_stability_withdraw(U128(1_000_000), Some(collateral_token()))
+                .0,
+            1_000
+        );
+
+        set_price(&mut contract, 2, 2);
+        set_caller(owner(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        assert_eq!(
+            contract
+                .simulate_stability_deposit(U128(600), Some(collateral_token()))
+                .0,
+            999_933
+        );
+        assert_eq!(
+            contract
+                .simulate_stability_withdraw(U128(1_000_000), Some(collateral_token()))
+                .0,
+            600
+        );
+    }
+
+    fn config_with_price_floor() -> CollateralConfig {
+        // 0.50 at 18 decimals.
+        CollateralConfig {
+            min_reasonable_price: Some(U128(500_000_000_000_000_000)),
+            ..collateral_config()
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Price below reasonable minimum")]
+    fn submit_price_rejects_implausibly_low_price() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, config_with_price_floor());
+        set_price(&mut contract, 50, 2);
+        set_price(&mut contract, 49_999_999, 8);
+    }
+
+    #[test]
+    fn owner_can_override_implausible_price() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, config_with_price_floor());
+        set_caller(owner(), 1);
+        contract.override_price(collateral_token(), U128(1), 2);
+        assert_eq!(contract.get_price(collateral_token()).unwrap().price.0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquidator cannot be its own referrer")]
+    fn liquidator_cannot_refer_itself() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        set_price(&mut contract, 2, 2);
+
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, Some(dave()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Penalty plus referrer share must be <= 100%")]
+    fn penalty_leaves_room_for_the_referrer_share() {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                liquidation_penalty_bps: 9_600,
+                ..collateral_config()
+            },
+        );
+    }
+
+    #[test]
+    fn liquidation_referrer_receives_configured_share() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        set_caller(owner(), 1);
+        contract.set_referrer_bps(100);
+        assert_eq!(contract.referrer_bps(), 100);
+        set_price(&mut contract, 2, 2);
+
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, Some(carol()), None);
+
+        let claimable = |contract: &Contract, account: AccountId| {
+            contract
+                .get_claimable_collateral_reward(account, collateral_token())
+                .0
+        };
+        assert_eq!(claimable(&contract, carol()), 100);
+        assert_eq!(claimable(&contract, owner()), 50);
+        assert_eq!(claimable(&contract, bob()), 9_850);
+        assert_eq!(
+            claimable(&contract, carol())
+                + claimable(&contract, owner())
+                + claimable(&contract, bob()),
+            10_000
+        );
+    }
+
+    #[test]
+    fn keeper_receives_nusd_reward_per_liquidated_trove() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        contract.nusd.internal_register_account(&dave());
+        set_caller(owner(), 1);
+        contract.set_keeper_nusd_reward(U128(25));
+        assert_eq!(contract.get_keeper_nusd_reward().0, 25);
+        assert_eq!(contract.get_config().keeper_nusd_reward.0, 25);
+
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![bob()], None, None, None);
+        assert_eq!(contract.ft_balance_of(dave()).0, 0);
+
+        set_price(&mut contract, 2, 2);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        assert_eq!(contract.ft_balance_of(dave()).0, 25);
+        assert_eq!(contract.get_bad_debt(collateral_token()).0, 25);
+    }
+
+    #[test]
+    fn register_collaterals_in_one_batch() {
+        let mut contract = setup_contract();
+        let tokens: Vec<AccountId> = ["wnear.fakes", "eth.fakes", "btc.fakes"]
+            .iter()
+            .map(|id| id.parse().unwrap())
+            .collect();
+        set_caller(owner(), 1);
+        contract.register_collaterals(
+            tokens
+                .iter()
+                .map(|token| (token.clone(), collateral_config()))
+                .collect(),
+        );
+        let listed = contract.list_collateral_tokens();
+        assert_eq!(listed.len(), 4);
+        for token in tokens {
+            assert!(listed.contains(&token));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Recovery ratio must be >= MCR")]
+    fn register_collaterals_rejects_whole_batch() {
+        let mut contract = setup_contract();
+        set_caller(owner(), 1);
+        contract.register_collaterals(vec![
+            ("wnear.fakes".parse().unwrap(), collateral_config()),
+            (
+                "eth.fakes".parse().unwrap(),
+                CollateralConfig {
+                    recovery_collateral_ratio_bps: 1200,
+                    ..collateral_config()
+                },
+            ),
+        ]);
+    }
+
+    #[test]
+    fn recovery_mode_reported_per_collateral() {
+        let mut contract = setup_contract();
+        set_caller(owner(), 1);
+        contract.register_collateral("wnear.fakes".parse().unwrap(), collateral_config());
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 6_000, 0);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 16_000);
+        assert!(!contract.is_any_collateral_in_recovery());
+
+        // 16000 * 0.05 = 800 against 4000 debt is a 2000 bps TCR.
+        set_price(&mut contract, 5, 2);
+        assert_eq!(
+            contract.get_total_collateral_ratio(collateral_token()),
+            Some(U128(2_000))
+        );
+        assert!(!contract.is_any_collateral_in_recovery());
+
+        set_caller(bob(), 1);
+        let _ = contract.withdraw_collateral(collateral_token(), U128(6_000), None);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 10_000);
+        assert!(contract.is_any_collateral_in_recovery());
+        assert_eq!(contract.collaterals_in_recovery(), vec![collateral_token()]);
+    }
+
+    fn set_caller_at(caller: AccountId, timestamp_ms: u64) {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("cdp.testnet".parse().unwrap())
+            .signer_account_id(caller.clone())
+            .predecessor_account_id(caller)
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .block_timestamp(timestamp_ms * 1_000_000);
+        testing_env!(context.build());
+    }
+
+    fn deposit_in_cooldown(contract: &mut Contract) {
+        open_trove(contract, alice(), 10_000, 4_000);
+        open_trove(contract, bob(), 1_000_000, 5_000);
+        set_caller(owner(), 1);
+        contract.set_stability_withdraw_cooldown_ms(60_000);
+        set_caller_at(bob(), 1_000);
+        contract.deposit_to_stability_pool(U128(5_000), Some(collateral_token()));
+    }
+
+    #[test]
+    fn paused_withdrawal_bypasses_cooldown_and_settles_rewards() {
+        let mut contract = setup_contract();
+        deposit_in_cooldown(&mut contract);
+        set_price(&mut contract, 2, 2);
+        set_caller_at(carol(), 2_000);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+
+        set_caller(owner(), 1);
+        contract.set_paused(true);
+        set_caller_at(bob(), 3_000);
+        contract.withdraw_from_stability_pool(Some(U128(500)), Some(collateral_token()));
+
+        let settled = contract
+            .collateral_rewards
+            .get(&types::CollateralRewardKey::new(
+                &bob(),
+                &collateral_token(),
+            ))
+            .unwrap_or(0);
+        assert!(settled > 0, "withdrawal should settle pool rewards");
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            500
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Stability deposit in withdraw cooldown")]
+    fn withdraw_cooldown_applies_again_after_unpause() {
+        let mut contract = setup_contract();
+        deposit_in_cooldown(&mut contract);
+        set_caller(owner(), 1);
+        contract.set_paused(true);
+        contract.set_paused(false);
+        set_caller_at(bob(), 3_000);
+        contract.withdraw_from_stability_pool(None, Some(collateral_token()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Stability deposit in withdraw cooldown")]
+    fn paused_withdrawal_respects_cooldown_when_bypass_disabled() {
+        let mut contract = setup_contract();
+        deposit_in_cooldown(&mut contract);
+        set_caller(owner(), 1);
+        contract.set_allow_withdrawals_when_paused(false);
+        contract.set_paused(true);
+        set_caller_at(bob(), 3_000);
+        contract.withdraw_from_stability_pool(None, Some(collateral_token()));
+    }
+
+    #[test]
+    fn withdraw_allowed_once_cooldown_elapses() {
+        let mut contract = setup_contract();
+        deposit_in_cooldown(&mut contract);
+        set_caller_at(bob(), 61_000);
+        contract.withdraw_from_stability_pool(None, Some(collateral_token()));
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            0
+        );
+    }
+
+    fn setup_paused_protocol() -> Contract {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        set_caller(owner(), 1);
+        contract.set_paused(true);
+        assert!(contract.is_paused());
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Protocol paused")]
+    fn pause_blocks_borrow() {
+        let mut contract = setup_paused_protocol();
+        set_caller(alice(), 1);
+        contract.borrow(collateral_token(), U128(1_000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Protocol paused")]
+    fn pause_blocks_collateral_deposits() {
+        let mut contract = setup_paused_protocol();
+        set_caller(collateral_token(), 0);
+        contract.ft_on_transfer(
+            alice(),
+            U128(1_000),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+    }
+
+    #[test]
+    fn pause_leaves_repay_open() {
+        let mut contract = setup_paused_protocol();
+        set_caller(alice(), 1);
+        contract.repay(collateral_token(), U128(1_000));
+        assert_eq!(trove_amounts(&contract, alice()).1, 3_000);
+
+        set_caller(owner(), 1);
+        contract.set_paused(false);
+        set_caller(alice(), 1);
+        contract.borrow(collateral_token(), U128(1_000), None);
+        assert_eq!(trove_amounts(&contract, alice()).1, 4_000);
+    }
+
+    fn setup_with_paused(operation: types::Operation) -> Contract {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 10_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        set_caller(owner(), 1);
+        contract.set_operation_paused(operation, true);
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Borrowing paused")]
+    fn borrow_pause_blocks_only_borrowing() {
+        let mut contract = setup_with_paused(types::Operation::Borrow);
+        assert!(contract.get_config().borrow_paused);
+        deposit_to_pool(&mut contract, bob(), 1_000);
+        set_caller(bob(), 1);
+        contract.redeem(collateral_token(), alice(), U128(1_000), None, None);
+        set_price(&mut contract, 2, 2);
+        set_caller(carol(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        set_caller(bob(), 1);
+        contract.borrow(collateral_token(), U128(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Redemptions paused")]
+    fn redeem_pause_blocks_only_redemptions() {
+        let mut contract = setup_with_paused(types::Operation::Redeem);
+        set_caller(bob(), 1);
+        contract.borrow(collateral_token(), U128(1_000), None);
+        deposit_to_pool(&mut contract, bob(), 1_000);
+        set_caller(bob(), 1);
+        contract.redeem(collateral_token(), alice(), U128(1_000), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquidations paused")]
+    fn liquidate_pause_blocks_only_liquidations() {
+        let mut contract = setup_with_paused(types::Operation::Liquidate);
+        set_caller(bob(), 1);
+        contract.borrow(collateral_token(), U128(1_000), None);
+        deposit_to_pool(&mut contract, bob(), 1_000);
+        set_caller(bob(), 1);
+        contract.redeem(collateral_token(), alice(), U128(1_000), None, None);
+        set_price(&mut contract, 2, 2);
+        set_caller(carol(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+    }
+
+    /// At a price of 160 the system ratio is 1422 bps: recovery mode, with
+    /// carol between MCR and the recovery ratio.
+    fn setup_recovery() -> Contract {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, bob(), 200, 200_000);
+        open_trove(&mut contract, carol(), 100, 110_000);
+        open_trove(&mut contract, alice(), 100, 140_000);
+        assert!(!contract.is_in_recovery_mode(collateral_token()));
+        set_price(&mut contract, 16_000, 2);
+        contract
+    }
+
+    #[test]
+    fn tcr_history_records_meaningful_moves() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 100_000);
+        let submit_at = |contract: &mut Contract, timestamp_ms: u64, price: u128| {
+            set_caller_at(oracle(), timestamp_ms);
+            contract.submit_price(collateral_token(), U128(price), 2);
+        };
+        submit_at(&mut contract, 1_000, 10_000);
+        // A 5 bps move is below the snapshot threshold.
+        submit_at(&mut contract, 2_000, 10_005);
+        submit_at(&mut contract, 3_000, 30_000);
+
+        let history: Vec<(u128, u64)> = contract
+            .get_tcr_history(collateral_token(), None)
+            .into_iter()
+            .map(|snapshot| (snapshot.tcr_bps.0, snapshot.timestamp.0))
+            .collect();
+        assert_eq!(history, vec![(20_000, 0), (10_000, 1_000), (30_000, 3_000)]);
+        let latest = contract.get_tcr_history(collateral_token(), Some(1));
+        assert_eq!(latest[0].tcr_bps.0, 30_000);
+
+        for step in 0..60u64 {
+            submit_at(
+                &mut contract,
+                10_000 + step,
+                10_000 + 1_000 * (step as u128 % 2),
+            );
+        }
+        let history = contract.get_tcr_history(collateral_token(), None);
+        assert_eq!(history.len(), types::MAX_TCR_HISTORY);
+        assert_eq!(history.last().unwrap().timestamp.0, 10_059);
+    }
+
+    #[test]
+    fn recovery_mode_liquidates_below_recovery_ratio() {
+        let mut contract = setup_recovery();
+        assert!(contract.is_in_recovery_mode(collateral_token()));
+        assert_eq!(
+            contract
+                .system_collateral_ratio(collateral_token())
+                .unwrap()
+                .0,
+            1_422
+        );
+        deposit_to_pool(&mut contract, bob(), 200_000);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![carol(), bob()], None, None, None);
+        assert!(contract.get_trove(carol(), collateral_token()).is_none());
+        assert!(contract.get_trove(bob(), collateral_token()).is_some());
+
+        set_price(&mut contract, 20_000, 2);
+        assert!(!contract.is_in_recovery_mode(collateral_token()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Borrow would leave system in recovery mode")]
+    fn recovery_mode_blocks_borrow_that_keeps_recovery() {
+        let mut contract = setup_recovery();
+        set_caller(bob(), 1);
+        contract.borrow(collateral_token(), U128(100), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Would violate recovery ratio")]
+    fn recovery_mode_raises_withdrawal_floor() {
+        let mut contract = setup_recovery();
+        set_caller(bob(), 1);
+        contract.withdraw_collateral(collateral_token(), U128(20), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stability pool paused")]
+    fn stability_pause_blocks_only_pool_operations() {
+        let mut contract = setup_with_paused(types::Operation::Stability);
+        set_caller(bob(), 1);
+        contract.borrow(collateral_token(), U128(1_000), None);
+        contract.redeem(collateral_token(), alice(), U128(1_000), None, None);
+        set_price(&mut contract, 2, 2);
+        set_caller(carol(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        deposit_to_pool(&mut contract, bob(), 1_000);
+    }
+
+    #[test]
+    fn preview_stability_withdraw_matches_burned_shares() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 3_000);
+        open_trove(&mut contract, carol(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 3_000);
+        deposit_to_pool(&mut contract, carol(), 4_000);
+        set_price(&mut contract, 2, 2);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+
+        // 3_000 nUSD back 7_000_000 shares (plus the virtual ones): 100 nUSD
+        // costs 233_288.9, rounded down.
+        let key = types::DepositKey::new(&bob(), &types::PoolId::Dedicated(collateral_token()));
+        let shares = |contract: &Contract| {
+            contract
+                .stability_pool_deposits
+                .get(&key)
+                .map_or(0, |deposit| deposit.shares)
+        };
+        let before = shares(&contract);
+        let preview =
+            contract.preview_stability_withdraw(bob(), U128(100), Some(collateral_token()));
+        assert_eq!(preview.0, 233_288);
+        set_caller(bob(), 1);
+        contract.withdraw_from_stability_pool(Some(U128(100)), Some(collateral_token()));
+        assert_eq!(before - shares(&contract), preview.0);
+
+        let before = shares(&contract);
+        let full = contract.get_stability_pool_deposit(bob(), Some(collateral_token()));
+        let preview = contract.preview_stability_withdraw(bob(), full, Some(collateral_token()));
+        set_caller(bob(), 1);
+        contract.withdraw_from_stability_pool(None, Some(collateral_token()));
+        assert_eq!(before - shares(&contract), preview.0);
+    }
+
+    fn queue_config(contract: &mut Contract) {
+        open_trove(contract, alice(), 10_000, 4_000);
+        open_trove(contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(contract, bob(), 5_000);
+        set_caller(owner(), 1);
+        contract.set_withdrawal_queue(Some(U128(1_000)), 60_000);
+    }
+
+    #[test]
+    fn small_stability_withdrawal_stays_instant() {
+        let mut contract = setup_contract();
+        queue_config(&mut contract);
+        set_caller_at(bob(), 1_000);
+        let queued =
+            contract.withdraw_from_stability_pool(Some(U128(1_000)), Some(collateral_token()));
+        assert!(queued.is_none());
+        assert_eq!(contract.ft_balance_of(bob()).0, 1_000);
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            4_000
+        );
+    }
+
+    #[test]
+    fn large_stability_withdrawal_queues_until_finalized() {
+        let mut contract = setup_contract();
+        queue_config(&mut contract);
+        set_caller_at(bob(), 1_000);
+        let id = contract
+            .withdraw_from_stability_pool(None, Some(collateral_token()))
+            .expect("withdrawal should be queued");
+        assert_eq!(contract.ft_balance_of(bob()).0, 0);
+        let queued = contract.get_queued_withdrawal(id).unwrap();
+        assert_eq!(queued.ready_at.0, 61_000);
+        assert_eq!(queued.collateral_id, Some(collateral_token()));
+
+        // The queued deposit still absorbs a liquidation in the meantime.
+        set_price(&mut contract, 2, 2);
+        set_caller_at(carol(), 2_000);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        let remaining = contract
+            .get_stability_pool_balance(Some(collateral_token()))
+            .0;
+        assert!(remaining < 5_000);
+
+        set_caller_at(bob(), 61_000);
+        assert_eq!(contract.finalize_withdrawal(id).0, remaining);
+        assert_eq!(contract.ft_balance_of(bob()).0, remaining);
+        assert!(contract.get_queued_withdrawal(id).is_none());
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal delay has not passed")]
+    fn queued_withdrawal_cannot_finalize_early() {
+        let mut contract = setup_contract();
+        queue_config(&mut contract);
+        set_caller_at(bob(), 1_000);
+        let id = contract
+            .withdraw_from_stability_pool(Some(U128(2_000)), Some(collateral_token()))
+            .unwrap();
+        set_caller_at(bob(), 60_999);
+        contract.finalize_withdrawal(id);
+    }
+
+    #[test]
+    fn split_stability_withdrawals_count_towards_the_threshold() {
+        let mut contract = setup_contract();
+        queue_config(&mut contract);
+        set_caller_at(bob(), 1_000);
+        assert!(contract
+            .withdraw_from_stability_pool(Some(U128(600)), Some(collateral_token()))
+            .is_none());
+        set_caller_at(bob(), 2_000);
+        assert!(contract
+            .withdraw_from_stability_pool(Some(U128(600)), Some(collateral_token()))
+            .is_some());
+
+        // A new window starts once the delay has passed.
+        set_caller_at(bob(), 61_000);
+        assert!(contract
+            .withdraw_from_stability_pool(Some(U128(600)), Some(collateral_token()))
+            .is_none());
+        assert_eq!(contract.ft_balance_of(bob()).0, 1_200);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient balance")]
+    fn queued_shares_cannot_be_withdrawn_instantly() {
+        let mut contract = setup_contract();
+        queue_config(&mut contract);
+        set_caller_at(bob(), 1_000);
+        contract
+            .withdraw_from_stability_pool(Some(U128(4_500)), Some(collateral_token()))
+            .unwrap();
+        set_caller_at(bob(), 61_000);
+        contract.withdraw_from_stability_pool(Some(U128(1_000)), Some(collateral_token()));
+    }
+
+    #[test]
+    fn reliquidation_cooldown_protects_partially_liquidated_trove() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        set_caller(owner(), 1);
+        contract.set_reliquidation_cooldown_ms(60_000);
+        set_price(&mut contract, 2, 2);
+
+        // Mark alice's trove as partially liquidated at t = 1000 ms.
+        let key = Contract::trove_key(&alice(), &collateral_token());
+        let mut trove = contract.troves.get(&key).unwrap();
+        trove.last_liquidation_timestamp = 1_000;
+        contract.troves.insert(&key, &trove);
+
+        set_caller_at(dave(), 30_000);
+        assert!(matches!(
+            contract.liquidate(collateral_token(), vec![alice()], None, None, None),
+            PromiseOrValue::Value(U64(0))
+        ));
+        assert!(contract.get_trove(alice(), collateral_token()).is_some());
+
+        set_caller_at(dave(), 61_000);
+        assert!(matches!(
+            contract.liquidate(collateral_token(), vec![alice()], None, None, None),
+            PromiseOrValue::Value(U64(1))
+        ));
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+    }
+
+    #[test]
+    fn price_usd_1e8_normalizes_decimals() {
+        let mut contract = setup_contract();
+        assert_eq!(contract.get_price_usd_1e8(alice()), None);
+        // 200.00 submitted with 2 decimals.
+        assert_eq!(
+            contract.get_price_usd_1e8(collateral_token()),
+            Some(U128(20_000_000_000))
+        );
+        set_price(&mut contract, 200_000_000_000_000_000_000, 18);
+        assert_eq!(
+            contract.get_price_usd_1e8(collateral_token()),
+            Some(U128(20_000_000_000))
+        );
+        set_price(&mut contract, 200, 0);
+        assert_eq!(
+            contract.get_price_usd_1e8(collateral_token()),
+            Some(U128(20_000_000_000))
+        );
+    }
+
+    fn setup_with_stale_price() -> Contract {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                max_price_age_ms: Some(60_000),
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 10_000, 0);
+        contract
+    }
+
+    #[test]
+    fn global_collateral_ratio_spans_collaterals() {
+        let mut contract = setup_contract();
+        assert_eq!(contract.get_global_collateral_ratio(), u64::MAX);
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(wbtc.clone(), collateral_config());
+        set_caller(oracle(), 0);
+        contract.submit_price(wbtc.clone(), U128(500), 0);
+
+        // 1_000 usdc at 200 = 200_000 against 100_000 debt.
+        open_trove(&mut contract, alice(), 1_000, 100_000);
+        // 600 wbtc at 500 = 300_000 against 150_000 debt.
+        contract.nusd.internal_register_account(&bob());
+        set_caller(wbtc.clone(), 0);
+        contract.ft_on_transfer(
+            bob(),
+            U128(600),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        set_caller(bob(), 1);
+        contract.borrow(wbtc, U128(150_000), None);
+
+        assert_eq!(contract.get_global_collateral_ratio(), 20_000);
+        set_price(&mut contract, 10_000, 2);
+        // usdc halves: (100_000 + 300_000) / 250_000.
+        assert_eq!(contract.get_global_collateral_ratio(), 16_000);
+    }
+
+    #[test]
+    fn oracle_health_flags_stale_and_missing_feeds() {
+        let mut contract = setup_with_stale_price();
+        let unpriced: AccountId = "wbtc.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(unpriced.clone(), collateral_config());
+
+        set_caller_at(alice(), 30_000);
+        let fresh = contract.get_oracle_health(collateral_token()).unwrap();
+        assert!(fresh.is_healthy);
+        assert_eq!(fresh.age_ms, Some(U64(30_000)));
+        assert_eq!(fresh.max_price_age_ms, Some(60_000));
+        let missing = contract.get_oracle_health(unpriced.clone()).unwrap();
+        assert!(!missing.is_healthy);
+        assert!(missing.last_update_timestamp.is_none());
+        assert_eq!(contract.list_unhealthy_oracles(), vec![unpriced.clone()]);
+        assert!(contract.get_oracle_health(carol()).is_none());
+
+        set_caller_at(alice(), 120_000);
+        let stale = contract.get_oracle_health(collateral_token()).unwrap();
+        assert!(!stale.is_healthy);
+        assert_eq!(stale.age_ms, Some(U64(120_000)));
+        let mut unhealthy = contract.list_unhealthy_oracles();
+        unhealthy.sort();
+        assert_eq!(unhealthy, vec![collateral_token(), unpriced]);
+    }
+
+    fn oracle_signing_key() -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    fn sign_price(price: u128, decimals: u8, publish_time: u64) -> Base64VecU8 {
+        use ed25519_dalek::Signer;
+        let message =
+            Contract::signed_price_message(&collateral_token(), price, decimals, publish_time);
+        Base64VecU8(oracle_signing_key().sign(&message).to_bytes().to_vec())
+    }
+
+    fn setup_with_oracle_key() -> Contract {
+        let mut contract = setup_contract();
+        let public_key = PublicKey::from_parts(
+            CurveType::ED25519,
+            oracle_signing_key().verifying_key().to_bytes().to_vec(),
+        )
+        .unwrap();
+        set_caller(owner(), 1);
+        contract.set_oracle_public_key(collateral_token(), Some(public_key.clone()));
+        assert_eq!(
+            contract.get_oracle_public_key(collateral_token()),
+            Some(public_key)
+        );
+        contract
+    }
+
+    #[test]
+    fn signed_price_can_be_relayed_by_anyone() {
+        let mut contract = setup_with_oracle_key();
+        set_caller_at(carol(), 5_000);
+        contract.submit_signed_price(
+            collateral_token(),
+            U128(30_000),
+            2,
+            U64(4_000),
+            sign_price(30_000, 2, 4_000),
+        );
+        let price = contract.get_price(collateral_token()).unwrap();
+        assert_eq!(price.price.0, 30_000);
+        assert_eq!(price.last_update_timestamp.0, 4_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid price signature")]
+    fn tampered_signed_price_is_rejected() {
+        let mut contract = setup_with_oracle_key();
+        set_caller_at(carol(), 5_000);
+        contract.submit_signed_price(
+            collateral_token(),
+            U128(1),
+            2,
+            U64(4_000),
+            sign_price(30_000, 2, 4_000),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Signed price is not newer")]
+    fn signed_price_cannot_be_replayed() {
+        let mut contract = setup_with_oracle_key();
+        set_caller_at(carol(), 5_000);
+        let signature = sign_price(30_000, 2, 4_000);
+        contract.submit_signed_price(
+            collateral_token(),
+            U128(30_000),
+            2,
+            U64(4_000),
+            signature.clone(),
+        );
+        contract.submit_signed_price(collateral_token(), U128(30_000), 2, U64(4_000), signature);
+    }
+
+    #[test]
+    #[should_panic(expected = "Signed price is older than the feed")]
+    fn signed_price_cannot_roll_back_a_newer_push() {
+        let mut contract = setup_with_oracle_key();
+        set_caller_at(oracle(), 5_000);
+        contract.submit_price(collateral_token(), U128(20_000), 2);
+        set_caller_at(carol(), 6_000);
+        contract.submit_signed_price(
+            collateral_token(),
+            U128(30_000),
+            2,
+            U64(4_000),
+            sign_price(30_000, 2, 4_000),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Signed price too old")]
+    fn signed_price_older_than_max_age_is_rejected() {
+        let mut contract = setup_with_oracle_key();
+        set_caller_at(carol(), 4_000 + types::MAX_SIGNED_PRICE_AGE_MS + 1);
+        contract.submit_signed_price(
+            collateral_token(),
+            U128(30_000),
+            2,
+            U64(4_000),
+            sign_price(30_000, 2, 4_000),
+        );
+    }
+
+    #[test]
+    fn stale_price_allows_safe_withdrawals() {
+        let mut contract = setup_with_stale_price();
+
+        set_caller_at(bob(), 120_000);
+        let _ = contract.withdraw_collateral(collateral_token(), U128(10_000), None);
+        assert_eq!(
+            contract
+                .get_trove(bob(), collateral_token())
+                .unwrap()
+                .collateral_amount
+                .0,
+            0
+        );
+
+        // Halving the debt while withdrawing a quarter raises the ratio.
+        set_caller_at(alice(), 120_000);
+        let _ = contract.repay_and_withdraw(collateral_token(), U128(2_000), U128(2_500), None);
+        let trove = contract.get_trove(alice(), collateral_token()).unwrap();
+        assert_eq!(trove.collateral_amount.0, 7_500);
+        assert_eq!(trove.debt_amount.0, 2_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price is stale")]
+    fn stale_price_blocks_ratio_lowering_withdrawal() {
+        let mut contract = setup_with_stale_price();
+        set_caller_at(alice(), 120_000);
+        let _ = contract.withdraw_collateral(collateral_token(), U128(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price too stale")]
+    fn stale_price_blocks_borrow() {
+        let mut contract = setup_with_stale_price();
+        set_caller_at(alice(), 60_000);
+        assert!(!contract.is_price_stale(collateral_token()));
+        set_caller_at(alice(), 120_000);
+        assert!(contract.is_price_stale(collateral_token()));
+        contract.borrow(collateral_token(), U128(100), None);
+    }
+
+    fn liquidatable_at_stale_price(liquidate_on_stale_price: bool) -> Contract {
+        let mut contract = setup_with_stale_price();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                max_price_age_ms: Some(60_000),
+                liquidate_on_stale_price,
+                ..collateral_config()
+            },
+        );
+        deposit_to_pool(&mut contract, alice(), 4_000);
+        set_price(&mut contract, 2, 2);
+        set_caller_at(carol(), 120_000);
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "Price too stale")]
+    fn stale_price_blocks_liquidation_by_default() {
+        let mut contract = liquidatable_at_stale_price(false);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+    }
+
+    #[test]
+    fn stale_price_liquidation_when_allowed() {
+        let mut contract = liquidatable_at_stale_price(true);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawals locked while liquidatable")]
+    fn liquidatable_trove_cannot_withdraw_when_locked() {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                lock_withdrawals_when_liquidatable: true,
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        set_price(&mut contract, 2, 2);
+        // Top-ups stay open.
+        open_trove(&mut contract, alice(), 1_000, 0);
+        assert_eq!(trove_amounts(&contract, alice()).0, 11_000);
+        set_caller(alice(), 1);
+        let _ = contract.withdraw_collateral(collateral_token(), U128(1), None);
+    }
+
+    #[test]
+    fn migrate_stability_deposit_preserves_shares_and_rewards() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, dave(), 10_000, 2_000);
+        open_trove(&mut contract, bob(), 1_000_000, 6_000);
+        open_trove(&mut contract, carol(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 6_000);
+        deposit_to_pool(&mut contract, carol(), 4_000);
+        set_price(&mut contract, 2, 2);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+
+        let claimable = |contract: &Contract, account: AccountId| {
+            contract
+                .get_claimable_collateral_reward(account, collateral_token())
+                .0
+        };
+        let bob_reward = claimable(&contract, bob());
+        let carol_reward = claimable(&contract, carol());
+
+        set_caller(bob(), 1);
+        let migrated = contract.migrate_stability_deposit(Some(collateral_token()), None, None);
+        assert_eq!(migrated.0, 3_600);
+        assert_eq!(claimable(&contract, bob()), bob_reward);
+        assert_eq!(contract.get_stability_pool_deposit(bob(), None).0, 3_600);
+        assert_eq!(
+            contract
+                .get_stability_pool_deposit(bob(), Some(collateral_token()))
+                .0,
+            0
+        );
+        assert_eq!(
+            contract
+                .get_stability_pool_deposit(carol(), Some(collateral_token()))
+                .0,
+            2_400
+        );
+        assert_eq!(contract.get_total_stability_pool_balance().0, 6_000);
+
+        // Once the collateral is backed by the shared pool, only bob earns.
+        let mut config = collateral_config();
+        config.stability_pool_mode = StabilityPoolMode::Shared;
+        update_config(&mut contract, config);
+        set_caller(alice(), 1);
+        contract.liquidate(collateral_token(), vec![dave()], None, None, None);
+        assert_eq!(claimable(&contract, carol()), carol_reward);
+        // 9_950 distributable, less one unit of rounding dust.
+        assert_eq!(claimable(&contract, bob()), bob_reward + 9_949);
+        assert_eq!(contract.get_stability_pool_balance(None).0, 1_600);
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            2_400
+        );
+    }
+
+    #[test]
+    fn dedicated_and_shared_pools_absorb_only_their_collaterals() {
+        let mut contract = setup_contract();
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(
+            wbtc.clone(),
+            CollateralConfig {
+                stability_pool_mode: StabilityPoolMode::Shared,
+                ..collateral_config()
+            },
+        );
+        set_caller(oracle(), 0);
+        contract.submit_price(wbtc.clone(), U128(500), 0);
+
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 6_000);
+        open_trove(&mut contract, carol(), 1_000_000, 6_000);
+        contract.nusd.internal_register_account(&dave());
+        set_caller(wbtc.clone(), 0);
+        contract.ft_on_transfer(
+            dave(),
+            U128(100),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        set_caller(dave(), 1);
+        contract.borrow(wbtc.clone(), U128(3_000), None);
+        // Bob backs usdc alone; carol backs the shared pool wbtc uses.
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        set_caller(carol(), 1);
+        contract.deposit_to_stability_pool(U128(5_000), None);
+
+        set_caller(oracle(), 0);
+        contract.submit_price(wbtc.clone(), U128(1), 0);
+        set_caller(alice(), 1);
+        contract.liquidate(wbtc.clone(), vec![dave()], None, None, None);
+        assert_eq!(contract.get_stability_pool_balance(None).0, 2_000);
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            5_000
+        );
+
+        set_price(&mut contract, 2, 2);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        assert_eq!(contract.get_stability_pool_balance(None).0, 2_000);
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            1_000
+        );
+
+        let claimable = |account: AccountId, collateral: &AccountId| {
+            contract
+                .get_claimable_collateral_reward(account, collateral.clone())
+                .0
+        };
+        assert!(claimable(carol(), &wbtc) > 0);
+        assert_eq!(claimable(bob(), &wbtc), 0);
+        assert!(claimable(bob(), &collateral_token()) > 0);
+        assert_eq!(claimable(carol(), &collateral_token()), 0);
+    }
+
+    #[test]
+    fn insurance_pool_absorbs_only_its_collaterals() {
+        let mut contract = setup_contract();
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        let risky: AccountId = "risky.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(wbtc.clone(), collateral_config());
+        contract.register_collateral(
+            risky.clone(),
+            CollateralConfig {
+                insurance_pool_id: Some(wbtc.clone()),
+                ..collateral_config()
+            },
+        );
+        set_caller(oracle(), 0);
+        contract.submit_price(risky.clone(), U128(500), 0);
+
+        open_trove(&mut contract, bob(), 1_000_000, 6_000);
+        open_trove(&mut contract, carol(), 1_000_000, 6_000);
+        contract.nusd.internal_register_account(&dave());
+        set_caller(risky.clone(), 0);
+        contract.ft_on_transfer(
+            dave(),
+            U128(100),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        set_caller(dave(), 1);
+        contract.borrow(risky.clone(), U128(3_000), None);
+        deposit_to_pool(&mut contract, bob(), 5_000);
+        set_caller(carol(), 1);
+        contract.deposit_to_stability_pool(U128(5_000), Some(wbtc.clone()));
+
+        set_caller(oracle(), 0);
+        contract.submit_price(risky.clone(), U128(1), 0);
+        set_caller(alice(), 1);
+        contract.liquidate(risky.clone(), vec![dave()], None, None, None);
+        assert_eq!(contract.get_stability_pool_balance(Some(wbtc)).0, 2_000);
+        assert_eq!(
+            contract
+                .get_stability_pool_balance(Some(collateral_token()))
+                .0,
+            5_000
+        );
+
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(bob(), risky.clone())
+                .0,
+            0
+        );
+        let reward = contract.get_claimable_collateral_reward(carol(), risky.clone());
+        assert!(reward.0 > 0);
+        set_caller(carol(), 1);
+        let claimed = contract.claim_rewards(vec![risky.clone()]);
+        assert_eq!(claimed, vec![(risky, reward)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insurance pool must be a dedicated collateral")]
+    fn insurance_pool_requires_dedicated_collateral() {
+        let mut contract = setup_contract();
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(
+            wbtc.clone(),
+            CollateralConfig {
+                stability_pool_mode: StabilityPoolMode::Shared,
+                ..collateral_config()
+            },
+        );
+        contract.register_collateral(
+            "risky.fakes".parse().unwrap(),
+            CollateralConfig {
+                insurance_pool_id: Some(wbtc),
+                ..collateral_config()
+            },
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Source and destination pools are the same")]
+    fn migrate_stability_deposit_rejects_same_pool() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, bob(), 1_000_000, 6_000);
+        deposit_to_pool(&mut contract, bob(), 6_000);
+        set_caller(bob(), 1);
+        contract.migrate_stability_deposit(
+            Some(collateral_token()),
+            Some(collateral_token()),
+            None,
+        );
+    }
+
+    #[test]
+    fn overflow_audit_logs_near_u128_limit() {
+        use crate::audit::OVERFLOW_AUDIT_THRESHOLD;
+        use near_sdk::test_utils::get_logs;
+
+        let contract = setup_contract();
+        let price = PriceFeedInternal {
+            price: 1,
+            decimals: 0,
+            last_update_timestamp: 0,
+        };
+        let limit = OVERFLOW_AUDIT_THRESHOLD / types::BPS_DENOMINATOR;
+
+        contract.collateral_ratio(limit, 1, &price);
+        assert!(get_logs().is_empty());
+        contract.collateral_ratio(limit + 1, 1, &price);
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                "Overflow audit: collateral ratio near u128 limit ({} * {})",
+                limit + 1,
+                types::BPS_DENOMINATOR
+            )]
+        );
+
+        let pool = types::StabilityPool {
+            total_shares: OVERFLOW_AUDIT_THRESHOLD / 2,
+            total_nusd: 1,
+            ..Default::default()
+        };
+        pool.shares_from_amount(3);
+        assert!(get_logs()
+            .last()
+            .unwrap()
+            .starts_with("Overflow audit: share issuance"));
+    }
+
+    fn set_redemption_self_policy(contract: &mut Contract, policy: types::RedemptionSelfPolicy) {
+        set_caller(owner(), 1);
+        contract.set_redemption_self_policy(policy);
+    }
+
+    #[test]
+    fn redeem_multi_skips_or_hits_redeemer_trove() {
+        let mut contract = setup_contract();
+        // Alice owns the riskiest trove (142%), then bob (285%), then carol.
+        open_trove(&mut contract, alice(), 100, 14_000);
+        open_trove(&mut contract, bob(), 200, 14_000);
+        open_trove(&mut contract, carol(), 1_000, 14_000);
+
+        set_redemption_self_policy(&mut contract, types::RedemptionSelfPolicy::Skip);
+        set_caller(alice(), 1);
+        assert_eq!(
+            contract
+                .redeem_multi(collateral_token(), U128(5_000), None)
+                .redeemed
+                .0,
+            5_000
+        );
+        let debt = |contract: &Contract, owner: AccountId| {
+            contract
+                .get_trove(owner, collateral_token())
+                .unwrap()
+                .debt_amount
+                .0
+        };
+        assert_eq!(debt(&contract, alice()), 14_000);
+        assert_eq!(debt(&contract, bob()), 9_000);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            25
+        );
+
+        set_redemption_self_policy(&mut contract, types::RedemptionSelfPolicy::Include);
+        set_caller(alice(), 1);
+        contract.redeem_multi(collateral_token(), U128(5_000), None);
+        assert_eq!(debt(&contract, alice()), 9_000);
+        assert_eq!(debt(&contract, bob()), 9_000);
+    }
+
+    #[test]
+    fn redeem_multi_hits_redeemer_trove_first() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 14_000);
+        open_trove(&mut contract, bob(), 100, 14_000);
+
+        set_redemption_self_policy(&mut contract, types::RedemptionSelfPolicy::First);
+        set_caller(alice(), 1);
+        // Bob's trove is riskier, but alice's own trove comes first.
+        contract.redeem_multi(collateral_token(), U128(14_000), Some(1));
+        let alice_trove = contract.get_trove(alice(), collateral_token()).unwrap();
+        assert_eq!(alice_trove.debt_amount.0, 0);
+        assert_eq!(alice_trove.collateral_amount.0, 930);
+        assert_eq!(
+            contract
+                .get_trove(bob(), collateral_token())
+                .unwrap()
+                .debt_amount
+                .0,
+            14_000
+        );
+    }
+
+    #[test]
+    fn redeem_multi_visits_at_most_max_iterations_troves() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 100, 10_000);
+        open_trove(&mut contract, carol(), 200, 10_000);
+
+        set_caller(alice(), 1);
+        let report = contract.redeem_multi(collateral_token(), U128(15_000), Some(1));
+        assert_eq!(report.redeemed.0, 10_000);
+        assert_eq!(trove_amounts(&contract, bob()).1, 0);
+        assert_eq!(trove_amounts(&contract, carol()).1, 10_000);
+    }
+
+    #[test]
+    fn redemption_index_orders_large_amounts_exactly() {
+        let key = |collateral: u128, debt: u128, owner: AccountId| types::RedemptionIndexKey {
+            collateral_amount: collateral,
+            normalized_debt: debt,
+            owner_id: owner,
+        };
+        let debt = 3 * 10u128.pow(30);
+        assert!(key(10u128.pow(30), debt, bob()) < key(10u128.pow(30) + 1, debt, alice()));
+        assert!(key(2, 4, bob()) > key(1, 2, alice()));
+        assert!(key(u128::MAX, u128::MAX - 1, alice()) > key(u128::MAX - 1, u128::MAX, bob()));
+    }
+
+    fn ramped_ceiling_config() -> CollateralConfig {
+        let mut config = collateral_config();
+        config.initial_debt_ceiling = Some(U128(10_000));
+        config.debt_ceiling = U128(110_000);
+        config.ceiling_ramp_duration_ms = 100_000;
+        config
+    }
+
+    #[test]
+    fn debt_ceiling_ramps_linearly_from_registration() {
+        let mut contract = setup_contract();
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        set_caller_at(owner(), 1_000);
+        contract.register_collateral(wbtc.clone(), ramped_ceiling_config());
+        let ceiling_at = |contract: &Contract, timestamp_ms: u64| {
+            set_caller_at(alice(), timestamp_ms);
+            contract.get_effective_debt_ceiling(wbtc.clone()).unwrap().0
+        };
+        assert_eq!(ceiling_at(&contract, 1_000), 10_000);
+        assert_eq!(ceiling_at(&contract, 26_000), 35_000);
+        assert_eq!(ceiling_at(&contract, 51_000), 60_000);
+        assert_eq!(ceiling_at(&contract, 101_000), 110_000);
+        assert_eq!(ceiling_at(&contract, 500_000), 110_000);
+
+        // Updating the config keeps the original ramp start.
+        set_caller_at(owner(), 51_000);
+        contract.register_collateral(wbtc.clone(), ramped_ceiling_config());
+        assert_eq!(ceiling_at(&contract, 51_000), 60_000);
+        assert_eq!(
+            contract
+                .get_effective_debt_ceiling(collateral_token())
+                .unwrap()
+                .0,
+            1_000_000_000_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Collateral debt ceiling reached")]
+    fn borrow_limited_by_ramped_ceiling() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, ramped_ceiling_config());
+        open_trove(&mut contract, alice(), 1_000, 10_001);
+    }
+
+    fn dynamic_interest_config() -> CollateralConfig {
+        let mut config = collateral_config();
+        config.debt_ceiling = U128(100_000);
+        config.interest_rate_bps = 500;
+        config.dynamic_interest = true;
+        config.max_interest_rate_bps = 5_500;
+        config.interest_kink_bps = 5_000;
+        config
+    }
+
+    #[test]
+    fn dynamic_interest_rate_follows_utilization() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, dynamic_interest_config());
+        assert_eq!(
+            contract.get_current_interest_rate(collateral_token()),
+            Some(500)
+        );
+
+        // 40% utilization is below the kink: the floor applies.
+        open_trove(&mut contract, alice(), 1_000, 40_000);
+        assert_eq!(
+            contract.get_current_interest_rate(collateral_token()),
+            Some(500)
+        );
+        // 80% is 3/5 of the way from the kink to the ceiling.
+        open_trove(&mut contract, bob(), 1_000, 40_000);
+        assert_eq!(
+            contract.get_current_interest_rate(collateral_token()),
+            Some(3_500)
+        );
+        open_trove(&mut contract, carol(), 1_000, 20_000);
+        assert_eq!(
+            contract.get_current_interest_rate(collateral_token()),
+            Some(5_500)
+        );
+
+        // A year at the ceiling rate adds 55% to alice's debt.
+        set_caller_at(alice(), types::MS_PER_YEAR);
+        contract.repay(collateral_token(), U128(1));
+        assert_eq!(
+            contract
+                .get_trove(alice(), collateral_token())
+                .unwrap()
+                .debt_amount
+                .0,
+            40_000 + 22_000 - 1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Max interest rate must be >= interest rate")]
+    fn dynamic_interest_rejects_inverted_curve() {
+        let mut contract = setup_contract();
+        let mut config = dynamic_interest_config();
+        config.max_interest_rate_bps = 100;
+        update_config(&mut contract, config);
+    }
+
+    #[test]
+    fn enabling_interest_does_not_charge_for_the_interest_free_period() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+
+        set_caller_at(owner(), types::MS_PER_YEAR);
+        let mut config = collateral_config();
+        config.interest_rate_bps = 500;
+        contract.register_collateral(collateral_token(), config);
+
+        set_caller_at(carol(), 2 * types::MS_PER_YEAR);
+        assert_eq!(
+            contract
+                .accrue_trove_interest(alice(), collateral_token())
+                .0,
+            500
+        );
+    }
+
+    #[test]
+    fn interest_funds_insurance_that_covers_bad_debt() {
+        let mut contract = setup_contract();
+        let mut config = collateral_config();
+        config.interest_rate_bps = 1_000;
+        update_config(&mut contract, config);
+        contract.set_interest_to_equity_bps(5_000);
+        open_trove(&mut contract, alice(), 1_000_000, 10_000);
+        open_trove(&mut contract, carol(), 10_000, 400);
+
+        // A year at 10% adds 1_000, split evenly between fund and treasury.
+        set_caller_at(alice(), types::MS_PER_YEAR);
+        contract.repay(collateral_token(), U128(1_000));
+        assert_eq!(
+            contract
+                .get_trove(alice(), collateral_token())
+                .unwrap()
+                .debt_amount
+                .0,
+            10_000
+        );
+        assert_eq!(contract.get_insurance_fund_balance().0, 500);
+        assert_eq!(contract.ft_balance_of(owner()).0, 500);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 10_400);
+
+        set_caller(owner(), 1);
+        contract.write_off_collateral(collateral_token());
+        set_caller(carol(), 1);
+        contract.abandon_trove(collateral_token());
+        assert_eq!(contract.get_bad_debt(collateral_token()).0, 400);
+
+        set_caller(owner(), 1);
+        assert_eq!(contract.cover_bad_debt(collateral_token(), None).0, 400);
+        assert_eq!(contract.get_bad_debt(collateral_token()).0, 0);
+        assert_eq!(contract.get_insurance_fund_balance().0, 100);
+        assert_eq!(
+            contract.ft_total_supply().0,
+            contract.get_total_debt(collateral_token()).0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient insurance fund")]
+    fn cover_bad_debt_limited_by_insurance_fund() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, carol(), 10_000, 400);
+        set_caller(owner(), 1);
+        contract.write_off_collateral(collateral_token());
+        set_caller(carol(), 1);
+        contract.abandon_trove(collateral_token());
+        set_caller(owner(), 1);
+        contract.cover_bad_debt(collateral_token(), None);
+    }
+
+    #[test]
+    fn zero_amount_transfer_is_a_no_op() {
+        let mut contract = setup_contract();
+        set_caller(collateral_token(), 0);
+        let result = contract.ft_on_transfer(alice(), U128(0), "ping".to_string());
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+    }
+
+    #[test]
+    fn borrow_respects_per_transaction_cap() {
+        let mut contract = setup_contract();
+        set_caller(owner(), 1);
+        contract.set_max_borrow_per_tx(Some(U128(5_000)));
+        assert_eq!(contract.max_borrow_per_tx(), Some(U128(5_000)));
+        open_trove(&mut contract, alice(), 1_000, 5_000);
+        set_caller(alice(), 1);
+        contract.borrow(collateral_token(), U128(5_000), None);
+        assert_eq!(
+            contract
+                .get_trove(alice(), collateral_token())
+                .unwrap()
+                .debt_amount
+                .0,
+            10_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Borrow exceeds per-transaction limit")]
+    fn borrow_above_per_transaction_cap_fails() {
+        let mut contract = setup_contract();
+        set_caller(owner(), 1);
+        contract.set_max_borrow_per_tx(Some(U128(5_000)));
+        open_trove(&mut contract, alice(), 1_000, 5_001);
+    }
+
+    #[test]
+    #[should_panic(expected = "System utilization cap reached")]
+    fn borrow_blocked_at_system_utilization_cap() {
+        let mut contract = setup_contract();
+        assert_eq!(contract.get_system_utilization(), None);
+        set_caller(owner(), 1);
+        contract.set_max_system_utilization_bps(Some(5_000));
+        // 1_000 collateral at 200 is worth 200_000 nUSD.
+        open_trove(&mut contract, alice(), 1_000, 100_000);
+        assert_eq!(contract.get_system_utilization(), Some(U128(5_000)));
+        assert_eq!(
+            contract.get_config().max_system_utilization_bps,
+            Some(5_000)
+        );
+        set_caller(alice(), 1);
+        contract.borrow(collateral_token(), U128(1), None);
+    }
+
+    #[test]
+    fn transferred_trove_can_be_repaid_by_new_owner() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        contract.nusd.internal_register_account(&bob());
+        set_caller(alice(), 1);
+        contract.transfer_trove(collateral_token(), bob());
+
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+        let trove = contract.get_trove(bob(), collateral_token()).unwrap();
+        assert_eq!(trove.owner_id, bob());
+        assert_eq!(trove.collateral_amount.0, 1_000);
+        assert_eq!(trove.debt_amount.0, 10_000);
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 1);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 1_000);
+
+        contract
+            .nusd
+            .internal_transfer(&alice(), &bob(), 10_000, None);
+        set_caller(bob(), 1);
+        contract.repay(collateral_token(), U128(10_000));
+        assert_eq!(
+            contract
+                .get_trove(bob(), collateral_token())
+                .unwrap()
+                .debt_amount
+                .0,
+            0
+        );
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "New owner already has a trove")]
+    fn transfer_trove_rejects_owner_with_existing_trove() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 0);
+        set_caller(alice(), 1);
+        contract.transfer_trove(collateral_token(), bob());
+    }
+
+    #[test]
+    fn merge_troves_combines_collateral_and_debt() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 500, 20_000);
+        set_caller(alice(), 1);
+        contract.approve_trove_merge(collateral_token(), Some(bob()));
+        set_caller(bob(), 1);
+        contract.merge_troves(collateral_token(), alice());
+
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+        let (collateral, debt) = trove_amounts(&contract, bob());
+        assert_eq!(collateral, 1_500);
+        assert_eq!(debt, 30_000);
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 1);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 1_500);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 30_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Would violate recovery ratio")]
+    fn merge_troves_needs_the_recovery_ratio_in_recovery() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 100, 10_000);
+        open_trove(&mut contract, bob(), 100, 10_000);
+        // Both troves, and the system, sit at 1_400 bps: above the 1_300
+        // MCR, below the 1_500 recovery ratio.
+        set_price(&mut contract, 1_400, 2);
+        set_caller(alice(), 1);
+        contract.approve_trove_merge(collateral_token(), Some(bob()));
+        set_caller(bob(), 1);
+        contract.merge_troves(collateral_token(), alice());
+    }
+
+    #[test]
+    #[should_panic(expected = "Merge not approved by source owner")]
+    fn merge_troves_requires_source_approval() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 500, 20_000);
+        set_caller(alice(), 1);
+        contract.approve_trove_merge(collateral_token(), Some(carol()));
+        set_caller(bob(), 1);
+        contract.merge_troves(collateral_token(), alice());
+    }
+
+    #[test]
+    fn deployed_metadata_drives_ft_metadata() {
+        set_caller(owner(), 0);
+        let mut custom = metadata();
+        custom.name = "White Label Dollar".to_string();
+        custom.symbol = "wUSD".to_string();
+        let mut contract = Contract::new(owner(), intents(), oracle(), custom);
+        assert_eq!(contract.ft_metadata().symbol, "wUSD");
+        assert_eq!(contract.ft_metadata().name, "White Label Dollar");
+
+        let mut updated = contract.ft_metadata();
+        updated.symbol = "xUSD".to_string();
+        set_caller(owner(), 1);
+        contract.set_metadata(updated);
+        let current = contract.ft_metadata();
+        assert_eq!(current.symbol, "xUSD");
+        assert_ne!(current.name, "nUSD");
+    }
+
+    #[test]
+    #[should_panic(expected = "Decimals cannot change")]
+    fn set_metadata_rejects_decimal_change() {
+        let mut contract = setup_contract();
+        let mut updated = metadata();
+        updated.decimals = 18;
+        set_caller(owner(), 1);
+        contract.set_metadata(updated);
+    }
+
+    fn dust_config() -> CollateralConfig {
+        CollateralConfig {
+            dust_trove_threshold: U128(1_000),
+            ..collateral_config()
+        }
+    }
+
+    #[test]
+    fn cleanup_trove_writes_off_the_debt_and_returns_the_dust() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, dust_config());
+        open_trove(&mut contract, alice(), 5, 700);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        // 5 collateral at 100 is worth 500: under the threshold and short of
+        // the debt.
+        set_price(&mut contract, 10_000, 2);
+
+        set_caller(carol(), 1);
+        assert_eq!(contract.cleanup_trove(collateral_token(), alice()).0, 700);
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+        assert_eq!(contract.get_bad_debt(collateral_token()).0, 700);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 10_000);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 1_000);
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 1);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            5
+        );
+        #[cfg(feature = "test-helpers")]
+        contract.assert_invariants();
+    }
+
+    #[test]
+    #[should_panic(expected = "Trove collateral covers its debt")]
+    fn cleanup_trove_rejects_dust_its_collateral_covers() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, dust_config());
+        // 4 collateral at 200 is worth 800, under the threshold but more
+        // than the debt.
+        open_trove(&mut contract, alice(), 4, 100);
+        set_caller(carol(), 1);
+        contract.cleanup_trove(collateral_token(), alice());
+    }
+
+    #[test]
+    #[should_panic(expected = "Trove is not dust")]
+    fn cleanup_trove_rejects_trove_above_threshold() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, dust_config());
+        open_trove(&mut contract, alice(), 5, 100);
+        set_caller(carol(), 1);
+        contract.cleanup_trove(collateral_token(), alice());
+    }
+
+    fn setup_with_treasury_reserve() -> Contract {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                min_treasury_reserve_bps: 1_000,
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        // 10 collateral at 200 is worth 2_000; the floor is 1_000.
+        contract.enqueue_collateral_reward(&owner(), &collateral_token(), 10);
+        contract
+    }
+
+    #[test]
+    fn treasury_claim_down_to_reserve_floor() {
+        let mut contract = setup_with_treasury_reserve();
+        assert_eq!(
+            contract.get_reserve_shortfall(collateral_token()),
+            Some(U128(0))
+        );
+        set_caller(owner(), 1);
+        let _ = contract.claim_collateral_reward(collateral_token(), Some(U128(5)));
+        assert_eq!(
+            contract.get_reserve_shortfall(collateral_token()),
+            Some(U128(0))
+        );
+        assert!(contract.claim_all_rewards().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Treasury reserve floor breached")]
+    fn treasury_claim_below_reserve_floor_fails() {
+        let mut contract = setup_with_treasury_reserve();
+        set_caller(owner(), 1);
+        let _ = contract.claim_collateral_reward(collateral_token(), Some(U128(6)));
+    }
+
+    #[test]
+    fn accrued_interest_view_matches_annual_rate() {
+        let mut contract = setup_contract();
+        let mut config = collateral_config();
+        config.interest_rate_bps = 500;
+        update_config(&mut contract, config);
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 0);
+        assert_eq!(
+            contract.get_accrued_interest(alice(), collateral_token()).0,
+            0
+        );
+
+        set_caller_at(alice(), types::MS_PER_YEAR / 4);
+        assert_eq!(
+            contract.get_accrued_interest(alice(), collateral_token()).0,
+            125
+        );
+        assert_eq!(
+            contract.get_accrued_interest(bob(), collateral_token()).0,
+            0
+        );
+        assert_eq!(
+            contract.get_accrued_interest(carol(), collateral_token()).0,
+            0
+        );
+        // The view does not accrue; the debt is unchanged until touched.
+        assert_eq!(
+            contract
+                .get_trove(alice(), collateral_token())
+                .unwrap()
+                .debt_amount
+                .0,
+            10_000
+        );
+    }
+
+    #[test]
+    fn borrowing_waits_for_minimum_stability_pool() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, bob(), 10_000, 5_000);
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                min_stability_pool_size: U128(3_000),
+                ..collateral_config()
+            },
+        );
+        assert_eq!(
+            contract.get_stability_pool_requirement(collateral_token()),
+            Some((U128(0), U128(3_000)))
+        );
+        deposit_to_pool(&mut contract, bob(), 3_000);
+        open_trove(&mut contract, alice(), 1_000, 1_000);
+        assert_eq!(trove_amounts(&contract, alice()).1, 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stability pool below minimum size")]
+    fn borrow_rejected_below_minimum_stability_pool() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, bob(), 10_000, 5_000);
+        deposit_to_pool(&mut contract, bob(), 2_999);
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                min_stability_pool_size: U128(3_000),
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 1_000, 1_000);
+    }
+
+    fn cdp_events() -> Vec<String> {
+        near_sdk::test_utils::get_logs()
+            .into_iter()
+            .filter(|log| log.starts_with(r#"EVENT_JSON:{"standard":"cdp""#))
+            .collect()
+    }
+
+    #[test]
+    fn core_event_level_suppresses_verbose_events() {
+        let mut contract = setup_contract();
+        assert!(contract.event_level() == types::EventLevel::Core);
+        set_price(&mut contract, 20_000, 2);
+        assert!(cdp_events().is_empty());
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        let events = cdp_events();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains(r#""event":"borrow""#));
+
+        set_caller(owner(), 1);
+        contract.set_event_level(types::EventLevel::Verbose);
+        set_price(&mut contract, 20_000, 2);
+        assert!(cdp_events()[0].contains(r#""event":"price_update""#));
+
+        set_caller(owner(), 1);
+        contract.set_event_level(types::EventLevel::Off);
+        set_caller(alice(), 1);
+        contract.repay(collateral_token(), U128(1_000));
+        assert!(cdp_events().is_empty());
+    }
+
+    #[test]
+    fn interest_compounds_alike_whichever_trove_is_poked() {
+        let mut contract = setup_contract();
+        let mut config = collateral_config();
+        config.interest_rate_bps = 500;
+        update_config(&mut contract, config);
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+
+        // Alice is poked every half year, bob only once after a full year.
+        // The index compounds at the poke, so both owe the same.
+        set_caller_at(carol(), types::MS_PER_YEAR / 2);
+        contract.accrue_trove_interest(alice(), collateral_token());
+        set_caller_at(carol(), types::MS_PER_YEAR);
+        assert_eq!(
+            contract
+                .accrue_trove_interest(alice(), collateral_token())
+                .0,
+            256
+        );
+        assert_eq!(
+            contract.accrue_trove_interest(bob(), collateral_token()).0,
+            506
+        );
+        assert_eq!(trove_amounts(&contract, alice()).1, 10_506);
+        assert_eq!(trove_amounts(&contract, bob()).1, 10_506);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 21_012);
+        assert_eq!(contract.ft_balance_of(owner()).0, 1_012);
+    }
+
+    #[test]
+    fn utilization_spike_only_charges_for_its_duration() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, dynamic_interest_config());
+        open_trove(&mut contract, alice(), 1_000, 40_000);
+        open_trove(&mut contract, bob(), 1_000, 0);
+
+        // Bob pushes utilization to the ceiling, pokes alice and repays
+        // in the same block: the ceiling rate applies for no time at all.
+        set_caller_at(bob(), types::MS_PER_YEAR / 2);
+        contract.borrow(collateral_token(), U128(60_000), None);
+        assert_eq!(
+            contract.get_current_interest_rate(collateral_token()),
+            Some(5_500)
+        );
+        set_caller_at(carol(), types::MS_PER_YEAR / 2);
+        contract.accrue_trove_interest(alice(), collateral_token());
+        set_caller_at(bob(), types::MS_PER_YEAR / 2);
+        contract.repay(collateral_token(), U128(60_000));
+
+        // Two halves at 5% compound to 2_025 on 40_000.
+        set_caller_at(carol(), types::MS_PER_YEAR);
+        contract.accrue_trove_interest(alice(), collateral_token());
+        assert_eq!(trove_amounts(&contract, alice()).1, 42_025);
+    }
+
+    #[test]
+    fn close_trove_preview_includes_accrued_interest() {
+        let mut contract = setup_contract();
+        let mut config = collateral_config();
+        config.interest_rate_bps = 500;
+        update_config(&mut contract, config);
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        assert!(contract
+            .get_close_trove_preview(carol(), collateral_token())
+            .is_none());
+
+        set_caller_at(alice(), types::MS_PER_YEAR / 2);
+        let preview = contract
+            .get_close_trove_preview(alice(), collateral_token())
+            .unwrap();
+        assert_eq!(preview.debt_to_repay.0, 10_250);
+        assert_eq!(preview.collateral_returned.0, 1_000);
+
+        // Bob's nUSD covers the interest alice has not minted.
+        set_caller(bob(), 1);
+        contract.ft_transfer(alice(), U128(250), None);
+        set_caller_at(alice(), types::MS_PER_YEAR / 2);
+        contract.repay(collateral_token(), preview.debt_to_repay);
+        let _ = contract.close_trove(collateral_token());
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+        assert_eq!(
+            contract.get_total_collateral(collateral_token()).0,
+            2_000 - preview.collateral_returned.0
+        );
+    }
+
+    #[test]
+    fn fee_on_transfer_deposit_is_credited_net() {
+        let mut contract = setup_contract();
+        let mut config = collateral_config();
+        config.has_transfer_fee = true;
+        config.transfer_fee_bps = 100;
+        update_config(&mut contract, config);
+        open_trove(&mut contract, alice(), 10_001, 0);
+        let trove = contract.get_trove(alice(), collateral_token()).unwrap();
+        assert_eq!(trove.collateral_amount.0, 9_900);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 9_900);
+    }
+
+    #[test]
+    fn force_epoch_resync_repairs_share_total_without_loss() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 6_000);
+        open_trove(&mut contract, carol(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 6_000);
+        deposit_to_pool(&mut contract, carol(), 4_000);
+        set_price(&mut contract, 2, 2);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        let claimable = |contract: &Contract, account: AccountId| {
+            contract
+                .get_claimable_collateral_reward(account, collateral_token())
+                .0
+        };
+        let bob_reward = claimable(&contract, bob());
+        let carol_reward = claimable(&contract, carol());
+
+        // Desync: the recorded total drifts away from the deposits.
+        let pool_id = types::PoolId::Dedicated(collateral_token());
+        let mut pool = contract.pool(&pool_id);
+        pool.total_shares = 20_000_000;
+        contract.stability_pools.insert(&pool_id, &pool);
+
+        set_caller(owner(), 1);
+        contract.set_operation_paused(types::Operation::Stability, true);
+        assert!(contract.force_epoch_resync(Some(collateral_token())));
+        assert_eq!(contract.pool(&pool_id).total_shares, 10_000_000);
+        assert_eq!(claimable(&contract, bob()), bob_reward);
+        assert_eq!(claimable(&contract, carol()), carol_reward);
+        assert_eq!(
+            contract
+                .get_stability_pool_deposit(bob(), Some(collateral_token()))
+                .0,
+            3_600
+        );
+
+        contract.force_epoch_resync(Some(collateral_token()));
+        assert_eq!(
+            near_sdk::test_utils::get_logs().last().unwrap(),
+            "Resync: epoch 0 consistent at 10000000 shares"
+        );
+        assert_eq!(contract.pool(&pool_id).total_shares, 10_000_000);
+    }
+
+    /// Pool with bob's and carol's 10_000 nUSD whose recorded share total
+    /// is overwritten with `total_shares`, ready for a resync.
+    fn desynced_pool(total_shares: Balance) -> Contract {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, bob(), 1_000_000, 6_000);
+        open_trove(&mut contract, carol(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 6_000);
+        deposit_to_pool(&mut contract, carol(), 4_000);
+        let pool_id = types::PoolId::Dedicated(collateral_token());
+        let mut pool = contract.pool(&pool_id);
+        pool.total_shares = total_shares;
+        contract.stability_pools.insert(&pool_id, &pool);
+        set_caller(owner(), 1);
+        contract.set_operation_paused(types::Operation::Stability, true);
+        contract
+    }
+
+    #[test]
+    fn force_epoch_resync_never_raises_the_share_total() {
+        let mut contract = desynced_pool(5_000_000);
+        assert!(contract.force_epoch_resync(Some(collateral_token())));
+        assert_eq!(
+            near_sdk::test_utils::get_logs().last().unwrap(),
+            "Resync: deposits exceed the recorded total, left unchanged"
+        );
+        let pool = contract.pool(&types::PoolId::Dedicated(collateral_token()));
+        assert_eq!(pool.total_shares, 5_000_000);
+        assert_eq!(pool.total_nusd, 10_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pause the stability pool before a resync")]
+    fn force_epoch_resync_requires_a_paused_pool() {
+        let mut contract = desynced_pool(20_000_000);
+        contract.set_operation_paused(types::Operation::Stability, false);
+        contract.force_epoch_resync(Some(collateral_token()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Depositor index incomplete")]
+    fn force_epoch_resync_requires_a_complete_index() {
+        let mut contract = desynced_pool(20_000_000);
+        unindex_depositor(&mut contract, &carol());
+        set_caller(owner(), 1);
+        contract.force_epoch_resync(Some(collateral_token()));
+    }
+
+    #[test]
+    fn force_epoch_resync_pages_through_depositors() {
+        let mut contract = desynced_pool(10_000_000);
+        let pool_id = types::PoolId::Dedicated(collateral_token());
+        for i in 0..types::MAX_DEPOSITORS_PER_BATCH {
+            let account_id: AccountId = format!("depositor{i}.near").parse().unwrap();
+            contract.pool_deposit(&account_id, &pool_id, 10);
+        }
+        let total_shares = contract.pool(&pool_id).total_shares;
+        let mut pool = contract.pool(&pool_id);
+        pool.total_shares += 1;
+        contract.stability_pools.insert(&pool_id, &pool);
+
+        set_caller(owner(), 1);
+        assert!(!contract.force_epoch_resync(Some(collateral_token())));
+        assert_eq!(
+            near_sdk::test_utils::get_logs().last().unwrap(),
+            "Resync: epoch 0 settled 50 of 52 depositors"
+        );
+        assert_eq!(contract.pool(&pool_id).total_shares, total_shares + 1);
+        assert!(contract.force_epoch_resync(Some(collateral_token())));
+        assert_eq!(contract.pool(&pool_id).total_shares, total_shares);
+    }
+
+    fn scaled_penalty_config() -> CollateralConfig {
+        CollateralConfig {
+            scaled_penalty: true,
+            max_penalty_bps: 1_350,
+            ..collateral_config()
+        }
+    }
+
+    #[test]
+    fn penalty_scales_with_shortfall_only_when_enabled() {
+        let flat: CollateralConfigInternal = collateral_config().into();
+        let scaled: CollateralConfigInternal = scaled_penalty_config().into();
+        // MCR is 1300 bps; 1200 and 600 are shallow and deep shortfalls.
+        assert_eq!(Contract::effective_penalty_bps(&flat, 1_200), 50);
+        assert_eq!(Contract::effective_penalty_bps(&flat, 600), 50);
+        assert_eq!(Contract::effective_penalty_bps(&scaled, 1_200), 150);
+        assert_eq!(Contract::effective_penalty_bps(&scaled, 600), 750);
+        assert_eq!(Contract::effective_penalty_bps(&scaled, 1_300), 50);
+    }
+
+    #[test]
+    fn liquidation_charges_scaled_penalty() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, scaled_penalty_config());
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 4_000);
+        // 10_000 at 0.02 covers 4_000 debt at 500 bps: 800 bps short.
+        set_price(&mut contract, 2, 2);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(owner(), collateral_token())
+                .0,
+            850
+        );
+    }
+
+    #[test]
+    fn list_stability_depositors_paginates_and_prunes() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 4_000);
+        open_trove(&mut contract, carol(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, alice(), 1_000);
+        deposit_to_pool(&mut contract, bob(), 2_000);
+        deposit_to_pool(&mut contract, carol(), 3_000);
+
+        let first = contract.list_stability_depositors(Some(collateral_token()), None, Some(2));
+        let rest = contract.list_stability_depositors(Some(collateral_token()), Some(2), Some(2));
+        assert_eq!(first, vec![(alice(), U128(1_000)), (bob(), U128(2_000))]);
+        assert_eq!(rest, vec![(carol(), U128(3_000))]);
+        assert!(contract
+            .list_stability_depositors(None, None, None)
+            .is_empty());
+
+        set_caller(bob(), 1);
+        contract.withdraw_from_stability_pool(None, Some(collateral_token()));
+        let remaining: Vec<AccountId> = contract
+            .list_stability_depositors(Some(collateral_token()), None, None)
+            .into_iter()
+            .map(|(account_id, _)| account_id)
+            .collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(!remaining.contains(&bob()));
+    }
+
+    /// Drops `account_id` from the depositor index as if its deposit
+    /// predated it.
+    fn unindex_depositor(contract: &mut Contract, account_id: &AccountId) {
+        let pool_id = types::PoolId::Dedicated(collateral_token());
+        let mut depositors = contract.stability_depositor_set(&pool_id);
+        depositors.remove(account_id);
+        contract.stability_depositors.insert(&pool_id, &depositors);
+        let shares = contract
+            .stability_pool_deposits
+            .get(&types::DepositKey::new(account_id, &pool_id))
+            .unwrap()
+            .shares;
+        let unindexed = contract
+            .unindexed_stability_shares
+            .get(&pool_id)
+            .unwrap_or(0);
+        contract
+            .unindexed_stability_shares
+            .insert(&pool_id, &(unindexed + shares));
+    }
+
+    #[test]
+    fn depositor_index_tracks_deposits_it_does_not_list() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 4_000);
+        open_trove(&mut contract, carol(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, alice(), 1_000);
+        deposit_to_pool(&mut contract, bob(), 2_000);
+        deposit_to_pool(&mut contract, carol(), 3_000);
+        unindex_depositor(&mut contract, &bob());
+        unindex_depositor(&mut contract, &carol());
+        let unindexed = |contract: &Contract| {
+            contract
+                .get_unindexed_stability_shares(Some(collateral_token()))
+                .0
+        };
+        assert_eq!(unindexed(&contract), 5_000_000);
+        assert_eq!(
+            contract.list_stability_depositors(Some(collateral_token()), None, None),
+            vec![(alice(), U128(1_000))]
+        );
+
+        // Backfilling skips accounts without a deposit.
+        assert_eq!(
+            contract
+                .index_stability_depositors(Some(collateral_token()), vec![bob(), dave()])
+                .0,
+            3_000_000
+        );
+        assert_eq!(
+            contract
+                .list_stability_depositors(Some(collateral_token()), None, None)
+                .len(),
+            2
+        );
+
+        // Touching a missing deposit indexes it too.
+        set_caller(carol(), 1);
+        contract.withdraw_from_stability_pool(Some(U128(1_000)), Some(collateral_token()));
+        assert_eq!(unindexed(&contract), 0);
+        assert!(contract
+            .list_stability_depositors(Some(collateral_token()), None, None)
+            .contains(&(carol(), U128(2_000))));
+    }
+
+    fn buffered_config() -> CollateralConfig {
+        CollateralConfig {
+            borrow_safety_buffer_bps: 200,
+            ..collateral_config()
+        }
+    }
+
+    #[test]
+    fn borrow_above_safety_buffer_succeeds() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, buffered_config());
+        assert_eq!(
+            contract.get_min_borrow_ratio(collateral_token()),
+            Some(U128(1_500))
+        );
+        // 1_300 collateral at 200.00 against 1_000_000 debt is a 2600 bps ratio.
+        open_trove(&mut contract, alice(), 1_300, 1_000_000);
+        assert_eq!(
+            contract
+                .get_trove(alice(), collateral_token())
+                .unwrap()
+                .debt_amount
+                .0,
+            1_000_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient collateral")]
+    fn borrow_at_mcr_rejected_with_safety_buffer() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, buffered_config());
+        // Exactly 1300 bps, acceptable without the buffer.
+        open_trove(&mut contract, alice(), 1_300, 2_000_000);
+    }
+
+    #[test]
+    fn redeem_split_conserves_value() {
+        for decimals in [0u8, 2, 6, 8, 18] {
+            let factor = Contract::decimals_factor(decimals);
+            for price in [1u128, 3, 7, 199, 20_000, 123_456_789, 10u128.pow(20)] {
+                let feed = PriceFeedInternal {
+                    price,
+                    decimals,
+                    last_update_timestamp: 0,
+                };
+                for amount in [0u128, 1, 9, 1_000, 14_001, 987_654_321, 10u128.pow(18) + 7] {
+                    let (collateral_out, nusd_used, nusd_refund) =
+                        Contract::redeem_split(amount, &feed);
+                    assert_eq!(nusd_used + nusd_refund, amount);
+                    // Used nUSD is the collateral's value rounded up, never more.
+                    assert!(nusd_used * factor >= collateral_out * price);
+                    assert!(nusd_used == 0 || (nusd_used - 1) * factor < collateral_out * price);
+                    // The remainder cannot buy another unit of collateral.
+                    assert!((collateral_out + 1) * price > amount * factor);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn redeem_burns_only_the_value_of_collateral_out() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        let preview = contract
+            .preview_redeem(collateral_token(), U128(5_150))
+            .unwrap();
+        assert_eq!(preview.collateral_out.0, 25);
+        assert_eq!(preview.nusd_used.0, 5_000);
+        assert_eq!(preview.nusd_refund.0, 150);
+
+        set_caller(alice(), 1);
+        contract.redeem(collateral_token(), bob(), U128(5_150), None, None);
+        assert_eq!(contract.ft_balance_of(alice()).0, 5_000);
+        let bob_trove = contract.get_trove(bob(), collateral_token()).unwrap();
+        assert_eq!(bob_trove.debt_amount.0, 5_000);
+        assert_eq!(bob_trove.collateral_amount.0, 975);
+    }
+
+    #[test]
+    fn redemption_fee_goes_to_treasury() {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                redemption_fee_bps: 200,
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        // 10_000 nUSD buys 50 collateral at 200; 2% of it is the fee.
+        assert_eq!(
+            contract.quote_redemption(collateral_token(), U128(10_000)),
+            Some((U128(49), U128(1)))
+        );
+
+        set_caller(alice(), 1);
+        contract.redeem(collateral_token(), bob(), U128(10_000), None, None);
+        let bob_trove = contract.get_trove(bob(), collateral_token()).unwrap();
+        assert_eq!(bob_trove.collateral_amount.0, 950);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            49
+        );
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(owner(), collateral_token())
+                .0,
+            1
+        );
+    }
+
+    #[test]
+    fn redemption_breakeven_follows_fee() {
+        let mut contract = setup_contract();
+        let with_fee = |redemption_fee_bps: u16| CollateralConfig {
+            redemption_fee_bps,
+            ..collateral_config()
+        };
+        assert_eq!(
+            contract.get_redemption_breakeven(collateral_token()),
+            Some(U128(100_000_000))
+        );
+        update_config(&mut contract, with_fee(50));
+        assert_eq!(
+            contract.get_redemption_breakeven(collateral_token()),
+            Some(U128(99_500_000))
+        );
+        update_config(&mut contract, with_fee(200));
+        assert_eq!(
+            contract.get_redemption_breakeven(collateral_token()),
+            Some(U128(98_000_000))
+        );
+        assert_eq!(contract.get_redemption_breakeven(dave()), None);
+    }
+
+    #[test]
+    fn ownership_moves_only_once_accepted() {
+        let mut contract = setup_contract();
+        set_caller(owner(), 1);
+        contract.propose_new_owner(alice());
+        assert_eq!(contract.get_pending_owner(), Some(alice()));
+        assert_eq!(contract.owner_id(), owner());
+
+        contract.cancel_ownership_transfer();
+        assert_eq!(contract.get_pending_owner(), None);
+        contract.propose_new_owner(bob());
+        set_caller(bob(), 1);
+        contract.accept_ownership();
+        assert_eq!(contract.owner_id(), bob());
+        assert_eq!(contract.get_pending_owner(), None);
+        contract.set_referrer_bps(100);
+        assert_eq!(contract.referrer_bps(), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the pending owner can accept")]
+    fn accept_ownership_rejects_other_accounts() {
+        let mut contract = setup_contract();
+        set_caller(owner(), 1);
+        contract.propose_new_owner(alice());
+        set_caller(bob(), 1);
+        contract.accept_ownership();
+    }
+
+    /// Writes state the way the first deployed layout did: alice's trove,
+    /// bob's deposit in the live epoch and carol's from a drained one, with
+    /// 2 collateral of reward per legacy share accrued.
+    fn write_baseline_state() {
+        use crate::migration::{
+            OldCollateralConfigInternal, OldContract, OldStabilityDeposit, OldTroveInternal,
+        };
+        let current_id: AccountId = "cdp.testnet".parse().unwrap();
+        set_caller(current_id.clone(), 0);
+        let mut nusd = FungibleToken::new(StorageKey::FungibleToken);
+        for account_id in [&current_id, &owner(), &alice(), &bob()] {
+            nusd.internal_register_account(account_id);
+        }
+        nusd.internal_deposit(&alice(), 4_000);
+        nusd.internal_deposit(&current_id, 3_000);
+        let mut metadata = LazyOption::new(StorageKey::TokenMetadata, Some(metadata()));
+        metadata.flush();
+        let mut old = OldContract {
+            owner_id: owner(),
+            intent_router_id: intents(),
+            pyth_oracle_id: oracle(),
+            configs: UnorderedMap::new(StorageKey::CollateralConfigs),
+            troves: LookupMap::new(StorageKey::Troves),
+            total_debt: LookupMap::new(StorageKey::TotalDebt),
+            price_feeds: LookupMap::new(StorageKey::PriceFeeds),
+            stability_pool_deposits: LookupMap::new(StorageKey::StabilityPoolDeposits),
+            collateral_rewards: LookupMap::new(StorageKey::CollateralRewards),
+            reward_per_share: UnorderedMap::new(StorageKey::RewardPerShare),
+            stability_pool_total_shares: 3_000,
+            stability_pool_total_nusd: 3_000,
+            stability_pool_epoch: 1,
+            nusd,
+            metadata,
+        };
+        old.configs.insert(
+            &collateral_token(),
+            &OldCollateralConfigInternal {
+                oracle_price_id: "usdc".to_string(),
+                min_collateral_ratio_bps: 1300,
+                recovery_collateral_ratio_bps: 1500,
+                debt_ceiling: 1_000_000_000_000,
+                liquidation_penalty_bps: 50,
+                stability_pool_mode: StabilityPoolMode::Dedicated,
+            },
+        );
+        old.troves.insert(
+            &TroveKey {
+                owner_id: alice(),
+                collateral_id: collateral_token(),
+            },
+            &OldTroveInternal {
+                owner_id: alice(),
+                collateral_id: collateral_token(),
+                collateral_amount: 10_000,
+                debt_amount: 4_000,
+                last_update_timestamp: 0,
+            },
+        );
+        old.total_debt.insert(&collateral_token(), &4_000);
+        old.price_feeds.insert(
+            &collateral_token(),
+            &PriceFeedInternal {
+                price: 20000,
+                decimals: 2,
+                last_update_timestamp: 0,
+            },
+        );
+        old.reward_per_share
+            .insert(&collateral_token(), &(2 * types::REWARD_SCALE));
+        old.stability_pool_deposits.insert(
+            &bob(),
+            &OldStabilityDeposit {
+                shares: 3_000,
+                reward_debt: BTreeMap::from([(collateral_token(), types::REWARD_SCALE)]),
+                epoch: 1,
+            },
+        );
+        old.stability_pool_deposits.insert(
+            &carol(),
+            &OldStabilityDeposit {
+                shares: 500,
+                reward_debt: BTreeMap::new(),
+                epoch: 0,
+            },
+        );
+        env::state_write(&old);
+    }
+
+    #[test]
+    fn migrate_converts_the_baseline_layout() {
+        write_baseline_state();
+        let mut contract = Contract::migrate();
+        let version = contract.get_version();
+        assert_eq!(version.version, crate::types::CONTRACT_VERSION);
+        assert!(version.features.contains(&"pause".to_string()));
+        assert!(contract.is_paused());
+        let config = contract.get_collateral_config(collateral_token()).unwrap();
+        assert_eq!(config.min_collateral_ratio_bps, 1300);
+        assert!(config.stability_pool_mode == StabilityPoolMode::Shared);
+        assert_eq!(contract.get_stability_pool_balance(None).0, 3_000);
+        assert_eq!(contract.ft_balance_of(alice()).0, 4_000);
+
+        set_caller(owner(), 1);
+        let troves = vec![(alice(), collateral_token()), (bob(), collateral_token())];
+        assert_eq!(contract.migrate_legacy_troves(troves.clone()), 1);
+        assert_eq!(contract.migrate_legacy_troves(troves), 0);
+        assert_eq!(trove_amounts(&contract, alice()), (10_000, 4_000));
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 10_000);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 4_000);
+
+        assert_eq!(contract.get_unindexed_stability_shares(None).0, 3_000_000);
+        assert_eq!(contract.migrate_legacy_deposits(vec![bob(), carol()]).0, 0);
+        assert_eq!(contract.get_stability_pool_deposit(bob(), None).0, 3_000);
+        let claimable = |contract: &Contract, account: AccountId| {
+            contract
+                .get_claimable_collateral_reward(account, collateral_token())
+                .0
+        };
+        assert_eq!(claimable(&contract, carol()), 1_000);
+
+        set_caller(bob(), 1);
+        contract.withdraw_from_stability_pool(None, None);
+        assert_eq!(contract.ft_balance_of(bob()).0, 3_000);
+        assert_eq!(claimable(&contract, bob()), 3_000);
+    }
+
+    #[test]
+    fn get_config_reflects_setters() {
+        let mut contract = setup_contract();
+        let router: AccountId = "fallback.testnet".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.set_reliquidation_cooldown_ms(60_000);
+        contract.set_referrer_bps(100);
+        contract.set_rebalance_fraction_bps(1_000);
+        contract.set_interest_to_equity_bps(2_500);
+        contract.set_redemption_self_policy(types::RedemptionSelfPolicy::Skip);
+        contract.set_max_borrow_per_tx(Some(U128(5_000)));
+        contract.set_fallback_router(Some(router.clone()), true);
+
+        let config = contract.get_config();
+        assert_eq!(config.owner_id, owner());
+        assert_eq!(config.intent_router_id, intents());
+        assert_eq!(config.pyth_oracle_id, oracle());
+        assert_eq!(config.fallback_router_id, Some(router));
+        assert!(config.retry_swaps_via_fallback);
+        assert_eq!(config.reliquidation_cooldown_ms, 60_000);
+        assert_eq!(config.referrer_bps, 100);
+        assert_eq!(config.rebalance_fraction_bps, 1_000);
+        assert_eq!(config.interest_to_equity_bps, 2_500);
+        assert!(config.redemption_self_policy == types::RedemptionSelfPolicy::Skip);
+        assert_eq!(config.max_borrow_per_tx, Some(U128(5_000)));
+    }
+
+    fn redemption_age_config() -> CollateralConfig {
+        CollateralConfig {
+            redemption_min_trove_age_ms: 60_000,
+            ..collateral_config()
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Trove too young to redeem")]
+    fn redeem_rejects_trove_younger_than_min_age() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, redemption_age_config());
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        set_caller_at(alice(), 59_999);
+        contract.redeem(collateral_token(), bob(), U128(1_000), None, None);
+    }
+
+    #[test]
+    fn redemption_allowed_once_trove_reaches_min_age() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, redemption_age_config());
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+
+        set_caller_at(alice(), 60_000);
+        contract.redeem(collateral_token(), bob(), U128(1_000), None, None);
+        assert_eq!(
+            contract
+                .get_trove(bob(), collateral_token())
+                .unwrap()
+                .debt_amount
+                .0,
+            9_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to redeem")]
+    fn redeem_multi_skips_troves_younger_than_min_age() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, redemption_age_config());
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        set_caller_at(alice(), 1_000);
+        contract.redeem_multi(collateral_token(), U128(1_000), None);
+    }
+
+    #[test]
+    fn redeem_multi_reports_skipped_troves() {
+        let mut contract = setup_contract();
+        update_config(&mut contract, redemption_age_config());
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 100, 10_000);
+        contract.nusd.internal_register_account(&carol());
+        set_caller_at(collateral_token(), 60_000);
+        contract.ft_on_transfer(
+            carol(),
+            U128(150),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        set_caller_at(carol(), 60_000);
+        contract.borrow(collateral_token(), U128(10_000), None);
+        contract.ft_transfer(alice(), U128(5_000), None);
+
+        // Carol's trove sits between bob's and alice's but is too young.
+        set_caller_at(alice(), 60_000);
+        let report = contract.redeem_multi(collateral_token(), U128(15_000), None);
+        assert_eq!(report.redeemed.0, 15_000);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].owner_id, carol());
+        assert_eq!(
+            report.skipped[0].reason,
+            types::RedemptionSkipReason::TooYoung
+        );
+        assert_eq!(trove_amounts(&contract, bob()).1, 0);
+        assert_eq!(trove_amounts(&contract, carol()).1, 10_000);
+        assert_eq!(trove_amounts(&contract, alice()).1, 5_000);
+    }
+
+    fn adjust_via_transfer(
+        contract: &mut Contract,
+        owner: AccountId,
+        collateral: u128,
+        debt: i128,
+    ) {
+        set_caller(collateral_token(), 0);
+        contract.ft_on_transfer(
+            owner,
+            U128(collateral),
+            format!(r#"{{"action":"adjust_trove","debt_delta":"{debt}"}}"#),
+        );
+    }
+
+    #[test]
+    fn collateral_only_adjustment_ignores_a_breached_ceiling() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                debt_ceiling: U128(5_000),
+                ..collateral_config()
+            },
+        );
+
+        adjust_via_transfer(&mut contract, alice(), 100, 0);
+        assert_eq!(trove_amounts(&contract, alice()), (1_100, 10_000));
+    }
+
+    fn trove_amounts(contract: &Contract, owner: AccountId) -> (u128, u128) {
+        let trove = contract.get_trove(owner, collateral_token()).unwrap();
+        (trove.collateral_amount.0, trove.debt_amount.0)
     }
 
-    #[payable]
-    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
-        self.nusd.storage_withdraw(amount)
+    #[test]
+    fn reduce_position_halves_debt_and_collateral() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_001, 10_001);
+        set_caller(alice(), 1);
+        let _ = contract.reduce_position(collateral_token(), 5_000, None);
+
+        let (collateral, debt) = trove_amounts(&contract, alice());
+        assert_eq!(collateral, 501);
+        assert_eq!(debt, 5_000);
+        assert_eq!(contract.nusd.ft_balance_of(alice()).0, 5_000);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 5_000);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 501);
     }
 
-    #[payable]
-    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
-        self.nusd.storage_unregister(force)
+    #[test]
+    fn reduce_position_in_full_removes_the_trove() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 2);
+        set_caller(alice(), 1);
+        let _ = contract.reduce_position(collateral_token(), 10_000, None);
+
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
+        assert_eq!(contract.get_active_trove_count(collateral_token()), 1);
+        assert_eq!(contract.nusd.ft_balance_of(alice()).0, 0);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 10_000);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 1_000);
     }
 
-    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
-        self.nusd.storage_balance_bounds()
+    #[test]
+    fn adjust_trove_adds_collateral_and_borrows() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        adjust_via_transfer(&mut contract, alice(), 500, 5_000);
+        assert_eq!(trove_amounts(&contract, alice()), (1_500, 15_000));
+        assert_eq!(contract.ft_balance_of(alice()).0, 15_000);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 15_000);
     }
 
-    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
-        self.nusd.storage_balance_of(account_id)
+    #[test]
+    fn adjust_trove_adds_collateral_and_repays() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        adjust_via_transfer(&mut contract, alice(), 500, -5_000);
+        assert_eq!(trove_amounts(&contract, alice()), (1_500, 5_000));
+        assert_eq!(contract.ft_balance_of(alice()).0, 5_000);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 5_000);
     }
-}
 
-#[near_bindgen]
-impl FungibleTokenMetadataProvider for Contract {
-    fn ft_metadata(&self) -> FungibleTokenMetadata {
-        self.metadata
-            .get()
-            .clone()
-            .unwrap_or(FungibleTokenMetadata {
-                spec: FT_METADATA_SPEC.to_string(),
-                name: "nUSD".to_string(),
-                symbol: "nUSD".to_string(),
-                icon: None,
-                reference: None,
-                reference_hash: None,
-                decimals: 24,
-            })
+    #[test]
+    fn adjust_trove_withdraws_collateral_and_borrows() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        set_caller(alice(), 1);
+        contract.adjust_trove(collateral_token(), I128(-500), I128(5_000), None);
+        assert_eq!(trove_amounts(&contract, alice()), (500, 15_000));
+        assert_eq!(contract.ft_balance_of(alice()).0, 15_000);
+        assert_eq!(contract.get_total_collateral(collateral_token()).0, 500);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::types::StabilityPoolMode;
-    use near_sdk::test_utils::VMContextBuilder;
-    use near_sdk::{testing_env, NearToken};
+    #[test]
+    fn adjust_trove_withdraws_collateral_and_repays() {
+        let mut contract = setup_contract();
+        // Bob's debt-free trove keeps the system out of recovery mode.
+        open_trove(&mut contract, bob(), 1_000, 0);
+        // 100 collateral at 200.00 against 150_000 debt is a 1333 bps ratio.
+        open_trove(&mut contract, alice(), 100, 150_000);
+        set_caller(alice(), 1);
+        // Withdrawing 10 alone would drop the ratio to 1200 bps; the net
+        // result after repaying is 1800 bps.
+        contract.adjust_trove(collateral_token(), I128(-10), I128(-50_000), None);
+        assert_eq!(trove_amounts(&contract, alice()), (90, 100_000));
+        assert_eq!(contract.ft_balance_of(alice()).0, 100_000);
+        assert_eq!(contract.get_total_debt(collateral_token()).0, 100_000);
+    }
 
-    fn metadata() -> FungibleTokenMetadata {
-        FungibleTokenMetadata {
-            spec: FT_METADATA_SPEC.to_string(),
-            name: "nUSD".to_string(),
-            symbol: "nUSD".to_string(),
-            icon: None,
-            reference: None,
-            reference_hash: None,
-            decimals: 24,
-        }
+    #[test]
+    #[should_panic(expected = "Insufficient collateral")]
+    fn adjust_trove_rejects_result_below_mcr() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        set_caller(alice(), 1);
+        contract.adjust_trove(collateral_token(), I128(-999), I128(2_000), None);
     }
 
-    fn alice() -> AccountId {
-        "alice.testnet".parse().unwrap()
+    fn dust_reward_contract() -> Contract {
+        let mut contract = setup_contract();
+        update_config(
+            &mut contract,
+            CollateralConfig {
+                min_claimable: U128(10),
+                ..collateral_config()
+            },
+        );
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        // 400 nUSD at 200.00 leaves alice a 2 unit collateral reward.
+        set_caller(alice(), 1);
+        contract.redeem(collateral_token(), bob(), U128(400), None, None);
+        contract
     }
 
-    fn owner() -> AccountId {
-        "owner.testnet".parse().unwrap()
+    #[test]
+    #[should_panic(expected = "Claim below minimum claimable amount")]
+    fn claim_below_min_claimable_fails() {
+        let mut contract = dust_reward_contract();
+        assert_eq!(
+            contract.get_min_claimable(collateral_token()),
+            Some(U128(10))
+        );
+        set_caller(alice(), 1);
+        contract.claim_collateral_reward(collateral_token(), None);
     }
 
-    fn intents() -> AccountId {
-        "intents.near".parse().unwrap()
+    #[test]
+    fn claim_all_rewards_skips_dust() {
+        let mut contract = dust_reward_contract();
+        set_caller(alice(), 1);
+        assert!(contract.claim_all_rewards().is_empty());
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            2
+        );
+
+        set_caller(alice(), 1);
+        contract.redeem(collateral_token(), bob(), U128(2_000), None, None);
+        set_caller(alice(), 1);
+        assert_eq!(
+            contract.claim_all_rewards(),
+            vec![(collateral_token(), U128(12))]
+        );
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            0
+        );
     }
 
-    fn oracle() -> AccountId {
-        "pyth.near".parse().unwrap()
+    #[test]
+    fn claim_rewards_sends_only_listed_collaterals() {
+        let mut contract = setup_contract();
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        let wnear: AccountId = "wnear.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(wbtc.clone(), collateral_config());
+        contract.register_collateral(wnear.clone(), collateral_config());
+        contract.enqueue_collateral_reward(&alice(), &collateral_token(), 100);
+        contract.enqueue_collateral_reward(&alice(), &wbtc, 200);
+
+        set_caller(alice(), 1);
+        let claimed = contract.claim_rewards(vec![wbtc.clone(), wnear]);
+        assert_eq!(claimed, vec![(wbtc.clone(), U128(200))]);
+        let receivers: Vec<AccountId> = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .map(|receipt| receipt.receiver_id)
+            .collect();
+        assert_eq!(receivers, vec![wbtc.clone()]);
+        assert_eq!(
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0,
+            100
+        );
+        assert_eq!(contract.get_claimable_collateral_reward(alice(), wbtc).0, 0);
     }
 
-    fn collateral_token() -> AccountId {
-        "usdc.fakes".parse().unwrap()
+    #[test]
+    fn pool_exposure_sums_liquidatable_debt_of_shared_collaterals() {
+        let mut contract = setup_contract();
+        let shared = CollateralConfig {
+            stability_pool_mode: StabilityPoolMode::Shared,
+            ..collateral_config()
+        };
+        update_config(&mut contract, shared.clone());
+        let wbtc: AccountId = "wbtc.fakes".parse().unwrap();
+        set_caller(owner(), 1);
+        contract.register_collateral(wbtc.clone(), shared);
+        set_caller(oracle(), 0);
+        contract.submit_price(wbtc.clone(), U128(500), 0);
+
+        open_trove(&mut contract, alice(), 1_000, 100_000);
+        open_trove(&mut contract, bob(), 1_000_000, 10_000);
+        contract.nusd.internal_register_account(&carol());
+        set_caller(wbtc.clone(), 0);
+        contract.ft_on_transfer(
+            carol(),
+            U128(600),
+            r#"{"action":"deposit_collateral"}"#.to_string(),
+        );
+        set_caller(carol(), 1);
+        contract.borrow(wbtc.clone(), U128(150_000), None);
+        assert!(contract.get_pool_collateral_exposure().is_empty());
+
+        set_price(&mut contract, 100, 2);
+        set_caller(oracle(), 0);
+        contract.submit_price(wbtc.clone(), U128(20), 0);
+        // bob's trove stays healthy, so only alice's debt counts for usdc.
+        assert_eq!(
+            contract.get_pool_collateral_exposure(),
+            vec![(collateral_token(), U128(100_000)), (wbtc, U128(150_000))]
+        );
     }
 
-    fn setup_contract() -> Contract {
+    #[test]
+    fn max_leverage_matches_closed_form() {
+        let mut contract = setup_contract();
+        let with_mcr = |mcr: u16| CollateralConfig {
+            min_collateral_ratio_bps: mcr,
+            recovery_collateral_ratio_bps: mcr,
+            ..collateral_config()
+        };
+        // 150% MCR: 1.5 / 0.5 = 3x.
+        update_config(&mut contract, with_mcr(15_000));
+        assert_eq!(contract.get_max_leverage(collateral_token()), Some(30_000));
+        // 110% MCR: 1.1 / 0.1 = 11x.
+        update_config(&mut contract, with_mcr(11_000));
+        assert_eq!(contract.get_max_leverage(collateral_token()), Some(110_000));
+        // 101% MCR would be 101x, over the cap.
+        update_config(&mut contract, with_mcr(10_100));
+        assert_eq!(
+            contract.get_max_leverage(collateral_token()),
+            Some(types::MAX_LEVERAGE_BPS)
+        );
+        assert_eq!(contract.get_max_leverage(dave()), None);
+    }
+
+    fn set_pull_result(result: PromiseResult) {
+        set_pull_result_at(result, 0);
+    }
+
+    fn set_pull_result_at(result: PromiseResult, timestamp_ms: u64) {
         let mut context = VMContextBuilder::new();
         context
             .current_account_id("cdp.testnet".parse().unwrap())
-            .signer_account_id(owner())
-            .predecessor_account_id(owner());
-        testing_env!(context.clone().build());
-        let mut contract = Contract::new(owner(), intents(), oracle(), metadata());
-
-        testing_env!(context
-            .predecessor_account_id(owner())
-            .attached_deposit(NearToken::from_yoctonear(1))
-            .build());
-        contract.register_collateral(
-            collateral_token(),
-            CollateralConfig {
-                oracle_price_id: "usdc".to_string(),
-                min_collateral_ratio_bps: 1300,
-                recovery_collateral_ratio_bps: 1500,
-                debt_ceiling: U128(1_000_000_000_000),
-                liquidation_penalty_bps: 50,
-                stability_pool_mode: StabilityPoolMode::Dedicated,
-            },
+            .predecessor_account_id("cdp.testnet".parse().unwrap())
+            .block_timestamp(timestamp_ms * 1_000_000);
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
         );
+    }
 
-        testing_env!(context
-            .predecessor_account_id(oracle())
-            .attached_deposit(NearToken::from_yoctonear(0))
-            .build());
-        contract.submit_price(collateral_token(), U128(20000), 2);
+    #[test]
+    fn treasury_convert_restores_collateral_the_router_returns() {
+        let mut contract = setup_contract();
+        contract.enqueue_collateral_reward(&owner(), &collateral_token(), 100);
+        set_caller(owner(), 1);
+        contract.treasury_convert(collateral_token(), U128(60), U128(50), None);
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, collateral_token());
+        let treasury_reward = |contract: &Contract| {
+            contract
+                .get_claimable_collateral_reward(owner(), collateral_token())
+                .0
+        };
+        assert_eq!(treasury_reward(&contract), 40);
 
-        contract
+        let conversion = types::TreasuryConversion {
+            collateral_id: collateral_token(),
+            amount: U128(60),
+            min_out: U128(50),
+            routing_hint: None,
+            treasury_id: owner(),
+        };
+        // The router swapped 45 and refunded 15.
+        set_pull_result(PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&U128(45)).unwrap(),
+        ));
+        contract.on_treasury_convert(conversion.clone(), false);
+        assert_eq!(treasury_reward(&contract), 55);
+
+        set_pull_result(PromiseResult::Failed);
+        contract.on_treasury_convert(conversion, false);
+        assert_eq!(treasury_reward(&contract), 115);
     }
 
     #[test]
-    fn borrow_and_repay_flow() {
+    fn sync_rebase_flags_diverged_trove() {
         let mut contract = setup_contract();
-        let mut context = VMContextBuilder::new();
-        context
-            .current_account_id("cdp.testnet".parse().unwrap())
-            .signer_account_id(alice())
-            .predecessor_account_id(alice());
-        let storage_deposit = contract.storage_balance_bounds().min;
-        testing_env!(context.clone().attached_deposit(storage_deposit).build());
-        contract.storage_deposit(Some(alice()), None);
+        let mut config = collateral_config();
+        config.is_rebasing = true;
+        update_config(&mut contract, config);
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
 
-        testing_env!(context
-            .predecessor_account_id(collateral_token())
-            .signer_account_id(collateral_token())
-            .attached_deposit(NearToken::from_yoctonear(0))
-            .build());
-        contract.ft_on_transfer(
-            alice(),
-            U128(10_000),
-            r#"{"action":"deposit_collateral"}"#.to_string(),
+        set_pull_result(PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&U128(1_800)).unwrap(),
+        ));
+        assert!(contract.on_rebase_balance(collateral_token(), alice()));
+        let divergence = contract
+            .get_rebase_divergence(alice(), collateral_token())
+            .unwrap();
+        assert_eq!(divergence.recorded.0, 1_000);
+        assert_eq!(divergence.actual.0, 900);
+        assert_eq!(trove_amounts(&contract, alice()).0, 1_000);
+
+        set_pull_result(PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&U128(2_000)).unwrap(),
+        ));
+        assert!(!contract.on_rebase_balance(collateral_token(), alice()));
+        assert!(contract
+            .get_rebase_divergence(alice(), collateral_token())
+            .is_none());
+    }
+
+    #[test]
+    fn sync_rebase_ignores_collateral_owed_as_rewards() {
+        let mut contract = setup_contract();
+        let mut config = collateral_config();
+        config.is_rebasing = true;
+        update_config(&mut contract, config);
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 4_000);
+        set_price(&mut contract, 2, 2);
+        set_caller(dave(), 1);
+        contract.liquidate(collateral_token(), vec![alice()], None, None, None);
+        assert_eq!(
+            contract.get_total_collateral(collateral_token()).0,
+            1_000_000
         );
 
-        testing_env!(context
-            .predecessor_account_id(alice())
-            .signer_account_id(alice())
-            .attached_deposit(NearToken::from_yoctonear(1))
-            .build());
-        contract.borrow(collateral_token(), U128(4_000));
-        assert_eq!(contract.ft_balance_of(alice()).0, 4_000);
+        // Nothing rebased: the contract still holds every deposited token,
+        // the liquidated collateral now owed to the pool and the treasury.
+        set_pull_result(PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&U128(1_010_000)).unwrap(),
+        ));
+        assert!(!contract.on_rebase_balance(collateral_token(), bob()));
+        assert!(contract
+            .get_rebase_divergence(bob(), collateral_token())
+            .is_none());
+    }
 
-        testing_env!(context
-            .predecessor_account_id(alice())
-            .signer_account_id(alice())
-            .attached_deposit(NearToken::from_yoctonear(1))
-            .build());
-        contract.repay(collateral_token(), U128(1_000));
-        assert_eq!(contract.ft_balance_of(alice()).0, 3_000);
-        let trove = contract
-            .get_trove(alice(), collateral_token())
-            .expect("trove missing");
-        assert_eq!(trove.debt_amount.0, 3_000);
+    #[test]
+    #[should_panic(expected = "Collateral is not rebasing")]
+    fn sync_rebase_requires_rebasing_collateral() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        contract.sync_rebase(collateral_token(), alice());
+    }
 
-        testing_env!(context
-            .predecessor_account_id(alice())
-            .signer_account_id(alice())
-            .attached_deposit(NearToken::from_yoctonear(1))
-            .build());
-        let _ = contract.withdraw_collateral(collateral_token(), U128(1_000), None);
+    #[test]
+    fn refreshed_liquidation_uses_pulled_price() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        open_trove(&mut contract, bob(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 4_000);
+
+        let pulled = r#"{"price":"2","conf":"0","expo":-2,"publish_time":0}"#;
+        set_pull_result(PromiseResult::Successful(pulled.as_bytes().to_vec()));
+        let processed = contract.on_price_refreshed_liquidate(
+            bob(),
+            collateral_token(),
+            vec![alice()],
+            None,
+            None,
+        );
+        assert_eq!(processed, U64(1));
+        let price = contract.get_price(collateral_token()).unwrap();
+        assert_eq!((price.price, price.decimals), (U128(2), 2));
+        assert!(contract.get_trove(alice(), collateral_token()).is_none());
     }
 
     #[test]
-    fn new_deposit_snapshot_prevents_reward_sniping() {
+    #[should_panic(expected = "Pulled price too old")]
+    fn refreshed_liquidation_rejects_a_stale_pulled_price() {
         let mut contract = setup_contract();
-        let collateral = collateral_token();
-        let alice = alice();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        let pulled = r#"{"price":"2","conf":"0","expo":-2,"publish_time":0}"#;
+        set_pull_result_at(
+            PromiseResult::Successful(pulled.as_bytes().to_vec()),
+            types::MAX_SIGNED_PRICE_AGE_MS + 1_000,
+        );
+        contract.on_price_refreshed_liquidate(bob(), collateral_token(), vec![alice()], None, None);
+    }
 
-        contract
-            .reward_per_share
-            .insert(&collateral, &types::REWARD_SCALE);
-        contract.stability_pool_total_shares = 1_000;
-        contract.stability_pool_total_nusd = 1_000;
+    #[test]
+    #[should_panic(expected = "Pulled price is older than the feed")]
+    fn refreshed_liquidation_keeps_a_newer_stored_price() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        set_caller_at(oracle(), 100_000);
+        contract.submit_price(collateral_token(), U128(20_000), 2);
 
-        let mut deposit = types::StabilityDeposit::new(contract.stability_pool_epoch);
-        deposit.shares = 1_000;
-        contract.sync_reward_debt_snapshot(&mut deposit);
-        contract.stability_pool_deposits.insert(&alice, &deposit);
+        let pulled = r#"{"price":"2","conf":"0","expo":-2,"publish_time":90}"#;
+        set_pull_result_at(
+            PromiseResult::Successful(pulled.as_bytes().to_vec()),
+            100_000,
+        );
+        contract.on_price_refreshed_liquidate(bob(), collateral_token(), vec![alice()], None, None);
+    }
 
-        contract.settle_stability_rewards(&alice);
+    #[test]
+    #[should_panic(expected = "Price refresh failed")]
+    fn refreshed_liquidation_aborts_when_pull_fails() {
+        let mut contract = setup_contract();
+        open_trove(&mut contract, alice(), 10_000, 4_000);
+        set_pull_result(PromiseResult::Failed);
+        contract.on_price_refreshed_liquidate(bob(), collateral_token(), vec![alice()], None, None);
+    }
 
-        let reward_after = contract
-            .collateral_rewards
-            .get(&types::CollateralRewardKey::new(&alice, &collateral))
-            .unwrap_or(0);
-        assert_eq!(
-            reward_after, 0,
-            "new deposit should not inherit historical rewards"
+    fn transfers_to(receiver: &AccountId) -> Vec<NearToken> {
+        near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .filter(|receipt| &receipt.receiver_id == receiver)
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                near_sdk::mock::MockAction::Transfer { deposit, .. } => Some(deposit),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn manual_swap_refunds_unused_deposit() {
+        let mut contract = setup_contract();
+        let outcome = r#"{"amount_out":"10","deposit_used":"250"}"#;
+        set_pull_result(PromiseResult::Successful(outcome.as_bytes().to_vec()));
+        contract.on_swap_complete(
+            owner(),
+            collateral_token(),
+            U128(10),
+            U128(1_000),
+            SwapPurpose::Manual,
+            None,
         );
+        assert_eq!(transfers_to(&owner()), vec![NearToken::from_yoctonear(750)]);
+
+        // A router that does not report usage keeps the whole deposit.
+        set_pull_result(PromiseResult::Successful(
+            br#"{"amount_out":"10"}"#.to_vec(),
+        ));
+        contract.on_swap_complete(
+            owner(),
+            collateral_token(),
+            U128(10),
+            U128(1_000),
+            SwapPurpose::Manual,
+            None,
+        );
+        assert!(transfers_to(&owner()).is_empty());
     }
 
     #[test]
-    fn accrue_without_deposit_rewards_owner() {
+    fn converted_redemption_falls_back_to_raw_collateral() {
         let mut contract = setup_contract();
-        let collateral = collateral_token();
+        open_trove(&mut contract, alice(), 1_000, 10_000);
+        open_trove(&mut contract, bob(), 1_000, 10_000);
+        let usdt: AccountId = "usdt.fakes".parse().unwrap();
+        set_caller(alice(), 1_000);
+        contract.redeem(
+            collateral_token(),
+            bob(),
+            U128(10_000),
+            None,
+            Some(RedeemConversion {
+                output_token: usdt,
+                min_out: U128(9_900),
+                routing_hint: None,
+            }),
+        );
+        let receivers: Vec<AccountId> = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .map(|receipt| receipt.receiver_id)
+            .collect();
+        assert!(receivers.contains(&intents()));
+        let claimable = |contract: &Contract| {
+            contract
+                .get_claimable_collateral_reward(alice(), collateral_token())
+                .0
+        };
+        assert_eq!(claimable(&contract), 0);
 
-        contract.accrue_reward_per_share(&collateral, 500);
+        set_pull_result(PromiseResult::Failed);
+        let swapped = contract.on_swap_complete(
+            alice(),
+            collateral_token(),
+            U128(50),
+            U128(1_000),
+            SwapPurpose::Redeem {
+                min_out: U128(9_900),
+            },
+            None,
+        );
+        assert!(matches!(swapped, PromiseOrValue::Value(false)));
+        assert_eq!(claimable(&contract), 50);
+    }
 
-        let owner_reward = contract
-            .collateral_rewards
-            .get(&types::CollateralRewardKey::new(
-                &contract.owner_id,
-                &collateral,
-            ))
-            .unwrap_or(0);
-        assert_eq!(owner_reward, 500, "owner should receive direct reward");
+    #[test]
+    fn estimate_reward_share_is_pro_rata() {
+        let mut contract = setup_contract();
+        let pool = Some(collateral_token());
+        assert_eq!(
+            contract.estimate_reward_share(U128(1_000), pool.clone()),
+            10_000
+        );
+        open_trove(&mut contract, bob(), 1_000_000, 4_000);
+        deposit_to_pool(&mut contract, bob(), 4_000);
+        assert_eq!(
+            contract.estimate_reward_share(U128(4_000), pool.clone()),
+            5_000
+        );
+        assert_eq!(contract.estimate_reward_share(U128(1_000), pool), 2_000);
     }
 }
@@ -0,0 +1,279 @@
+//! State written by the first deployed layout and its conversion.
+//!
+//! `migrate` converts the contract fields, every collateral config and the
+//! single stability pool in one call. Troves and stability deposits live in
+//! maps that cannot be enumerated on chain, so the owner converts them in
+//! batches afterwards with `migrate_legacy_troves` and
+//! `migrate_legacy_deposits`. The protocol comes out of `migrate` paused
+//! and stays so until the owner resumes it.
+
+use crate::types::{
+    CollateralConfigInternal, CollateralRewardKey, DepositKey, PoolId, PriceFeedInternal,
+    StabilityDeposit, StabilityPool, StabilityPoolMode, StorageKey, TokenId, TroveInternal,
+    TroveKey, MAX_DEPOSITORS_PER_BATCH, POOL_VIRTUAL_NUSD, POOL_VIRTUAL_SHARES,
+};
+use crate::{Contract, ContractExt};
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::{Balance, FungibleToken};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::U128;
+use near_sdk::store::LazyOption;
+use near_sdk::{assert_one_yocto, env, log, near, near_bindgen, require, AccountId};
+use std::collections::BTreeMap;
+
+/// Legacy shares are worth about one nUSD each; the current pool issues
+/// this many per nUSD, so converted shares keep their value next to the
+/// virtual ones.
+pub const LEGACY_SHARE_SCALE: u128 = POOL_VIRTUAL_SHARES / POOL_VIRTUAL_NUSD;
+
+#[near(serializers=[borsh])]
+pub struct OldContract {
+    pub owner_id: AccountId,
+    pub intent_router_id: AccountId,
+    pub pyth_oracle_id: AccountId,
+    pub configs: UnorderedMap<TokenId, OldCollateralConfigInternal>,
+    pub troves: LookupMap<TroveKey, OldTroveInternal>,
+    pub total_debt: LookupMap<TokenId, Balance>,
+    pub price_feeds: LookupMap<TokenId, PriceFeedInternal>,
+    pub stability_pool_deposits: LookupMap<AccountId, OldStabilityDeposit>,
+    pub collateral_rewards: LookupMap<CollateralRewardKey, Balance>,
+    pub reward_per_share: UnorderedMap<TokenId, u128>,
+    pub stability_pool_total_shares: Balance,
+    pub stability_pool_total_nusd: Balance,
+    pub stability_pool_epoch: u64,
+    pub nusd: FungibleToken,
+    pub metadata: LazyOption<FungibleTokenMetadata>,
+}
+
+#[near(serializers=[borsh])]
+pub struct OldCollateralConfigInternal {
+    pub oracle_price_id: String,
+    pub min_collateral_ratio_bps: u16,
+    pub recovery_collateral_ratio_bps: u16,
+    pub debt_ceiling: Balance,
+    pub liquidation_penalty_bps: u16,
+    pub stability_pool_mode: StabilityPoolMode,
+}
+
+#[near(serializers=[borsh])]
+pub struct OldTroveInternal {
+    pub owner_id: AccountId,
+    pub collateral_id: AccountId,
+    pub collateral_amount: Balance,
+    pub debt_amount: Balance,
+    pub last_update_timestamp: u64,
+}
+
+#[near(serializers=[borsh])]
+pub struct OldStabilityDeposit {
+    pub shares: Balance,
+    pub reward_debt: BTreeMap<AccountId, u128>,
+    pub epoch: u64,
+}
+
+impl OldCollateralConfigInternal {
+    /// Every setting added since defaults to what an omitted JSON field
+    /// gives `register_collateral`.
+    fn into_current(self, registered_at_ms: u64) -> CollateralConfigInternal {
+        CollateralConfigInternal {
+            oracle_price_id: self.oracle_price_id,
+            min_collateral_ratio_bps: self.min_collateral_ratio_bps,
+            recovery_collateral_ratio_bps: self.recovery_collateral_ratio_bps,
+            debt_ceiling: self.debt_ceiling,
+            liquidation_penalty_bps: self.liquidation_penalty_bps,
+            stability_pool_mode: self.stability_pool_mode,
+            max_active_troves: None,
+            expected_price_decimals: None,
+            min_reasonable_price: None,
+            max_price_age_ms: None,
+            interest_rate_bps: 0,
+            has_transfer_fee: false,
+            transfer_fee_bps: 0,
+            scaled_penalty: false,
+            max_penalty_bps: 0,
+            borrow_safety_buffer_bps: 0,
+            redemption_min_trove_age_ms: 0,
+            min_claimable: 0,
+            dust_trove_threshold: 0,
+            min_treasury_reserve_bps: 0,
+            borrow_fee_bps: 0,
+            dynamic_interest: false,
+            max_interest_rate_bps: 0,
+            interest_kink_bps: 0,
+            initial_debt_ceiling: None,
+            ceiling_ramp_duration_ms: 0,
+            is_rebasing: false,
+            liquidate_on_stale_price: false,
+            lock_withdrawals_when_liquidatable: false,
+            redemption_fee_bps: 0,
+            min_stability_pool_size: 0,
+            insurance_pool_id: None,
+            registered_at_ms,
+        }
+    }
+}
+
+/// Raw storage key of `key` in a legacy map created with `prefix`.
+fn legacy_key(prefix: StorageKey, key: &impl BorshSerialize) -> Vec<u8> {
+    let mut raw = borsh::to_vec(&prefix).expect("Storage key serialization");
+    raw.extend(borsh::to_vec(key).expect("Storage key serialization"));
+    raw
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Called by the contract itself right after deploying this code over
+    /// the first layout. The single stability pool becomes the shared pool,
+    /// which every collateral then uses.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let OldContract {
+            owner_id,
+            intent_router_id,
+            pyth_oracle_id,
+            mut configs,
+            mut reward_per_share,
+            stability_pool_total_shares,
+            stability_pool_total_nusd,
+            stability_pool_epoch,
+            nusd,
+            metadata,
+            ..
+        } = env::state_read().unwrap_or_else(|| env::panic_str("No state to migrate"));
+        let mut contract =
+            Self::initial_state(owner_id, intent_router_id, pyth_oracle_id, nusd, metadata);
+        contract.paused = true;
+        contract.liquidate_paused = true;
+        contract.redeem_paused = true;
+
+        let old_configs = configs.to_vec();
+        configs.clear();
+        for (token_id, config) in old_configs {
+            // The first layout had a single pool for every collateral;
+            // keep liquidations flowing to it.
+            let mut config = config.into_current(Self::now_ms());
+            config.stability_pool_mode = StabilityPoolMode::Shared;
+            contract.configs.insert(&token_id, &config);
+        }
+
+        // Converted shares are scaled up, so the accumulators scale down.
+        let pool = StabilityPool {
+            total_shares: stability_pool_total_shares
+                .checked_mul(LEGACY_SHARE_SCALE)
+                .expect("Pool share overflow"),
+            total_nusd: stability_pool_total_nusd,
+            epoch: stability_pool_epoch,
+            reward_per_share: reward_per_share
+                .iter()
+                .map(|(collateral_id, global)| (collateral_id, global / LEGACY_SHARE_SCALE))
+                .collect(),
+        };
+        reward_per_share.clear();
+        if pool.total_shares > 0 {
+            contract
+                .unindexed_stability_shares
+                .insert(&PoolId::Shared, &pool.total_shares);
+        }
+        contract.stability_pools.insert(&PoolId::Shared, &pool);
+        contract.stability_pool_total_nusd = stability_pool_total_nusd;
+        log!(
+            "Migrated {} collaterals and {} nUSD of stability deposits",
+            contract.configs.len(),
+            stability_pool_total_nusd
+        );
+        contract
+    }
+
+    /// Converts the listed `(owner_id, collateral_id)` troves from the first
+    /// layout and indexes them. Troves already converted or missing are
+    /// skipped; returns how many were converted.
+    #[payable]
+    pub fn migrate_legacy_troves(&mut self, troves: Vec<(AccountId, AccountId)>) -> u64 {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(troves.len() <= MAX_DEPOSITORS_PER_BATCH, "Too many troves");
+        let mut converted = 0;
+        for (owner_id, collateral_id) in troves {
+            let key = legacy_key(
+                StorageKey::Troves,
+                &Self::trove_key(&owner_id, &collateral_id),
+            );
+            let Some(old) =
+                env::storage_read(&key).and_then(|raw| OldTroveInternal::try_from_slice(&raw).ok())
+            else {
+                continue;
+            };
+            env::storage_remove(&key);
+            let trove = TroveInternal {
+                owner_id: old.owner_id,
+                collateral_id: old.collateral_id,
+                collateral_amount: old.collateral_amount,
+                debt_amount: old.debt_amount,
+                last_update_timestamp: old.last_update_timestamp,
+                last_liquidation_timestamp: 0,
+                opened_timestamp: old.last_update_timestamp,
+                interest_index: self.update_interest_index(&collateral_id),
+            };
+            self.add_trove_owner(&owner_id, &collateral_id);
+            self.save_trove(&owner_id, &collateral_id, &trove);
+            converted += 1;
+        }
+        log!("Migrated {} legacy troves", converted);
+        converted
+    }
+
+    /// Moves the listed accounts' deposits from the first layout into the
+    /// shared pool. A deposit from an earlier epoch is paid out against the
+    /// accumulators as they stood at `migrate`, as the old code would have.
+    /// Returns the shares still waiting for conversion.
+    #[payable]
+    pub fn migrate_legacy_deposits(&mut self, account_ids: Vec<AccountId>) -> U128 {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            account_ids.len() <= MAX_DEPOSITORS_PER_BATCH,
+            "Too many depositors"
+        );
+        let pool_id = PoolId::Shared;
+        let pool = self.pool(&pool_id);
+        for account_id in &account_ids {
+            let key = legacy_key(StorageKey::StabilityPoolDeposits, account_id);
+            let Some(raw) = env::storage_read(&key) else {
+                continue;
+            };
+            let old = OldStabilityDeposit::try_from_slice(&raw)
+                .unwrap_or_else(|_| env::panic_str("Unreadable legacy deposit"));
+            env::storage_remove(&key);
+            let mut deposit = StabilityDeposit::new(old.epoch);
+            deposit.shares = old
+                .shares
+                .checked_mul(LEGACY_SHARE_SCALE)
+                .expect("Deposit share overflow");
+            deposit.reward_debt = old
+                .reward_debt
+                .into_iter()
+                .map(|(collateral_id, paid)| (collateral_id, paid / LEGACY_SHARE_SCALE))
+                .collect();
+            if deposit.epoch != pool.epoch {
+                for (collateral_id, global) in &pool.reward_per_share {
+                    let pending = deposit.pending_reward(collateral_id, *global);
+                    self.enqueue_collateral_reward(account_id, collateral_id, pending);
+                }
+                continue;
+            }
+            if deposit.shares == 0 {
+                continue;
+            }
+            let deposit_key = DepositKey::new(account_id, &pool_id);
+            require!(
+                self.stability_pool_deposits.get(&deposit_key).is_none(),
+                "Deposit already converted"
+            );
+            self.stability_pool_deposits.insert(&deposit_key, &deposit);
+            self.index_stability_depositor(account_id, &pool_id, deposit.shares);
+        }
+        U128(self.unindexed_stability_shares.get(&pool_id).unwrap_or(0))
+    }
+}
@@ -0,0 +1,72 @@
+use crate::types::PoolId;
+use crate::{Contract, ContractExt};
+use near_contract_standards::fungible_token::Balance;
+use near_sdk::{env, near_bindgen, require};
+
+#[near_bindgen]
+impl Contract {
+    /// Panics when the core accounting invariants do not hold. Compiled only
+    /// with the `test-helpers` feature so integration tests can check state
+    /// after each step without shipping the scan in production wasm.
+    pub fn assert_invariants(&self) {
+        let mut system_debt: Balance = 0;
+        for collateral_id in self.configs.keys() {
+            let recorded = self.total_debt.get(&collateral_id).unwrap_or(0);
+            let mut trove_debt: Balance = 0;
+            let mut trove_collateral: Balance = 0;
+            for owner_id in self.trove_owner_set(&collateral_id).iter() {
+                let trove = self
+                    .troves
+                    .get(&Self::trove_key(&owner_id, &collateral_id))
+                    .unwrap_or_else(|| env::panic_str("Invariant: indexed trove missing"));
+                trove_debt = trove_debt
+                    .checked_add(trove.debt_amount)
+                    .expect("Invariant: trove debt overflow");
+                trove_collateral = trove_collateral
+                    .checked_add(trove.collateral_amount)
+                    .expect("Invariant: trove collateral overflow");
+            }
+            require!(
+                trove_debt == recorded,
+                "Invariant: total debt does not match trove debt"
+            );
+            require!(
+                trove_collateral == self.total_collateral.get(&collateral_id).unwrap_or(0),
+                "Invariant: total collateral does not match trove collateral"
+            );
+            system_debt = system_debt
+                .checked_add(recorded)
+                .and_then(|debt| debt.checked_add(self.bad_debt.get(&collateral_id).unwrap_or(0)))
+                .expect("Invariant: system debt overflow");
+        }
+        require!(
+            self.nusd.total_supply == system_debt,
+            "Invariant: nUSD supply does not match system debt"
+        );
+
+        let pool_balance = self
+            .nusd
+            .internal_unwrap_balance_of(&env::current_account_id());
+        require!(
+            pool_balance >= self.stability_pool_total_nusd + self.insurance_fund_balance,
+            "Invariant: stability pool is not backed by contract nUSD"
+        );
+        let mut pools_nusd: Balance = 0;
+        let pool_ids =
+            std::iter::once(PoolId::Shared).chain(self.configs.keys().map(PoolId::Dedicated));
+        for pool_id in pool_ids {
+            let pool = self.pool(&pool_id);
+            require!(
+                (pool.total_shares == 0) == (pool.total_nusd == 0),
+                "Invariant: stability pool shares and balance disagree"
+            );
+            pools_nusd = pools_nusd
+                .checked_add(pool.total_nusd)
+                .expect("Invariant: pool balance overflow");
+        }
+        require!(
+            pools_nusd == self.stability_pool_total_nusd,
+            "Invariant: stability pool balances do not add up"
+        );
+    }
+}
@@ -1,16 +1,64 @@
+use crate::audit::audit_mul;
 use near_contract_standards::fungible_token::Balance;
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::{U128, U64};
-use near_sdk::{near, AccountId, BorshStorageKey, Gas};
+use near_sdk::json_types::{I128, I64, U128, U64};
+use near_sdk::{near, AccountId, BorshStorageKey, Gas, PublicKey};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
+/// Bumped on every deployed upgrade; see `get_version`.
+pub const CONTRACT_VERSION: &str = "0.1.0";
+/// Capabilities of this build, as listed by `get_version`.
+pub const FEATURE_FLAGS: &[&str] = &[
+    "pause",
+    "interest_accrual",
+    "recovery_mode",
+    "borrow_fee",
+    "redemption_fee",
+    "redemption_conversion",
+    "dedicated_stability_pools",
+    "insurance_pools",
+    "event_levels",
+    "two_step_ownership",
+];
 pub const BPS_DENOMINATOR: u128 = 10_000;
 pub const GAS_FOR_SWAP: Gas = Gas::from_tgas(50);
 pub const GAS_FOR_CALLBACK: Gas = Gas::from_tgas(25);
+/// Enough for a swap callback to re-issue the swap and its own callback.
+pub const GAS_FOR_CALLBACK_WITH_RETRY: Gas = Gas::from_tgas(110);
+/// Enough for a refreshed-price redeem callback to dispatch its swap.
+pub const GAS_FOR_REDEEM_CONVERSION: Gas = Gas::from_tgas(190);
 pub const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
+/// Covers the router's `ft_on_transfer`, its payout and the token's resolve.
+pub const GAS_FOR_SWAP_TRANSFER_CALL: Gas = Gas::from_tgas(70);
+pub const GAS_FOR_PRICE_PULL: Gas = Gas::from_tgas(10);
+pub const GAS_FOR_BALANCE_QUERY: Gas = Gas::from_tgas(10);
 pub const REWARD_SCALE: u128 = 10u128.pow(24);
+/// $1 at the 8-decimal scale of `get_price_usd_1e8`.
+pub const USD_1E8: u128 = 100_000_000;
+pub const MAX_REFERRER_BPS: u16 = 500;
+pub const MAX_COLLATERALS_PER_BATCH: usize = 10;
+pub const MAX_COLLATERALS: u64 = 32;
+/// Cap on the troves one `redeem_multi` call visits.
+pub const MAX_REDEMPTION_ITERATIONS: u64 = 50;
+/// Cap on the depositors one index or resync call walks.
+pub const MAX_DEPOSITORS_PER_BATCH: usize = 50;
+/// Snapshots kept per collateral by `get_tcr_history`.
+pub const MAX_TCR_HISTORY: usize = 48;
+/// A new TCR is only snapshotted when it moved at least this far.
+pub const TCR_HISTORY_MIN_CHANGE_BPS: u128 = 10;
+/// Cap on `get_max_leverage`, 100x in bps.
+pub const MAX_LEVERAGE_BPS: u64 = 1_000_000;
+/// Unowned shares and nUSD added to every stability pool's share math.
+pub const POOL_VIRTUAL_SHARES: u128 = 1_000;
+pub const POOL_VIRTUAL_NUSD: u128 = 1;
+/// Oldest signed price `submit_signed_price` accepts for a collateral
+/// without `max_price_age_ms`.
+pub const MAX_SIGNED_PRICE_AGE_MS: u64 = 60_000;
+pub const MS_PER_YEAR: u64 = 365 * 24 * 60 * 60 * 1000;
+/// A collateral's interest index starts at this value.
+pub const INTEREST_INDEX_SCALE: u128 = 1_000_000_000_000_000_000;
 
 pub type TokenId = AccountId;
 
@@ -25,7 +73,37 @@ pub enum StorageKey {
     PriceFeeds,
     StabilityPoolDeposits,
     CollateralRewards,
+    /// Retired global reward-per-share map, kept so its prefix is never
+    /// reused for another value type.
     RewardPerShare,
+    TroveOwners,
+    TroveOwnersPerCollateral {
+        collateral_hash: Vec<u8>,
+    },
+    EpochRewardSnapshots,
+    WrittenOffCollaterals,
+    BadDebt,
+    UnsettledRewards,
+    TotalCollateral,
+    StabilityDepositors,
+    StabilityDepositorsPerPool {
+        pool_hash: Vec<u8>,
+    },
+    OracleSigners,
+    QueuedWithdrawals,
+    MergeApprovals,
+    RebaseDivergences,
+    TcrHistory,
+    OwnerCollaterals,
+    StabilityPools,
+    InterestIndices,
+    RedemptionIndex,
+    RedemptionIndexPerCollateral {
+        collateral_hash: Vec<u8>,
+    },
+    UnindexedStabilityShares,
+    EpochResyncs,
+    CollateralRewardTotals,
 }
 
 #[derive(Clone, Serialize, Deserialize, JsonSchema)]
@@ -38,6 +116,95 @@ pub struct CollateralConfig {
     pub debt_ceiling: U128,
     pub liquidation_penalty_bps: u16,
     pub stability_pool_mode: StabilityPoolMode,
+    #[serde(default)]
+    pub max_active_troves: Option<u64>,
+    #[serde(default)]
+    pub expected_price_decimals: Option<u8>,
+    /// Lowest plausible price, scaled to 18 decimals.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub min_reasonable_price: Option<U128>,
+    #[serde(default)]
+    pub max_price_age_ms: Option<u64>,
+    /// Annual interest charged on trove debt, in bps.
+    #[serde(default)]
+    pub interest_rate_bps: u16,
+    /// The token takes a fee on transfer; deposits are credited net of
+    /// `transfer_fee_bps`, which should be at least the token's actual fee.
+    #[serde(default)]
+    pub has_transfer_fee: bool,
+    #[serde(default)]
+    pub transfer_fee_bps: u16,
+    /// Raise the penalty from `liquidation_penalty_bps` towards
+    /// `max_penalty_bps` as a trove's ratio falls below MCR.
+    #[serde(default)]
+    pub scaled_penalty: bool,
+    #[serde(default)]
+    pub max_penalty_bps: u16,
+    /// Extra ratio above MCR that `borrow` requires of the resulting trove.
+    #[serde(default)]
+    pub borrow_safety_buffer_bps: u16,
+    /// Troves younger than this cannot be redeemed against.
+    #[serde(default)]
+    pub redemption_min_trove_age_ms: u64,
+    /// Smallest reward amount that can be claimed; dust stays claimable
+    /// until it grows past this.
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub min_claimable: U128,
+    /// Troves whose collateral is worth less than this (in nUSD) can be
+    /// swept by `cleanup_trove`; zero disables the sweep.
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub dust_trove_threshold: U128,
+    /// Value, as a share of the collateral's debt, of the treasury's
+    /// collateral that must stay unclaimed.
+    #[serde(default)]
+    pub min_treasury_reserve_bps: u16,
+    /// One-time fee added to the trove's debt on every borrow.
+    #[serde(default)]
+    pub borrow_fee_bps: u16,
+    /// Raise the rate from `interest_rate_bps` towards
+    /// `max_interest_rate_bps` as debt-ceiling utilization climbs past
+    /// `interest_kink_bps`.
+    #[serde(default)]
+    pub dynamic_interest: bool,
+    #[serde(default)]
+    pub max_interest_rate_bps: u16,
+    #[serde(default)]
+    pub interest_kink_bps: u16,
+    /// Launch ceiling, raised linearly to `debt_ceiling` over
+    /// `ceiling_ramp_duration_ms` from first registration.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub initial_debt_ceiling: Option<U128>,
+    #[serde(default)]
+    pub ceiling_ramp_duration_ms: u64,
+    /// Balances change out-of-band; `sync_rebase` checks troves against the
+    /// contract's actual token balance.
+    #[serde(default)]
+    pub is_rebasing: bool,
+    /// Let `liquidate` use a price older than `max_price_age_ms`.
+    #[serde(default)]
+    pub liquidate_on_stale_price: bool,
+    /// Block collateral withdrawals from a trove while it is liquidatable,
+    /// so the owner cannot pull collateral ahead of a keeper.
+    #[serde(default)]
+    pub lock_withdrawals_when_liquidatable: bool,
+    /// Share of redeemed collateral kept back for the treasury.
+    #[serde(default)]
+    pub redemption_fee_bps: u16,
+    /// New debt is refused while the pool that backs this collateral holds
+    /// less nUSD than this.
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub min_stability_pool_size: U128,
+    /// Registered dedicated-mode collateral whose pool absorbs this
+    /// collateral's liquidations instead of its own or the shared pool, so
+    /// risky collaterals can be grouped behind one isolated pool.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub insurance_pool_id: Option<AccountId>,
 }
 
 #[derive(Clone)]
@@ -49,6 +216,34 @@ pub struct CollateralConfigInternal {
     pub debt_ceiling: Balance,
     pub liquidation_penalty_bps: u16,
     pub stability_pool_mode: StabilityPoolMode,
+    pub max_active_troves: Option<u64>,
+    pub expected_price_decimals: Option<u8>,
+    pub min_reasonable_price: Option<Balance>,
+    pub max_price_age_ms: Option<u64>,
+    pub interest_rate_bps: u16,
+    pub has_transfer_fee: bool,
+    pub transfer_fee_bps: u16,
+    pub scaled_penalty: bool,
+    pub max_penalty_bps: u16,
+    pub borrow_safety_buffer_bps: u16,
+    pub redemption_min_trove_age_ms: u64,
+    pub min_claimable: Balance,
+    pub dust_trove_threshold: Balance,
+    pub min_treasury_reserve_bps: u16,
+    pub borrow_fee_bps: u16,
+    pub dynamic_interest: bool,
+    pub max_interest_rate_bps: u16,
+    pub interest_kink_bps: u16,
+    pub initial_debt_ceiling: Option<Balance>,
+    pub ceiling_ramp_duration_ms: u64,
+    pub is_rebasing: bool,
+    pub liquidate_on_stale_price: bool,
+    pub lock_withdrawals_when_liquidatable: bool,
+    pub redemption_fee_bps: u16,
+    pub min_stability_pool_size: Balance,
+    pub insurance_pool_id: Option<AccountId>,
+    /// Start of the ceiling ramp; kept when the config is updated.
+    pub registered_at_ms: u64,
 }
 
 impl From<CollateralConfigInternal> for CollateralConfig {
@@ -60,6 +255,32 @@ impl From<CollateralConfigInternal> for CollateralConfig {
             debt_ceiling: U128(value.debt_ceiling),
             liquidation_penalty_bps: value.liquidation_penalty_bps,
             stability_pool_mode: value.stability_pool_mode,
+            max_active_troves: value.max_active_troves,
+            expected_price_decimals: value.expected_price_decimals,
+            min_reasonable_price: value.min_reasonable_price.map(U128),
+            max_price_age_ms: value.max_price_age_ms,
+            interest_rate_bps: value.interest_rate_bps,
+            has_transfer_fee: value.has_transfer_fee,
+            transfer_fee_bps: value.transfer_fee_bps,
+            scaled_penalty: value.scaled_penalty,
+            max_penalty_bps: value.max_penalty_bps,
+            borrow_safety_buffer_bps: value.borrow_safety_buffer_bps,
+            redemption_min_trove_age_ms: value.redemption_min_trove_age_ms,
+            min_claimable: U128(value.min_claimable),
+            dust_trove_threshold: U128(value.dust_trove_threshold),
+            min_treasury_reserve_bps: value.min_treasury_reserve_bps,
+            borrow_fee_bps: value.borrow_fee_bps,
+            dynamic_interest: value.dynamic_interest,
+            max_interest_rate_bps: value.max_interest_rate_bps,
+            interest_kink_bps: value.interest_kink_bps,
+            initial_debt_ceiling: value.initial_debt_ceiling.map(U128),
+            ceiling_ramp_duration_ms: value.ceiling_ramp_duration_ms,
+            is_rebasing: value.is_rebasing,
+            liquidate_on_stale_price: value.liquidate_on_stale_price,
+            lock_withdrawals_when_liquidatable: value.lock_withdrawals_when_liquidatable,
+            redemption_fee_bps: value.redemption_fee_bps,
+            min_stability_pool_size: U128(value.min_stability_pool_size),
+            insurance_pool_id: value.insurance_pool_id,
         }
     }
 }
@@ -73,22 +294,58 @@ impl From<CollateralConfig> for CollateralConfigInternal {
             debt_ceiling: value.debt_ceiling.0,
             liquidation_penalty_bps: value.liquidation_penalty_bps,
             stability_pool_mode: value.stability_pool_mode,
+            max_active_troves: value.max_active_troves,
+            expected_price_decimals: value.expected_price_decimals,
+            min_reasonable_price: value.min_reasonable_price.map(|price| price.0),
+            max_price_age_ms: value.max_price_age_ms,
+            interest_rate_bps: value.interest_rate_bps,
+            has_transfer_fee: value.has_transfer_fee,
+            transfer_fee_bps: value.transfer_fee_bps,
+            scaled_penalty: value.scaled_penalty,
+            max_penalty_bps: value.max_penalty_bps,
+            borrow_safety_buffer_bps: value.borrow_safety_buffer_bps,
+            redemption_min_trove_age_ms: value.redemption_min_trove_age_ms,
+            min_claimable: value.min_claimable.0,
+            dust_trove_threshold: value.dust_trove_threshold.0,
+            min_treasury_reserve_bps: value.min_treasury_reserve_bps,
+            borrow_fee_bps: value.borrow_fee_bps,
+            dynamic_interest: value.dynamic_interest,
+            max_interest_rate_bps: value.max_interest_rate_bps,
+            interest_kink_bps: value.interest_kink_bps,
+            initial_debt_ceiling: value.initial_debt_ceiling.map(|ceiling| ceiling.0),
+            ceiling_ramp_duration_ms: value.ceiling_ramp_duration_ms,
+            is_rebasing: value.is_rebasing,
+            liquidate_on_stale_price: value.liquidate_on_stale_price,
+            lock_withdrawals_when_liquidatable: value.lock_withdrawals_when_liquidatable,
+            redemption_fee_bps: value.redemption_fee_bps,
+            min_stability_pool_size: value.min_stability_pool_size.0,
+            insurance_pool_id: value.insurance_pool_id,
+            registered_at_ms: 0,
         }
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[derive(Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 #[near(serializers=[borsh])]
 pub enum StabilityPoolMode {
+    #[default]
     Dedicated,
     Shared,
 }
 
-impl Default for StabilityPoolMode {
-    fn default() -> Self {
-        Self::Dedicated
-    }
+/// How `redeem_multi` treats the redeemer's own trove.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+#[near(serializers=[borsh])]
+pub enum RedemptionSelfPolicy {
+    /// The redeemer's trove keeps its place in ratio order.
+    #[default]
+    Include,
+    /// The redeemer's trove is never redeemed against.
+    Skip,
+    /// The redeemer's trove is redeemed against before any other.
+    First,
 }
 
 #[derive(Clone)]
@@ -98,6 +355,52 @@ pub struct TroveKey {
     pub collateral_id: AccountId,
 }
 
+/// Entry in a collateral's redemption index. Collateral per unit of debt
+/// at the base interest index ranks troves by collateral ratio at any price
+/// and any later index; the owner breaks ties.
+#[derive(Clone, Debug)]
+#[near(serializers=[borsh])]
+pub struct RedemptionIndexKey {
+    pub collateral_amount: Balance,
+    pub normalized_debt: Balance,
+    pub owner_id: AccountId,
+}
+
+impl Ord for RedemptionIndexKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        mul_wide(self.collateral_amount, other.normalized_debt)
+            .cmp(&mul_wide(other.collateral_amount, self.normalized_debt))
+            .then_with(|| self.owner_id.cmp(&other.owner_id))
+    }
+}
+
+impl PartialOrd for RedemptionIndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RedemptionIndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for RedemptionIndexKey {}
+
+/// Full 256-bit product of `a * b` as `(high, low)` halves.
+fn mul_wide(a: u128, b: u128) -> (u128, u128) {
+    const LOW: u128 = u64::MAX as u128;
+    let (a_high, a_low) = (a >> 64, a & LOW);
+    let (b_high, b_low) = (b >> 64, b & LOW);
+    let low_low = a_low * b_low;
+    let high_low = a_high * b_low;
+    let low_high = a_low * b_high;
+    let middle = (low_low >> 64) + (high_low & LOW) + (low_high & LOW);
+    let high = a_high * b_high + (high_low >> 64) + (low_high >> 64) + (middle >> 64);
+    (high, (middle << 64) | (low_low & LOW))
+}
+
 #[derive(Clone)]
 #[near(serializers=[borsh])]
 pub struct TroveInternal {
@@ -106,6 +409,11 @@ pub struct TroveInternal {
     pub collateral_amount: Balance,
     pub debt_amount: Balance,
     pub last_update_timestamp: u64,
+    /// Zero until the trove is partially liquidated.
+    pub last_liquidation_timestamp: u64,
+    pub opened_timestamp: u64,
+    /// The collateral's interest index when the debt was last accrued.
+    pub interest_index: u128,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Clone, Serialize, Deserialize, JsonSchema)]
@@ -121,6 +429,10 @@ pub struct Trove {
     pub debt_amount: U128,
     #[schemars(with = "String")]
     pub last_update_timestamp: U64,
+    #[schemars(with = "String")]
+    pub last_liquidation_timestamp: U64,
+    #[schemars(with = "String")]
+    pub opened_timestamp: U64,
 }
 
 impl From<TroveInternal> for Trove {
@@ -131,10 +443,133 @@ impl From<TroveInternal> for Trove {
             collateral_amount: U128(value.collateral_amount),
             debt_amount: U128(value.debt_amount),
             last_update_timestamp: U64(value.last_update_timestamp),
+            last_liquidation_timestamp: U64(value.last_liquidation_timestamp),
+            opened_timestamp: U64(value.opened_timestamp),
         }
     }
 }
 
+/// Deployed version and capabilities, as returned by `get_version`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VersionInfo {
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+/// Global governance parameters, as returned by `get_config`.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractConfig {
+    #[schemars(with = "String")]
+    pub owner_id: AccountId,
+    #[schemars(with = "String")]
+    pub treasury_id: AccountId,
+    #[schemars(with = "String")]
+    pub pyth_oracle_id: AccountId,
+    #[schemars(with = "String")]
+    pub intent_router_id: AccountId,
+    #[schemars(with = "Option<String>")]
+    pub fallback_router_id: Option<AccountId>,
+    pub retry_swaps_via_fallback: bool,
+    pub reliquidation_cooldown_ms: u64,
+    pub stability_withdraw_cooldown_ms: u64,
+    pub paused: bool,
+    pub allow_withdrawals_when_paused: bool,
+    pub referrer_bps: u16,
+    pub rebalance_fraction_bps: u16,
+    pub interest_to_equity_bps: u16,
+    pub fee_to_stability_pool_bps: u16,
+    pub redemption_self_policy: RedemptionSelfPolicy,
+    #[schemars(with = "Option<String>")]
+    pub max_borrow_per_tx: Option<U128>,
+    pub max_system_utilization_bps: Option<u16>,
+    #[schemars(with = "Option<String>")]
+    pub large_withdrawal_threshold: Option<U128>,
+    pub withdrawal_queue_delay_ms: u64,
+    pub borrow_paused: bool,
+    pub redeem_paused: bool,
+    pub liquidate_paused: bool,
+    pub stability_paused: bool,
+    #[schemars(with = "String")]
+    pub keeper_nusd_reward: U128,
+    pub event_level: EventLevel,
+}
+
+/// Which `CdpEvent`s are logged. Levels are ordered: `Verbose` also logs
+/// every `Core` event.
+#[derive(
+    Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+#[near(serializers=[borsh])]
+pub enum EventLevel {
+    Off,
+    /// Borrows, repayments, redemptions and liquidations.
+    #[default]
+    Core,
+    /// Also price updates and at-risk trove warnings.
+    Verbose,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedeemPreview {
+    #[schemars(with = "String")]
+    pub collateral_out: U128,
+    #[schemars(with = "String")]
+    pub nusd_used: U128,
+    #[schemars(with = "String")]
+    pub nusd_refund: U128,
+}
+
+/// Why `redeem_multi` passed over a trove.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum RedemptionSkipReason {
+    /// Below MCR; left to liquidation.
+    Liquidatable,
+    /// Opened within the collateral's `redemption_min_trove_age_ms`.
+    TooYoung,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SkippedTrove {
+    #[schemars(with = "String")]
+    pub owner_id: AccountId,
+    pub reason: RedemptionSkipReason,
+}
+
+/// The nUSD `redeem_multi` redeemed and the troves it skipped on the way.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedemptionReport {
+    #[schemars(with = "String")]
+    pub redeemed: U128,
+    pub skipped: Vec<SkippedTrove>,
+}
+
+/// What `borrow` minted to the receiver and the fee added to the debt on
+/// top of it.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BorrowResult {
+    #[schemars(with = "String")]
+    pub minted: U128,
+    #[schemars(with = "String")]
+    pub fee: U128,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClosePreview {
+    #[schemars(with = "String")]
+    pub debt_to_repay: U128,
+    #[schemars(with = "String")]
+    pub collateral_returned: U128,
+}
+
 #[derive(Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 pub struct PriceFeed {
@@ -145,6 +580,27 @@ pub struct PriceFeed {
     pub last_update_timestamp: U64,
 }
 
+/// Off-chain key whose signed prices anyone may relay for a collateral.
+#[near(serializers=[borsh])]
+pub struct OracleSigner {
+    pub public_key: PublicKey,
+    /// Publish time of the last accepted signed price; older ones are
+    /// rejected so a relayer cannot replay them.
+    pub last_publish_time: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleHealth {
+    /// `None` until a first price arrives.
+    #[schemars(with = "Option<String>")]
+    pub last_update_timestamp: Option<U64>,
+    #[schemars(with = "Option<String>")]
+    pub age_ms: Option<U64>,
+    pub max_price_age_ms: Option<u64>,
+    pub is_healthy: bool,
+}
+
 #[derive(Clone)]
 #[near(serializers=[borsh])]
 pub struct PriceFeedInternal {
@@ -163,11 +619,267 @@ impl From<PriceFeedInternal> for PriceFeed {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SwapPurpose {
+    Manual,
+    Rebalance {
+        #[schemars(with = "String")]
+        min_out: U128,
+    },
+    /// Redeemed collateral swapped for the redeemer, who receives the
+    /// output from the router directly.
+    Redeem {
+        #[schemars(with = "String")]
+        min_out: U128,
+    },
+}
+
+/// Swap `redeem` applies to the collateral out before it reaches the
+/// redeemer.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedeemConversion {
+    #[schemars(with = "String")]
+    pub output_token: AccountId,
+    #[schemars(with = "String")]
+    pub min_out: U128,
+    pub routing_hint: Option<String>,
+}
+
+/// Treasury collateral sent to a router with `ft_transfer_call`, kept so
+/// the callback can restore whatever the router hands back.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryConversion {
+    #[schemars(with = "String")]
+    pub collateral_id: AccountId,
+    #[schemars(with = "String")]
+    pub amount: U128,
+    #[schemars(with = "String")]
+    pub min_out: U128,
+    pub routing_hint: Option<String>,
+    /// Treasury at the time of the call; receives the nUSD and any refund.
+    #[schemars(with = "String")]
+    pub treasury_id: AccountId,
+}
+
+/// `msg` of the `ft_transfer_call` that hands input tokens to a router:
+/// swap them for at least `min_out` of `output_token`, delivered to
+/// `receiver_id`. The router returns the input it did not use.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapTransferMsg {
+    pub output_token: AccountId,
+    pub min_out: U128,
+    pub routing_hint: Option<String>,
+    pub receiver_id: AccountId,
+}
+
+/// Arguments of an intents `execute_swap` call, kept so a failed swap can be
+/// re-issued through the fallback router.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapRequest {
+    #[schemars(with = "String")]
+    pub caller_id: AccountId,
+    #[schemars(with = "String")]
+    pub input_token: AccountId,
+    #[schemars(with = "String")]
+    pub output_token: AccountId,
+    #[schemars(with = "String")]
+    pub amount_in: U128,
+    #[schemars(with = "String")]
+    pub min_out: U128,
+    pub routing_hint: Option<String>,
+    /// Yocto NEAR attached to the router call.
+    #[schemars(with = "String")]
+    pub deposit: U128,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapOutcome {
+    pub amount_out: U128,
+    /// Part of the attached deposit the router kept; it returns the rest to
+    /// the contract. Routers that do not report it keep everything.
+    #[serde(default)]
+    pub deposit_used: Option<U128>,
+}
+
+/// Price as returned by the Pyth contract's `get_price`: `price * 10^expo`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PythPrice {
+    pub price: I64,
+    pub conf: U64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+/// An operation class governance can pause protocol-wide on its own.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Operation {
+    /// `borrow` and debt increases through `adjust_trove`.
+    Borrow,
+    Redeem,
+    Liquidate,
+    /// Stability pool deposits, withdrawals and migrations.
+    Stability,
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum LiquidationSortBy {
+    /// Lowest collateral ratio first.
+    #[default]
+    Ratio,
+    /// Largest outstanding debt first.
+    Debt,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LiquidatableTrove {
+    #[schemars(with = "String")]
+    pub owner_id: AccountId,
+    #[schemars(with = "String")]
+    pub collateral_amount: U128,
+    #[schemars(with = "String")]
+    pub debt_amount: U128,
+    #[schemars(with = "String")]
+    pub collateral_ratio_bps: U128,
+}
+
+/// Where a depositor's collateral rewards stand: settled into the
+/// claimable balance, or still pending in a pool deposit.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardAccounting {
+    pub collaterals: Vec<CollateralRewardStatus>,
+    pub deposits: Vec<DepositEpochStatus>,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollateralRewardStatus {
+    #[schemars(with = "String")]
+    pub collateral_id: AccountId,
+    #[schemars(with = "String")]
+    pub claimable: U128,
+    #[schemars(with = "String")]
+    pub pending: U128,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositEpochStatus {
+    /// The dedicated pool's collateral, or `None` for the shared pool.
+    #[schemars(with = "Option<String>")]
+    pub collateral_id: Option<AccountId>,
+    #[schemars(with = "String")]
+    pub shares: U128,
+    pub deposit_epoch: u64,
+    pub current_epoch: u64,
+    /// The pool drained since the deposit was last touched; its rewards
+    /// settle from the frozen snapshot and its shares reset on next touch.
+    pub epoch_reset_pending: bool,
+}
+
+/// A large stability withdrawal waiting out `withdrawal_queue_delay_ms`.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QueuedWithdrawalView {
+    #[schemars(with = "String")]
+    pub account_id: AccountId,
+    /// The dedicated pool's collateral, or `None` for the shared pool.
+    #[schemars(with = "Option<String>")]
+    pub collateral_id: Option<AccountId>,
+    /// Shares still in the pool; their value is paid out on finalization.
+    #[schemars(with = "String")]
+    pub shares: U128,
+    #[schemars(with = "String")]
+    pub ready_at: U64,
+}
+
+/// `tcr_bps` is `u128::MAX` while the collateral has no debt.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TcrSnapshotView {
+    #[schemars(with = "String")]
+    pub tcr_bps: U128,
+    #[schemars(with = "String")]
+    pub timestamp: U64,
+}
+
+/// A trove whose stored collateral no longer matches its pro-rata share of
+/// the contract's rebasing-token balance.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RebaseDivergenceView {
+    #[schemars(with = "String")]
+    pub recorded: U128,
+    #[schemars(with = "String")]
+    pub actual: U128,
+    #[schemars(with = "String")]
+    pub synced_at: U64,
+}
+
+/// Everything a keeper needs to decide whether to liquidate one trove.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TroveRiskBundle {
+    pub trove: Trove,
+    #[schemars(with = "Option<String>")]
+    pub collateral_ratio_bps: Option<U128>,
+    pub min_collateral_ratio_bps: u16,
+    pub is_liquidatable: bool,
+    pub price: Option<PriceFeed>,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LiquidationBatchEstimate {
+    #[schemars(with = "Vec<String>")]
+    pub owners: Vec<AccountId>,
+    #[schemars(with = "String")]
+    pub total_debt: U128,
+    #[schemars(with = "String")]
+    pub total_collateral: U128,
+}
+
+/// What a hypothetical price would do to one collateral's troves.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceImpactReport {
+    pub liquidatable_troves: u64,
+    /// Liquidatable at the hypothetical price but not at the current one.
+    pub newly_liquidatable_troves: u64,
+    #[schemars(with = "String")]
+    pub debt_at_risk: U128,
+    #[schemars(with = "String")]
+    pub stability_pool_nusd: U128,
+    /// Debt at risk the stability pool could not absorb.
+    #[schemars(with = "String")]
+    pub projected_bad_debt: U128,
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
 pub enum TransferAction {
-    DepositCollateral { target_account: Option<AccountId> },
-    RepayDebt { collateral_id: AccountId },
+    DepositCollateral {
+        target_account: Option<AccountId>,
+    },
+    RepayDebt {
+        collateral_id: AccountId,
+    },
+    /// Adds the transferred collateral and changes debt by `debt_delta` in
+    /// one step, as `adjust_trove` does for withdrawals.
+    AdjustTrove {
+        debt_delta: I128,
+        receiver: Option<AccountId>,
+    },
 }
 
 #[derive(Clone)]
@@ -186,12 +898,118 @@ impl CollateralRewardKey {
     }
 }
 
+/// The global shared pool, or the pool dedicated to one collateral.
+#[derive(Clone, PartialEq, Eq)]
+#[near(serializers=[borsh])]
+pub enum PoolId {
+    Shared,
+    Dedicated(TokenId),
+}
+
+#[derive(Clone, Default)]
+#[near(serializers=[borsh])]
+pub struct StabilityPool {
+    pub total_shares: Balance,
+    pub total_nusd: Balance,
+    pub epoch: u64,
+    pub reward_per_share: BTreeMap<TokenId, u128>,
+}
+
+impl StabilityPool {
+    /// Share issuance counts `POOL_VIRTUAL_SHARES` shares backed by
+    /// `POOL_VIRTUAL_NUSD` that nobody owns, so nUSD donated to a nearly
+    /// empty pool mostly accrues to them and cannot be used to round a
+    /// later deposit down.
+    pub fn shares_from_amount(&self, amount: Balance) -> Balance {
+        let shares = self.total_shares + POOL_VIRTUAL_SHARES;
+        audit_mul("share issuance", amount, shares);
+        amount.checked_mul(shares).expect("Share calc overflow")
+            / (self.total_nusd + POOL_VIRTUAL_NUSD)
+    }
+
+    pub fn amount_from_shares(&self, shares: Balance) -> Balance {
+        if self.total_shares == 0 {
+            return 0;
+        }
+        let nusd = self.total_nusd + POOL_VIRTUAL_NUSD;
+        audit_mul("share redemption", shares, nusd);
+        shares.checked_mul(nusd).expect("Share amount overflow")
+            / (self.total_shares + POOL_VIRTUAL_SHARES)
+    }
+}
+
+#[derive(Clone)]
+#[near(serializers=[borsh])]
+pub struct DepositKey {
+    pub account_id: AccountId,
+    pub pool_id: PoolId,
+}
+
+impl DepositKey {
+    pub fn new(account_id: &AccountId, pool_id: &PoolId) -> Self {
+        Self {
+            account_id: account_id.clone(),
+            pool_id: pool_id.clone(),
+        }
+    }
+}
+
 #[derive(Clone)]
 #[near(serializers=[borsh])]
 pub struct StabilityDeposit {
     pub shares: Balance,
     pub reward_debt: BTreeMap<AccountId, u128>,
     pub epoch: u64,
+    /// Start of the withdraw cooldown, reset by every deposit.
+    pub last_deposit_timestamp: u64,
+    /// Shares promised to queued withdrawals; instant exits cannot use them.
+    pub queued_shares: Balance,
+    /// Start of the current `withdrawal_queue_delay_ms` window and the nUSD
+    /// withdrawn instantly within it.
+    pub withdrawal_window_start: u64,
+    pub withdrawn_in_window: Balance,
+}
+
+/// Shares stay in the deposit until finalized, so they keep absorbing
+/// liquidations; a pool drain (new `epoch`) voids the request.
+#[near(serializers=[borsh])]
+pub struct QueuedWithdrawal {
+    pub account_id: AccountId,
+    pub pool_id: PoolId,
+    pub epoch: u64,
+    pub shares: Balance,
+    pub ready_at: u64,
+}
+
+/// Progress of a paged `force_epoch_resync` over a pool's depositor
+/// index; a new pool `epoch` restarts it.
+#[near(serializers=[borsh])]
+pub struct EpochResync {
+    pub epoch: u64,
+    pub next_index: u64,
+    pub shares: Balance,
+}
+
+/// Cumulative interest growth of a collateral's debt since registration,
+/// scaled by `INTEREST_INDEX_SCALE`.
+#[derive(Clone)]
+#[near(serializers=[borsh])]
+pub struct InterestIndex {
+    pub value: u128,
+    pub last_update_timestamp: u64,
+}
+
+#[near(serializers=[borsh])]
+pub struct TcrSnapshot {
+    pub tcr_bps: u128,
+    pub timestamp_ms: u64,
+}
+
+#[near(serializers=[borsh])]
+pub struct RebaseDivergence {
+    pub recorded: Balance,
+    pub actual: Balance,
+    pub synced_at: u64,
 }
 
 impl StabilityDeposit {
@@ -200,17 +1018,28 @@ impl StabilityDeposit {
             shares: 0,
             reward_debt: BTreeMap::new(),
             epoch,
+            last_deposit_timestamp: 0,
+            queued_shares: 0,
+            withdrawal_window_start: 0,
+            withdrawn_in_window: 0,
         }
     }
 
-    pub fn amount(&self, total_nusd: Balance, total_shares: Balance) -> Balance {
-        if self.shares == 0 || total_shares == 0 || total_nusd == 0 {
-            0
-        } else {
-            self.shares
-                .checked_mul(total_nusd)
-                .expect("Share amount overflow")
-                / total_shares
+    /// Shares not promised to a queued withdrawal.
+    pub fn unlocked_shares(&self) -> Balance {
+        self.shares.saturating_sub(self.queued_shares)
+    }
+
+    /// Collateral earned since the last settlement, given the accumulator
+    /// value that applies to this deposit's epoch.
+    pub fn pending_reward(&self, collateral_id: &AccountId, reward_per_share: u128) -> Balance {
+        let paid = self.reward_debt.get(collateral_id).copied().unwrap_or(0);
+        if self.shares == 0 || reward_per_share <= paid {
+            return 0;
         }
+        self.shares
+            .checked_mul(reward_per_share - paid)
+            .expect("Reward mul overflow")
+            / REWARD_SCALE
     }
 }
@@ -1,61 +1,865 @@
-use crate::types::{CollateralConfig, CollateralRewardKey, PriceFeed, Trove, REWARD_SCALE};
+use crate::types::{
+    ClosePreview, CollateralConfig, CollateralRewardKey, CollateralRewardStatus, ContractConfig,
+    DepositEpochStatus, DepositKey, EventLevel, LiquidatableTrove, LiquidationBatchEstimate,
+    LiquidationSortBy, OracleHealth, PoolId, PriceFeed, PriceFeedInternal, PriceImpactReport,
+    QueuedWithdrawalView, RebaseDivergenceView, RedeemPreview, RedemptionSelfPolicy,
+    RewardAccounting, TcrSnapshotView, Trove, TroveRiskBundle, VersionInfo, BPS_DENOMINATOR,
+    CONTRACT_VERSION, FEATURE_FLAGS, MAX_LEVERAGE_BPS, USD_1E8,
+};
 use crate::{Contract, ContractExt};
-use near_sdk::json_types::U128;
-use near_sdk::{near_bindgen, AccountId};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{near_bindgen, require, AccountId, PublicKey};
+use std::collections::BTreeMap;
 
 #[near_bindgen]
 impl Contract {
+    /// Semantic version and feature flags of the deployed code.
+    pub fn get_version(&self) -> VersionInfo {
+        VersionInfo {
+            version: CONTRACT_VERSION.to_string(),
+            features: FEATURE_FLAGS.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    /// Every global setting in one call.
+    pub fn get_config(&self) -> ContractConfig {
+        ContractConfig {
+            owner_id: self.owner_id.clone(),
+            treasury_id: self.treasury_id.clone(),
+            pyth_oracle_id: self.pyth_oracle_id.clone(),
+            intent_router_id: self.intent_router_id.clone(),
+            fallback_router_id: self.fallback_router_id.clone(),
+            retry_swaps_via_fallback: self.retry_swaps_via_fallback,
+            reliquidation_cooldown_ms: self.reliquidation_cooldown_ms,
+            stability_withdraw_cooldown_ms: self.stability_withdraw_cooldown_ms,
+            paused: self.paused,
+            allow_withdrawals_when_paused: self.allow_withdrawals_when_paused,
+            referrer_bps: self.referrer_bps,
+            rebalance_fraction_bps: self.rebalance_fraction_bps,
+            interest_to_equity_bps: self.interest_to_equity_bps,
+            fee_to_stability_pool_bps: self.fee_to_stability_pool_bps,
+            redemption_self_policy: self.redemption_self_policy,
+            max_borrow_per_tx: self.max_borrow_per_tx.map(U128),
+            max_system_utilization_bps: self.max_system_utilization_bps,
+            large_withdrawal_threshold: self.large_withdrawal_threshold.map(U128),
+            withdrawal_queue_delay_ms: self.withdrawal_queue_delay_ms,
+            borrow_paused: self.borrow_paused,
+            redeem_paused: self.redeem_paused,
+            liquidate_paused: self.liquidate_paused,
+            stability_paused: self.stability_paused,
+            keeper_nusd_reward: U128(self.keeper_nusd_reward),
+            event_level: self.event_level,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
     pub fn owner_id(&self) -> AccountId {
         self.owner_id.clone()
     }
 
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    pub fn treasury_id(&self) -> AccountId {
+        self.treasury_id.clone()
+    }
+
+    pub fn reliquidation_cooldown_ms(&self) -> u64 {
+        self.reliquidation_cooldown_ms
+    }
+
+    pub fn redemption_self_policy(&self) -> RedemptionSelfPolicy {
+        self.redemption_self_policy
+    }
+
+    pub fn event_level(&self) -> EventLevel {
+        self.event_level
+    }
+
+    pub fn max_borrow_per_tx(&self) -> Option<U128> {
+        self.max_borrow_per_tx.map(U128)
+    }
+
+    /// nUSD supply as a share of all priced collateral value, in bps.
+    pub fn get_system_utilization(&self) -> Option<U128> {
+        self.system_utilization_bps().map(U128)
+    }
+
+    pub fn interest_to_equity_bps(&self) -> u16 {
+        self.interest_to_equity_bps
+    }
+
+    /// How far the treasury's claimable `collateral_id` falls short, in
+    /// nUSD value, of `min_treasury_reserve_bps` of the collateral's debt.
+    /// `None` for an unregistered or unpriced collateral.
+    pub fn get_reserve_shortfall(&self, collateral_id: AccountId) -> Option<U128> {
+        self.treasury_reserve(&collateral_id)
+            .map(|(required, held)| U128(required.saturating_sub(held)))
+    }
+
+    pub fn get_insurance_fund_balance(&self) -> U128 {
+        U128(self.insurance_fund_balance)
+    }
+
+    /// nUSD minted to the keeper for each trove it liquidates.
+    pub fn get_keeper_nusd_reward(&self) -> U128 {
+        U128(self.keeper_nusd_reward)
+    }
+
+    pub fn referrer_bps(&self) -> u16 {
+        self.referrer_bps
+    }
+
+    pub fn rebalance_fraction_bps(&self) -> u16 {
+        self.rebalance_fraction_bps
+    }
+
+    pub fn get_withdrawable_near(&self) -> U128 {
+        U128(self.withdrawable_near())
+    }
+
     pub fn intent_router_id(&self) -> AccountId {
         self.intent_router_id.clone()
     }
 
+    pub fn fallback_router_id(&self) -> Option<AccountId> {
+        self.fallback_router_id.clone()
+    }
+
+    pub fn retry_swaps_via_fallback(&self) -> bool {
+        self.retry_swaps_via_fallback
+    }
+
     pub fn pyth_oracle_id(&self) -> AccountId {
         self.pyth_oracle_id.clone()
     }
 
     pub fn list_collateral_tokens(&self) -> Vec<AccountId> {
-        self.configs.keys_as_vector().to_vec()
+        self.sorted_collateral_ids()
+    }
+
+    /// A page of `list_collateral_tokens`; empty once `from_index` is past
+    /// the end.
+    pub fn list_collateral_tokens_paged(&self, from_index: u64, limit: u64) -> Vec<AccountId> {
+        self.sorted_collateral_ids()
+            .into_iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    /// `list_collateral_configs` with required bounds.
+    pub fn get_collateral_configs(
+        &self,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(AccountId, CollateralConfig)> {
+        self.list_collateral_configs(Some(from_index), Some(limit))
+    }
+
+    pub fn list_collateral_configs(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, CollateralConfig)> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.configs.len());
+        self.sorted_collateral_ids()
+            .into_iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|token_id| {
+                let config = self.expect_config(&token_id).into();
+                (token_id, config)
+            })
+            .collect()
     }
 
     pub fn get_collateral_config(&self, token_id: AccountId) -> Option<CollateralConfig> {
         self.configs.get(&token_id).map(Into::into)
     }
 
+    pub fn get_mcr(&self, collateral_id: AccountId) -> Option<u16> {
+        self.configs
+            .get(&collateral_id)
+            .map(|config| config.min_collateral_ratio_bps)
+    }
+
+    pub fn get_debt_ceiling(&self, collateral_id: AccountId) -> Option<U128> {
+        self.configs
+            .get(&collateral_id)
+            .map(|config| U128(config.debt_ceiling))
+    }
+
+    /// The ceiling `borrow` enforces now, part-way up a configured ramp.
+    pub fn get_effective_debt_ceiling(&self, collateral_id: AccountId) -> Option<U128> {
+        self.configs
+            .get(&collateral_id)
+            .map(|config| U128(Self::effective_debt_ceiling(&config)))
+    }
+
+    /// Lowest collateral ratio `borrow` accepts, MCR plus the safety buffer.
+    pub fn get_min_borrow_ratio(&self, collateral_id: AccountId) -> Option<U128> {
+        self.configs
+            .get(&collateral_id)
+            .map(|config| U128(Self::min_borrow_ratio_bps(&config)))
+    }
+
+    pub fn get_min_claimable(&self, collateral_id: AccountId) -> Option<U128> {
+        self.configs
+            .get(&collateral_id)
+            .map(|config| U128(config.min_claimable))
+    }
+
+    /// Leverage, in bps, reached by looping borrowed nUSD back into
+    /// collateral until the trove sits at MCR: `MCR / (MCR - 1)`. Capped at
+    /// `MAX_LEVERAGE_BPS`, which also covers an MCR at or below 100%.
+    pub fn get_max_leverage(&self, collateral_id: AccountId) -> Option<u64> {
+        self.configs.get(&collateral_id).map(|config| {
+            let mcr = config.min_collateral_ratio_bps as u64;
+            let one = BPS_DENOMINATOR as u64;
+            if mcr <= one {
+                return MAX_LEVERAGE_BPS;
+            }
+            (mcr * one / (mcr - one)).min(MAX_LEVERAGE_BPS)
+        })
+    }
+
+    pub fn get_liquidation_penalty(&self, collateral_id: AccountId) -> Option<u16> {
+        self.configs
+            .get(&collateral_id)
+            .map(|config| config.liquidation_penalty_bps)
+    }
+
+    pub fn get_oracle_public_key(&self, collateral_id: AccountId) -> Option<PublicKey> {
+        self.oracle_signers
+            .get(&collateral_id)
+            .map(|signer| signer.public_key)
+    }
+
     pub fn get_price(&self, collateral_id: AccountId) -> Option<PriceFeed> {
         self.price_feeds.get(&collateral_id).map(Into::into)
     }
 
+    /// Whether the price is older than `max_price_age_ms`, so borrows are
+    /// rejected; a collateral without a price counts as stale.
+    pub fn is_price_stale(&self, collateral_id: AccountId) -> bool {
+        let config = self.expect_config(&collateral_id);
+        self.price_feeds
+            .get(&collateral_id)
+            .is_none_or(|price| self.is_feed_stale(&price, &config))
+    }
+
+    /// Age of the collateral's price against its `max_price_age_ms`. A feed
+    /// with no price is unhealthy; one without a max age never goes stale.
+    pub fn get_oracle_health(&self, collateral_id: AccountId) -> Option<OracleHealth> {
+        let config = self.configs.get(&collateral_id)?;
+        let price = self.price_feeds.get(&collateral_id);
+        Some(OracleHealth {
+            last_update_timestamp: price.as_ref().map(|price| U64(price.last_update_timestamp)),
+            age_ms: price
+                .as_ref()
+                .map(|price| U64(Self::now_ms().saturating_sub(price.last_update_timestamp))),
+            max_price_age_ms: config.max_price_age_ms,
+            is_healthy: price
+                .as_ref()
+                .is_some_and(|price| !self.is_feed_stale(price, &config)),
+        })
+    }
+
+    /// Collaterals, written-off ones excluded, whose oracle is not healthy.
+    pub fn list_unhealthy_oracles(&self) -> Vec<AccountId> {
+        self.sorted_collateral_ids()
+            .into_iter()
+            .filter(|collateral_id| !self.written_off_collaterals.contains(collateral_id))
+            .filter(|collateral_id| {
+                !self
+                    .get_oracle_health(collateral_id.clone())
+                    .is_some_and(|health| health.is_healthy)
+            })
+            .collect()
+    }
+
+    pub fn collateral_to_nusd_value(&self, collateral_id: AccountId, amount: U128) -> Option<U128> {
+        self.price_feeds
+            .get(&collateral_id)
+            .map(|price| U128(Self::collateral_value(amount.0, &price)))
+    }
+
+    /// Price rescaled to 8 decimals; finer feeds are truncated.
+    pub fn get_price_usd_1e8(&self, collateral_id: AccountId) -> Option<U128> {
+        self.price_feeds.get(&collateral_id).map(|feed| {
+            let price = if feed.decimals <= 8 {
+                feed.price
+                    .checked_mul(Self::decimals_factor(8 - feed.decimals))
+                    .expect("Price overflow")
+            } else {
+                feed.price / Self::decimals_factor(feed.decimals - 8)
+            };
+            U128(price)
+        })
+    }
+
+    /// nUSD market price (USD, 8 decimals) below which buying nUSD and
+    /// redeeming it is profitable. A redemption pays out the oracle value of
+    /// the nUSD burned less `redemption_fee_bps`, so the breakeven is that
+    /// share of $1. `None` while the collateral has no price to redeem at.
+    pub fn get_redemption_breakeven(&self, collateral_id: AccountId) -> Option<U128> {
+        let config = self.configs.get(&collateral_id)?;
+        self.price_feeds.get(&collateral_id)?;
+        Some(U128(Self::mul_div(
+            USD_1E8,
+            BPS_DENOMINATOR - config.redemption_fee_bps as u128,
+            BPS_DENOMINATOR,
+        )))
+    }
+
+    /// Every trove of `owner_id`, in the order they were opened.
+    pub fn get_troves_by_owner(&self, owner_id: AccountId) -> Vec<Trove> {
+        self.owner_collaterals
+            .get(&owner_id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|collateral_id| self.troves.get(&Self::trove_key(&owner_id, collateral_id)))
+            .map(Into::into)
+            .collect()
+    }
+
     pub fn get_trove(&self, owner_id: AccountId, collateral_id: AccountId) -> Option<Trove> {
         self.troves
             .get(&Self::trove_key(&owner_id, &collateral_id))
             .map(Into::into)
     }
 
+    /// The trove with interest accrued up to the current block.
+    pub fn get_trove_with_accrued_interest(
+        &self,
+        owner_id: AccountId,
+        collateral_id: AccountId,
+    ) -> Option<Trove> {
+        self.projected_trove(&owner_id, &collateral_id)
+            .map(Into::into)
+    }
+
+    /// Annual rate, in bps, the collateral's troves accrue at right now.
+    pub fn get_current_interest_rate(&self, collateral_id: AccountId) -> Option<u16> {
+        self.configs
+            .get(&collateral_id)
+            .map(|config| self.interest_rate_bps(&collateral_id, &config))
+    }
+
+    /// Interest the next interaction with the trove would add to its debt;
+    /// zero for a missing trove.
+    pub fn get_accrued_interest(&self, owner_id: AccountId, collateral_id: AccountId) -> U128 {
+        let Some(config) = self.configs.get(&collateral_id) else {
+            return U128(0);
+        };
+        let index = self.current_interest_index(&collateral_id, &config);
+        self.troves
+            .get(&Self::trove_key(&owner_id, &collateral_id))
+            .map_or(U128(0), |trove| {
+                U128(Self::pending_interest(&trove, index.value))
+            })
+    }
+
+    /// What `close_trove` requires and returns if called now.
+    pub fn get_close_trove_preview(
+        &self,
+        owner_id: AccountId,
+        collateral_id: AccountId,
+    ) -> Option<ClosePreview> {
+        self.get_trove_with_accrued_interest(owner_id, collateral_id)
+            .map(|trove| ClosePreview {
+                debt_to_repay: trove.debt_amount,
+                collateral_returned: trove.collateral_amount,
+            })
+    }
+
+    /// How `redeem` would split `amount` at the current price: the nUSD
+    /// burned pays exactly for the collateral out, the rest is not taken.
+    /// `collateral_out` is net of the redemption fee.
+    pub fn preview_redeem(&self, collateral_id: AccountId, amount: U128) -> Option<RedeemPreview> {
+        let price = self.price_feeds.get(&collateral_id)?;
+        let (_, nusd_used, nusd_refund) = Self::redeem_split(amount.0, &price);
+        let (collateral_out, _) = self.quote_redemption(collateral_id, amount)?;
+        Some(RedeemPreview {
+            collateral_out,
+            nusd_used: U128(nusd_used),
+            nusd_refund: U128(nusd_refund),
+        })
+    }
+
+    /// Collateral `redeem` would pay out for `amount` at the current price
+    /// and the fee kept back from it for the treasury.
+    pub fn quote_redemption(&self, collateral_id: AccountId, amount: U128) -> Option<(U128, U128)> {
+        let config = self.configs.get(&collateral_id)?;
+        let price = self.price_feeds.get(&collateral_id)?;
+        let (gross, _, _) = Self::redeem_split(amount.0, &price);
+        let fee = Self::redemption_fee(&config, gross);
+        Some((U128(gross - fee), U128(fee)))
+    }
+
+    pub fn get_active_trove_count(&self, collateral_id: AccountId) -> u64 {
+        self.trove_owners
+            .get(&collateral_id)
+            .map(|owners| owners.len())
+            .unwrap_or(0)
+    }
+
+    /// Same as `get_active_trove_count`; the total `list_troves` pages over.
+    pub fn get_trove_count(&self, collateral_id: AccountId) -> u64 {
+        self.get_active_trove_count(collateral_id)
+    }
+
+    /// A page of the collateral's troves in owner-index order, for keepers
+    /// scanning positions without an off-chain indexer.
+    pub fn list_troves(
+        &self,
+        collateral_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<Trove> {
+        let owners = self.trove_owner_set(&collateral_id);
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(owners.len());
+        owners
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter_map(|owner_id| self.troves.get(&Self::trove_key(&owner_id, &collateral_id)))
+            .map(Into::into)
+            .collect()
+    }
+
+    pub fn get_max_active_troves(&self, collateral_id: AccountId) -> Option<u64> {
+        self.configs
+            .get(&collateral_id)
+            .and_then(|config| config.max_active_troves)
+    }
+
+    /// Liquidatable troves within one page of the collateral's owner index,
+    /// sorted within that page.
+    pub fn list_liquidatable(
+        &self,
+        collateral_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+        sort_by: Option<LiquidationSortBy>,
+    ) -> Vec<LiquidatableTrove> {
+        let owners = self.trove_owner_set(&collateral_id);
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(owners.len());
+        self.liquidatable_troves(
+            &collateral_id,
+            owners.iter().skip(from_index as usize).take(limit as usize),
+            sort_by.unwrap_or_default(),
+        )
+    }
+
+    /// One trove with interest accrued, its ratio at the stored price and
+    /// whether `liquidate` would take it right now.
+    pub fn get_trove_risk_bundle(
+        &self,
+        owner_id: AccountId,
+        collateral_id: AccountId,
+    ) -> Option<TroveRiskBundle> {
+        let trove = self.projected_trove(&owner_id, &collateral_id)?;
+        let config = self.configs.get(&collateral_id)?;
+        let price = self.price_feeds.get(&collateral_id);
+        let collateral_ratio_bps = price.as_ref().map(|price| {
+            U128(self.collateral_ratio(trove.collateral_amount, trove.debt_amount, price))
+        });
+        let is_liquidatable = price.as_ref().is_some_and(|price| {
+            !self.written_off_collaterals.contains(&collateral_id)
+                && self.is_liquidatable(&trove, &config, price)
+                && !self.in_reliquidation_cooldown(&trove)
+        });
+        Some(TroveRiskBundle {
+            trove: trove.into(),
+            collateral_ratio_bps,
+            min_collateral_ratio_bps: config.min_collateral_ratio_bps,
+            is_liquidatable,
+            price: price.map(Into::into),
+        })
+    }
+
+    /// The next batch a keeper should submit: candidates in `sort_by` order,
+    /// stopping at `max_troves` or at the first trove the stability pool can
+    /// no longer cover.
+    pub fn estimate_liquidation_batch(
+        &self,
+        collateral_id: AccountId,
+        max_troves: u64,
+        sort_by: Option<LiquidationSortBy>,
+    ) -> LiquidationBatchEstimate {
+        let candidates = self.liquidatable_troves(
+            &collateral_id,
+            self.trove_owner_set(&collateral_id).iter(),
+            sort_by.unwrap_or_default(),
+        );
+        let pool_nusd = self.configs.get(&collateral_id).map_or(0, |_| {
+            self.pool(&self.pool_for_collateral(&collateral_id))
+                .total_nusd
+        });
+        let mut owners = Vec::new();
+        let mut total_debt = 0u128;
+        let mut total_collateral = 0u128;
+        for candidate in candidates.into_iter().take(max_troves as usize) {
+            let debt = total_debt + candidate.debt_amount.0;
+            if debt > pool_nusd {
+                break;
+            }
+            total_debt = debt;
+            total_collateral += candidate.collateral_amount.0;
+            owners.push(candidate.owner_id);
+        }
+        LiquidationBatchEstimate {
+            owners,
+            total_debt: U128(total_debt),
+            total_collateral: U128(total_collateral),
+        }
+    }
+
+    /// Stress test: every trove of the collateral, accrued interest
+    /// included, re-rated at `price` against the current stability pool.
+    pub fn simulate_price_impact(
+        &self,
+        collateral_id: AccountId,
+        price: U128,
+        decimals: u8,
+    ) -> PriceImpactReport {
+        let config = self.expect_config(&collateral_id);
+        let current_price = self.price_feeds.get(&collateral_id);
+        let stressed_price = PriceFeedInternal {
+            price: price.0,
+            decimals,
+            last_update_timestamp: Self::now_ms(),
+        };
+        let mut liquidatable_troves = 0;
+        let mut newly_liquidatable_troves = 0;
+        let mut debt_at_risk = 0u128;
+        for owner_id in self.trove_owner_set(&collateral_id).iter() {
+            let Some(trove) = self.projected_trove(&owner_id, &collateral_id) else {
+                continue;
+            };
+            if !self.is_liquidatable(&trove, &config, &stressed_price) {
+                continue;
+            }
+            liquidatable_troves += 1;
+            if !current_price
+                .as_ref()
+                .is_some_and(|current| self.is_liquidatable(&trove, &config, current))
+            {
+                newly_liquidatable_troves += 1;
+            }
+            debt_at_risk = debt_at_risk
+                .checked_add(trove.debt_amount)
+                .expect("Debt overflow");
+        }
+        let pool_nusd = self
+            .pool(&self.pool_for_collateral(&collateral_id))
+            .total_nusd;
+        PriceImpactReport {
+            liquidatable_troves,
+            newly_liquidatable_troves,
+            debt_at_risk: U128(debt_at_risk),
+            stability_pool_nusd: U128(pool_nusd),
+            projected_bad_debt: U128(debt_at_risk.saturating_sub(pool_nusd)),
+        }
+    }
+
+    pub fn is_collateral_written_off(&self, collateral_id: AccountId) -> bool {
+        self.written_off_collaterals.contains(&collateral_id)
+    }
+
+    pub fn get_bad_debt(&self, collateral_id: AccountId) -> U128 {
+        U128(self.bad_debt.get(&collateral_id).unwrap_or(0))
+    }
+
+    /// Every collateral with bad debt still waiting for coverage.
+    pub fn list_bad_debt(&self) -> Vec<(AccountId, U128)> {
+        self.sorted_collateral_ids()
+            .into_iter()
+            .filter_map(|collateral_id| {
+                let bad_debt = self.bad_debt.get(&collateral_id).unwrap_or(0);
+                (bad_debt > 0).then_some((collateral_id, U128(bad_debt)))
+            })
+            .collect()
+    }
+
+    pub fn get_total_bad_debt(&self) -> U128 {
+        U128(
+            self.list_bad_debt()
+                .into_iter()
+                .try_fold(0u128, |total, (_, bad_debt)| total.checked_add(bad_debt.0))
+                .expect("Bad debt overflow"),
+        )
+    }
+
+    pub fn get_total_collateral(&self, collateral_id: AccountId) -> U128 {
+        U128(self.total_collateral.get(&collateral_id).unwrap_or(0))
+    }
+
+    pub fn get_total_collateral_ratio(&self, collateral_id: AccountId) -> Option<U128> {
+        self.total_collateral_ratio(&collateral_id).map(U128)
+    }
+
+    /// Value of all priced collateral over all trove debt, in bps;
+    /// `u64::MAX` without debt.
+    pub fn get_global_collateral_ratio(&self) -> u64 {
+        let debt = self.system_debt();
+        if debt == 0 {
+            return u64::MAX;
+        }
+        let ratio = Self::mul_div(self.system_collateral_value(), BPS_DENOMINATOR, debt);
+        u64::try_from(ratio).unwrap_or(u64::MAX)
+    }
+
+    /// Total collateral value over total debt for one collateral, in bps.
+    /// Same figure as `get_total_collateral_ratio`.
+    pub fn system_collateral_ratio(&self, collateral_id: AccountId) -> Option<U128> {
+        self.total_collateral_ratio(&collateral_id).map(U128)
+    }
+
+    /// The most recent `limit` TCR snapshots, oldest first.
+    pub fn get_tcr_history(
+        &self,
+        collateral_id: AccountId,
+        limit: Option<u64>,
+    ) -> Vec<TcrSnapshotView> {
+        let history = self.tcr_history.get(&collateral_id).unwrap_or_default();
+        let limit = limit.map_or(history.len(), |limit| limit as usize);
+        history
+            .iter()
+            .skip(history.len().saturating_sub(limit))
+            .map(|snapshot| TcrSnapshotView {
+                tcr_bps: U128(snapshot.tcr_bps),
+                timestamp: U64(snapshot.timestamp_ms),
+            })
+            .collect()
+    }
+
+    /// Whether borrows, withdrawals and liquidations of this collateral use
+    /// the recovery ratio instead of MCR.
+    pub fn is_in_recovery_mode(&self, collateral_id: AccountId) -> bool {
+        self.is_in_recovery(&collateral_id, &self.expect_config(&collateral_id))
+    }
+
+    /// Collaterals whose system-wide ratio is below their recovery ratio.
+    /// Bounded by `MAX_COLLATERALS`.
+    pub fn collaterals_in_recovery(&self) -> Vec<AccountId> {
+        self.sorted_collateral_ids()
+            .into_iter()
+            .filter(|collateral_id| {
+                self.is_in_recovery(collateral_id, &self.expect_config(collateral_id))
+            })
+            .collect()
+    }
+
+    pub fn is_any_collateral_in_recovery(&self) -> bool {
+        self.configs
+            .iter()
+            .any(|(collateral_id, config)| self.is_in_recovery(&collateral_id, &config))
+    }
+
     pub fn get_total_debt(&self, collateral_id: AccountId) -> U128 {
         U128(self.total_debt.get(&collateral_id).unwrap_or(0))
     }
 
-    pub fn get_stability_pool_balance(&self) -> U128 {
+    /// Balance of the pool dedicated to `collateral_id`, or of the shared
+    /// pool when it is omitted.
+    pub fn get_stability_pool_balance(&self, collateral_id: Option<AccountId>) -> U128 {
+        U128(self.pool(&Self::resolve_pool(collateral_id)).total_nusd)
+    }
+
+    /// Balance of the pool backing `collateral_id` and the collateral's
+    /// `min_stability_pool_size`; borrowing stops while the first is lower.
+    pub fn get_stability_pool_requirement(&self, collateral_id: AccountId) -> Option<(U128, U128)> {
+        let config = self.configs.get(&collateral_id)?;
+        let pool = self.pool(&self.pool_for_collateral(&collateral_id));
+        Some((U128(pool.total_nusd), U128(config.min_stability_pool_size)))
+    }
+
+    pub fn get_total_stability_pool_balance(&self) -> U128 {
         U128(self.stability_pool_total_nusd)
     }
 
-    pub fn get_stability_pool_deposit(&self, account_id: AccountId) -> U128 {
-        self.stability_pool_deposits
-            .get(&account_id)
-            .filter(|deposit| deposit.epoch == self.stability_pool_epoch)
-            .map(|deposit| {
-                U128(deposit.amount(
-                    self.stability_pool_total_nusd,
-                    self.stability_pool_total_shares,
-                ))
+    /// Debt the shared pool would absorb if every currently liquidatable
+    /// trove of each shared-mode collateral were liquidated.
+    pub fn get_pool_collateral_exposure(&self) -> Vec<(AccountId, U128)> {
+        self.sorted_collateral_ids()
+            .into_iter()
+            .filter(|collateral_id| self.pool_for_collateral(collateral_id) == PoolId::Shared)
+            .filter_map(|collateral_id| {
+                let exposure = self
+                    .liquidatable_troves(
+                        &collateral_id,
+                        self.trove_owner_set(&collateral_id).iter(),
+                        LiquidationSortBy::default(),
+                    )
+                    .into_iter()
+                    .try_fold(0u128, |total, trove| total.checked_add(trove.debt_amount.0))
+                    .expect("Debt overflow");
+                (exposure > 0).then_some((collateral_id, U128(exposure)))
             })
+            .collect()
+    }
+
+    pub fn simulate_stability_deposit(
+        &self,
+        amount: U128,
+        collateral_id: Option<AccountId>,
+    ) -> U128 {
+        U128(
+            self.pool(&Self::resolve_pool(collateral_id))
+                .shares_from_amount(amount.0),
+        )
+    }
+
+    pub fn simulate_stability_withdraw(
+        &self,
+        shares: U128,
+        collateral_id: Option<AccountId>,
+    ) -> U128 {
+        U128(
+            self.pool(&Self::resolve_pool(collateral_id))
+                .amount_from_shares(shares.0),
+        )
+    }
+
+    /// Share, in bps, of future rewards that a new deposit of
+    /// `deposit_amount` would earn at the current pool size.
+    pub fn estimate_reward_share(
+        &self,
+        deposit_amount: U128,
+        collateral_id: Option<AccountId>,
+    ) -> u64 {
+        let pool_nusd = self.pool(&Self::resolve_pool(collateral_id)).total_nusd;
+        if pool_nusd == 0 {
+            return BPS_DENOMINATOR as u64;
+        }
+        let total = pool_nusd
+            .checked_add(deposit_amount.0)
+            .expect("Pool balance overflow");
+        Self::mul_div(deposit_amount.0, BPS_DENOMINATOR, total) as u64
+    }
+
+    pub fn get_stability_pool_deposit(
+        &self,
+        account_id: AccountId,
+        collateral_id: Option<AccountId>,
+    ) -> U128 {
+        let pool_id = Self::resolve_pool(collateral_id);
+        let pool = self.pool(&pool_id);
+        self.stability_pool_deposits
+            .get(&DepositKey::new(&account_id, &pool_id))
+            .filter(|deposit| deposit.epoch == pool.epoch)
+            .map(|deposit| U128(pool.amount_from_shares(deposit.shares)))
             .unwrap_or(U128(0))
     }
 
+    /// Last divergence `sync_rebase` found for this trove, if it has not
+    /// since been resolved.
+    pub fn get_rebase_divergence(
+        &self,
+        account_id: AccountId,
+        collateral_id: AccountId,
+    ) -> Option<RebaseDivergenceView> {
+        self.rebase_divergences
+            .get(&Self::trove_key(&account_id, &collateral_id))
+            .map(|divergence| RebaseDivergenceView {
+                recorded: U128(divergence.recorded),
+                actual: U128(divergence.actual),
+                synced_at: U64(divergence.synced_at),
+            })
+    }
+
+    pub fn get_queued_withdrawal(&self, id: U64) -> Option<QueuedWithdrawalView> {
+        self.queued_withdrawals
+            .get(&id.0)
+            .map(|queued| QueuedWithdrawalView {
+                account_id: queued.account_id,
+                collateral_id: match queued.pool_id {
+                    PoolId::Shared => None,
+                    PoolId::Dedicated(collateral_id) => Some(collateral_id),
+                },
+                shares: U128(queued.shares),
+                ready_at: U64(queued.ready_at),
+            })
+    }
+
+    /// Shares `withdraw_from_stability_pool` would burn for `amount`; fails
+    /// like the withdrawal when the deposit holds less.
+    pub fn preview_stability_withdraw(
+        &self,
+        account_id: AccountId,
+        amount: U128,
+        collateral_id: Option<AccountId>,
+    ) -> U128 {
+        let pool_id = Self::resolve_pool(collateral_id);
+        let pool = self.pool(&pool_id);
+        let shares = self
+            .stability_pool_deposits
+            .get(&DepositKey::new(&account_id, &pool_id))
+            .filter(|deposit| deposit.epoch == pool.epoch)
+            .map_or(0, |deposit| deposit.unlocked_shares());
+        require!(amount.0 > 0, "Amount must be > 0");
+        require!(
+            amount.0 <= pool.amount_from_shares(shares),
+            "Insufficient balance"
+        );
+        U128(pool.shares_from_amount(amount.0))
+    }
+
+    /// Depositors of the pool for `collateral_id` (the shared pool when
+    /// omitted) with their current nUSD balance, in index order.
+    pub fn list_stability_depositors(
+        &self,
+        collateral_id: Option<AccountId>,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, U128)> {
+        let pool_id = Self::resolve_pool(collateral_id);
+        let pool = self.pool(&pool_id);
+        let depositors = self.stability_depositor_set(&pool_id);
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(depositors.len());
+        depositors
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|account_id| {
+                let amount = self
+                    .stability_pool_deposits
+                    .get(&DepositKey::new(&account_id, &pool_id))
+                    .filter(|deposit| deposit.epoch == pool.epoch)
+                    .map_or(0, |deposit| pool.amount_from_shares(deposit.shares));
+                (account_id, U128(amount))
+            })
+            .collect()
+    }
+
+    /// Shares of the pool for `collateral_id` (the shared pool when
+    /// omitted) held by deposits `list_stability_depositors` does not list
+    /// yet; 0 once the index is complete.
+    pub fn get_unindexed_stability_shares(&self, collateral_id: Option<AccountId>) -> U128 {
+        U128(
+            self.unindexed_stability_shares
+                .get(&Self::resolve_pool(collateral_id))
+                .unwrap_or(0),
+        )
+    }
+
+    /// Collateral accrued to stability pool depositors that has not yet been
+    /// settled into individual claimable balances.
+    pub fn get_total_pending_rewards(&self, collateral_id: AccountId) -> U128 {
+        U128(self.unsettled_rewards.get(&collateral_id).unwrap_or(0))
+    }
+
     pub fn get_claimable_collateral_reward(
         &self,
         account_id: AccountId,
@@ -63,24 +867,83 @@ impl Contract {
     ) -> U128 {
         let key = CollateralRewardKey::new(&account_id, &collateral_id);
         let mut total = self.collateral_rewards.get(&key).unwrap_or(0);
-        if let Some(deposit) = self.stability_pool_deposits.get(&account_id) {
-            if deposit.shares > 0 {
-                let global = self.reward_per_share.get(&collateral_id).unwrap_or(0);
-                let paid = deposit
-                    .reward_debt
-                    .get(&collateral_id)
-                    .copied()
-                    .unwrap_or(0);
-                if global > paid {
-                    let pending = deposit
-                        .shares
-                        .checked_mul(global - paid)
-                        .expect("View reward overflow")
-                        / REWARD_SCALE;
-                    total = total.checked_add(pending).expect("Reward overflow");
-                }
-            }
+        let mut pool_ids = vec![PoolId::Shared, PoolId::Dedicated(collateral_id.clone())];
+        if let Some(insurance_pool_id) = self
+            .configs
+            .get(&collateral_id)
+            .and_then(|config| config.insurance_pool_id)
+            .filter(|pool_id| pool_id != &collateral_id)
+        {
+            pool_ids.push(PoolId::Dedicated(insurance_pool_id));
+        }
+        for pool_id in pool_ids {
+            let Some(deposit) = self
+                .stability_pool_deposits
+                .get(&DepositKey::new(&account_id, &pool_id))
+            else {
+                continue;
+            };
+            let global = self
+                .epoch_reward_per_share(&pool_id, deposit.epoch)
+                .get(&collateral_id)
+                .copied()
+                .unwrap_or(0);
+            let pending = deposit.pending_reward(&collateral_id, global);
+            total = total.checked_add(pending).expect("Reward overflow");
         }
         U128(total)
     }
+
+    /// Every collateral reward the account has, split into settled
+    /// (claimable) and pending, plus the epoch status of each deposit.
+    pub fn get_reward_accounting(&self, account_id: AccountId) -> RewardAccounting {
+        let collateral_ids = self.sorted_collateral_ids();
+        let pool_ids = std::iter::once(PoolId::Shared)
+            .chain(collateral_ids.iter().cloned().map(PoolId::Dedicated));
+        let mut pending: BTreeMap<AccountId, u128> = BTreeMap::new();
+        let mut deposits = Vec::new();
+        for pool_id in pool_ids {
+            let Some(deposit) = self
+                .stability_pool_deposits
+                .get(&DepositKey::new(&account_id, &pool_id))
+            else {
+                continue;
+            };
+            for (collateral_id, global) in self.epoch_reward_per_share(&pool_id, deposit.epoch) {
+                let amount = deposit.pending_reward(&collateral_id, global);
+                let entry = pending.entry(collateral_id).or_default();
+                *entry = entry.checked_add(amount).expect("Reward overflow");
+            }
+            let current_epoch = self.pool(&pool_id).epoch;
+            deposits.push(DepositEpochStatus {
+                collateral_id: match pool_id {
+                    PoolId::Shared => None,
+                    PoolId::Dedicated(collateral_id) => Some(collateral_id),
+                },
+                shares: U128(deposit.shares),
+                deposit_epoch: deposit.epoch,
+                current_epoch,
+                epoch_reset_pending: deposit.epoch != current_epoch,
+            });
+        }
+        let collaterals = collateral_ids
+            .into_iter()
+            .filter_map(|collateral_id| {
+                let claimable = self
+                    .collateral_rewards
+                    .get(&CollateralRewardKey::new(&account_id, &collateral_id))
+                    .unwrap_or(0);
+                let pending = pending.get(&collateral_id).copied().unwrap_or(0);
+                (claimable > 0 || pending > 0).then_some(CollateralRewardStatus {
+                    collateral_id,
+                    claimable: U128(claimable),
+                    pending: U128(pending),
+                })
+            })
+            .collect();
+        RewardAccounting {
+            collaterals,
+            deposits,
+        }
+    }
 }
@@ -0,0 +1,61 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{I64, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::store::LookupMap;
+use near_sdk::{env, near_bindgen, require, AccountId, PanicOnDefault};
+
+/// Same shape as the Pyth contract's `Price`: `price * 10^expo`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct Price {
+    pub price: I64,
+    pub conf: U64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct MockPyth {
+    owner_id: AccountId,
+    prices: LookupMap<String, Price>,
+}
+
+#[near_bindgen]
+impl MockPyth {
+    #[init]
+    pub fn new(owner_id: AccountId) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self {
+            owner_id,
+            prices: LookupMap::new(b"p"),
+        }
+    }
+
+    /// `publish_time` (seconds) defaults to the current block time.
+    pub fn set_price(
+        &mut self,
+        price_identifier: String,
+        price: I64,
+        expo: i32,
+        publish_time: Option<i64>,
+    ) {
+        require!(env::predecessor_account_id() == self.owner_id, "Owner only");
+        let publish_time = publish_time.unwrap_or((env::block_timestamp() / 1_000_000_000) as i64);
+        self.prices.insert(
+            price_identifier,
+            Price {
+                price,
+                conf: U64(0),
+                expo,
+                publish_time,
+            },
+        );
+    }
+
+    pub fn get_price(&self, price_identifier: String) -> Option<Price> {
+        self.prices.get(&price_identifier).cloned()
+    }
+}
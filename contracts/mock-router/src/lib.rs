@@ -0,0 +1,171 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, near_bindgen, require, serde_json, AccountId, Gas, NearToken,
+    PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
+};
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_RESOLVE: Gas = Gas::from_tgas(10);
+const BPS_DENOMINATOR: u128 = 10_000;
+
+#[ext_contract(ext_ft)]
+pub trait ExternalFungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapOutcome {
+    pub amount_out: U128,
+    pub deposit_used: U128,
+}
+
+/// `msg` of an `ft_transfer_call` asking for a swap of the sent tokens.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapTransferMsg {
+    pub output_token: AccountId,
+    pub min_out: U128,
+    pub routing_hint: Option<String>,
+    pub receiver_id: AccountId,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockRouter {
+    owner_id: AccountId,
+    rate_bps: u128,
+    should_fail: bool,
+    /// Cap on the attached deposit the router keeps; the rest goes back to
+    /// the caller. `None` keeps the whole deposit.
+    max_deposit_used: Option<u128>,
+}
+
+#[near_bindgen]
+impl MockRouter {
+    #[init]
+    pub fn new(owner_id: AccountId) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self {
+            owner_id,
+            rate_bps: BPS_DENOMINATOR,
+            should_fail: false,
+            max_deposit_used: None,
+        }
+    }
+
+    pub fn set_rate_bps(&mut self, rate_bps: u128) {
+        self.assert_owner();
+        self.rate_bps = rate_bps;
+    }
+
+    pub fn set_should_fail(&mut self, should_fail: bool) {
+        self.assert_owner();
+        self.should_fail = should_fail;
+    }
+
+    pub fn set_max_deposit_used(&mut self, max_deposit_used: Option<U128>) {
+        self.assert_owner();
+        self.max_deposit_used = max_deposit_used.map(|amount| amount.0);
+    }
+
+    /// Pays `amount_in * rate_bps` of `output_token` from the router's own
+    /// balance to `caller_id`, then resolves to the delivered amount.
+    #[payable]
+    pub fn execute_swap(
+        &mut self,
+        caller_id: AccountId,
+        input_token: AccountId,
+        output_token: AccountId,
+        amount_in: U128,
+        min_out: U128,
+        routing_hint: Option<String>,
+    ) -> Promise {
+        require!(!self.should_fail, "Swap failed");
+        let amount_out = amount_in.0 * self.rate_bps / BPS_DENOMINATOR;
+        require!(amount_out >= min_out.0, "Slippage exceeded");
+        env::log_str(&format!(
+            "mock swap: {} {} -> {} {} (hint: {:?})",
+            amount_in.0, input_token, amount_out, output_token, routing_hint
+        ));
+        let attached = env::attached_deposit().as_yoctonear();
+        let deposit_used = self
+            .max_deposit_used
+            .map_or(attached, |max| max.min(attached));
+        if attached > deposit_used {
+            Promise::new(env::predecessor_account_id())
+                .transfer(NearToken::from_yoctonear(attached - deposit_used));
+        }
+        ext_ft::ext(output_token)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(caller_id, U128(amount_out), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_output_delivered(U128(amount_out), U128(deposit_used)),
+            )
+    }
+
+    /// Swaps tokens sent with `ft_transfer_call`, paying `receiver_id` from
+    /// the router's own balance. A failed swap keeps none of the input.
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let request: SwapTransferMsg = serde_json::from_str(&msg).expect("Invalid swap message");
+        let amount_out = amount.0 * self.rate_bps / BPS_DENOMINATOR;
+        if self.should_fail || amount_out < request.min_out.0 {
+            env::log_str("mock swap failed: returning input");
+            return PromiseOrValue::Value(amount);
+        }
+        env::log_str(&format!(
+            "mock swap for {}: {} {} -> {} {} (hint: {:?})",
+            sender_id,
+            amount.0,
+            env::predecessor_account_id(),
+            amount_out,
+            request.output_token,
+            request.routing_hint
+        ));
+        PromiseOrValue::Promise(
+            ext_ft::ext(request.output_token)
+                .with_attached_deposit(NearToken::from_yoctonear(1))
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(request.receiver_id, U128(amount_out), None)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_RESOLVE)
+                        .on_transfer_swapped(amount),
+                ),
+        )
+    }
+
+    /// Unused input of a transfer-call swap: all of it if the payout failed.
+    #[private]
+    pub fn on_transfer_swapped(&mut self, amount_in: U128) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => U128(0),
+            _ => amount_in,
+        }
+    }
+
+    #[private]
+    pub fn on_output_delivered(&mut self, amount_out: U128, deposit_used: U128) -> SwapOutcome {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => SwapOutcome {
+                amount_out,
+                deposit_used,
+            },
+            _ => env::panic_str("Output delivery failed"),
+        }
+    }
+
+    fn assert_owner(&self) {
+        require!(env::predecessor_account_id() == self.owner_id, "Owner only");
+    }
+}
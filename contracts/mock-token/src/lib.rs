@@ -2,7 +2,7 @@ use near_contract_standards::fungible_token::core::FungibleTokenCore;
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
 };
-use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::fungible_token::receiver::{ext_ft_receiver, FungibleTokenReceiver};
 use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::FungibleToken;
 use near_contract_standards::storage_management::{
@@ -11,7 +11,7 @@ use near_contract_standards::storage_management::{
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
 use near_sdk::store::LazyOption;
 use near_sdk::{
-    assert_one_yocto, env, near_bindgen, AccountId, NearToken, PanicOnDefault, PromiseOrValue,
+    assert_one_yocto, env, near_bindgen, AccountId, Gas, NearToken, PanicOnDefault, PromiseOrValue,
 };
 use near_sdk::{json_types::U128, require};
 
@@ -21,6 +21,7 @@ pub struct MockToken {
     owner_id: AccountId,
     token: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
+    transfer_fee_bps: u16,
 }
 
 #[near_bindgen]
@@ -34,6 +35,7 @@ impl MockToken {
             owner_id,
             token,
             metadata: LazyOption::new(b"m".to_vec(), Some(metadata)),
+            transfer_fee_bps: 0,
         }
     }
 
@@ -47,6 +49,31 @@ impl MockToken {
         self.token.internal_deposit(&account_id, amount.0);
     }
 
+    /// Makes `ft_transfer_call` burn a fee from the transferred amount while
+    /// still reporting the gross amount to the receiver.
+    #[payable]
+    pub fn set_transfer_fee_bps(&mut self, transfer_fee_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.transfer_fee_bps = transfer_fee_bps;
+    }
+
+    /// Sets `account_id`'s balance directly, as an elastic-supply token's
+    /// rebase would.
+    #[payable]
+    pub fn rebase(&mut self, account_id: AccountId, balance: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        let current = self.token.ft_balance_of(account_id.clone()).0;
+        if balance.0 > current {
+            self.token
+                .internal_deposit(&account_id, balance.0 - current);
+        } else {
+            self.token
+                .internal_withdraw(&account_id, current - balance.0);
+        }
+    }
+
     fn assert_owner(&self) {
         require!(env::predecessor_account_id() == self.owner_id, "Owner only");
     }
@@ -67,7 +94,25 @@ impl FungibleTokenCore for MockToken {
         memo: Option<String>,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+        if self.transfer_fee_bps == 0 {
+            return self.token.ft_transfer_call(receiver_id, amount, memo, msg);
+        }
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let fee = amount.0 * self.transfer_fee_bps as u128 / 10_000;
+        let net = amount.0 - fee;
+        self.token
+            .internal_transfer(&sender_id, &receiver_id, net, memo);
+        self.token.internal_withdraw(&sender_id, fee);
+        ext_ft_receiver::ext(receiver_id.clone())
+            .with_static_gas(Gas::from_tgas(30))
+            .ft_on_transfer(sender_id.clone(), amount, msg)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas::from_tgas(10))
+                    .ft_resolve_transfer(sender_id, receiver_id, U128(net)),
+            )
+            .into()
     }
 
     fn ft_total_supply(&self) -> U128 {
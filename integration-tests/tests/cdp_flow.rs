@@ -33,9 +33,31 @@ fn mock_token_wasm_path() -> PathBuf {
         .join("mock_token.wasm")
 }
 
+fn mock_router_wasm_path() -> PathBuf {
+    workspace_root()
+        .join("target")
+        .join("near")
+        .join("mock_router")
+        .join("mock_router.wasm")
+}
+
+fn mock_pyth_wasm_path() -> PathBuf {
+    workspace_root()
+        .join("target")
+        .join("near")
+        .join("mock_pyth")
+        .join("mock_pyth.wasm")
+}
+
 fn build_contract_wasm() -> Result<()> {
     let status = Command::new("cargo")
-        .args(["near", "build", "non-reproducible-wasm"])
+        .args([
+            "near",
+            "build",
+            "non-reproducible-wasm",
+            "--features",
+            "test-helpers",
+        ])
         .current_dir(contract_project_dir())
         .status()
         .context("failed to run `cargo near build`")?;
@@ -56,6 +78,26 @@ fn build_mock_token_wasm() -> Result<()> {
     Ok(())
 }
 
+fn build_mock_router_wasm() -> Result<()> {
+    let status = Command::new("cargo")
+        .args(["near", "build", "non-reproducible-wasm"])
+        .current_dir(workspace_root().join("contracts").join("mock-router"))
+        .status()
+        .context("failed to run `cargo near build` for mock router")?;
+    ensure!(status.success(), "`cargo build -p mock-router` failed");
+    Ok(())
+}
+
+fn build_mock_pyth_wasm() -> Result<()> {
+    let status = Command::new("cargo")
+        .args(["near", "build", "non-reproducible-wasm"])
+        .current_dir(workspace_root().join("contracts").join("mock-pyth"))
+        .status()
+        .context("failed to run `cargo near build` for mock pyth")?;
+    ensure!(status.success(), "`cargo build -p mock-pyth` failed");
+    Ok(())
+}
+
 async fn load_contract_wasm() -> Result<Vec<u8>> {
     if !wasm_artifact_path().exists() {
         build_contract_wasm()?;
@@ -74,13 +116,34 @@ async fn load_mock_token_wasm() -> Result<Vec<u8>> {
         .context("unable to read compiled mock token wasm")
 }
 
+async fn load_mock_router_wasm() -> Result<Vec<u8>> {
+    if !mock_router_wasm_path().exists() {
+        build_mock_router_wasm()?;
+    }
+    fs::read(mock_router_wasm_path())
+        .await
+        .context("unable to read compiled mock router wasm")
+}
+
+async fn load_mock_pyth_wasm() -> Result<Vec<u8>> {
+    if !mock_pyth_wasm_path().exists() {
+        build_mock_pyth_wasm()?;
+    }
+    fs::read(mock_pyth_wasm_path())
+        .await
+        .context("unable to read compiled mock pyth wasm")
+}
+
 struct TestEnv {
     #[allow(dead_code)]
     worker: Worker<Sandbox>,
     contract: Contract,
     owner: Account,
+    /// The mock Pyth contract's account, which also pushes prices directly.
     oracle: Account,
+    pyth: Contract,
     collateral_token: Contract,
+    router: Contract,
     borrower: Account,
 }
 
@@ -90,10 +153,25 @@ async fn setup_borrow_env() -> Result<TestEnv> {
     let contract = worker.dev_deploy(&wasm).await?;
 
     let owner = worker.dev_create_account().await?;
-    let oracle = worker.dev_create_account().await?;
+    let pyth = worker.dev_deploy(&load_mock_pyth_wasm().await?).await?;
+    let oracle = pyth.as_account().clone();
     let borrower = worker.dev_create_account().await?;
     let collateral_wasm = load_mock_token_wasm().await?;
     let collateral_token = worker.dev_deploy(&collateral_wasm).await?;
+    let router_wasm = load_mock_router_wasm().await?;
+    let router = worker.dev_deploy(&router_wasm).await?;
+
+    router
+        .call("new")
+        .args_json(json!({ "owner_id": owner.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    pyth.call("new")
+        .args_json(json!({ "owner_id": owner.id() }))
+        .transact()
+        .await?
+        .into_result()?;
 
     collateral_token
         .call("new")
@@ -118,7 +196,7 @@ async fn setup_borrow_env() -> Result<TestEnv> {
         .call("new")
         .args_json(json!({
             "owner_id": owner.id(),
-            "intent_router_id": owner.id(),
+            "intent_router_id": router.id(),
             "pyth_oracle_id": oracle.id(),
             "metadata": {
                 "spec": "ft-1.0.0",
@@ -173,7 +251,9 @@ async fn setup_borrow_env() -> Result<TestEnv> {
         contract,
         owner,
         oracle,
+        pyth,
         collateral_token,
+        router,
         borrower,
     };
 
@@ -213,6 +293,7 @@ async fn borrow_flow_smoke_test() -> Result<()> {
             .unwrap_or_default(),
         "4000"
     );
+    assert_invariants(&env).await?;
 
     Ok(())
 }
@@ -267,7 +348,7 @@ async fn stability_pool_liquidates_underwater_trove() -> Result<()> {
 
     env.borrower
         .call(env.contract.id(), "deposit_to_stability_pool")
-        .args_json(json!({ "amount": "4000" }))
+        .args_json(json!({ "amount": "4000", "collateral_id": env.collateral_token.id() }))
         .deposit(NearToken::from_yoctonear(1))
         .max_gas()
         .transact()
@@ -318,7 +399,10 @@ async fn stability_pool_liquidates_underwater_trove() -> Result<()> {
     let pool_balance: String = env
         .contract
         .view("get_stability_pool_deposit")
-        .args_json(json!({ "account_id": env.borrower.id() }))
+        .args_json(json!({
+            "account_id": env.borrower.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
         .await?
         .json()?;
     assert_eq!(pool_balance, "0", "depositor balance should be depleted");
@@ -350,6 +434,7 @@ async fn stability_pool_liquidates_underwater_trove() -> Result<()> {
         owner_reward, "50",
         "owner should receive liquidation penalty"
     );
+    assert_invariants(&env).await?;
 
     env.borrower
         .call(env.contract.id(), "claim_collateral_reward")
@@ -390,6 +475,130 @@ async fn stability_pool_liquidates_underwater_trove() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn liquidate_with_refresh_pulls_pyth_price_first() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let liquidated = env.worker.dev_create_account().await?;
+
+    open_trove_for(&env, &liquidated, "10000", "4000").await?;
+    env.borrower
+        .call(env.contract.id(), "deposit_to_stability_pool")
+        .args_json(json!({ "amount": "4000", "collateral_id": env.collateral_token.id() }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // Only Pyth knows about the drop; the stored price is still 200.00.
+    env.owner
+        .call(env.pyth.id(), "set_price")
+        .args_json(json!({ "price_identifier": "usdc", "price": "5", "expo": -2 }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let liquidator = env.worker.dev_create_account().await?;
+    liquidator
+        .call(env.contract.id(), "liquidate")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "owners": [liquidated.id()],
+            "refresh": true
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    let price: Value = env
+        .contract
+        .view("get_price")
+        .args_json(json!({ "collateral_id": env.collateral_token.id() }))
+        .await?
+        .json()?;
+    assert_eq!(price["price"], "5");
+    assert_eq!(price["decimals"], 2);
+    let trove: Value = env
+        .contract
+        .view("get_trove")
+        .args_json(json!({
+            "owner_id": liquidated.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
+        .await?
+        .json()?;
+    assert_eq!(
+        trove,
+        Value::Null,
+        "trove should be liquidated at the pulled price"
+    );
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn liquidate_with_refresh_rejects_a_stale_pyth_price() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let liquidated = env.worker.dev_create_account().await?;
+
+    open_trove_for(&env, &liquidated, "10000", "4000").await?;
+    // Pyth still serves a price published long before the stored one.
+    env.owner
+        .call(env.pyth.id(), "set_price")
+        .args_json(json!({
+            "price_identifier": "usdc",
+            "price": "5",
+            "expo": -2,
+            "publish_time": 1
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let err = env
+        .worker
+        .dev_create_account()
+        .await?
+        .call(env.contract.id(), "liquidate")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "owners": [liquidated.id()],
+            "refresh": true
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()
+        .expect_err("a stale pulled price should not be stored");
+    assert!(format!("{err:?}").contains("Pulled price is older than the feed"));
+
+    let price: Value = env
+        .contract
+        .view("get_price")
+        .args_json(json!({ "collateral_id": env.collateral_token.id() }))
+        .await?
+        .json()?;
+    assert_eq!(price["price"], "20000");
+    let trove: Value = env
+        .contract
+        .view("get_trove")
+        .args_json(json!({
+            "owner_id": liquidated.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
+        .await?
+        .json()?;
+    assert_ne!(trove, Value::Null, "trove should survive the stale price");
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn stability_pool_new_deposit_does_not_get_past_rewards() -> Result<()> {
@@ -402,7 +611,7 @@ async fn stability_pool_new_deposit_does_not_get_past_rewards() -> Result<()> {
 
     env.borrower
         .call(env.contract.id(), "deposit_to_stability_pool")
-        .args_json(json!({ "amount": "4000" }))
+        .args_json(json!({ "amount": "4000", "collateral_id": env.collateral_token.id() }))
         .deposit(NearToken::from_yoctonear(1))
         .max_gas()
         .transact()
@@ -465,7 +674,7 @@ async fn stability_pool_new_deposit_does_not_get_past_rewards() -> Result<()> {
 
     late_depositor
         .call(env.contract.id(), "deposit_to_stability_pool")
-        .args_json(json!({ "amount": "10" }))
+        .args_json(json!({ "amount": "10", "collateral_id": env.collateral_token.id() }))
         .deposit(NearToken::from_yoctonear(1))
         .max_gas()
         .transact()
@@ -589,6 +798,165 @@ async fn redeem_reduces_trove_and_awards_collateral() -> Result<()> {
         total_debt, "7000",
         "system debt should reflect redemption burn"
     );
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn redemption_fee_is_quoted_and_sent_to_treasury() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let target = env.worker.dev_create_account().await?;
+
+    env.owner
+        .call(env.contract.id(), "register_collateral")
+        .args_json(json!({
+            "token_id": env.collateral_token.id(),
+            "config": {
+                "oracle_price_id": "usdc",
+                "min_collateral_ratio_bps": 1300,
+                "recovery_collateral_ratio_bps": 1500,
+                "debt_ceiling": "1000000000000",
+                "liquidation_penalty_bps": 50,
+                "stability_pool_mode": "Dedicated",
+                "redemption_fee_bps": 500
+            }
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    open_trove_for(&env, &target, "10000", "4000").await?;
+
+    // 4000 nUSD buys 20 collateral at 200; 5% of it is kept as the fee.
+    let quote: (String, String) = env
+        .contract
+        .view("quote_redemption")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "amount": "4000"
+        }))
+        .await?
+        .json()?;
+    assert_eq!(quote, ("19".to_string(), "1".to_string()));
+
+    env.borrower
+        .call(env.contract.id(), "redeem")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "trove_owner": target.id(),
+            "amount": "4000"
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    let trove: Value = env
+        .contract
+        .view("get_trove")
+        .args_json(json!({
+            "owner_id": target.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
+        .await?
+        .json()?;
+    assert_eq!(
+        trove["collateral_amount"], "9980",
+        "the trove gives up the gross collateral"
+    );
+    for (account, expected) in [(env.borrower.id(), "19"), (env.owner.id(), "1")] {
+        let claimable: String = env
+            .contract
+            .view("get_claimable_collateral_reward")
+            .args_json(json!({
+                "account_id": account,
+                "collateral_id": env.collateral_token.id()
+            }))
+            .await?
+            .json()?;
+        assert_eq!(claimable, expected);
+    }
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn redeem_converts_collateral_through_router() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let target = env.worker.dev_create_account().await?;
+    open_trove_for(&env, &target, "10000", "4000").await?;
+
+    let usdt = env
+        .worker
+        .dev_deploy(&load_mock_token_wasm().await?)
+        .await?;
+    usdt.call("new")
+        .args_json(json!({
+            "owner_id": env.owner.id(),
+            "metadata": {
+                "spec": "ft-1.0.0",
+                "name": "Mock USDT",
+                "symbol": "mUSDT",
+                "icon": null,
+                "reference": null,
+                "reference_hash": null,
+                "decimals": 24
+            }
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    ensure_token_storage(&usdt, env.router.as_account()).await?;
+    ensure_token_storage(&usdt, &env.borrower).await?;
+    mint_collateral(&usdt, &env.owner, env.router.as_account(), "100").await?;
+
+    let redeem_converted = || async {
+        env.borrower
+            .call(env.contract.id(), "redeem")
+            .args_json(json!({
+                "collateral_id": env.collateral_token.id(),
+                "trove_owner": target.id(),
+                "amount": "2000",
+                "convert": {
+                    "output_token": usdt.id(),
+                    "min_out": "10",
+                    "routing_hint": "usdc-usdt"
+                }
+            }))
+            .deposit(NearToken::from_millinear(100))
+            .max_gas()
+            .transact()
+            .await?
+            .into_result()?;
+        anyhow::Ok(())
+    };
+
+    // 2000 nUSD redeems 10 collateral, swapped 1:1 by the mock router.
+    redeem_converted().await?;
+    assert_eq!(ft_balance(&usdt, &env.borrower).await?, "10");
+    assert_eq!(claimable_reward(&env, &env.borrower).await?, "0");
+
+    env.owner
+        .call(env.router.id(), "set_should_fail")
+        .args_json(json!({ "should_fail": true }))
+        .transact()
+        .await?
+        .into_result()?;
+    redeem_converted().await?;
+    assert_eq!(ft_balance(&usdt, &env.borrower).await?, "10");
+    assert_eq!(
+        claimable_reward(&env, &env.borrower).await?,
+        "10",
+        "a failed swap should leave the raw collateral claimable"
+    );
+    assert_invariants(&env).await?;
 
     Ok(())
 }
@@ -600,7 +968,7 @@ async fn stability_pool_withdraw_returns_balance() -> Result<()> {
 
     env.borrower
         .call(env.contract.id(), "deposit_to_stability_pool")
-        .args_json(json!({ "amount": "3000" }))
+        .args_json(json!({ "amount": "3000", "collateral_id": env.collateral_token.id() }))
         .deposit(NearToken::from_yoctonear(1))
         .max_gas()
         .transact()
@@ -609,7 +977,7 @@ async fn stability_pool_withdraw_returns_balance() -> Result<()> {
 
     env.borrower
         .call(env.contract.id(), "withdraw_from_stability_pool")
-        .args_json(json!({ "amount": "1000" }))
+        .args_json(json!({ "amount": "1000", "collateral_id": env.collateral_token.id() }))
         .deposit(NearToken::from_yoctonear(1))
         .max_gas()
         .transact()
@@ -619,10 +987,14 @@ async fn stability_pool_withdraw_returns_balance() -> Result<()> {
     let remaining: String = env
         .contract
         .view("get_stability_pool_deposit")
-        .args_json(json!({ "account_id": env.borrower.id() }))
+        .args_json(json!({
+            "account_id": env.borrower.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
         .await?
         .json()?;
     assert_eq!(remaining, "2000", "partial withdraw should leave the rest");
+    assert_invariants(&env).await?;
 
     let borrower_balance = nusd_balance(&env.contract, &env.borrower).await?;
     assert_eq!(
@@ -632,7 +1004,9 @@ async fn stability_pool_withdraw_returns_balance() -> Result<()> {
 
     env.borrower
         .call(env.contract.id(), "withdraw_from_stability_pool")
-        .args_json(json!({ "amount": Option::<String>::None }))
+        .args_json(
+            json!({ "amount": Option::<String>::None, "collateral_id": env.collateral_token.id() }),
+        )
         .deposit(NearToken::from_yoctonear(1))
         .max_gas()
         .transact()
@@ -642,49 +1016,907 @@ async fn stability_pool_withdraw_returns_balance() -> Result<()> {
     let final_balance: String = env
         .contract
         .view("get_stability_pool_deposit")
-        .args_json(json!({ "account_id": env.borrower.id() }))
+        .args_json(json!({
+            "account_id": env.borrower.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
         .await?
         .json()?;
     assert_eq!(
         final_balance, "0",
         "withdrawing without amount should drain deposit"
     );
+    assert_invariants(&env).await?;
 
     Ok(())
 }
 
-async fn open_trove_for(
-    env: &TestEnv,
-    borrower: &Account,
-    collateral_amount: &str,
-    debt_amount: &str,
-) -> Result<()> {
-    borrower
-        .call(env.contract.id(), "storage_deposit")
-        .args_json(json!({
-            "account_id": borrower.id(),
-            "registration_only": Option::<bool>::None
-        }))
-        .deposit(NearToken::from_near(1))
-        .max_gas()
-        .transact()
-        .await?
-        .into_result()?;
-
-    ensure_token_storage(&env.collateral_token, borrower).await?;
+#[tokio::test]
+#[serial]
+async fn treasury_convert_swaps_penalty_collateral_to_nusd() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let liquidated = env.worker.dev_create_account().await?;
+    let funder = env.worker.dev_create_account().await?;
+
+    open_trove_for(&env, &liquidated, "10000", "4000").await?;
+    env.borrower
+        .call(env.contract.id(), "deposit_to_stability_pool")
+        .args_json(json!({ "amount": "4000", "collateral_id": env.collateral_token.id() }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    submit_price(&env, "5", 2).await?;
+    env.borrower
+        .call(env.contract.id(), "liquidate")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "owners": [liquidated.id()]
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // Seed the router with nUSD so it can pay out the swap.
+    open_trove_for(&env, &funder, "10000", "1000").await?;
+    funder
+        .call(env.contract.id(), "storage_deposit")
+        .args_json(json!({ "account_id": env.router.id(), "registration_only": true }))
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    funder
+        .call(env.contract.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": env.router.id(), "amount": "1000" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    ensure_token_storage(&env.collateral_token, env.router.as_account()).await?;
+
+    env.owner
+        .call(env.contract.id(), "treasury_convert")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "amount": "30",
+            "min_out": "30",
+            "routing_hint": Option::<String>::None
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    assert_eq!(nusd_balance(&env.contract, &env.owner).await?, "30");
+    assert_eq!(
+        claimable_reward(&env, &env.owner).await?,
+        "20",
+        "converted collateral should leave the treasury reward balance"
+    );
+
+    env.owner
+        .call(env.router.id(), "set_should_fail")
+        .args_json(json!({ "should_fail": true }))
+        .transact()
+        .await?
+        .into_result()?;
+    env.owner
+        .call(env.contract.id(), "treasury_convert")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "amount": "20",
+            "min_out": "20",
+            "routing_hint": Option::<String>::None
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    assert_eq!(nusd_balance(&env.contract, &env.owner).await?, "30");
+    assert_eq!(
+        claimable_reward(&env, &env.owner).await?,
+        "20",
+        "failed swap should restore the treasury collateral"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn manual_swap_refunds_unused_router_deposit() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    ensure_token_storage(&env.collateral_token, env.router.as_account()).await?;
     mint_collateral(
         &env.collateral_token,
-        &env.owner,
+        &env.owner,
+        env.router.as_account(),
+        "100",
+    )
+    .await?;
+    env.owner
+        .call(env.router.id(), "set_max_deposit_used")
+        .args_json(json!({
+            "max_deposit_used": NearToken::from_millinear(250).as_yoctonear().to_string()
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let before = env.owner.view_account().await?.balance;
+    env.owner
+        .call(env.contract.id(), "trigger_swap_via_intents")
+        .args_json(json!({
+            "input_token": env.collateral_token.id(),
+            "output_token": env.collateral_token.id(),
+            "amount_in": "10",
+            "min_out": "10",
+            "routing_hint": Option::<String>::None
+        }))
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let after = env.owner.view_account().await?.balance;
+
+    assert_eq!(ft_balance(&env.collateral_token, &env.owner).await?, "10");
+    // The router kept 0.25 NEAR; the rest came back, less gas.
+    let spent = before.saturating_sub(after);
+    assert!(
+        spent >= NearToken::from_millinear(250) && spent < NearToken::from_millinear(300),
+        "unused deposit should be refunded, spent {spent}"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn failed_swap_is_retried_through_fallback_router() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let liquidated = env.worker.dev_create_account().await?;
+    let funder = env.worker.dev_create_account().await?;
+
+    open_trove_for(&env, &liquidated, "10000", "4000").await?;
+    env.borrower
+        .call(env.contract.id(), "deposit_to_stability_pool")
+        .args_json(json!({ "amount": "4000", "collateral_id": env.collateral_token.id() }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    submit_price(&env, "5", 2).await?;
+    env.borrower
+        .call(env.contract.id(), "liquidate")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "owners": [liquidated.id()]
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    let fallback = env
+        .worker
+        .dev_deploy(&load_mock_router_wasm().await?)
+        .await?;
+    fallback
+        .call("new")
+        .args_json(json!({ "owner_id": env.owner.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    // Only the fallback router holds nUSD to pay out the swap.
+    open_trove_for(&env, &funder, "10000", "1000").await?;
+    funder
+        .call(env.contract.id(), "storage_deposit")
+        .args_json(json!({ "account_id": fallback.id(), "registration_only": true }))
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    funder
+        .call(env.contract.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": fallback.id(), "amount": "1000" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    ensure_token_storage(&env.collateral_token, fallback.as_account()).await?;
+
+    env.owner
+        .call(env.router.id(), "set_should_fail")
+        .args_json(json!({ "should_fail": true }))
+        .transact()
+        .await?
+        .into_result()?;
+    env.owner
+        .call(env.contract.id(), "set_fallback_router")
+        .args_json(json!({ "fallback_router_id": fallback.id(), "retry_on_failure": true }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    env.owner
+        .call(env.contract.id(), "treasury_convert")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "amount": "30",
+            "min_out": "30",
+            "routing_hint": Option::<String>::None
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    assert_eq!(nusd_balance(&env.contract, &env.owner).await?, "30");
+    assert_eq!(
+        claimable_reward(&env, &env.owner).await?,
+        "20",
+        "fallback swap should consume the treasury collateral"
+    );
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn liquidate_and_rebalance_refills_stability_pool() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let first = env.worker.dev_create_account().await?;
+    let second = env.worker.dev_create_account().await?;
+    let funder = env.worker.dev_create_account().await?;
+
+    open_trove_for(&env, &first, "10000", "1000").await?;
+    open_trove_for(&env, &second, "10000", "1000").await?;
+    open_trove_for(&env, &funder, "1000000", "3000").await?;
+    for (depositor, amount) in [(&env.borrower, "4000"), (&funder, "2000")] {
+        depositor
+            .call(env.contract.id(), "deposit_to_stability_pool")
+            .args_json(json!({ "amount": amount, "collateral_id": env.collateral_token.id() }))
+            .deposit(NearToken::from_yoctonear(1))
+            .max_gas()
+            .transact()
+            .await?
+            .into_result()?;
+    }
+
+    // Seed the router with nUSD so it can pay out the swap.
+    funder
+        .call(env.contract.id(), "storage_deposit")
+        .args_json(json!({ "account_id": env.router.id(), "registration_only": true }))
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    funder
+        .call(env.contract.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": env.router.id(), "amount": "1000" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    env.owner
+        .call(env.contract.id(), "set_rebalance_fraction_bps")
+        .args_json(json!({ "rebalance_fraction_bps": 1000 }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    submit_price(&env, "1", 2).await?;
+
+    // 10000 seized, 50 penalty, 10% of the remaining 9950 swapped back to nUSD.
+    env.borrower
+        .call(env.contract.id(), "liquidate_and_rebalance")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "owners": [first.id()],
+            "min_out": "995",
+            "routing_hint": Option::<String>::None
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(stability_pool_balance(&env).await?, "5995");
+    assert_invariants(&env).await?;
+
+    env.owner
+        .call(env.router.id(), "set_should_fail")
+        .args_json(json!({ "should_fail": true }))
+        .transact()
+        .await?
+        .into_result()?;
+    env.borrower
+        .call(env.contract.id(), "liquidate_and_rebalance")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "owners": [second.id()],
+            "min_out": "995",
+            "routing_hint": Option::<String>::None
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(stability_pool_balance(&env).await?, "4995");
+    assert_eq!(
+        claimable_reward(&env, &env.borrower).await?,
+        "12603",
+        "failed swap should leave the withheld collateral with depositors"
+    );
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn fee_on_transfer_collateral_is_credited_net() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let depositor = env.worker.dev_create_account().await?;
+
+    env.owner
+        .call(env.collateral_token.id(), "set_transfer_fee_bps")
+        .args_json(json!({ "transfer_fee_bps": 100 }))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+    env.owner
+        .call(env.contract.id(), "register_collateral")
+        .args_json(json!({
+            "token_id": env.collateral_token.id(),
+            "config": {
+                "oracle_price_id": "usdc",
+                "min_collateral_ratio_bps": 1300,
+                "recovery_collateral_ratio_bps": 1500,
+                "debt_ceiling": "1000000000000",
+                "liquidation_penalty_bps": 50,
+                "stability_pool_mode": "Dedicated",
+                "has_transfer_fee": true,
+                "transfer_fee_bps": 100
+            }
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    open_trove_for(&env, &depositor, "10000", "1000").await?;
+
+    let contract_balance: String = env
+        .collateral_token
+        .view("ft_balance_of")
+        .args_json(json!({ "account_id": env.contract.id() }))
+        .await?
+        .json()?;
+    assert_eq!(
+        contract_balance, "19900",
+        "token should have taken a 1% fee"
+    );
+    let trove: Value = env
+        .contract
+        .view("get_trove")
+        .args_json(json!({
+            "owner_id": depositor.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
+        .await?
+        .json()?;
+    assert_eq!(trove["collateral_amount"], "9900");
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn price_drop_flips_collateral_into_recovery_mode_and_back() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    assert!(!in_recovery_mode(&env).await?);
+
+    // 10000 collateral at 0.056 against 4000 debt is a 1400 bps TCR: above
+    // MCR (1300) but below the recovery ratio (1500).
+    submit_price(&env, "56", 3).await?;
+    assert!(in_recovery_mode(&env).await?);
+    let tcr: String = env
+        .contract
+        .view("system_collateral_ratio")
+        .args_json(json!({ "collateral_id": env.collateral_token.id() }))
+        .await?
+        .json()?;
+    assert_eq!(tcr, "1400");
+    let err = env
+        .borrower
+        .call(env.contract.id(), "borrow")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "amount": "100"
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()
+        .expect_err("borrow below the recovery ratio should fail");
+    assert!(format!("{err:?}").contains("Would violate recovery ratio"));
+
+    submit_price(&env, "20000", 2).await?;
+    assert!(!in_recovery_mode(&env).await?);
+    env.borrower
+        .call(env.contract.id(), "borrow")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "amount": "100"
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    env.borrower
+        .call(env.contract.id(), "deposit_to_stability_pool")
+        .args_json(json!({ "amount": "4100", "collateral_id": env.collateral_token.id() }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // Back in recovery, a 1365 bps trove is liquidatable even though it is
+    // above MCR; clearing its debt ends recovery mode.
+    submit_price(&env, "56", 3).await?;
+    assert!(in_recovery_mode(&env).await?);
+    let liquidator = env.worker.dev_create_account().await?;
+    liquidator
+        .call(env.contract.id(), "liquidate")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "owners": [env.borrower.id()]
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let trove: Value = env
+        .contract
+        .view("get_trove")
+        .args_json(json!({
+            "owner_id": env.borrower.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
+        .await?
+        .json()?;
+    assert_eq!(trove, Value::Null);
+    assert!(!in_recovery_mode(&env).await?);
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn sync_rebase_flags_negative_rebase() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let depositor = env.worker.dev_create_account().await?;
+    let keeper = env.worker.dev_create_account().await?;
+
+    env.owner
+        .call(env.contract.id(), "register_collateral")
+        .args_json(json!({
+            "token_id": env.collateral_token.id(),
+            "config": {
+                "oracle_price_id": "usdc",
+                "min_collateral_ratio_bps": 1300,
+                "recovery_collateral_ratio_bps": 1500,
+                "debt_ceiling": "1000000000000",
+                "liquidation_penalty_bps": 50,
+                "stability_pool_mode": "Dedicated",
+                "is_rebasing": true
+            }
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    open_trove_for(&env, &depositor, "10000", "1000").await?;
+
+    let contract_balance = ft_balance(&env.collateral_token, env.contract.as_account()).await?;
+    assert_eq!(contract_balance, "20000");
+    env.owner
+        .call(env.collateral_token.id(), "rebase")
+        .args_json(json!({ "account_id": env.contract.id(), "balance": "18000" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let diverged: bool = keeper
+        .call(env.contract.id(), "sync_rebase")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "account_id": depositor.id()
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?
+        .json()?;
+    assert!(diverged, "a 10% negative rebase should be flagged");
+    let divergence: Value = env
+        .contract
+        .view("get_rebase_divergence")
+        .args_json(json!({
+            "account_id": depositor.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
+        .await?
+        .json()?;
+    assert_eq!(divergence["recorded"], "10000");
+    assert_eq!(divergence["actual"], "9000");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn shared_pool_isolates_rewards_per_collateral() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let shared_config = |oracle_price_id: &str| {
+        json!({
+            "oracle_price_id": oracle_price_id,
+            "min_collateral_ratio_bps": 1300,
+            "recovery_collateral_ratio_bps": 1500,
+            "debt_ceiling": "1000000000000",
+            "liquidation_penalty_bps": 50,
+            "stability_pool_mode": "Shared"
+        })
+    };
+
+    let wbtc = env
+        .worker
+        .dev_deploy(&load_mock_token_wasm().await?)
+        .await?;
+    wbtc.call("new")
+        .args_json(json!({
+            "owner_id": env.owner.id(),
+            "metadata": {
+                "spec": "ft-1.0.0",
+                "name": "Mock WBTC",
+                "symbol": "mWBTC",
+                "icon": null,
+                "reference": null,
+                "reference_hash": null,
+                "decimals": 24
+            }
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    ensure_token_storage(&wbtc, &env.owner).await?;
+    ensure_token_storage(&wbtc, env.contract.as_account()).await?;
+    env.owner
+        .call(env.contract.id(), "register_collaterals")
+        .args_json(json!({
+            "entries": [
+                [env.collateral_token.id(), shared_config("usdc")],
+                [wbtc.id(), shared_config("wbtc")]
+            ]
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    env.oracle
+        .call(env.contract.id(), "submit_price")
+        .args_json(json!({
+            "collateral_id": wbtc.id(),
+            "price": "20000",
+            "decimals": 2
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // Two depositors hold 3/4 and 1/4 of the shared pool.
+    let large_depositor = env.worker.dev_create_account().await?;
+    let small_depositor = env.worker.dev_create_account().await?;
+    open_trove_for(&env, &large_depositor, "100000", "3000").await?;
+    open_trove_for(&env, &small_depositor, "100000", "1000").await?;
+    for (depositor, amount) in [(&large_depositor, "3000"), (&small_depositor, "1000")] {
+        depositor
+            .call(env.contract.id(), "deposit_to_stability_pool")
+            .args_json(json!({ "amount": amount, "collateral_id": Option::<String>::None }))
+            .deposit(NearToken::from_yoctonear(1))
+            .max_gas()
+            .transact()
+            .await?
+            .into_result()?;
+    }
+
+    let usdc_victim = env.worker.dev_create_account().await?;
+    let wbtc_victim = env.worker.dev_create_account().await?;
+    open_trove_for(&env, &usdc_victim, "1000", "400").await?;
+    open_trove_in(&env, &wbtc, &wbtc_victim, "1000", "800").await?;
+
+    // 995 collateral (1000 less the 0.5% penalty) splits 746 / 248.
+    liquidate_at_price(&env, &env.collateral_token, &usdc_victim, "5").await?;
+    let usdc = &env.collateral_token;
+    assert_eq!(
+        claimable_reward_in(&env, usdc, &large_depositor).await?,
+        "746"
+    );
+    assert_eq!(
+        claimable_reward_in(&env, usdc, &small_depositor).await?,
+        "248"
+    );
+    assert_eq!(
+        claimable_reward_in(&env, &wbtc, &large_depositor).await?,
+        "0"
+    );
+    assert_eq!(
+        claimable_reward_in(&env, &wbtc, &small_depositor).await?,
+        "0"
+    );
+
+    liquidate_at_price(&env, &wbtc, &wbtc_victim, "5").await?;
+    assert_eq!(
+        claimable_reward_in(&env, &wbtc, &large_depositor).await?,
+        "746"
+    );
+    assert_eq!(
+        claimable_reward_in(&env, &wbtc, &small_depositor).await?,
+        "248"
+    );
+    assert_eq!(
+        claimable_reward_in(&env, usdc, &large_depositor).await?,
+        "746",
+        "a wbtc liquidation must not touch usdc rewards"
+    );
+    assert_eq!(
+        claimable_reward_in(&env, usdc, &small_depositor).await?,
+        "248"
+    );
+
+    let pool_balance: String = env
+        .contract
+        .view("get_stability_pool_balance")
+        .args_json(json!({ "collateral_id": Option::<String>::None }))
+        .await?
+        .json()?;
+    assert_eq!(pool_balance, "2800");
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+/// First-depositor inflation attack: the attacker holds the only shares of an
+/// empty pool, inflates its nUSD with a borrow fee routed to the pool, and
+/// hopes a later deposit rounds down to a share count the attacker then
+/// splits.  The virtual shares absorb most of the donation, so the attacker
+/// loses money and the second depositor keeps its deposit.
+#[tokio::test]
+#[serial]
+async fn stability_pool_resists_first_depositor_inflation() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let attacker = &env.borrower;
+    let victim = env.worker.dev_create_account().await?;
+
+    env.owner
+        .call(env.contract.id(), "register_collateral")
+        .args_json(json!({
+            "token_id": env.collateral_token.id(),
+            "config": {
+                "oracle_price_id": "usdc",
+                "min_collateral_ratio_bps": 1300,
+                "recovery_collateral_ratio_bps": 1500,
+                "debt_ceiling": "1000000000000",
+                "liquidation_penalty_bps": 50,
+                "stability_pool_mode": "Dedicated",
+                "borrow_fee_bps": 1000
+            }
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    env.owner
+        .call(env.contract.id(), "set_fee_to_stability_pool_bps")
+        .args_json(json!({ "fee_to_stability_pool_bps": 10000 }))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+
+    attacker
+        .call(env.contract.id(), "deposit_to_stability_pool")
+        .args_json(json!({ "amount": "1", "collateral_id": env.collateral_token.id() }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    // The 10_000 fee lands in the pool, behind the attacker's single share.
+    attacker
+        .call(env.contract.id(), "borrow")
+        .args_json(json!({ "collateral_id": env.collateral_token.id(), "amount": "100000" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let donated = 10_000u128;
+
+    open_trove_for(&env, &victim, "10000", "20000").await?;
+    victim
+        .call(env.contract.id(), "deposit_to_stability_pool")
+        .args_json(json!({ "amount": "20000", "collateral_id": env.collateral_token.id() }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    let before: u128 = nusd_balance(&env.contract, attacker).await?.parse()?;
+    attacker
+        .call(env.contract.id(), "withdraw_from_stability_pool")
+        .args_json(
+            json!({ "amount": Option::<String>::None, "collateral_id": env.collateral_token.id() }),
+        )
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let withdrawn = nusd_balance(&env.contract, attacker)
+        .await?
+        .parse::<u128>()?
+        - before;
+    ensure!(
+        withdrawn < 1 + donated,
+        "attacker withdrew {withdrawn}, at least the {} it put in",
+        1 + donated
+    );
+
+    let victim_deposit: String = env
+        .contract
+        .view("get_stability_pool_deposit")
+        .args_json(json!({
+            "account_id": victim.id(),
+            "collateral_id": env.collateral_token.id()
+        }))
+        .await?
+        .json()?;
+    ensure!(
+        victim_deposit.parse::<u128>()? >= 20_000 - 1,
+        "victim deposit shrank to {victim_deposit}"
+    );
+    assert_invariants(&env).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn list_troves_pages_through_all_owners() -> Result<()> {
+    let env = setup_borrow_env().await?;
+    let mut expected = vec![env.borrower.id().to_string()];
+    for _ in 0..4 {
+        let borrower = env.worker.dev_create_account().await?;
+        open_trove_for(&env, &borrower, "10000", "1000").await?;
+        expected.push(borrower.id().to_string());
+    }
+
+    let count: u64 = env
+        .contract
+        .view("get_trove_count")
+        .args_json(json!({ "collateral_id": env.collateral_token.id() }))
+        .await?
+        .json()?;
+    assert_eq!(count, 5);
+
+    let mut listed = Vec::new();
+    for from_index in [0, 2, 4] {
+        let page: Vec<Value> = env
+            .contract
+            .view("list_troves")
+            .args_json(json!({
+                "collateral_id": env.collateral_token.id(),
+                "from_index": from_index,
+                "limit": 2
+            }))
+            .await?
+            .json()?;
+        assert_eq!(page.len(), if from_index == 4 { 1 } else { 2 });
+        listed.extend(
+            page.iter()
+                .map(|trove| trove["owner_id"].as_str().unwrap_or_default().to_string()),
+        );
+    }
+    listed.sort();
+    expected.sort();
+    assert_eq!(listed, expected);
+
+    Ok(())
+}
+
+async fn open_trove_for(
+    env: &TestEnv,
+    borrower: &Account,
+    collateral_amount: &str,
+    debt_amount: &str,
+) -> Result<()> {
+    open_trove_in(
+        env,
+        &env.collateral_token,
         borrower,
         collateral_amount,
+        debt_amount,
     )
-    .await?;
+    .await
+}
+
+async fn open_trove_in(
+    env: &TestEnv,
+    token: &Contract,
+    borrower: &Account,
+    collateral_amount: &str,
+    debt_amount: &str,
+) -> Result<()> {
+    borrower
+        .call(env.contract.id(), "storage_deposit")
+        .args_json(json!({
+            "account_id": borrower.id(),
+            "registration_only": Option::<bool>::None
+        }))
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    ensure_token_storage(token, borrower).await?;
+    mint_collateral(token, &env.owner, borrower, collateral_amount).await?;
 
     let deposit_msg =
         json!({ "action": "deposit_collateral", "target_account": borrower.id() }).to_string();
 
     borrower
-        .call(env.collateral_token.id(), "ft_transfer_call")
+        .call(token.id(), "ft_transfer_call")
         .args_json(json!({
             "receiver_id": env.contract.id(),
             "amount": collateral_amount,
@@ -699,7 +1931,7 @@ async fn open_trove_for(
     borrower
         .call(env.contract.id(), "borrow")
         .args_json(json!({
-            "collateral_id": env.collateral_token.id(),
+            "collateral_id": token.id(),
             "amount": debt_amount
         }))
         .deposit(NearToken::from_yoctonear(1))
@@ -761,3 +1993,94 @@ async fn nusd_balance(contract: &Contract, account: &Account) -> Result<String>
         .await?
         .json()?)
 }
+
+async fn stability_pool_balance(env: &TestEnv) -> Result<String> {
+    Ok(env
+        .contract
+        .view("get_stability_pool_balance")
+        .args_json(json!({ "collateral_id": env.collateral_token.id() }))
+        .await?
+        .json()?)
+}
+
+async fn claimable_reward(env: &TestEnv, account: &Account) -> Result<String> {
+    claimable_reward_in(env, &env.collateral_token, account).await
+}
+
+async fn claimable_reward_in(env: &TestEnv, token: &Contract, account: &Account) -> Result<String> {
+    Ok(env
+        .contract
+        .view("get_claimable_collateral_reward")
+        .args_json(json!({
+            "account_id": account.id(),
+            "collateral_id": token.id()
+        }))
+        .await?
+        .json()?)
+}
+
+/// Drops the token's price and liquidates `owner` from a fresh account.
+async fn liquidate_at_price(
+    env: &TestEnv,
+    token: &Contract,
+    owner: &Account,
+    price: &str,
+) -> Result<()> {
+    env.oracle
+        .call(env.contract.id(), "submit_price")
+        .args_json(json!({
+            "collateral_id": token.id(),
+            "price": price,
+            "decimals": 2
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    env.worker
+        .dev_create_account()
+        .await?
+        .call(env.contract.id(), "liquidate")
+        .args_json(json!({
+            "collateral_id": token.id(),
+            "owners": [owner.id()]
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn in_recovery_mode(env: &TestEnv) -> Result<bool> {
+    Ok(env
+        .contract
+        .view("is_in_recovery_mode")
+        .args_json(json!({ "collateral_id": env.collateral_token.id() }))
+        .await?
+        .json()?)
+}
+
+async fn submit_price(env: &TestEnv, price: &str, decimals: u8) -> Result<()> {
+    env.oracle
+        .call(env.contract.id(), "submit_price")
+        .args_json(json!({
+            "collateral_id": env.collateral_token.id(),
+            "price": price,
+            "decimals": decimals
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn assert_invariants(env: &TestEnv) -> Result<()> {
+    env.contract
+        .view("assert_invariants")
+        .await
+        .context("contract invariants violated")?;
+    Ok(())
+}